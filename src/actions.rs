@@ -0,0 +1,822 @@
+use crate::app_config::{AuditConfig, DnsCanaryConfig, HooksConfig, HoursConfig, MeteredConfig};
+use crate::audit::{self, Requester};
+use crate::clock;
+use crate::config::{self, BroughtUp, TunnelOptions};
+use crate::conflicts;
+use crate::dns_canary::{self, DnsCheck};
+use crate::encrypted;
+use crate::error;
+use crate::expiry;
+use crate::fetch;
+use crate::health;
+use crate::hooks;
+use crate::hotspot;
+use crate::ifname;
+use crate::mock;
+use crate::netns;
+use crate::owner;
+use crate::precheck;
+use crate::profiles::{self, Profile};
+use crate::refresh::Sinks;
+use crate::runs::{self, Runs};
+use crate::schedule;
+use crate::state::{self, Stage, StateStore, TransitionKind};
+use crate::template;
+use crate::uplink;
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+/// How long an ignored toggle shows the `busy` status.
+const BUSY_DISPLAY: Duration = Duration::from_secs(1);
+
+/// Everything the state-changing commands share besides the profile itself.
+#[derive(Clone)]
+pub struct Context<'a> {
+    pub store: &'a StateStore,
+    /// Told about every change of the status.
+    pub refresh: Sinks,
+    pub debug: bool,
+    pub hooks: &'a HooksConfig,
+    pub metered: &'a MeteredConfig,
+    pub audit: &'a AuditConfig,
+    /// Who the actions are taken for, in the audit log.
+    pub requester: Requester,
+    pub dns_canary: &'a DnsCanaryConfig,
+    /// Windows outside of which a profile may not be up, by interface name.
+    pub allowed_hours: &'a HashMap<String, HoursConfig>,
+    /// Refuse to connect while another VPN interface is up.
+    pub exclusive: bool,
+    /// Minimum time between two toggles of an interface.
+    pub toggle_interval: Duration,
+    /// Time given for the second click confirming a disconnect, if one is required.
+    pub confirm_disconnect: Option<Duration>,
+    /// Time given to the first handshake before the connection is reported.
+    pub handshake_timeout: Option<Duration>,
+    /// Consecutive failures after which the module asks for attention.
+    pub attention_after: u32,
+    /// Which profiles of the directory are considered, from `--match` and `--tag`.
+    pub profile_filter: profiles::Filter<'a>,
+    /// Directory of the profiles.
+    pub profile_dir: &'a Path,
+    /// Tunnel options of a profile, by interface name.
+    pub options_for: &'a (dyn Fn(&str) -> TunnelOptions<'a> + Sync),
+}
+
+/// Bring the interface up, removing whatever was set up if that fails half-way.
+pub fn connect(
+    config_path: &Path,
+    interface_name: &str,
+    options: &TunnelOptions,
+    progress: &dyn Fn(Stage),
+) -> Result<BroughtUp, error::Error> {
+    if fetch::is_fetched(config_path) {
+        progress(Stage::Fetching);
+        fetch::fetch(config_path, interface_name)?;
+    }
+    if template::is_template(config_path) {
+        template::render(config_path, options.variables)?;
+    }
+    if encrypted::is_encrypted(config_path) {
+        encrypted::decrypt(config_path)?;
+    }
+    let tunnel = config::TunnelBuilder::new(config_path, interface_name, options);
+    // Nothing is changed before the plan is complete.
+    let plan = tunnel.plan(progress)?;
+    match tunnel.apply(plan, progress) {
+        Ok(brought_up) => Ok(brought_up),
+        Err(e) => {
+            if let error::Error::WireGuardApi { .. }
+            | error::Error::Routing(_)
+            | error::Error::Netns(_)
+            | error::Error::SplitTunnel(_) = e.root()
+            {
+                tunnel.teardown()?;
+            }
+            Err(e)
+        }
+    }
+}
+
+impl Context<'_> {
+    /// Mark the interface as changing state and let Waybar show it right away.
+    fn begin_transition(
+        &self,
+        interface_name: &str,
+        kind: TransitionKind,
+    ) -> Result<(), error::Error> {
+        self.store
+            .update(interface_name, |state| state.set_transition(kind))?;
+        self.show_progress();
+        Ok(())
+    }
+
+    /// Record the current step of a connection and let Waybar show it.
+    fn report_stage(&self, interface_name: &str, stage: Stage) {
+        if let Err(e) = self
+            .store
+            .update(interface_name, |state| state.set_stage(stage))
+            && self.debug
+        {
+            println!("Failed to record stage {}: {}", stage.as_str(), e);
+        }
+        self.show_progress();
+    }
+
+    /// Refresh the UI for an intermediate update, where failing is not worth
+    /// aborting and waiting for Waybar to show up is left to the final refresh.
+    fn show_progress(&self) {
+        if let Err(e) = self.refresh.update()
+            && self.debug
+        {
+            println!("Failed to refresh: {}", e);
+        }
+    }
+
+    /// Refresh the UI once an action is done, where failing is not worth aborting.
+    fn refresh_waybar(&self) {
+        if let Err(e) = self.refresh.refresh()
+            && self.debug
+        {
+            println!("Failed to refresh: {}", e);
+        }
+    }
+
+    /// Refuse to connect `profile` outside its hours, once expired, or next
+    /// to another VPN with `--exclusive`.
+    fn check_may_connect(&self, profile: &Profile) -> Result<(), error::Error> {
+        self.check_allowed_hours(profile)?;
+        expiry::check(profile)?;
+        if self.exclusive {
+            let conflicts = conflicts::detect(&profile.name);
+            if !conflicts.is_empty() {
+                return Err(error::Error::Conflict(conflicts::describe(&conflicts)));
+            }
+        }
+        Ok(())
+    }
+
+    fn connect(&self, profile: &Profile) -> Result<(), error::Error> {
+        self.check_may_connect(profile)?;
+        if self.profile_filter.interface_template.is_some() {
+            ifname::claim(&profile.name, &profile.path)?;
+        }
+        let mut options = (self.options_for)(&profile.name);
+        if self.metered.disable_keepalive && self.is_metered(profile) {
+            options.keepalive = false;
+        }
+        hooks::dispatch(
+            "pre-up",
+            &profile.name,
+            json!({"config": profile.path}),
+            self.debug,
+        );
+        let progress = |stage| self.report_stage(&profile.name, stage);
+        let brought_up = connect(&profile.path, &profile.name, &options, &progress)?;
+        if let Some(family) = brought_up.family {
+            if self.debug {
+                println!("{} connected over {}", profile.name, family.as_str());
+            }
+            // Tried first next time.
+            self.store
+                .update(&profile.name, |state| state.endpoint_family = Some(family))?;
+        }
+        let timed_out = self
+            .handshake_timeout
+            .and_then(|timeout| self.wait_handshake(profile, options.netns, timeout));
+        let clock_skew = timed_out.and_then(|_| self.check_clock());
+        let dns_check = timed_out
+            .is_none()
+            .then(|| self.check_dns(profile, options.netns))
+            .flatten();
+        let health_check_failure = timed_out
+            .is_none()
+            .then(|| self.check_health(profile, options.netns))
+            .flatten();
+        let session_start = config::transfer(&profile.name, options.netns).ok();
+        // Routing inside a namespace says nothing of the hotspot's clients.
+        let hotspot = options
+            .netns
+            .is_none()
+            .then(|| hotspot::check(&profile.name))
+            .flatten();
+        self.store.update(&profile.name, |state| {
+            state.handshake_timed_out = timed_out;
+            state.clock_skew = clock_skew;
+            state.session_start = session_start;
+            state.dns_check = dns_check;
+            state.health_check_failure = health_check_failure;
+            state.hotspot = hotspot;
+            state.strict_rp_filter = brought_up.strict_rp_filter;
+        })
+    }
+
+    /// Request the `# HealthCheck` URL of the profile, returning why it failed if it did.
+    fn check_health(&self, profile: &Profile, netns: Option<&str>) -> Option<String> {
+        let url = profile.health_check()?;
+        let failure = health::request(&url, netns, Duration::from_secs(5)).err();
+        if self.debug {
+            println!(
+                "Health check of {}: {}",
+                url,
+                failure.as_deref().unwrap_or("ok")
+            );
+        }
+        failure
+    }
+
+    /// Seconds the clock is off by after a handshake timeout, if enough to explain it.
+    fn check_clock(&self) -> Option<i64> {
+        match clock::skew(Duration::from_secs(2)) {
+            Ok(skew) => {
+                let skew = skew.round() as i64;
+                if self.debug {
+                    println!("Clock skew: {} s", skew);
+                }
+                (skew.abs() >= clock::SKEW_THRESHOLD).then_some(skew)
+            }
+            Err(e) => {
+                if self.debug {
+                    println!("Cannot check the clock: {}", e);
+                }
+                None
+            }
+        }
+    }
+
+    /// Resolve the canary name through the first DNS server of the profile, if it sets one.
+    fn check_dns(&self, profile: &Profile, netns: Option<&str>) -> Option<DnsCheck> {
+        // A simulated tunnel reaches no DNS server.
+        if !self.dns_canary.enabled || mock::enabled() {
+            return None;
+        }
+        let server = *config::dns_servers(&profile.path).ok()?.first()?;
+        let check = dns_canary::check(
+            server,
+            &self.dns_canary.name,
+            netns,
+            Duration::from_millis(self.dns_canary.timeout),
+        );
+        if self.debug {
+            println!("{}", check.describe());
+        }
+        Some(check)
+    }
+
+    /// Wait for the first handshake, returning the seconds waited if none came.
+    ///
+    /// The tunnel is left up either way, the status showing a warning otherwise.
+    fn wait_handshake(
+        &self,
+        profile: &Profile,
+        netns: Option<&str>,
+        timeout: Duration,
+    ) -> Option<u64> {
+        self.report_stage(&profile.name, Stage::Handshaking);
+        match health::wait_online(&profile.name, netns, None, timeout, self.debug) {
+            Ok(()) => None,
+            Err(e) => {
+                if self.debug {
+                    println!("{}", e);
+                }
+                Some(timeout.as_secs())
+            }
+        }
+    }
+
+    /// Briefly show that a toggle was ignored, then the actual status again.
+    fn show_busy(&self, profile: &Profile) -> Result<(), error::Error> {
+        let until = state::now_millis() + BUSY_DISPLAY.as_millis() as u64;
+        self.store
+            .update(&profile.name, |state| state.busy_until = Some(until))?;
+        self.show_progress();
+        std::thread::sleep(BUSY_DISPLAY);
+        self.show_progress();
+        Ok(())
+    }
+
+    /// Ask for a second click before disconnecting, returning whether it was given.
+    ///
+    /// The first click only records when the confirmation window closes: the
+    /// module shows it until then, and its status afterwards, from that time.
+    fn confirm_disconnect(&self, profile: &Profile) -> Result<bool, error::Error> {
+        let Some(window) = self.confirm_disconnect else {
+            return Ok(true);
+        };
+        let now = state::now_millis();
+        if self
+            .store
+            .load(&profile.name)?
+            .confirm_until
+            .is_some_and(|until| until > now)
+        {
+            return Ok(true);
+        }
+        let until = now + window.as_millis() as u64;
+        self.store
+            .update(&profile.name, |state| state.confirm_until = Some(until))?;
+        self.show_progress();
+        Ok(false)
+    }
+
+    /// Whether the uplink the profile would use is metered.
+    fn is_metered(&self, profile: &Profile) -> bool {
+        uplink::for_profile(&profile.path).is_some_and(|uplink| uplink.metered)
+    }
+
+    /// Whether `profile` may be up now, per the allowed hours of the app config.
+    pub fn is_allowed_now(&self, profile: &Profile) -> bool {
+        self.allowed_hours
+            .get(&profile.name)
+            .is_none_or(|hours| hours.allows(&schedule::local_now()))
+    }
+
+    fn check_allowed_hours(&self, profile: &Profile) -> Result<(), error::Error> {
+        match self.allowed_hours.get(&profile.name) {
+            Some(hours) if !self.is_allowed_now(profile) => Err(error::Error::Restricted(format!(
+                "{} may only be up {}",
+                profile.name, hours
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Refuse a connection the user did not ask for on a metered uplink, if configured.
+    fn check_auto_connect(&self, profile: &Profile) -> Result<(), error::Error> {
+        if self.metered.refuse_auto_connect && self.is_metered(profile) {
+            return Err(error::Error::Metered(format!(
+                "not connecting {} automatically",
+                profile.name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Refuse to touch a tunnel another user brought up, before anything is torn down.
+    fn check_owner(&self, profile: &Profile) -> Result<(), error::Error> {
+        match owner::other(&profile.name) {
+            Some(owner) if matches!(self.is_active(profile), Ok(true)) => Err(error::Error::InUse(
+                format!("{} was brought up by {}", profile.name, owner.user),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn disconnect(&self, profile: &Profile) -> Result<(), error::Error> {
+        self.check_owner(profile)?;
+        let options = (self.options_for)(&profile.name);
+        config::TunnelBuilder::new(&profile.path, &profile.name, &options).teardown()?;
+        fetch::discard(&profile.path);
+        template::discard(&profile.path);
+        encrypted::discard(&profile.path);
+        Ok(())
+    }
+
+    fn is_active(&self, profile: &Profile) -> Result<bool, error::Error> {
+        config::is_active(&profile.name, (self.options_for)(&profile.name).netns)
+    }
+
+    fn run_hook(&self, command: &Option<String>, interface_name: &str) {
+        if let Some(command) = command {
+            hooks::run(command, interface_name, None, self.debug);
+        }
+    }
+
+    /// Count a failed connection, asking for attention once they keep coming.
+    fn count_failure(&self, state: &mut state::InterfaceState, profile: &Profile, reason: &str) {
+        state.failures += 1;
+        if state.failures >= self.attention_after {
+            state.attention = true;
+            hooks::notify_critical(
+                "VPN keeps failing",
+                &format!(
+                    "{}: {} failures in a row, last: {}",
+                    profile.name, state.failures, reason
+                ),
+                self.debug,
+            );
+        }
+    }
+
+    /// The profiles of the directory `--match` and `--tag` select.
+    pub fn profiles(&self) -> Result<Vec<Profile>, error::Error> {
+        profiles::discover(self.profile_dir, self.profile_filter)
+    }
+
+    /// Trace `action` on `profile` and how it ended, if the audit log is enabled.
+    pub fn audit(&self, action: &str, profile: &str, result: &Result<(), error::Error>) {
+        audit::record(self.audit, &self.requester, action, profile, result);
+    }
+
+    /// Trace `action` refused to the requester, if the audit log is enabled.
+    pub fn audit_denied(&self, action: &str, profile: &str) {
+        audit::record_denied(self.audit, &self.requester, action, profile);
+    }
+
+    /// Store the outcome of an action in the state file and run the matching hook.
+    ///
+    /// `now_active` is the profile left up by a successful action, if any; it is
+    /// what gets restored after a reboot.
+    fn record_result(
+        &self,
+        profile: &Profile,
+        result: Result<(), error::Error>,
+        now_active: bool,
+    ) -> Result<(), error::Error> {
+        let action = if now_active { "connect" } else { "disconnect" };
+        self.audit(action, &profile.name, &result);
+        let mut state = self.store.load(&profile.name)?;
+        state.transition = None;
+        state.idle_deadline = None;
+        state.confirm_until = None;
+        state.pending = false;
+        match result {
+            Ok(_) => {
+                state.error = None;
+                state.error_kind = None;
+                if !now_active {
+                    state.handshake_timed_out = None;
+                    state.clock_skew = None;
+                    state.session_start = None;
+                    state.dns_check = None;
+                    state.hotspot = None;
+                } else if state.handshake_timed_out.is_some() {
+                    self.count_failure(&mut state, profile, "no handshake");
+                } else {
+                    state.failures = 0;
+                    state.attention = false;
+                }
+                state.active = now_active.then(|| profile.path.clone());
+                self.store.save(&profile.name, &state)?;
+                if now_active {
+                    if let Err(e) = owner::claim(&profile.name)
+                        && self.debug
+                    {
+                        println!("{}: cannot record who brought it up: {}", profile.name, e);
+                    }
+                } else {
+                    owner::release(&profile.name);
+                }
+                let hook = if now_active {
+                    &self.hooks.on_connect
+                } else {
+                    &self.hooks.on_disconnect
+                };
+                self.run_hook(hook, &profile.name);
+                if now_active {
+                    hooks::dispatch(
+                        "post-up",
+                        &profile.name,
+                        json!({"endpoint_family": state.endpoint_family}),
+                        self.debug,
+                    );
+                }
+            }
+            Err(e) => {
+                state.error = Some(e.to_string());
+                state.error_kind = Some(e.kind());
+                // A policy saying no is not the tunnel flapping.
+                if !e.is_refusal() {
+                    self.count_failure(&mut state, profile, &e.to_string());
+                }
+                self.store.save(&profile.name, &state)?;
+                hooks::dispatch(
+                    "error",
+                    &profile.name,
+                    json!({"error": e.to_string(), "error_kind": e.kind()}),
+                    self.debug,
+                );
+                if let Some(command) = &self.hooks.on_error {
+                    hooks::run(command, &profile.name, Some(&e.to_string()), self.debug);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn toggle(ctx: &Context, profile: &Profile) -> Result<(), error::Error> {
+    if !ctx.store.claim_toggle(&profile.name, ctx.toggle_interval)? {
+        if ctx.debug {
+            println!(
+                "Ignoring toggle of {}, too soon after the last one",
+                profile.name
+            );
+        }
+        return ctx.show_busy(profile);
+    }
+    ctx.check_owner(profile)?;
+    if ctx.store.load(&profile.name)?.pending {
+        ctx.store
+            .update(&profile.name, |state| state.pending = false)?;
+        ctx.refresh.refresh()?;
+        return Ok(());
+    }
+    let was_active = ctx.is_active(profile);
+    let disconnecting = matches!(was_active, Ok(true));
+    if disconnecting && !ctx.confirm_disconnect(profile)? {
+        return Ok(());
+    }
+    if matches!(was_active, Ok(false)) && !uplink::is_online() {
+        // Remembered instead of failing, daemon mode connects once the network is back.
+        ctx.store.update(&profile.name, |state| {
+            state.pending = true;
+            state.error = None;
+            state.error_kind = None;
+        })?;
+        ctx.refresh.refresh()?;
+        return Ok(());
+    }
+    let kind = if disconnecting {
+        TransitionKind::Disconnecting
+    } else {
+        TransitionKind::Connecting
+    };
+    ctx.begin_transition(&profile.name, kind)?;
+    let result = match was_active {
+        Ok(true) => ctx.disconnect(profile),
+        Ok(false) => ctx.connect(profile),
+        Err(e) => Err(e),
+    };
+
+    ctx.record_result(profile, result, !disconnecting)?;
+    ctx.refresh.refresh()?;
+    Ok(())
+}
+
+/// Frontends other than Waybar (tray, JSON-RPC) may run without it, where
+/// failing to signal it is not an error.
+pub fn ignore_missing_waybar(result: Result<(), error::Error>) -> Result<(), error::Error> {
+    match result {
+        Err(error::Error::Signal(_)) => Ok(()),
+        result => result,
+    }
+}
+
+/// Bring `profile` up or down unless it already is, e.g. when a schedule window
+/// opens or closes.
+#[cfg(any(feature = "daemon", feature = "providers"))]
+pub fn ensure(ctx: &Context, profile: &Profile, up: bool) -> Result<(), error::Error> {
+    if ctx.is_active(profile)? == up {
+        return Ok(());
+    }
+    if up {
+        ctx.check_auto_connect(profile)?;
+    }
+    let kind = if up {
+        TransitionKind::Connecting
+    } else {
+        TransitionKind::Disconnecting
+    };
+    ctx.begin_transition(&profile.name, kind)?;
+    let result = if up {
+        ctx.connect(profile)
+    } else {
+        ctx.disconnect(profile)
+    };
+    ctx.record_result(profile, result, up)?;
+    ctx.refresh_waybar();
+    Ok(())
+}
+
+/// Run `command` through `profile`, bringing it up first unless it already is
+/// and back down afterwards if a run brought it up and no other run still
+/// goes through it, e.g. for a backup that must go through the VPN. Returns
+/// the exit code of `command`.
+pub fn run_through(
+    ctx: &Context,
+    profile: &Profile,
+    command: &[String],
+) -> Result<i32, error::Error> {
+    runs::guard_signals();
+    let runs = Runs::open(&profile.name)?;
+    let pid = std::process::id();
+    {
+        let _lock = runs.lock()?;
+        if runs.join(pid, ctx.is_active(profile)?)? {
+            ctx.begin_transition(&profile.name, TransitionKind::Connecting)?;
+            let result = ctx.connect(profile);
+            let failure = result.as_ref().err().map(ToString::to_string);
+            ctx.record_result(profile, result, true)?;
+            ctx.refresh_waybar();
+            if let Some(message) = failure {
+                runs.forget();
+                runs.leave(pid)?;
+                return Err(error::Error::Run(format!(
+                    "{} did not come up: {}",
+                    profile.name, message
+                )));
+            }
+        }
+    }
+    let netns = (ctx.options_for)(&profile.name).netns;
+    let status = netns::command(netns, command)
+        .spawn()
+        .and_then(|mut child| {
+            runs::started(&child);
+            child.wait()
+        });
+    let _lock = runs.lock()?;
+    if runs.leave(pid)? {
+        ctx.begin_transition(&profile.name, TransitionKind::Disconnecting)?;
+        let result = ctx.disconnect(profile);
+        let failure = result.as_ref().err().map(ToString::to_string);
+        ctx.record_result(profile, result, false)?;
+        ctx.refresh_waybar();
+        if let Some(message) = failure {
+            return Err(error::Error::Run(format!(
+                "{} did not go down after {}: {}",
+                profile.name, command[0], message
+            )));
+        }
+    }
+    let status = status.map_err(|e| error::Error::Run(format!("{}: {}", command[0], e)))?;
+    Ok(runs::exit_code(status))
+}
+
+/// Make the connection toggled while offline, once a default route is back.
+#[cfg(feature = "daemon")]
+pub fn complete_pending(ctx: &Context, profile: &Profile) -> Result<(), error::Error> {
+    if !ctx.store.load(&profile.name)?.pending || !uplink::is_online() {
+        return Ok(());
+    }
+    // Like a toggle, so that it neither races one nor follows it too closely.
+    if !ctx
+        .store
+        .claim_pending(&profile.name, ctx.toggle_interval)?
+        || ctx.is_active(profile)?
+    {
+        return Ok(());
+    }
+    if ctx.debug {
+        println!("Network is back, connecting {}", profile.name);
+    }
+    ctx.begin_transition(&profile.name, TransitionKind::Connecting)?;
+    let result = ctx.connect(profile);
+    ctx.record_result(profile, result, true)?;
+    ctx.refresh_waybar();
+    Ok(())
+}
+
+/// Disconnect every other profile of the directory, then connect `target`.
+pub fn switch(ctx: &Context, profiles: &[Profile], target: &str) -> Result<(), error::Error> {
+    let target = profiles::find(profiles, target)?;
+    let others: Vec<&Profile> = profiles
+        .iter()
+        .filter(|profile| profile.name != target.name)
+        .collect();
+    let switching = others
+        .iter()
+        .any(|profile| ctx.is_active(profile).unwrap_or(false));
+    let kind = if switching {
+        TransitionKind::Reconnecting
+    } else {
+        TransitionKind::Connecting
+    };
+    ctx.begin_transition(&target.name, kind)?;
+    let result = (|| {
+        // A profile that cannot be reached must not cost the tunnel in use.
+        // Fetched and encrypted profiles only have a configuration once connecting.
+        if switching && !fetch::is_fetched(&target.path) && !encrypted::is_encrypted(&target.path) {
+            precheck::endpoints(&target.path)?;
+        }
+        let mut previous = Vec::new();
+        for profile in others {
+            if ctx.is_active(profile)? {
+                let result = ctx.disconnect(profile);
+                let disconnected = result.is_ok();
+                ctx.record_result(profile, result, false)?;
+                if disconnected {
+                    previous.push(profile);
+                }
+            }
+        }
+        if !ctx.is_active(target)?
+            && let Err(e) = ctx.connect(target)
+        {
+            if ctx.debug && !previous.is_empty() {
+                let names: Vec<&str> = previous
+                    .iter()
+                    .map(|profile| profile.name.as_str())
+                    .collect();
+                println!(
+                    "Connecting {} failed, restoring {}",
+                    target.name,
+                    names.join(", ")
+                );
+            }
+            for profile in previous {
+                let result = ctx.connect(profile);
+                ctx.record_result(profile, result, true)?;
+            }
+            return Err(e);
+        }
+        Ok(())
+    })();
+
+    ctx.record_result(target, result, true)?;
+    ctx.refresh.refresh()?;
+    Ok(())
+}
+
+/// Tear `target` down and bring it back up, e.g. after moving to another network.
+///
+/// A profile that is down is simply connected. A failed connection is rolled back
+/// like any other, leaving the profile down with the error shown.
+pub fn restart(ctx: &Context, profiles: &[Profile], target: &str) -> Result<(), error::Error> {
+    let target = profiles::find(profiles, target)?;
+    ctx.begin_transition(&target.name, TransitionKind::Reconnecting)?;
+    let result = (|| {
+        // Before the teardown, so that a refused connect leaves the tunnel up.
+        ctx.check_may_connect(target)?;
+        if ctx.is_active(target)? {
+            ctx.disconnect(target)?;
+        }
+        ctx.connect(target)
+    })();
+    ctx.record_result(target, result, true)?;
+    ctx.refresh.refresh()?;
+    Ok(())
+}
+
+/// Forget the state of deleted profiles, keeping that of interfaces still up.
+pub fn collect_garbage(ctx: &Context) {
+    let is_up = |name: &str| config::is_active(name, (ctx.options_for)(name).netns).unwrap_or(true);
+    // Every profile, also those `--match` and `--tag` leave out.
+    let filter = profiles::Filter {
+        pattern: None,
+        tag: None,
+        ..ctx.profile_filter
+    };
+    let names: Vec<String> = match profiles::discover(ctx.profile_dir, filter) {
+        Ok(profiles) => profiles.into_iter().map(|profile| profile.name).collect(),
+        Err(e) => {
+            if ctx.debug {
+                println!("Failed to list the profiles to clean up the state: {}", e);
+            }
+            return;
+        }
+    };
+    let has_profile = |name: &str| names.iter().any(|known| known == name);
+    match ctx.store.collect_garbage(is_up, has_profile) {
+        Ok(removed) => {
+            if ctx.debug && !removed.is_empty() {
+                println!("Forgot the state of {}", removed.join(", "));
+            }
+        }
+        Err(e) => {
+            if ctx.debug {
+                println!("Failed to clean up the state: {}", e);
+            }
+        }
+    }
+}
+
+/// Bring back up every profile the state file records as active but which is down,
+/// e.g. after a reboot.
+pub fn restore(ctx: &Context) -> Result<(), error::Error> {
+    collect_garbage(ctx);
+    let mut restored = false;
+    for name in ctx.store.interfaces()? {
+        let Some(path) = ctx.store.load(&name)?.active else {
+            continue;
+        };
+        let profile = match Profile::from_path(&path) {
+            Ok(profile) => Profile { name, ..profile },
+            Err(e) => {
+                if ctx.debug {
+                    println!("Not restoring {}: {}", name, e);
+                }
+                continue;
+            }
+        };
+        match ctx.is_active(&profile) {
+            Ok(false) => {}
+            Ok(true) => continue,
+            Err(e) => {
+                if ctx.debug {
+                    println!("Not restoring {}: {}", profile.name, e);
+                }
+                continue;
+            }
+        }
+        if let Err(e) = ctx.check_auto_connect(&profile) {
+            if ctx.debug {
+                println!("{}", e);
+            }
+            continue;
+        }
+        if ctx.debug {
+            println!("Restoring {} from {}", profile.name, profile.path.display());
+        }
+        ctx.begin_transition(&profile.name, TransitionKind::Connecting)?;
+        let result = ctx.connect(&profile);
+        ctx.record_result(&profile, result, true)?;
+        restored = true;
+    }
+    if restored {
+        ctx.refresh_waybar();
+    }
+    Ok(())
+}
@@ -0,0 +1,258 @@
+use crate::config;
+use crate::error;
+use crate::import::{self, Imported, Section};
+use crate::routing;
+use crate::state::StateStore;
+use base64::prelude::*;
+use std::path::Path;
+use std::process::Command;
+
+/// `local/prefixlen` of every address of `ip -j addr show dev <interface>`.
+fn parse_addresses(output: &str) -> Vec<String> {
+    let links: serde_json::Value = serde_json::from_str(output).unwrap_or_default();
+    links
+        .get(0)
+        .and_then(|link| link.get("addr_info"))
+        .and_then(|info| info.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|info| {
+            let local = info.get("local")?.as_str()?;
+            let prefix_length = info.get("prefixlen")?.as_u64()?;
+            Some(format!("{}/{}", local, prefix_length))
+        })
+        .collect()
+}
+
+/// `mtu` of `ip -j link show dev <interface>`.
+fn parse_mtu(output: &str) -> Option<u64> {
+    let links: serde_json::Value = serde_json::from_str(output).ok()?;
+    links.get(0)?.get("mtu")?.as_u64()
+}
+
+/// `Table` that installs the routes of `ip -j route show table all dev <interface>`:
+/// `off` without any, the table they are in when they are not in `main` nor in
+/// the table of `fwmark` or [`routing::TUNNEL_FWMARK`], where wg-quick puts
+/// default routes, and `None` for `auto`.
+fn parse_table(output: &str, fwmark: Option<u32>) -> Option<String> {
+    let routes: serde_json::Value = serde_json::from_str(output).unwrap_or_default();
+    // Those of the addresses, not of the peers.
+    let tables: Vec<&str> = routes
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|route| route.get("protocol").and_then(|p| p.as_str()) != Some("kernel"))
+        .map(|route| {
+            route
+                .get("table")
+                .and_then(|t| t.as_str())
+                .unwrap_or("main")
+        })
+        .filter(|table| *table != "local")
+        .collect();
+    if tables.is_empty() {
+        return Some("off".to_string());
+    }
+    tables
+        .into_iter()
+        .filter(|table| *table != "main")
+        .find(|table| {
+            let table = table.parse::<u32>().ok();
+            table != fwmark && table != Some(routing::TUNNEL_FWMARK)
+        })
+        .map(str::to_string)
+}
+
+/// `ip -j <args>` about the interface, in `netns` when given.
+fn ip(netns: Option<&str>, args: &[&str]) -> Option<String> {
+    let mut cmd = Command::new("ip");
+    if let Some(name) = netns {
+        cmd.args(["-n", name]);
+    }
+    cmd.arg("-j").args(args);
+    cmd.output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn addresses(interface_name: &str, netns: Option<&str>) -> Vec<String> {
+    ip(netns, &["addr", "show", "dev", interface_name])
+        .map(|output| parse_addresses(&output))
+        .unwrap_or_default()
+}
+
+/// The mark of the encrypted packets, from `wg show <interface> fwmark`, which
+/// prints `off` without one. Unknown without wireguard-tools.
+fn fwmark(interface_name: &str, netns: Option<&str>) -> Option<u32> {
+    let mut cmd = match netns {
+        Some(name) => {
+            let mut cmd = Command::new("ip");
+            cmd.args(["netns", "exec", name, "wg"]);
+            cmd
+        }
+        None => Command::new("wg"),
+    };
+    let output = cmd
+        .args(["show", interface_name, "fwmark"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let mark = String::from_utf8_lossy(&output.stdout);
+    let mark = mark.trim();
+    match mark.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => mark.parse().ok(),
+    }
+    .filter(|mark| *mark != 0)
+}
+
+/// DNS servers systemd-resolved uses on the interface, from `resolvectl dns <interface>`:
+/// `Link 5 (wg0): 10.2.0.1`.
+fn dns(interface_name: &str) -> Option<String> {
+    let output = Command::new("resolvectl")
+        .args(["dns", interface_name])
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let (_, servers) = output.split_once("):")?;
+    let servers: Vec<&str> = servers.split_whitespace().collect();
+    (!servers.is_empty()).then(|| servers.join(", "))
+}
+
+/// The running interface as a wg-quick configuration.
+///
+/// Endpoints come as the addresses they resolved to.
+fn snapshot(interface_name: &str, netns: Option<&str>) -> Result<String, error::Error> {
+    let host = config::read_host(interface_name, netns)?;
+    let private_key = host
+        .private_key
+        .as_ref()
+        .map(|key| BASE64_STANDARD.encode(key.as_slice()))
+        .ok_or_else(|| error::Error::InvalidFormat {
+            message: format!("{} has no private key", interface_name),
+        })?;
+    let addresses = addresses(interface_name, netns);
+    if addresses.is_empty() {
+        return Err(error::Error::InvalidFormat {
+            message: format!("{} has no address", interface_name),
+        });
+    }
+    let fwmark = fwmark(interface_name, netns);
+    let table = ip(
+        netns,
+        &["route", "show", "table", "all", "dev", interface_name],
+    )
+    .and_then(|output| parse_table(&output, fwmark));
+    let mtu =
+        ip(netns, &["link", "show", "dev", interface_name]).and_then(|output| parse_mtu(&output));
+    let interface: Section = vec![
+        ("PrivateKey", Some(private_key)),
+        ("Address", Some(addresses.join(", "))),
+        (
+            "DNS",
+            netns.is_none().then(|| dns(interface_name)).flatten(),
+        ),
+        (
+            "ListenPort",
+            (host.listen_port != 0).then(|| host.listen_port.to_string()),
+        ),
+        ("MTU", mtu.map(|mtu| mtu.to_string())),
+        ("FwMark", fwmark.map(|mark| format!("{:#x}", mark))),
+        ("Table", table),
+    ];
+    let mut peers: Vec<_> = host.peers.values().collect();
+    peers.sort_by_key(|peer| peer.public_key.as_slice().to_vec());
+    let peers = peers
+        .into_iter()
+        .map(|peer| {
+            let public_key = BASE64_STANDARD.encode(peer.public_key.as_slice());
+            let allowed_ips: Vec<String> = peer
+                .allowed_ips
+                .iter()
+                .map(|network| format!("{}/{}", network.ip, network.cidr))
+                .collect();
+            vec![
+                ("PublicKey", Some(public_key)),
+                (
+                    "PresharedKey",
+                    peer.preshared_key
+                        .as_ref()
+                        .map(|key| BASE64_STANDARD.encode(key.as_slice())),
+                ),
+                (
+                    "Endpoint",
+                    peer.endpoint.map(|endpoint| endpoint.to_string()),
+                ),
+                ("AllowedIPs", Some(allowed_ips.join(", "))),
+                (
+                    "PersistentKeepalive",
+                    peer.persistent_keepalive_interval
+                        .filter(|seconds| *seconds > 0)
+                        .map(|seconds| seconds.to_string()),
+                ),
+            ]
+        })
+        .collect();
+    Ok(import::render(interface, peers))
+}
+
+/// Take over an interface brought up by other means: write its configuration
+/// as a profile of `dir` and record it as up, so that `toggle` and `status`
+/// manage it from now on.
+pub fn run(
+    store: &StateStore,
+    dir: &Path,
+    interface_name: &str,
+    netns: Option<&str>,
+    force: bool,
+) -> Result<(), error::Error> {
+    let imported = Imported {
+        name: interface_name.to_string(),
+        config: snapshot(interface_name, netns)?,
+    };
+    let path = import::write(dir, &imported, force)?;
+    store.update(interface_name, |state| state.active = Some(path.clone()))?;
+    println!("{}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_every_address_of_the_interface() {
+        let ip = r#"[{"ifindex":7,"ifname":"wg0","addr_info":[{"family":"inet","local":"10.2.0.2","prefixlen":32},{"family":"inet6","local":"fd00::2","prefixlen":128}]}]"#;
+        assert_eq!(parse_addresses(ip), ["10.2.0.2/32", "fd00::2/128"]);
+        assert!(parse_addresses("[]").is_empty());
+    }
+
+    #[test]
+    fn reads_the_mtu_and_the_table_of_the_routes() {
+        assert_eq!(
+            parse_mtu(r#"[{"ifindex":7,"ifname":"wg0","mtu":1420}]"#),
+            Some(1420)
+        );
+        let address =
+            r#"{"dst":"10.2.0.0/24","protocol":"kernel","scope":"link","prefsrc":"10.2.0.2"}"#;
+        let local = r#"{"type":"local","dst":"10.2.0.2","table":"local","protocol":"kernel"}"#;
+        let routes = |routes: &[&str]| format!("[{}]", routes.join(","));
+        assert_eq!(
+            parse_table(&routes(&[address, local]), None).as_deref(),
+            Some("off")
+        );
+        assert_eq!(
+            parse_table(&routes(&[r#"{"dst":"10.20.0.0/16","scope":"link"}"#]), None),
+            None
+        );
+        let default = r#"{"dst":"default","table":"51820","scope":"link"}"#;
+        assert_eq!(parse_table(&routes(&[default]), None), None);
+        let default = r#"{"dst":"default","table":"1234","scope":"link"}"#;
+        assert_eq!(parse_table(&routes(&[default]), Some(1234)), None);
+        assert_eq!(
+            parse_table(&routes(&[default]), None).as_deref(),
+            Some("1234")
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use crate::config;
+use crate::error;
+use crate::schedule::{TimeOfDay, Weekday};
+use crate::utils;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+pub const CONFIG_FILENAME: &str = "config.toml";
+
+/// Settings read from `$XDG_CONFIG_HOME/wg-waybar/config.toml`.
+///
+/// Every section is optional; a missing file behaves like an empty one.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    /// Split tunneling settings keyed by interface name.
+    pub split_tunnel: HashMap<String, SplitTunnelConfig>,
+    pub hooks: HooksConfig,
+    pub publish: PublishConfig,
+    /// Windows during which daemon mode keeps a profile connected.
+    pub schedule: Vec<ScheduleConfig>,
+    /// Idle auto-disconnect settings keyed by interface name.
+    pub idle: HashMap<String, IdleConfig>,
+    /// Daemon behavior on battery keyed by interface name.
+    pub battery: HashMap<String, BatteryConfig>,
+    /// Physical interface the encrypted traffic is pinned to, keyed by interface name.
+    pub bind_interface: HashMap<String, String>,
+    pub metered: MeteredConfig,
+    /// Profiles brought up or down by daemon mode as the sway/Hyprland focus changes.
+    pub focus_rule: Vec<FocusRule>,
+    /// Hours outside of which a profile may not be up, keyed by interface name.
+    pub allowed_hours: HashMap<String, HoursConfig>,
+    /// What each user may do through the control socket, keyed by user name.
+    pub access: HashMap<String, AccessConfig>,
+    pub audit: AuditConfig,
+    /// Values of the `{NAME}` placeholders of template profiles, keyed by interface name.
+    pub variables: HashMap<String, HashMap<String, String>>,
+    /// LAN subnets routed through the tunnel while it is up, keyed by interface name.
+    pub gateway: HashMap<String, GatewayConfig>,
+    /// Pool `export-peer` hands addresses out of, keyed by interface name.
+    pub export_peer: HashMap<String, ExportPeerConfig>,
+    pub sysctl: SysctlConfig,
+    pub dns_canary: DnsCanaryConfig,
+    pub refresh: RefreshConfig,
+}
+
+/// What is told to read the status again after a change.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RefreshConfig {
+    pub sinks: Vec<SinkKind>,
+    /// File touched by the `file` sink, `$XDG_RUNTIME_DIR/wg-waybar.stamp` by default.
+    pub file: Option<PathBuf>,
+}
+
+impl Default for RefreshConfig {
+    fn default() -> Self {
+        Self {
+            sinks: vec![SinkKind::Signal],
+            file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SinkKind {
+    /// Waybar's realtime signal, `--signal`.
+    Signal,
+    /// SIGUSR1 to the running `watch` commands.
+    Watch,
+    /// A datagram to the socket of each running `watch`.
+    Ipc,
+    /// Touch a file.
+    File,
+    /// `org.wgwaybar.Status.Changed` on the session bus.
+    Dbus,
+}
+
+/// Name resolved through the DNS server of the tunnel once it is up, to catch
+/// tunnels that are connected but cannot resolve. Off unless enabled, the query
+/// reaching a server the user may not want told.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsCanaryConfig {
+    pub enabled: bool,
+    pub name: String,
+    /// Milliseconds to wait for the answer.
+    pub timeout: u64,
+}
+
+impl Default for DnsCanaryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            name: "example.com".to_string(),
+            timeout: 2000,
+        }
+    }
+}
+
+/// Kernel settings changed for as long as a tunnel is up.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SysctlConfig {
+    /// Switch strict reverse path filtering to loose mode when it would drop
+    /// the replies of a full or policy-routed tunnel, instead of only warning.
+    pub relax_rp_filter: bool,
+}
+
+/// Act as the gateway of a LAN: forward its traffic into the tunnel, masqueraded.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayConfig {
+    /// Subnets of the LAN, e.g. `192.168.50.0/24`.
+    #[serde(default)]
+    pub lan: Vec<String>,
+    /// Cover the subnet of the Wi-Fi hotspot the machine serves, if any, as well.
+    #[serde(default)]
+    pub hotspot: bool,
+    /// firewalld zone of the LAN the tunnel joins, the default zone if unset.
+    #[serde(default)]
+    pub zone: Option<String>,
+}
+
+impl GatewayConfig {
+    /// Refuse a `lan` entry that is not a subnet, before any of it is applied.
+    fn validate(&self, interface_name: &str) -> Result<(), error::Error> {
+        for subnet in &self.lan {
+            let invalid = |reason: String| error::Error::InvalidFormat {
+                message: format!("[gateway.{}] lan {}: {}", interface_name, subnet, reason),
+            };
+            let mask = config::parse_ip_addr_mask(subnet).map_err(|e| invalid(e.to_string()))?;
+            let width = if mask.ip.is_ipv4() { 32 } else { 128 };
+            if mask.cidr > width {
+                return Err(invalid(format!("prefix longer than {} bits", width)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Peers generated by `export-peer` for a profile acting as their server.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportPeerConfig {
+    /// Subnet the addresses of the peers come from, e.g. `10.8.0.0/24`.
+    pub pool: String,
+    /// `host:port` the peers reach the profile at.
+    pub endpoint: String,
+    /// AllowedIPs of the peers towards the profile, the pool if empty.
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+    /// DNS servers of the peers.
+    #[serde(default)]
+    pub dns: Vec<String>,
+}
+
+/// Trail of the tunnels brought up or down with root privileges.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    /// File the records are appended to as JSON lines.
+    pub path: PathBuf,
+    /// Send the records to journald with structured fields instead.
+    pub journald: bool,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: PathBuf::from("/var/log/wg-waybar/audit.jsonl"),
+            journald: false,
+        }
+    }
+}
+
+/// Profiles a user of `serve --socket` may bring up or down.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessConfig {
+    /// Globs of the profile names, e.g. `mullvad-*`.
+    pub profiles: Vec<String>,
+}
+
+/// Connect (or disconnect) `profile` while a workspace or window is focused.
+///
+/// Both `workspace` and `app_id` must match when both are given.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "daemon"), allow(dead_code))]
+pub struct FocusRule {
+    pub profile: String,
+    /// Workspace name.
+    pub workspace: Option<String>,
+    /// Wayland `app_id` or window class of the focused window.
+    pub app_id: Option<String>,
+    #[serde(default)]
+    pub action: FocusAction,
+    /// Undo the action once the rule no longer matches.
+    #[serde(default)]
+    pub revert: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FocusAction {
+    #[default]
+    Connect,
+    Disconnect,
+}
+
+/// Behavior on uplinks NetworkManager flags as metered.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MeteredConfig {
+    /// Skip connections made without the user asking: restore, schedules and focus rules.
+    pub refuse_auto_connect: bool,
+    /// Ignore `PersistentKeepalive`, sending nothing while the tunnel is unused.
+    pub disable_keepalive: bool,
+    /// Skip the handshake probes of `rank`, `list --probe` and `menu --probe`.
+    pub disable_probes: bool,
+}
+
+/// How daemon mode behaves while the machine runs on battery.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "daemon"), allow(dead_code))]
+pub struct BatteryConfig {
+    /// Seconds between two status checks on battery, instead of `--interval`.
+    pub interval: Option<u64>,
+    /// Battery percentage at or below which the tunnel is disconnected.
+    pub critical: Option<u8>,
+    /// Skip the handshake probes of `rank`, `list --probe` and `menu --probe` on battery.
+    #[serde(default)]
+    pub skip_probes: bool,
+}
+
+/// Disconnect the tunnel in daemon mode once its traffic stays low for a while.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "daemon"), allow(dead_code))]
+pub struct IdleConfig {
+    /// Bytes per second, both directions combined, below which the tunnel is idle.
+    #[serde(default = "default_idle_threshold")]
+    pub threshold: u64,
+    /// Minutes of idleness before the tunnel is about to disconnect.
+    pub minutes: u64,
+    /// Seconds between the notification and the disconnect, during which
+    /// resumed traffic cancels it.
+    #[serde(default = "default_idle_grace")]
+    pub grace: u64,
+}
+
+fn default_idle_threshold() -> u64 {
+    1024
+}
+
+fn default_idle_grace() -> u64 {
+    60
+}
+
+/// Connect `profile` when the window opens and disconnect it when it closes.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "daemon"), allow(dead_code))]
+pub struct ScheduleConfig {
+    pub profile: String,
+    /// Days the window opens on, every day if empty.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+}
+
+/// Window during which a profile may be up; connecting outside of it is refused
+/// and daemon mode disconnects it once the window closes.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HoursConfig {
+    /// Days the window opens on, every day if empty.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+}
+
+/// Where daemon mode publishes state changes.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PublishConfig {
+    /// URL receiving each event as a JSON `POST`.
+    pub webhook: Option<String>,
+    pub mqtt: Option<MqttConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "daemon"), allow(dead_code))]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    pub topic: String,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Keep the last state on the broker for clients subscribing later.
+    #[serde(default = "default_true")]
+    pub retain: bool,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "wg-waybar".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Shell commands run as the invoking user when the VPN state changes.
+///
+/// They receive `WG_WAYBAR_INTERFACE` and, for `on_error`, `WG_WAYBAR_ERROR`
+/// in their environment.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    pub on_connect: Option<String>,
+    pub on_disconnect: Option<String>,
+    pub on_error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SplitTunnelConfig {
+    /// cgroup v2 paths relative to /sys/fs/cgroup, e.g.
+    /// `user.slice/user-1000.slice/user@1000.service/app.slice/app-firefox.slice`.
+    pub cgroups: Vec<String>,
+    /// Mark applied to the traffic of those cgroups.
+    #[serde(default = "default_fwmark")]
+    pub fwmark: u32,
+}
+
+fn default_fwmark() -> u32 {
+    0x5757
+}
+
+impl AppConfig {
+    pub fn load() -> Result<Self, error::Error> {
+        let path = utils::get_config_home("wg-waybar")
+            .map_err(|e| error::Error::UnCaught(error::UnCaughtError(e.to_string())))?
+            .join(CONFIG_FILENAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(path)?;
+        Self::parse(&content)
+    }
+
+    fn parse(content: &str) -> Result<Self, error::Error> {
+        let config: Self = toml::from_str(content)?;
+        for (interface_name, gateway) in &config.gateway {
+            gateway.validate(interface_name)?;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_gateway_subnets_that_are_not_subnets() {
+        let config =
+            AppConfig::parse("[gateway.wg0]\nlan = [\"192.168.50.0/24\", \"fd00:50::/64\"]\n")
+                .unwrap();
+        assert_eq!(config.gateway["wg0"].lan.len(), 2);
+        for lan in [
+            "192.168.50.0",
+            "192.168.50.0/33",
+            "lan/24",
+            "192.168.50.0/24; reboot",
+        ] {
+            let content = format!("[gateway.wg0]\nlan = [\"{}\"]\n", lan);
+            assert!(matches!(
+                AppConfig::parse(&content),
+                Err(error::Error::InvalidFormat { .. })
+            ));
+        }
+    }
+}
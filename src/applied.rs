@@ -0,0 +1,71 @@
+use crate::error;
+use crate::paths;
+use crate::utils;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::path::PathBuf;
+
+/// Where what was set up for each tunnel outside its interface is kept until
+/// the teardown, so that the same routes and rules are removed the same way
+/// even if the profile, the app config or the firewall in use changed since.
+fn path(kind: &str, interface_name: &str) -> Result<PathBuf, error::Error> {
+    let dir = match paths::runtime_dir() {
+        Some(dir) => dir,
+        None => paths::state_dir()?,
+    };
+    Ok(dir
+        .join("applied")
+        .join(format!("{}-{}.json", kind, interface_name)))
+}
+
+/// Remember what `kind` set up for the tunnel, for [`take`].
+pub fn save<T: Serialize>(
+    kind: &str,
+    interface_name: &str,
+    applied: &T,
+) -> Result<(), error::Error> {
+    let path = path(kind, interface_name)?;
+    if let Some(dir) = path.parent() {
+        if let Some(base) = dir.parent() {
+            paths::ensure(base.to_path_buf())?;
+        }
+        paths::ensure(dir.to_path_buf())?;
+    }
+    utils::replace_file(&path, serde_json::to_string(applied)?, 0o600)
+}
+
+/// What `kind` set up for the tunnel, kept for [`take`].
+pub fn load<T: DeserializeOwned>(kind: &str, interface_name: &str) -> Option<T> {
+    let content = std::fs::read_to_string(path(kind, interface_name).ok()?).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// What `kind` set up for the tunnels other than `interface_name`.
+pub fn others<T: DeserializeOwned>(kind: &str, interface_name: &str) -> Vec<T> {
+    let Ok(own) = path(kind, interface_name) else {
+        return Vec::new();
+    };
+    let prefix = format!("{}-", kind);
+    own.parent()
+        .and_then(|dir| std::fs::read_dir(dir).ok())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| *path != own)
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".json"))
+        })
+        .filter_map(|path| serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok())
+        .collect()
+}
+
+/// What `kind` set up for the tunnel, forgotten as it is read.
+pub fn take<T: DeserializeOwned>(kind: &str, interface_name: &str) -> Option<T> {
+    let path = path(kind, interface_name).ok()?;
+    let content = std::fs::read_to_string(&path).ok()?;
+    let _ = std::fs::remove_file(&path);
+    serde_json::from_str(&content).ok()
+}
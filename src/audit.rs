@@ -0,0 +1,211 @@
+use crate::app_config::AuditConfig;
+use serde::Serialize;
+use std::fmt::Display;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Socket of the native journald protocol.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Whoever asked for an action.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Requester {
+    pub user: String,
+    pub uid: u32,
+}
+
+impl Requester {
+    pub fn from_uid(uid: u32) -> Self {
+        let user = uzers::get_user_by_uid(uid)
+            .map(|user| user.name().to_string_lossy().into_owned())
+            .unwrap_or_else(|| uid.to_string());
+        Self { user, uid }
+    }
+
+    /// The user running the command, the one behind sudo if any.
+    pub fn invoking() -> Self {
+        std::env::var("SUDO_UID")
+            .ok()
+            .and_then(|uid| uid.parse().ok())
+            .map(Self::from_uid)
+            .unwrap_or_else(|| Self::from_uid(uzers::get_current_uid()))
+    }
+}
+
+/// One line of the audit log.
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    /// Unix timestamp of the end of the action.
+    timestamp: u64,
+    #[serde(flatten)]
+    requester: &'a Requester,
+    action: &'a str,
+    profile: &'a str,
+    /// `ok`, `error`, or `denied` when the requester was not allowed to.
+    result: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Record<'_> {
+    fn message(&self) -> String {
+        let message = format!(
+            "{} ({}) {} {}: {}",
+            self.requester.user, self.requester.uid, self.action, self.profile, self.result
+        );
+        match &self.error {
+            Some(error) => format!("{}: {}", message, error),
+            None => message,
+        }
+    }
+}
+
+/// Append `name=value` to a journald datagram, length-prefixed when the
+/// value spans several lines.
+fn journal_field(datagram: &mut Vec<u8>, name: &str, value: &str) {
+    datagram.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
+
+fn journal_datagram(record: &Record) -> Vec<u8> {
+    let priority = if record.result == "ok" { "5" } else { "4" };
+    let uid = record.requester.uid.to_string();
+    let mut datagram = Vec::new();
+    for (name, value) in [
+        ("MESSAGE", record.message().as_str()),
+        ("PRIORITY", priority),
+        ("SYSLOG_IDENTIFIER", "wg-waybar"),
+        ("WG_WAYBAR_USER", &record.requester.user),
+        ("WG_WAYBAR_UID", &uid),
+        ("WG_WAYBAR_ACTION", record.action),
+        ("WG_WAYBAR_PROFILE", record.profile),
+        ("WG_WAYBAR_RESULT", record.result),
+    ] {
+        journal_field(&mut datagram, name, value);
+    }
+    if let Some(error) = &record.error {
+        journal_field(&mut datagram, "WG_WAYBAR_ERROR", error);
+    }
+    datagram
+}
+
+/// Append to the log file, only readable by root and its group.
+fn append(path: &Path, record: &Record) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o640)
+        .open(path)?
+        .write_all(line.as_bytes())
+}
+
+fn record_as(
+    config: &AuditConfig,
+    requester: &Requester,
+    action: &str,
+    profile: &str,
+    result: &str,
+    error: Option<String>,
+) {
+    // Only actions taken with root privileges are worth tracing.
+    if !config.enabled || uzers::get_effective_uid() != 0 {
+        return;
+    }
+    let record = Record {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default(),
+        requester,
+        action,
+        profile,
+        result,
+        error,
+    };
+    let written = if config.journald {
+        UnixDatagram::unbound()
+            .and_then(|socket| socket.send_to(&journal_datagram(&record), JOURNAL_SOCKET))
+            .map(|_| ())
+    } else {
+        append(&config.path, &record)
+    };
+    if let Err(e) = written {
+        eprintln!("Failed to write the audit log: {}", e);
+    }
+}
+
+/// Trace an action `requester` asked for and how it ended.
+pub fn record<E: Display>(
+    config: &AuditConfig,
+    requester: &Requester,
+    action: &str,
+    profile: &str,
+    outcome: &Result<(), E>,
+) {
+    match outcome {
+        Ok(()) => record_as(config, requester, action, profile, "ok", None),
+        Err(e) => record_as(
+            config,
+            requester,
+            action,
+            profile,
+            "error",
+            Some(e.to_string()),
+        ),
+    }
+}
+
+/// Trace an action refused to `requester`.
+pub fn record_denied(config: &AuditConfig, requester: &Requester, action: &str, profile: &str) {
+    record_as(config, requester, action, profile, "denied", None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_journal_fields() {
+        let requester = Requester {
+            user: "alice".into(),
+            uid: 1000,
+        };
+        let record = Record {
+            timestamp: 1,
+            requester: &requester,
+            action: "toggle",
+            profile: "wg-work",
+            result: "error",
+            error: Some("first\nsecond".into()),
+        };
+        let datagram = journal_datagram(&record);
+        let text = String::from_utf8_lossy(&datagram);
+        assert!(text.contains("PRIORITY=4\n"));
+        assert!(text.contains("WG_WAYBAR_UID=1000\n"));
+        // Multi-line values are length-prefixed instead.
+        let mut error = b"WG_WAYBAR_ERROR\n".to_vec();
+        error.extend_from_slice(&12u64.to_le_bytes());
+        error.extend_from_slice(b"first\nsecond\n");
+        assert!(datagram.ends_with(&error));
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"timestamp":1,"user":"alice","uid":1000,"action":"toggle","profile":"wg-work","result":"error","error":"first\nsecond"}"#
+        );
+    }
+}
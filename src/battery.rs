@@ -0,0 +1,192 @@
+#[cfg(feature = "daemon")]
+use crate::actions::{self, Context};
+#[cfg(feature = "daemon")]
+use crate::app_config::BatteryConfig;
+#[cfg(feature = "daemon")]
+use crate::config;
+#[cfg(feature = "daemon")]
+use crate::error;
+#[cfg(feature = "daemon")]
+use crate::hooks;
+#[cfg(feature = "daemon")]
+use crate::profiles::Profile;
+use std::path::Path;
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+/// One entry of `/sys/class/power_supply`.
+#[derive(Debug, Default)]
+struct Supply {
+    /// `Battery`, `Mains`, `USB`, ...
+    kind: String,
+    /// `System`, or `Device` for the battery of a mouse or headset.
+    scope: String,
+    online: bool,
+    /// `Charging`, `Discharging`, `Full`, ... for batteries.
+    status: String,
+    capacity: Option<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct PowerState {
+    pub on_battery: bool,
+    /// Lowest capacity among the batteries, in percent.
+    pub capacity: Option<u8>,
+}
+
+/// Peripherals powering only themselves are left out.
+fn power_state(supplies: &[Supply]) -> PowerState {
+    let system: Vec<&Supply> = supplies.iter().filter(|s| s.scope != "Device").collect();
+    let batteries: Vec<&Supply> = system
+        .iter()
+        .copied()
+        .filter(|s| s.kind == "Battery")
+        .collect();
+    let external_power = system.iter().any(|s| s.kind != "Battery" && s.online);
+    PowerState {
+        on_battery: !external_power && batteries.iter().any(|s| s.status == "Discharging"),
+        capacity: batteries.iter().filter_map(|s| s.capacity).min(),
+    }
+}
+
+fn read_supply(dir: &Path) -> Supply {
+    let read = |name: &str| {
+        std::fs::read_to_string(dir.join(name))
+            .map(|value| value.trim().to_string())
+            .unwrap_or_default()
+    };
+    Supply {
+        kind: read("type"),
+        scope: read("scope"),
+        online: read("online") == "1",
+        status: read("status"),
+        capacity: read("capacity").parse().ok(),
+    }
+}
+
+/// Power state from sysfs; machines without a battery are never on battery.
+pub fn read() -> PowerState {
+    let supplies: Vec<Supply> = std::fs::read_dir(POWER_SUPPLY_DIR)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| read_supply(&entry.path()))
+                .collect()
+        })
+        .unwrap_or_default();
+    power_state(&supplies)
+}
+
+/// Adapts daemon mode to the power source of the machine.
+#[cfg(feature = "daemon")]
+#[derive(Default)]
+pub struct BatteryWatch {
+    /// Whether the interface was already disconnected for the current critical level.
+    critical_handled: bool,
+}
+
+#[cfg(feature = "daemon")]
+impl BatteryWatch {
+    /// Disconnect the profile once when the battery reaches its critical level,
+    /// and return the polling interval to use on battery, if configured.
+    pub fn check(
+        &mut self,
+        ctx: &Context,
+        battery: &BatteryConfig,
+        profile: &Profile,
+        netns: Option<&str>,
+    ) -> Result<Option<u64>, error::Error> {
+        let power = read();
+        if !power.on_battery {
+            self.critical_handled = false;
+            return Ok(None);
+        }
+        let critical = match (battery.critical, power.capacity) {
+            (Some(critical), Some(capacity)) => capacity <= critical,
+            _ => false,
+        };
+        if !critical {
+            self.critical_handled = false;
+        } else if !self.critical_handled {
+            self.critical_handled = true;
+            if config::is_active(&profile.name, netns)? {
+                actions::ensure(ctx, profile, false)?;
+                hooks::notify(
+                    "VPN disconnected",
+                    &format!(
+                        "{} was disconnected, battery at {}%",
+                        profile.name,
+                        power.capacity.unwrap_or_default()
+                    ),
+                    ctx.debug,
+                );
+            }
+        }
+        Ok(battery.interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supply(kind: &str, online: bool, status: &str, capacity: Option<u8>) -> Supply {
+        Supply {
+            kind: kind.to_string(),
+            scope: String::new(),
+            online,
+            status: status.to_string(),
+            capacity,
+        }
+    }
+
+    #[test]
+    fn on_battery_only_when_discharging_without_external_power() {
+        let discharging = [
+            supply("Mains", false, "", None),
+            supply("Battery", false, "Discharging", Some(40)),
+            supply("Battery", false, "Discharging", Some(12)),
+        ];
+        assert_eq!(
+            power_state(&discharging),
+            PowerState {
+                on_battery: true,
+                capacity: Some(12),
+            }
+        );
+        let plugged = [
+            supply("Mains", true, "", None),
+            supply("Battery", false, "Charging", Some(40)),
+        ];
+        assert!(!power_state(&plugged).on_battery);
+        let peripheral = |kind: &str, online: bool, status: &str, capacity: Option<u8>| Supply {
+            scope: "Device".to_string(),
+            ..supply(kind, online, status, capacity)
+        };
+        let wireless_mouse = [
+            supply("Mains", true, "", None),
+            supply("Battery", false, "Full", Some(100)),
+            peripheral("Battery", false, "Discharging", Some(3)),
+        ];
+        assert_eq!(
+            power_state(&wireless_mouse),
+            PowerState {
+                on_battery: false,
+                capacity: Some(100),
+            }
+        );
+        let charging_headset = [
+            supply("Mains", false, "", None),
+            supply("Battery", false, "Discharging", Some(60)),
+            peripheral("USB", true, "", None),
+        ];
+        assert!(power_state(&charging_headset).on_battery);
+        assert_eq!(
+            power_state(&[]),
+            PowerState {
+                on_battery: false,
+                capacity: None,
+            }
+        );
+    }
+}
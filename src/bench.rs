@@ -0,0 +1,158 @@
+use crate::actions;
+use crate::config::{self, TunnelOptions};
+use crate::error;
+use crate::health;
+use crate::profiles::Profile;
+use std::time::{Duration, Instant};
+
+const HANDSHAKE_POLL: Duration = Duration::from_millis(50);
+
+/// Timings of one connection, measured from the start of the connect.
+struct Sample {
+    interface: Duration,
+    handshake: Option<Duration>,
+    first_byte: Option<Duration>,
+}
+
+/// Minimum, mean and maximum of a series of timings.
+#[derive(Debug, PartialEq)]
+struct Summary {
+    min: Duration,
+    mean: Duration,
+    max: Duration,
+}
+
+impl Summary {
+    fn of(durations: &[Duration]) -> Option<Self> {
+        let min = *durations.iter().min()?;
+        let max = *durations.iter().max()?;
+        let mean = durations.iter().sum::<Duration>() / durations.len() as u32;
+        Some(Self { min, mean, max })
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+}
+
+fn wait_for_handshake(
+    interface_name: &str,
+    netns: Option<&str>,
+    start: Instant,
+    timeout: Duration,
+) -> Result<Option<Duration>, error::Error> {
+    while start.elapsed() < timeout {
+        if config::last_handshake(interface_name, netns)?.is_some() {
+            return Ok(Some(start.elapsed()));
+        }
+        std::thread::sleep(HANDSHAKE_POLL);
+    }
+    Ok(None)
+}
+
+fn measure(
+    profile: &Profile,
+    options: &TunnelOptions,
+    url: &str,
+    timeout: Duration,
+    debug: bool,
+) -> Result<Sample, error::Error> {
+    let start = Instant::now();
+    actions::connect(&profile.path, &profile.name, options, &|_| {})?;
+    let interface = start.elapsed();
+    let result = (|| {
+        let handshake = wait_for_handshake(&profile.name, options.netns, start, timeout)?;
+        let first_byte = match health::request(url, options.netns, timeout) {
+            Ok(()) => Some(start.elapsed()),
+            Err(e) => {
+                if debug {
+                    println!("{}", e);
+                }
+                None
+            }
+        };
+        Ok(Sample {
+            interface,
+            handshake,
+            first_byte,
+        })
+    })();
+    config::TunnelBuilder::new(&profile.path, &profile.name, options).teardown()?;
+    result
+}
+
+fn print_summary(label: &str, durations: &[Duration], iterations: u32) {
+    match Summary::of(durations) {
+        Some(summary) => println!(
+            "{:<16}min {:>10}  mean {:>10}  max {:>10}  ({}/{})",
+            label,
+            millis(summary.min),
+            millis(summary.mean),
+            millis(summary.max),
+            durations.len(),
+            iterations,
+        ),
+        None => println!("{:<16}no successful measurement", label),
+    }
+}
+
+/// Connect and disconnect `profile` `iterations` times and print timing statistics.
+///
+/// The profile must be down beforehand, so that every iteration starts from scratch.
+pub fn run(
+    profile: &Profile,
+    options: &TunnelOptions,
+    iterations: u32,
+    url: &str,
+    timeout: Duration,
+    debug: bool,
+) -> Result<(), error::Error> {
+    if config::is_active(&profile.name, options.netns)? {
+        return Err(error::Error::InvalidFormat {
+            message: format!("{} is up, disconnect it before benchmarking", profile.name),
+        });
+    }
+    let mut samples = Vec::new();
+    for iteration in 1..=iterations {
+        let sample = measure(profile, options, url, timeout, debug)?;
+        println!(
+            "#{} interface {}  handshake {}  first byte {}",
+            iteration,
+            millis(sample.interface),
+            sample.handshake.map_or("timeout".into(), millis),
+            sample.first_byte.map_or("failed".into(), millis),
+        );
+        samples.push(sample);
+    }
+    println!();
+    let interface: Vec<Duration> = samples.iter().map(|s| s.interface).collect();
+    let handshake: Vec<Duration> = samples.iter().filter_map(|s| s.handshake).collect();
+    let first_byte: Vec<Duration> = samples.iter().filter_map(|s| s.first_byte).collect();
+    print_summary("interface", &interface, iterations);
+    print_summary("handshake", &handshake, iterations);
+    print_summary("first byte", &first_byte, iterations);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_durations() {
+        let durations = [
+            Duration::from_millis(30),
+            Duration::from_millis(10),
+            Duration::from_millis(20),
+        ];
+        assert_eq!(
+            Summary::of(&durations),
+            Some(Summary {
+                min: Duration::from_millis(10),
+                mean: Duration::from_millis(20),
+                max: Duration::from_millis(30),
+            })
+        );
+        assert_eq!(Summary::of(&[]), None);
+    }
+}
@@ -0,0 +1,83 @@
+use serde_json::json;
+use std::env;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Whether `program` is an executable of one of the `PATH` directories.
+fn in_path(program: &str, path: Option<&str>) -> bool {
+    path.unwrap_or_default()
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(dir).join(program))
+        .any(|file| {
+            file.metadata()
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        })
+}
+
+/// TLS stack HTTPS requests go through, if any was built in.
+fn tls() -> Option<&'static str> {
+    if cfg!(feature = "rustls") {
+        Some("rustls")
+    } else if cfg!(feature = "native-tls") {
+        Some("native-tls")
+    } else {
+        None
+    }
+}
+
+/// Whether updates can be verified: built in, with a release key.
+fn self_update() -> bool {
+    #[cfg(feature = "self-update")]
+    return crate::self_update::has_key();
+    #[cfg(not(feature = "self-update"))]
+    false
+}
+
+/// What this build and machine support, for wrapper scripts and snippet generators.
+///
+/// Subsystems report whether they were compiled in, see the cargo features;
+/// those relying on external programs also whether they were found.
+pub fn report() -> serde_json::Value {
+    let path = env::var("PATH").ok();
+    let has = |program: &str| in_path(program, path.as_deref());
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "backends": {
+            "kernel": cfg!(target_os = "linux") && Path::new("/sys/module/wireguard").exists(),
+            "userspace": !cfg!(target_os = "linux"),
+        },
+        "dns": {
+            "resolvconf": has("resolvconf"),
+        },
+        "notifications": cfg!(feature = "notifications") && has("notify-send"),
+        "daemon": cfg!(feature = "daemon"),
+        "tray": cfg!(feature = "tray"),
+        "tui": cfg!(feature = "tui"),
+        "serve": true,
+        "http": cfg!(feature = "http"),
+        "tls": tls(),
+        "providers": cfg!(feature = "providers"),
+        "netns": has("ip"),
+        "split_tunnel": has("nft"),
+        "top": has("nft"),
+        "secrets": has("pinentry"),
+        "self_update": self_update(),
+        "schema": cfg!(feature = "schema"),
+        "hardware_keys": cfg!(feature = "hardware-keys"),
+        "prometheus": cfg!(feature = "prometheus"),
+        "procfs": cfg!(feature = "procfs"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_programs_in_path() {
+        assert!(in_path("sh", Some("/nonexistent:/bin:/usr/bin")));
+        assert!(!in_path("sh", Some("/nonexistent")));
+        assert!(!in_path("sh", None));
+    }
+}
@@ -1,19 +1,34 @@
-use clap::{Parser, Subcommand};
+use crate::error::ErrorKind;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[cfg(feature = "http")]
+use crate::speedtest;
 
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Cli {
-    /// Path to the wireguard configuration file
+    /// Path to the wireguard configuration file, or to a directory of profiles
+    #[arg(required_unless_present_any = ["capabilities"], default_value = "", hide_default_value = true)]
     pub config: String,
+
+    /// Print what this build supports as JSON and exit
+    #[arg(long, exclusive = true)]
+    pub capabilities: bool,
     /// Signal to use
     #[arg(long, default_value_t = 9)]
     pub signal: i32,
 
+    /// Waybar config (JSONC) whose wg-waybar module's `signal` replaces `--signal`, with a warning when they differ
+    #[arg(long)]
+    pub waybar_config: Option<String>,
+
     /// Enable debug output
     #[arg(short, long)]
     pub debug: bool,
 
-    /// State filename
+    /// State index filename, per-interface state lives next to it in `interfaces/`
     #[arg(long, default_value="status.json")]
     pub state_filename: String, 
 
@@ -21,6 +36,119 @@ pub struct Cli {
     #[arg(long, default_value_t = 40077)]
     pub port: u32,
 
+    /// How traffic is routed through the tunnel
+    #[arg(long, value_enum, default_value_t = RouteMode::Default)]
+    pub route_mode: RouteMode,
+
+    /// Create the interface inside this network namespace (created if needed)
+    #[arg(long)]
+    pub netns: Option<String>,
+
+    /// Keep the encrypted traffic on this physical interface, e.g. `eth0`
+    #[arg(long)]
+    pub bind_interface: Option<String>,
+
+    /// Only configure the peers in these groups, e.g. `infra,offices`; peers
+    /// without a `# Groups` comment always are
+    #[arg(long, value_delimiter = ',')]
+    pub groups: Vec<String>,
+
+    /// Firewall the gateway and kill switch rules go through
+    #[arg(long, value_enum, default_value_t = FirewallBackend::Auto)]
+    pub firewall_backend: FirewallBackend,
+
+    /// Drop whatever leaves the machine outside of the tunnel while it is up,
+    /// the encrypted traffic to the peers aside
+    #[arg(long)]
+    pub kill_switch: bool,
+
+    /// What brings tunnels up: the system's WireGuard, or `mock` to simulate
+    /// them without WireGuard or root, e.g. to style the module
+    #[arg(long, value_enum, default_value_t = Backend::System)]
+    pub backend: Backend,
+
+    /// Write every call to WireGuard, `ip`, `nft` and the firewall tools, and
+    /// every sysctl, with its result, to this file, private and preshared keys
+    /// left out, e.g. to attach to a bug report
+    #[arg(long, value_name = "TRACE", conflicts_with = "replay")]
+    pub record: Option<String>,
+
+    /// Answer the calls to WireGuard and the system tools from a file written by
+    /// `--record` instead of the system, to reproduce a failure
+    #[arg(long, value_name = "TRACE")]
+    pub replay: Option<String>,
+
+    /// Format of the status output
+    #[arg(long, value_enum, default_value_t = OutputFormat::Waybar)]
+    pub output_format: OutputFormat,
+
+    /// How much the text shows, details being in the tooltip otherwise
+    #[arg(long, value_enum, default_value_t = Style::Full)]
+    pub style: Style,
+
+    /// Accessibility preset of classes, colors and shapes; `init-waybar` writes the matching CSS
+    #[arg(long, value_enum, default_value_t = Theme::Default)]
+    pub theme: Theme,
+
+    /// Template of the text, with `{text}`, `{interface}`, `{interface_short}` and `{status}`
+    #[arg(long)]
+    pub format: Option<String>,
+
+    /// Truncate the text to this many characters, ending with `…`
+    #[arg(long)]
+    pub max_length: Option<usize>,
+
+    /// Render the Waybar tooltip as Pango markup, with a bold heading and errors in red
+    #[arg(long)]
+    pub tooltip_markup: bool,
+
+    /// Show the peers in the Waybar tooltip as a markup table, with handshake and transfer
+    #[arg(long)]
+    pub tooltip_peers: bool,
+
+    /// Seconds during which further toggles are ignored, against accidental double clicks
+    #[arg(long, default_value = "2", value_parser = seconds)]
+    pub min_toggle_interval: Duration,
+
+    /// Disconnect only on a second click within this many seconds
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "5")]
+    pub confirm_disconnect: Option<u64>,
+
+    /// Wait this many seconds for the first handshake before reporting a connection
+    #[arg(long)]
+    pub handshake_timeout: Option<u64>,
+
+    /// Wait up to this many seconds for Waybar to start before signaling it, e.g. when restoring tunnels at login
+    #[arg(long, value_name = "SECONDS")]
+    pub wait_for_waybar: Option<u64>,
+
+    /// Failed connections in a row after which the module gets the `attention` class
+    #[arg(long, default_value_t = 3)]
+    pub attention_after: u32,
+
+    /// Only consider the profiles of the directory whose name matches this glob, e.g. `mullvad-*`
+    #[arg(long = "match")]
+    pub match_pattern: Option<String>,
+
+    /// Forward status, toggle and switch to the system daemon listening on this socket, `config` naming the profile
+    #[arg(long)]
+    pub connect: Option<String>,
+
+    /// Only consider the profiles carrying this `# Tags =` tag, e.g. `streaming`
+    #[arg(long)]
+    pub tag: Option<String>,
+
+    /// Name interfaces after this template instead of the profile, e.g. `wg-{profile}-{n}`, `{n}` counting up past names in use
+    #[arg(long)]
+    pub interface_name: Option<String>,
+
+    /// Refuse to connect while another VPN interface (tun0, tailscale0, ...) is up
+    #[arg(long)]
+    pub exclusive: bool,
+
+    /// Only report the status of a tunnel brought up by something else (wg-quick, systemd-networkd, ...); `config` may be the bare interface name and commands changing the tunnel are refused
+    #[arg(long)]
+    pub observe: bool,
 
     #[command(subcommand)]
     pub command: Option<Commands>,
@@ -29,6 +157,489 @@ pub struct Cli {
 
 #[derive(Subcommand)]
 pub enum Commands {
+    /// Print the status, as done without a command
+    Status {
+        /// Also signal Waybar to refresh the module, with the signal recorded in the state file
+        #[arg(long)]
+        refresh: bool,
+    },
     /// Toggle the vpn (switch state)
     Toggle,
+    /// Connect to another profile of the directory, disconnecting the current one
+    Switch {
+        /// Profile name (file stem of its .conf file)
+        profile: String,
+    },
+    /// List the profiles of the directory in order, `*` marking those up
+    List {
+        /// Handshake with the endpoint of every profile, a few at a time, and show its round-trip time
+        #[arg(long)]
+        probe: bool,
+    },
+    /// Switch to the profile after the shown one, e.g. on scroll
+    Next,
+    /// Disconnect and reconnect a profile in one go
+    Restart {
+        /// Profile name (file stem of its .conf file)
+        profile: String,
+    },
+    /// Generate a Waybar menu listing the profiles of the directory
+    Menu {
+        /// Where to write the menu file [default: ~/.config/wg-waybar/menu.xml]
+        #[arg(long)]
+        output: Option<String>,
+        /// Show the round-trip time of each endpoint, greying out those unreachable
+        #[arg(long)]
+        probe: bool,
+        /// How the menu entries get the privileges to bring tunnels up
+        #[arg(long, value_enum, default_value_t = Elevate::Sudo)]
+        elevate: Elevate,
+    },
+    /// Write the Waybar CSS of `--theme` and print the module running with it
+    InitWaybar {
+        /// Where to write the CSS [default: ~/.config/waybar/wg-waybar.css]
+        #[arg(long)]
+        output: Option<String>,
+        /// How the module gets the privileges to bring tunnels up
+        #[arg(long, value_enum, default_value_t = Elevate::Sudo)]
+        elevate: Elevate,
+    },
+    /// Watch the interface, refreshing Waybar and publishing state changes
+    #[cfg(feature = "daemon")]
+    Daemon {
+        /// Seconds between two status checks
+        #[arg(long, default_value_t = 5)]
+        interval: u64,
+        /// Bring back up the profiles that were active before, e.g. after a reboot
+        #[arg(long)]
+        restore_on_start: bool,
+        /// Show a StatusNotifierItem tray icon with a toggle and profile menu
+        #[arg(long)]
+        tray: bool,
+    },
+    /// Bring back up the profiles that were active before, e.g. after a reboot
+    Restore,
+    /// Repeatedly connect and disconnect a profile, printing connection timings
+    #[cfg(feature = "http")]
+    Bench {
+        /// Profile name (file stem of its .conf file)
+        profile: String,
+        /// Number of connect/disconnect cycles
+        #[arg(long, default_value_t = 5)]
+        iterations: u32,
+        /// URL requested through the tunnel to measure the time to first byte
+        #[arg(long, default_value = "http://1.1.1.1/")]
+        url: String,
+        /// Seconds to wait for the handshake and the first byte
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+    },
+    /// Rank the profiles of the directory by the latency of their endpoint, without connecting
+    Rank {
+        /// Handshakes sent to each endpoint
+        #[arg(long, default_value_t = 3)]
+        count: u32,
+        /// Switch to the profile with the lowest latency
+        #[arg(long)]
+        connect_best: bool,
+    },
+    /// Print connect, disconnect, error, reconnect and endpoint-roam events of the profiles as NDJSON
+    #[cfg(feature = "daemon")]
+    Events {
+        /// Seconds between two status checks
+        #[arg(long, default_value_t = 1)]
+        interval: u64,
+    },
+    /// Full-screen dashboard of the profiles with throughput graphs, to toggle and switch them
+    #[cfg(feature = "tui")]
+    Tui {
+        /// Seconds between two samples
+        #[arg(long, default_value_t = 1)]
+        interval: u64,
+    },
+    /// Show which AllowedIPs networks carry the traffic of the connected tunnel, counted with nftables
+    Top {
+        /// Seconds to count the traffic for
+        #[arg(long, default_value_t = 5)]
+        seconds: u64,
+        /// Networks shown, those that carried the most first
+        #[arg(long, default_value_t = 10)]
+        count: usize,
+    },
+    /// Print the state and transfer of the profiles in Prometheus' text format
+    #[cfg(feature = "prometheus")]
+    Metrics,
+    /// Pick which peer carries the default route when several claim it
+    ExitNode {
+        #[command(subcommand)]
+        command: ExitNodeCommand,
+    },
+    /// Check the endpoints, handshake and clock of the shown profile, exiting non-zero on a problem
+    Doctor,
+    /// Check that the shown profile parses, without resolving or changing anything
+    Validate,
+    /// Print what connecting the shown profile would configure, without changing anything
+    DryRun {
+        /// Print only how the running interface differs from it
+        #[arg(long)]
+        diff: bool,
+    },
+    /// Wait until a profile has a recent handshake, exiting non-zero on timeout
+    WaitOnline {
+        /// Profile name (file stem of its .conf file)
+        profile: String,
+        /// Seconds to wait
+        #[arg(long, default_value_t = 30)]
+        timeout: u64,
+        /// Also wait until this URL responds through the tunnel
+        #[arg(long)]
+        url: Option<String>,
+    },
+    /// Serve a JSON-RPC 2.0 control protocol for other frontends
+    Serve {
+        /// Read requests from stdin and answer on stdout, one per line
+        #[arg(long, required_unless_present = "socket", conflicts_with = "socket")]
+        stdio: bool,
+        /// Listen on this Unix socket instead, for the clients of every user (system mode)
+        #[arg(long)]
+        socket: Option<String>,
+        /// Group whose members may connect to the socket
+        #[arg(long, default_value = "wg-waybar")]
+        group: String,
+        /// Seconds between two status checks for subscribers
+        #[arg(long, default_value_t = 1)]
+        interval: u64,
+    },
+    /// Print the status continuously, one line whenever it changes
+    #[cfg(feature = "daemon")]
+    Watch {
+        /// Seconds between two status checks after a change or SIGUSR1
+        #[arg(long, default_value_t = 1)]
+        interval: u64,
+        /// Seconds between two status checks once nothing changed for `--settle` seconds
+        #[arg(long, default_value_t = 10)]
+        idle_interval: u64,
+        /// Seconds after a change during which `--interval` applies
+        #[arg(long, default_value_t = 30)]
+        settle: u64,
+        /// Milliseconds a new status must last before it is printed, so that a
+        /// flapping link does not make the module blink
+        #[arg(long, default_value_t = 0)]
+        hold: u64,
+    },
+    /// Measure throughput through the tunnel and store it in the usage history
+    #[cfg(feature = "http")]
+    Speedtest {
+        /// URL downloaded, `{bytes}` is replaced by the payload size
+        #[arg(long, default_value = speedtest::DEFAULT_DOWNLOAD_URL)]
+        download_url: String,
+        /// URL the payload is uploaded to
+        #[arg(long, default_value = speedtest::DEFAULT_UPLOAD_URL)]
+        upload_url: String,
+        /// Payload size in bytes
+        #[arg(long, default_value_t = 10_000_000)]
+        bytes: u64,
+        /// Print the previous results of the interface instead of running a test
+        #[arg(long)]
+        history: bool,
+    },
+    /// Snapshot an interface brought up by other means into a profile of the directory and manage it
+    Adopt {
+        /// Name of the running interface
+        interface: String,
+        /// Overwrite an existing profile
+        #[arg(long)]
+        force: bool,
+    },
+    /// Convert a connection exported from another tool into a profile of the directory
+    Import {
+        /// Exported file
+        file: String,
+        /// Format of the export
+        #[arg(long, value_enum)]
+        format: ImportFormat,
+        /// Profile name, instead of the one found in the export
+        #[arg(long)]
+        name: Option<String>,
+        /// Overwrite an existing profile
+        #[arg(long)]
+        force: bool,
+    },
+    /// Add a peer with the next free address of the pool to the profile and print its configuration
+    ExportPeer {
+        /// Name of the new peer
+        name: String,
+    },
+    /// Generate and connect profiles from a VPN provider's relay list
+    #[cfg(feature = "providers")]
+    Provider {
+        #[command(subcommand)]
+        provider: ProviderCommand,
+    },
+    /// Run a program through the tunnel, inside the `--netns` namespace when one
+    /// is used, bringing it up before and down after unless it was already up
+    Run {
+        /// Profile to go through instead of the shown one
+        #[arg(long)]
+        profile: Option<String>,
+        /// Program and arguments to run
+        #[arg(trailing_var_arg = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Print the causes and fixes of an error kind, as given in the `error_kind` of the output
+    Explain {
+        /// Error kind, e.g. `handshake-timeout`
+        kind: ErrorKind,
+    },
+    /// Print the `# PrivateKeyFrom` line of a hardware key and the public key to give the server
+    #[cfg(feature = "hardware-keys")]
+    Enroll {
+        /// `tpm2:<handle>`, or `fido2:<device>` to make a credential on the security key
+        source: String,
+    },
+    /// Print the JSON Schema of a machine-readable output
+    #[cfg(feature = "schema")]
+    Schema {
+        /// Output described by the schema
+        #[arg(value_enum)]
+        kind: SchemaKind,
+    },
+    /// Replace this binary with the latest signed GitHub release
+    #[cfg(feature = "self-update")]
+    SelfUpdate {
+        /// Only tell whether a newer release is available
+        #[arg(long)]
+        check: bool,
+    },
+}
+
+impl Commands {
+    /// Whether the command may bring tunnels up or down, refused with `--observe`.
+    pub fn changes_tunnel(&self) -> bool {
+        match self {
+            Commands::Status { .. }
+            | Commands::List { .. }
+            | Commands::Top { .. }
+            | Commands::Doctor
+            | Commands::Validate
+            | Commands::Explain { .. }
+            | Commands::DryRun { .. }
+            | Commands::InitWaybar { .. } => false,
+            #[cfg(feature = "daemon")]
+            Commands::Watch { .. } | Commands::Events { .. } => false,
+            #[cfg(feature = "self-update")]
+            Commands::SelfUpdate { .. } => false,
+            #[cfg(feature = "schema")]
+            Commands::Schema { .. } => false,
+            #[cfg(feature = "hardware-keys")]
+            Commands::Enroll { .. } => false,
+            #[cfg(feature = "prometheus")]
+            Commands::Metrics => false,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum ExitNodeCommand {
+    /// Route the default route through a peer
+    Set {
+        /// Position of the peer in the configuration, name, public key prefix or endpoint
+        peer: String,
+    },
+    /// List the peers able to act as exit node, `*` marking the selected one
+    List,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// WireGuard of the kernel (the userspace implementation outside Linux)
+    System,
+    /// Interfaces that only exist in a file of the runtime directory, with
+    /// made-up handshakes and counters
+    Mock,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SchemaKind {
+    /// `--output-format eww`, the status with every field
+    Status,
+    /// The state file of an interface
+    State,
+    /// Lines of the `events` stream
+    #[cfg(feature = "daemon")]
+    Events,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Waybar custom module JSON
+    #[default]
+    Waybar,
+    /// Newline-delimited flat JSON for Eww `deflisten` and AGS
+    Eww,
+    /// i3bar protocol blocks, for i3status wrappers and i3blocks' JSON format
+    I3bar,
+}
+
+#[cfg(feature = "providers")]
+#[derive(Subcommand)]
+pub enum ProviderCommand {
+    /// Mullvad, through its public relay list
+    Mullvad {
+        /// Account number, needed once to register the device key
+        #[arg(long)]
+        account: Option<String>,
+        /// Keep the account number in the state directory for later registrations
+        #[arg(long)]
+        save_account: bool,
+        #[command(subcommand)]
+        command: MullvadCommand,
+    },
+    /// ProtonVPN, through its public server list
+    #[command(name = "protonvpn")]
+    ProtonVpn {
+        /// WireGuard configuration downloaded from the ProtonVPN account page, whose key
+        /// is used for every server
+        #[arg(long)]
+        key_from: Option<String>,
+        #[command(subcommand)]
+        command: ProtonVpnCommand,
+    },
+}
+
+#[cfg(feature = "providers")]
+#[derive(Subcommand)]
+pub enum MullvadCommand {
+    /// List the locations, or the relays of a country, city or hostname
+    List { location: Option<String> },
+    /// Connect the `mullvad` profile to a relay of a country (`se`), city (`se-sto`) or hostname
+    Connect { location: String },
+    /// Move to another relay of the current city
+    Rotate,
+}
+
+#[cfg(feature = "providers")]
+#[derive(Subcommand)]
+pub enum ProtonVpnCommand {
+    /// List the countries, or the servers of a country
+    List { country: Option<String> },
+    /// Connect the `protonvpn` profile to a server of a country (`ch`) or of that name (`CH#1`)
+    Connect { location: String },
+    /// Move to another server of the current country
+    Rotate,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ImportFormat {
+    /// Device configuration JSON from defguard
+    Defguard,
+    /// Mullvad device key and addresses with a relay list entry
+    MullvadJson,
+    /// NetworkManager `.nmconnection` keyfile
+    NmKeyfile,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Style {
+    /// The interface name or connection stage
+    #[default]
+    Full,
+    /// A single glyph: 🔒, 🔓 or ⚠
+    Compact,
+    /// A single ASCII mark: [+], [-] or [!]
+    Ascii,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Theme {
+    /// rose-pine colors in markup tooltips
+    #[default]
+    Default,
+    /// Okabe–Ito colors, a severity class and a shape before the text
+    Colorblind,
+    /// No colors: a severity class, a shape before the text and no percentage
+    Mono,
+}
+
+impl Theme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Theme::Default => "default",
+            Theme::Colorblind => "colorblind",
+            Theme::Mono => "mono",
+        }
+    }
+}
+
+/// What runs wg-waybar with the privileges it needs in generated Waybar modules.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Elevate {
+    /// `sudo`, with a NOPASSWD rule for the binary
+    Sudo,
+    /// `doas`, with a `nopass` rule
+    Doas,
+    /// `run0` of systemd, through polkit
+    Run0,
+    /// Nothing, e.g. for a binary given CAP_NET_ADMIN
+    None,
+}
+
+impl Elevate {
+    pub fn program(&self) -> Option<&'static str> {
+        match self {
+            Elevate::Sudo => Some("sudo"),
+            Elevate::Doas => Some("doas"),
+            Elevate::Run0 => Some("run0"),
+            Elevate::None => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RouteMode {
+    /// Route AllowedIPs according to the configuration's `Table` key
+    Default,
+    /// Route only traffic originating from the tunnel's addresses
+    Policy,
+    /// Route only traffic of the cgroups listed in the app config
+    Cgroup,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FirewallBackend {
+    /// Whichever of firewalld, ufw and iptables is in use, nftables otherwise
+    Auto,
+    /// A table of its own
+    Nftables,
+    /// Runtime zone and rich rules
+    Firewalld,
+    /// Route rules, masquerading with nftables
+    Ufw,
+    /// Rules in the iptables chains, e.g. iptables-nft next to Docker
+    Iptables,
+}
+
+/// A duration given in seconds, fractions allowed.
+fn seconds(value: &str) -> Result<Duration, String> {
+    let seconds: f64 = value
+        .parse()
+        .map_err(|e: std::num::ParseFloatError| e.to_string())?;
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| format!("{} is not a number of seconds", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_seconds() {
+        assert_eq!(seconds("0.5"), Ok(Duration::from_millis(500)));
+        assert!(seconds("-1").is_err());
+        assert!(seconds("NaN").is_err());
+        assert!(seconds("soon").is_err());
+    }
 }
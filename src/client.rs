@@ -0,0 +1,64 @@
+use crate::app_config::AppConfig;
+use crate::cli::{self, Cli};
+use crate::refresh::Sinks;
+use crate::status::{self, Status, output_json};
+use crate::{error, serve};
+use std::path::Path;
+use std::time::Duration;
+
+/// Forward the command to the system daemon listening on `socket`.
+pub fn run(cli: &Cli, socket: &Path) -> Result<(), error::Error> {
+    let profile = if cli.config.is_empty() {
+        serde_json::json!({})
+    } else {
+        serde_json::json!({"profile": cli.config})
+    };
+    let (method, params) = match &cli.command {
+        None | Some(cli::Commands::Status { .. }) => ("status", profile),
+        Some(cli::Commands::Toggle) => ("toggle", profile),
+        Some(cli::Commands::Switch { profile }) => {
+            ("switch", serde_json::json!({"profile": profile}))
+        }
+        Some(_) => {
+            return Err(error::Error::InvalidFormat {
+                message: "only status, toggle and switch go through --connect".to_string(),
+            });
+        }
+    };
+    let mut status = match serve::call(socket, method, params) {
+        Ok(status) => status,
+        Err(e) => {
+            output_json("VPN: Error", Status::Error, &e.to_string())?;
+            return Err(e);
+        }
+    };
+    if let Some(status) = status.as_object_mut() {
+        status.remove("interface");
+        for key in ["text", "tooltip"] {
+            if let Some(serde_json::Value::String(value)) = status.get_mut(key) {
+                *value = status::escape_markup(value);
+            }
+        }
+    }
+    println!("{}", status);
+    // The daemon may not see our Waybar, e.g. with hidepid.
+    let refresh = matches!(cli.command, Some(cli::Commands::Status { refresh: true }));
+    if method != "status" || refresh {
+        let config = AppConfig::load().unwrap_or_else(|e| {
+            if cli.debug {
+                println!(
+                    "Failed to load the app config, only signaling Waybar: {}",
+                    e
+                );
+            }
+            AppConfig::default()
+        });
+        let wait = cli.wait_for_waybar.map(Duration::from_secs);
+        if let Err(e) = Sinks::new(&config.refresh, cli.signal, wait, cli.debug).refresh()
+            && cli.debug
+        {
+            println!("Failed to refresh: {}", e);
+        }
+    }
+    Ok(())
+}
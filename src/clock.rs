@@ -0,0 +1,121 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// time.cloudflare.com and time.google.com, by address: the resolver may well
+/// be the DNS server of the tunnel that fails to handshake.
+const NTP_SERVERS: [&str; 2] = ["162.159.200.1:123", "216.239.35.0:123"];
+/// Seconds between the NTP era (1900) and the Unix epoch.
+const NTP_TO_UNIX: f64 = 2_208_988_800.0;
+/// Offset from which the clock is suspected of breaking handshakes.
+pub const SKEW_THRESHOLD: i64 = 30;
+
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs_f64())
+        .unwrap_or_default()
+}
+
+/// An SNTP client request: version 4, mode 3.
+fn request() -> [u8; 48] {
+    let mut packet = [0u8; 48];
+    packet[0] = 0x23;
+    packet
+}
+
+/// Transmit timestamp of a server response, in Unix seconds.
+fn transmit_time(response: &[u8]) -> Option<f64> {
+    // Mode 4 (server), and a stratum telling it is synchronized.
+    if response.len() < 48 || response[0] & 0x07 != 4 || response[1] == 0 {
+        return None;
+    }
+    let seconds = u32::from_be_bytes(response[40..44].try_into().ok()?);
+    let fraction = u32::from_be_bytes(response[44..48].try_into().ok()?);
+    Some(f64::from(seconds) + f64::from(fraction) / 4_294_967_296.0 - NTP_TO_UNIX)
+}
+
+/// Give the socket the fwmark of the tunnel, so that the question goes out of
+/// a full tunnel that does not handshake.
+///
+/// Best effort: without CAP_NET_ADMIN the question follows the routes.
+#[cfg(target_os = "linux")]
+fn bypass_tunnel(socket: &UdpSocket) {
+    use std::os::fd::AsRawFd;
+    let mark = crate::routing::TUNNEL_FWMARK;
+    unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &mark as *const u32 as *const libc::c_void,
+            std::mem::size_of::<u32>() as libc::socklen_t,
+        );
+    }
+}
+
+/// Fwmark routing is Linux's.
+#[cfg(not(target_os = "linux"))]
+fn bypass_tunnel(_socket: &UdpSocket) {}
+
+/// Seconds the system clock is behind NTP, negative when ahead, from the first
+/// server answering.
+pub fn skew(timeout: Duration) -> Result<f64, String> {
+    let mut failure = String::new();
+    for server in NTP_SERVERS {
+        match ask(server.parse().unwrap(), timeout) {
+            Ok(skew) => return Ok(skew),
+            Err(e) => failure = format!("{}: {}", server, e),
+        }
+    }
+    Err(failure)
+}
+
+fn ask(server: SocketAddr, timeout: Duration) -> Result<f64, String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+    bypass_tunnel(&socket);
+    socket
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| e.to_string())?;
+    let sent = unix_now();
+    socket
+        .send_to(&request(), server)
+        .map_err(|e| e.to_string())?;
+    let mut response = [0u8; 48];
+    let length = socket.recv(&mut response).map_err(|e| e.to_string())?;
+    let received = unix_now();
+    let server_time =
+        transmit_time(&response[..length]).ok_or_else(|| "invalid NTP answer".to_string())?;
+    Ok(server_time - (sent + received) / 2.0)
+}
+
+/// Guidance for a skew over [`SKEW_THRESHOLD`].
+pub fn describe(skew: i64) -> String {
+    let direction = if skew > 0 { "behind" } else { "ahead of" };
+    format!(
+        "Possible clock skew: the clock is {} s {} NTP, which can make the server drop handshakes; enable time sync with `timedatectl set-ntp true`",
+        skew.abs(),
+        direction
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_server_time() {
+        assert_eq!(request()[0], 0x23);
+        let mut response = [0u8; 48];
+        response[0] = 0x24;
+        response[1] = 2;
+        // 2024-01-01T00:00:00Z and a half.
+        response[40..44].copy_from_slice(&(1_704_067_200u32 + 2_208_988_800).to_be_bytes());
+        response[44..48].copy_from_slice(&0x8000_0000u32.to_be_bytes());
+        assert_eq!(transmit_time(&response), Some(1_704_067_200.5));
+
+        // Kiss-o'-death, stratum 0.
+        response[1] = 0;
+        assert_eq!(transmit_time(&response), None);
+        assert!(describe(-125).contains("125 s ahead of NTP"));
+    }
+}
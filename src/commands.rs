@@ -0,0 +1,444 @@
+use crate::export_peer;
+#[cfg(feature = "prometheus")]
+use crate::metrics;
+#[cfg(feature = "providers")]
+use crate::provider;
+#[cfg(feature = "self-update")]
+use crate::self_update;
+#[cfg(feature = "tui")]
+use crate::tui;
+use crate::{
+    actions, app_config, cli, config, doctor, error, exit_node, format, health, import, menu,
+    profiles, rank, refresh, serve, state, status, theme, top, utils,
+};
+#[cfg(feature = "http")]
+use crate::{bench, speedtest};
+#[cfg(feature = "daemon")]
+use crate::{daemon, events, watch};
+use std::io::IsTerminal;
+use std::path::Path;
+use std::time::Duration;
+
+fn status(
+    profile: &profiles::Profile,
+    store: &state::StateStore,
+    options: &config::TunnelOptions,
+    formatter: &format::Formatter,
+) -> Result<(), error::Error> {
+    formatter.output(
+        &profile.name,
+        &status::report_profile(profile, store, options)?,
+    )?;
+    Ok(())
+}
+
+/// Runtime of the long-running commands; one-shot commands stay synchronous.
+///
+/// Two workers, so that timers, signals and background tasks keep running
+/// while the loop blocks on netlink or a connect in `block_in_place`.
+#[cfg(feature = "daemon")]
+fn runtime() -> Result<tokio::runtime::Runtime, std::io::Error> {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_io()
+        .enable_time()
+        .build()
+}
+
+/// Run the subcommand against the selected profile, returning the exit code
+/// the process ends with when it is not the default.
+pub fn run(
+    cli: &cli::Cli,
+    ctx: &actions::Context,
+    profile: &profiles::Profile,
+    formatter: &format::Formatter,
+    app_config: &app_config::AppConfig,
+    #[cfg_attr(not(feature = "http"), allow(unused_variables))] state_home: &Path,
+) -> Result<Option<i32>, Box<dyn std::error::Error>> {
+    let config_path = Path::new(&cli.config);
+    let filter = ctx.profile_filter;
+    let store = ctx.store;
+    let options_for = ctx.options_for;
+    let interface_name = profile.name.as_str();
+    let options = options_for(interface_name);
+    let probe_skip = || rank::Skip::new(ctx.metered, &app_config.battery);
+    match &cli.command {
+        Some(cli::Commands::Toggle) => actions::toggle(ctx, profile)?,
+        Some(cli::Commands::Run {
+            profile: target,
+            command,
+        }) => {
+            let target = match target {
+                Some(target) => {
+                    let profiles = ctx.profiles()?;
+                    profiles::find(&profiles, target)?.clone()
+                }
+                None => profile.clone(),
+            };
+            return Ok(Some(actions::run_through(ctx, &target, command)?));
+        }
+
+        #[cfg(feature = "daemon")]
+        Some(cli::Commands::Daemon {
+            interval,
+            restore_on_start,
+            tray,
+        }) => {
+            if *restore_on_start {
+                actions::restore(ctx)?;
+            }
+            let profiles = ctx.profiles()?;
+            runtime()?.block_on(daemon::run(
+                ctx,
+                interface_name,
+                &options,
+                Duration::from_secs(*interval),
+                app_config,
+                &profiles,
+                *tray,
+            ))?
+        }
+        Some(cli::Commands::Restore) => actions::restore(ctx)?,
+
+        Some(cli::Commands::Switch { profile: target }) => {
+            let profiles = ctx.profiles()?;
+            actions::switch(ctx, &profiles, target)?
+        }
+        Some(cli::Commands::List { probe }) => {
+            let profiles = ctx.profiles()?;
+            let dir = profiles::directory(config_path);
+            for (name, files) in profiles::shadowed(dir).unwrap_or_default() {
+                let files: Vec<String> = files
+                    .iter()
+                    .filter_map(|path| path.file_name())
+                    .map(|file| file.to_string_lossy().into_owned())
+                    .collect();
+                eprintln!(
+                    "Several profiles are named {} ({}), using {}",
+                    name,
+                    files.join(", "),
+                    files[0]
+                );
+            }
+            let probes = probe.then(|| rank::reachability(&profiles, options.netns, &probe_skip()));
+            for (i, profile) in profiles.iter().enumerate() {
+                let up = config::is_active(&profile.name, options_for(&profile.name).netns)
+                    .unwrap_or(false);
+                let mut line = format!("{} {}", if up { "*" } else { " " }, profile.name);
+                let tags = profile.tags();
+                if !tags.is_empty() {
+                    line.push_str(&format!(" [{}]", tags.join(", ")));
+                }
+                if let Some(note) = profile.note() {
+                    line.push_str(&format!(" — {}", note));
+                }
+                if let Some(rtt) = probes.as_ref().map(|probes| &probes[i]) {
+                    line.push_str(&format!("  {}", rank::describe(rtt)));
+                }
+                println!("{}", line);
+            }
+        }
+        Some(cli::Commands::Next) => {
+            let profiles = ctx.profiles()?;
+            if let Some(next) = profiles::next(&profiles, interface_name) {
+                actions::switch(ctx, &profiles, &next.name)?
+            }
+        }
+        Some(cli::Commands::Restart { profile: target }) => {
+            let profiles = ctx.profiles()?;
+            actions::restart(ctx, &profiles, target)?
+        }
+        #[cfg(feature = "http")]
+        Some(cli::Commands::Bench {
+            profile: target,
+            iterations,
+            url,
+            timeout,
+        }) => {
+            let profiles = ctx.profiles()?;
+            let target = profiles::find(&profiles, target)?;
+            let result = bench::run(
+                target,
+                &options_for(&target.name),
+                *iterations,
+                url,
+                Duration::from_secs(*timeout),
+                cli.debug,
+            );
+            ctx.audit("bench", &target.name, &result);
+            result?
+        }
+        Some(cli::Commands::Rank {
+            count,
+            connect_best,
+        }) => {
+            let profiles = ctx.profiles()?;
+            rank::run(
+                ctx,
+                &profiles,
+                *count,
+                *connect_best,
+                options.netns,
+                &probe_skip(),
+            )?
+        }
+        Some(cli::Commands::Serve {
+            stdio: _,
+            socket,
+            group,
+            interval,
+        }) => match socket {
+            Some(socket) => serve::run_socket(
+                ctx,
+                config_path,
+                cli.netns.as_deref(),
+                Duration::from_secs(*interval),
+                Path::new(socket),
+                group,
+                &app_config.access,
+            )?,
+            None => serve::run_stdio(
+                ctx,
+                config_path,
+                cli.netns.as_deref(),
+                Duration::from_secs(*interval),
+            )?,
+        },
+        #[cfg(feature = "daemon")]
+        Some(cli::Commands::Watch {
+            interval,
+            idle_interval,
+            settle,
+            hold,
+        }) => runtime()?.block_on(watch::run(
+            ctx,
+            config_path,
+            cli.netns.as_deref(),
+            formatter,
+            &watch::Polling {
+                fast: Duration::from_secs(*interval),
+                idle: Duration::from_secs(*idle_interval),
+                settle: Duration::from_secs(*settle),
+            },
+            Duration::from_millis(*hold),
+        ))?,
+        Some(cli::Commands::Doctor) => {
+            if !doctor::run(profile, options.netns) {
+                return Ok(Some(1));
+            }
+        }
+        Some(cli::Commands::Validate) => {
+            let wg_config =
+                config::TunnelBuilder::new(&profile.path, interface_name, &options).parse()?;
+            for note in wg_config.notes() {
+                println!("{}: note: {}", profile.name, note);
+            }
+            println!("{}: ok", profile.name);
+        }
+        Some(cli::Commands::DryRun { diff }) => {
+            let plan = config::TunnelBuilder::new(&profile.path, interface_name, &options)
+                .plan(&|_| {})?;
+            let lines = if *diff {
+                let host = config::is_active(interface_name, options.netns)?
+                    .then(|| config::read_host(interface_name, options.netns))
+                    .transpose()?;
+                plan.diff(host.as_ref())
+            } else {
+                plan.describe()
+            };
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+        Some(cli::Commands::WaitOnline {
+            profile: target,
+            timeout,
+            url,
+        }) => {
+            let profiles = ctx.profiles()?;
+            let target = profiles::find(&profiles, target)?;
+            health::wait_online(
+                &target.name,
+                cli.netns.as_deref(),
+                url.as_deref(),
+                Duration::from_secs(*timeout),
+                cli.debug,
+            )?
+        }
+        #[cfg(feature = "daemon")]
+        Some(cli::Commands::Events { interval }) => {
+            let profiles = ctx.profiles()?;
+            runtime()?.block_on(events::run(ctx, &profiles, Duration::from_secs(*interval)))?
+        }
+        #[cfg(feature = "tui")]
+        Some(cli::Commands::Tui { interval }) => {
+            let profiles = ctx.profiles()?;
+            tui::run(ctx, &profiles, Duration::from_secs(*interval))?
+        }
+        Some(cli::Commands::Top { seconds, count }) => top::run(
+            profile,
+            cli.netns.as_deref(),
+            Duration::from_secs(*seconds),
+            *count,
+        )?,
+        #[cfg(feature = "prometheus")]
+        Some(cli::Commands::Metrics) => {
+            let profiles = ctx.profiles()?;
+            let samples: Vec<_> = profiles
+                .iter()
+                .map(|profile| metrics::Sample::read(profile, options_for(&profile.name).netns))
+                .collect();
+            print!("{}", metrics::render(&samples));
+        }
+        Some(cli::Commands::ExitNode { command }) => match command {
+            cli::ExitNodeCommand::Set { peer } => {
+                let result = exit_node::set(store, profile, &options, peer);
+                ctx.audit("exit-node", &profile.name, &result);
+                result?
+            }
+            cli::ExitNodeCommand::List => exit_node::list(store, profile, &options)?,
+        },
+        #[cfg(feature = "http")]
+        Some(cli::Commands::Speedtest {
+            download_url,
+            upload_url,
+            bytes,
+            history,
+        }) => {
+            let history_path = state_home.join("history.jsonl");
+            if *history {
+                speedtest::print_history(interface_name, &history_path)?
+            } else {
+                speedtest::run(
+                    interface_name,
+                    &options,
+                    download_url,
+                    upload_url,
+                    *bytes,
+                    &history_path,
+                    cli.debug,
+                )?
+            }
+        }
+        Some(cli::Commands::Import {
+            file,
+            format,
+            name,
+            force,
+        }) => import::run(
+            profiles::directory(config_path),
+            Path::new(file),
+            *format,
+            name.as_deref(),
+            *force,
+        )?,
+        Some(cli::Commands::ExportPeer { name }) => {
+            let config = app_config.export_peer.get(&profile.name).ok_or_else(|| {
+                error::Error::Pool(format!(
+                    "No [export_peer.{}] section in the app config",
+                    profile.name
+                ))
+            })?;
+            export_peer::run(profile, config, name)?
+        }
+        #[cfg(feature = "providers")]
+        Some(cli::Commands::Provider {
+            provider:
+                cli::ProviderCommand::Mullvad {
+                    account,
+                    save_account,
+                    command,
+                },
+        }) => {
+            let dir = profiles::directory(config_path);
+            match command {
+                cli::MullvadCommand::List { location } => provider::list(location.as_deref())?,
+                cli::MullvadCommand::Connect { location } => {
+                    provider::connect(ctx, dir, location, account.as_deref(), *save_account)?
+                }
+                cli::MullvadCommand::Rotate => provider::rotate(ctx, dir)?,
+            }
+        }
+        #[cfg(feature = "providers")]
+        Some(cli::Commands::Provider {
+            provider: cli::ProviderCommand::ProtonVpn { key_from, command },
+        }) => {
+            let dir = profiles::directory(config_path);
+            match command {
+                cli::ProtonVpnCommand::List { country } => {
+                    provider::proton_list(country.as_deref())?
+                }
+                cli::ProtonVpnCommand::Connect { location } => provider::proton_connect(
+                    ctx,
+                    dir,
+                    location,
+                    key_from.as_deref().map(Path::new),
+                )?,
+                cli::ProtonVpnCommand::Rotate => provider::proton_rotate(ctx, dir)?,
+            }
+        }
+        Some(cli::Commands::Menu {
+            output,
+            probe,
+            elevate,
+        }) => {
+            let config_dir = profiles::directory(config_path);
+            let profiles = profiles::discover(config_dir, filter)?;
+            let output = match output {
+                Some(output) => std::path::PathBuf::from(output),
+                None => utils::get_config_home("wg-waybar")?.join("menu.xml"),
+            };
+            let probes = probe.then(|| rank::reachability(&profiles, options.netns, &probe_skip()));
+            let snippet = menu::generate(
+                &profiles,
+                config_dir,
+                cli.signal,
+                &output,
+                probes.as_deref(),
+                *elevate,
+            )?;
+            println!("{}", serde_json::to_string_pretty(&snippet)?);
+        }
+        Some(cli::Commands::InitWaybar { output, elevate }) => {
+            let output = match output {
+                Some(output) => std::path::PathBuf::from(output),
+                None => utils::get_config_home("waybar")?.join("wg-waybar.css"),
+            };
+            let snippet =
+                theme::init_waybar(cli.theme, &cli.config, cli.signal, &output, *elevate)?;
+            println!("{}", serde_json::to_string_pretty(&snippet)?);
+        }
+
+        // Done before selecting a profile.
+        #[cfg(feature = "schema")]
+        Some(cli::Commands::Schema { .. }) => {}
+        Some(cli::Commands::Explain { .. }) => {}
+        Some(cli::Commands::Adopt { .. }) => {}
+        #[cfg(feature = "hardware-keys")]
+        Some(cli::Commands::Enroll { .. }) => {}
+
+        #[cfg(feature = "self-update")]
+        Some(cli::Commands::SelfUpdate { check }) => self_update::run(*check, cli.debug)?,
+
+        Some(cli::Commands::Status { refresh }) => {
+            status(profile, store, &options, formatter)?;
+            if *refresh {
+                // The signal of the module, whatever `--signal` says when run by hand.
+                let signal_num = store.signal()?.unwrap_or(cli.signal);
+                let wait = cli.wait_for_waybar.map(Duration::from_secs);
+                refresh::Sinks::new(&app_config.refresh, signal_num, wait, cli.debug).refresh()?;
+            }
+        }
+        None => {
+            status(profile, store, &options, formatter)?;
+            // Waybar reads the status through a pipe, a terminal is someone checking by hand.
+            if !std::io::stdout().is_terminal()
+                && let Err(e) = store.record_signal(cli.signal)
+                && cli.debug
+            {
+                println!("Failed to record the signal: {}", e);
+            }
+        }
+    };
+    Ok(None)
+}
@@ -0,0 +1,323 @@
+use crate::app_config::{FocusAction, FocusRule};
+use crate::error;
+use crate::utils;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const I3_IPC_MAGIC: &[u8; 6] = b"i3-ipc";
+const SWAY_GET_WORKSPACES: u32 = 1;
+const SWAY_SUBSCRIBE: u32 = 2;
+const SWAY_WORKSPACE_EVENT: u32 = 0x8000_0000;
+const SWAY_WINDOW_EVENT: u32 = 0x8000_0003;
+
+/// Wait before following a compositor again after losing it, doubled on each
+/// failure in a row up to [`RECONNECT_MAX_DELAY`].
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// What currently has the focus in the compositor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Focus {
+    pub workspace: Option<String>,
+    /// Wayland `app_id`, or X11/Hyprland window class.
+    pub app_id: Option<String>,
+}
+
+impl FocusRule {
+    fn matches(&self, focus: &Focus) -> bool {
+        let workspace = self
+            .workspace
+            .as_ref()
+            .is_none_or(|workspace| focus.workspace.as_ref() == Some(workspace));
+        let app_id = self
+            .app_id
+            .as_ref()
+            .is_none_or(|app_id| focus.app_id.as_ref() == Some(app_id));
+        workspace && app_id
+    }
+
+    /// Whether the profile should be up once the rule starts or stops matching.
+    fn wants_up(&self, matching: bool) -> bool {
+        (self.action == FocusAction::Connect) == matching
+    }
+}
+
+/// Tracks which rules match the focus, to act only when that changes.
+#[derive(Default)]
+pub struct FocusRules {
+    matching: Vec<bool>,
+}
+
+impl FocusRules {
+    /// Profiles to bring up (`true`) or down after a focus change.
+    ///
+    /// A rule acts when it starts matching, and when it stops matching only if
+    /// it asks to `revert`.
+    pub fn changes<'a>(&mut self, rules: &'a [FocusRule], focus: &Focus) -> Vec<(&'a str, bool)> {
+        self.matching.resize(rules.len(), false);
+        let mut changes = Vec::new();
+        for (index, rule) in rules.iter().enumerate() {
+            let matching = rule.matches(focus);
+            if matching == self.matching[index] {
+                continue;
+            }
+            self.matching[index] = matching;
+            if matching || rule.revert {
+                changes.push((rule.profile.as_str(), rule.wants_up(matching)));
+            }
+        }
+        changes
+    }
+}
+
+fn ipc_error(e: impl std::fmt::Display) -> error::Error {
+    error::Error::Compositor(e.to_string())
+}
+
+fn sway_socket() -> Option<PathBuf> {
+    if let Some(path) = utils::get_environ("SWAYSOCK") {
+        return Some(PathBuf::from(path));
+    }
+    std::fs::read_dir(utils::user_runtime_dir()?)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("sway-ipc.") && name.ends_with(".sock"))
+        })
+}
+
+/// Directory of the running Hyprland instance, holding its sockets.
+fn hyprland_dir() -> Option<PathBuf> {
+    let hypr = utils::user_runtime_dir()?.join("hypr");
+    if let Some(signature) = utils::get_environ("HYPRLAND_INSTANCE_SIGNATURE") {
+        return Some(hypr.join(signature));
+    }
+    std::fs::read_dir(hypr)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| path.join(".socket2.sock").exists())
+}
+
+fn sway_send(stream: &mut UnixStream, kind: u32, payload: &str) -> std::io::Result<()> {
+    let mut message = I3_IPC_MAGIC.to_vec();
+    message.extend((payload.len() as u32).to_ne_bytes());
+    message.extend(kind.to_ne_bytes());
+    message.extend(payload.as_bytes());
+    stream.write_all(&message)
+}
+
+fn sway_receive(stream: &mut UnixStream) -> std::io::Result<(u32, serde_json::Value)> {
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header)?;
+    let length = u32::from_ne_bytes(header[6..10].try_into().expect("4 bytes"));
+    let kind = u32::from_ne_bytes(header[10..14].try_into().expect("4 bytes"));
+    let mut payload = vec![0u8; length as usize];
+    stream.read_exact(&mut payload)?;
+    Ok((kind, serde_json::from_slice(&payload)?))
+}
+
+/// Update `focus` from a sway event, returning whether it changed.
+fn apply_sway_event(kind: u32, event: &serde_json::Value, focus: &mut Focus) -> bool {
+    if event.get("change").and_then(|change| change.as_str()) != Some("focus") {
+        return false;
+    }
+    let previous = focus.clone();
+    match kind {
+        SWAY_WORKSPACE_EVENT => {
+            focus.workspace = event["current"]["name"].as_str().map(str::to_string);
+        }
+        SWAY_WINDOW_EVENT => {
+            let container = &event["container"];
+            focus.app_id = container["app_id"]
+                .as_str()
+                .or_else(|| container["window_properties"]["class"].as_str())
+                .map(str::to_string);
+        }
+        _ => {}
+    }
+    *focus != previous
+}
+
+/// Update `focus` from a line of Hyprland's event socket, returning whether it changed.
+fn apply_hyprland_event(line: &str, focus: &mut Focus) -> bool {
+    let Some((event, data)) = line.split_once(">>") else {
+        return false;
+    };
+    let previous = focus.clone();
+    match event {
+        "workspace" => focus.workspace = Some(data.to_string()),
+        "activewindow" => {
+            let class = data.split(',').next().unwrap_or_default();
+            focus.app_id = (!class.is_empty()).then(|| class.to_string());
+        }
+        _ => {}
+    }
+    *focus != previous
+}
+
+fn follow_sway(path: PathBuf, on_focus: &dyn Fn(Focus)) -> Result<(), error::Error> {
+    let mut stream = UnixStream::connect(path).map_err(ipc_error)?;
+    let mut focus = Focus::default();
+    sway_send(&mut stream, SWAY_GET_WORKSPACES, "").map_err(ipc_error)?;
+    let (_, workspaces) = sway_receive(&mut stream).map_err(ipc_error)?;
+    focus.workspace = workspaces
+        .as_array()
+        .and_then(|workspaces| workspaces.iter().find(|w| w["focused"] == true))
+        .and_then(|workspace| workspace["name"].as_str())
+        .map(str::to_string);
+    on_focus(focus.clone());
+    sway_send(&mut stream, SWAY_SUBSCRIBE, r#"["workspace","window"]"#).map_err(ipc_error)?;
+    loop {
+        let (kind, event) = sway_receive(&mut stream).map_err(ipc_error)?;
+        if apply_sway_event(kind, &event, &mut focus) {
+            on_focus(focus.clone());
+        }
+    }
+}
+
+fn follow_hyprland(dir: PathBuf, on_focus: &dyn Fn(Focus)) -> Result<(), error::Error> {
+    let mut focus = Focus::default();
+    if let Ok(mut stream) = UnixStream::connect(dir.join(".socket.sock")) {
+        let mut reply = String::new();
+        if stream.write_all(b"j/activeworkspace").is_ok()
+            && stream.read_to_string(&mut reply).is_ok()
+        {
+            focus.workspace = serde_json::from_str::<serde_json::Value>(&reply)
+                .ok()
+                .and_then(|workspace| workspace["name"].as_str().map(str::to_string));
+        }
+    }
+    on_focus(focus.clone());
+    let stream = UnixStream::connect(dir.join(".socket2.sock")).map_err(ipc_error)?;
+    for line in BufReader::new(stream).lines() {
+        if apply_hyprland_event(&line.map_err(ipc_error)?, &mut focus) {
+            on_focus(focus.clone());
+        }
+    }
+    Err(ipc_error("Hyprland closed its event socket"))
+}
+
+/// Follow whichever of sway or Hyprland runs, looking for its socket anew so
+/// that a restarted compositor is found again.
+fn follow(on_focus: &dyn Fn(Focus)) -> Result<(), error::Error> {
+    if let Some(path) = sway_socket() {
+        follow_sway(path, on_focus)
+    } else if let Some(dir) = hyprland_dir() {
+        follow_hyprland(dir, on_focus)
+    } else {
+        Err(ipc_error("no sway or Hyprland IPC socket found"))
+    }
+}
+
+/// Wait before the next attempt, after one that waited `last` and then
+/// followed the compositor for `lasted`: a connection that held starts over.
+fn retry_delay(last: Duration, lasted: Duration) -> Duration {
+    if lasted >= RECONNECT_MAX_DELAY {
+        RECONNECT_DELAY
+    } else {
+        (last * 2).clamp(RECONNECT_DELAY, RECONNECT_MAX_DELAY)
+    }
+}
+
+/// Follow the focus of sway or Hyprland on a background thread, reconnecting
+/// whenever the compositor closes its socket or restarts.
+///
+/// `on_focus` is called with the initial focus on each connection, then on
+/// every change.
+pub fn listen(on_focus: impl Fn(Focus) + Send + 'static, debug: bool) -> Result<(), error::Error> {
+    if sway_socket().is_none() && hyprland_dir().is_none() {
+        return Err(ipc_error("no sway or Hyprland IPC socket found"));
+    }
+    std::thread::spawn(move || {
+        let mut delay = Duration::ZERO;
+        loop {
+            let started = Instant::now();
+            let result = follow(&on_focus);
+            delay = retry_delay(delay, started.elapsed());
+            if let Err(e) = result
+                && debug
+            {
+                println!(
+                    "Lost the compositor focus, retrying in {} s: {}",
+                    delay.as_secs(),
+                    e
+                );
+            }
+            std::thread::sleep(delay);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(workspace: &str, revert: bool) -> FocusRule {
+        FocusRule {
+            profile: "bank".to_string(),
+            workspace: Some(workspace.to_string()),
+            app_id: None,
+            action: FocusAction::Connect,
+            revert,
+        }
+    }
+
+    fn on(workspace: &str) -> Focus {
+        Focus {
+            workspace: Some(workspace.to_string()),
+            app_id: None,
+        }
+    }
+
+    #[test]
+    fn acts_when_rules_start_or_stop_matching() {
+        let rules = [rule("banking", false), rule("work", true)];
+        let mut state = FocusRules::default();
+        assert_eq!(state.changes(&rules, &on("banking")), vec![("bank", true)]);
+        assert!(state.changes(&rules, &on("banking")).is_empty());
+        assert_eq!(state.changes(&rules, &on("work")), vec![("bank", true)]);
+        assert_eq!(state.changes(&rules, &on("mail")), vec![("bank", false)]);
+    }
+
+    #[test]
+    fn backs_off_while_the_compositor_is_gone() {
+        let quick = Duration::from_millis(10);
+        let mut delay = Duration::ZERO;
+        let mut delays = Vec::new();
+        for _ in 0..8 {
+            delay = retry_delay(delay, quick);
+            delays.push(delay.as_secs());
+        }
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(
+            retry_delay(RECONNECT_MAX_DELAY, Duration::from_secs(3600)),
+            RECONNECT_DELAY
+        );
+    }
+
+    #[test]
+    fn parses_compositor_events() {
+        let mut focus = Focus::default();
+        assert!(apply_hyprland_event("workspace>>banking", &mut focus));
+        assert!(apply_hyprland_event(
+            "activewindow>>firefox,Bank",
+            &mut focus
+        ));
+        assert!(!apply_hyprland_event(
+            "activewindow>>firefox,Other tab",
+            &mut focus
+        ));
+        assert_eq!(focus.app_id.as_deref(), Some("firefox"));
+
+        let event = serde_json::json!({"change": "focus", "current": {"name": "2"}});
+        assert!(apply_sway_event(SWAY_WORKSPACE_EVENT, &event, &mut focus));
+        assert_eq!(focus.workspace.as_deref(), Some("2"));
+    }
+}
@@ -1,27 +1,84 @@
-use crate::error;
+use crate::app_config::{GatewayConfig, SplitTunnelConfig};
+use crate::applied;
+use crate::cli::{FirewallBackend, RouteMode};
+use crate::encrypted;
+use crate::error::{self, WithContext};
+use crate::eyeballs::{self, Family};
+use crate::fetch;
+use crate::firewall;
+use crate::gateway;
+use crate::hardware_key::{self, KeySource};
+use crate::mock;
+use crate::netns;
+use crate::routing::{self, RoutingTable};
+use crate::split_tunnel;
+use crate::state::{Counters, Stage};
+use crate::sysctl;
+use crate::template;
+use crate::toml_profile;
+use crate::trace;
+use crate::utils;
 use base64::prelude::*;
 use defguard_wireguard_rs::key::Key;
 use defguard_wireguard_rs::net::IpAddrMask;
-use defguard_wireguard_rs::{InterfaceConfiguration, host::Peer};
-use defguard_wireguard_rs::{Kernel, WGApi, WireguardInterfaceApi};
+use defguard_wireguard_rs::{
+    InterfaceConfiguration,
+    host::{Host, Peer},
+};
 use ini::{Ini, Properties};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
-use std::net::{IpAddr, SocketAddr};
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::path::Path;
 use std::str::FromStr;
-use x25519_dalek::PublicKey;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use x25519_dalek::{PublicKey, StaticSecret};
 
+/// A profile as read from its file, before anything is resolved.
 #[derive(Debug)]
-struct WireGuardConfig {
+pub struct WireGuardConfig {
     interface: InterfaceConfig,
     peers: Vec<PeerConfig>,
 }
 
+impl WireGuardConfig {
+    /// What was assumed while reading the profile, like the prefix of a bare `Address`.
+    pub fn notes(&self) -> &[String] {
+        &self.interface.notes
+    }
+}
+
+/// `PrivateKey` of the interface, or the device it is unsealed from.
+enum PrivateKey {
+    Inline(String),
+    /// `# PrivateKeyFrom =`, only read when the tunnel comes up.
+    Hardware(KeySource),
+}
+
+impl PrivateKey {
+    fn resolve(&self) -> Result<String, error::Error> {
+        match self {
+            PrivateKey::Inline(key) => Ok(key.clone()),
+            PrivateKey::Hardware(source) => hardware_key::private_key(source),
+        }
+    }
+}
+
 struct InterfaceConfig {
-    private_key: String,
+    private_key: PrivateKey,
     addresses: Vec<String>,
     dns: Option<Vec<String>>,
     listen_port: Option<u32>,
+    table: RoutingTable,
+    /// `# Metric =` of the routes of every peer without one of its own.
+    metric: Option<u32>,
+    mtu: Option<u32>,
+    /// Mark of the encrypted packets, set with `wg` as the API has no way to.
+    fwmark: Option<u32>,
+    /// What was assumed while reading the profile, for `validate` to report.
+    notes: Vec<String>,
 }
 impl std::fmt::Debug for InterfaceConfig {
     // To avoid debugging private_key
@@ -30,17 +87,42 @@ impl std::fmt::Debug for InterfaceConfig {
             .field("name", &self.addresses)
             .field("addresses", &self.addresses)
             .field("listen_port", &self.listen_port)
+            .field("table", &self.table)
+            .field("metric", &self.metric)
+            .field("mtu", &self.mtu)
+            .field("fwmark", &self.fwmark)
             .finish_non_exhaustive()
     }
 }
 
+/// Some providers write `Address = 10.2.0.2`; a bare address is a single host.
+fn with_host_prefix(address: &str, ip: IpAddr) -> String {
+    if address.contains('/') {
+        return address.to_string();
+    }
+    let prefix = if ip.is_ipv4() { 32 } else { 128 };
+    format!("{}/{}", address, prefix)
+}
+
 impl InterfaceConfig {
     fn load(properties: &Properties) -> Result<Self, error::Error> {
-        let private_key = properties
-            .get("PrivateKey")
-            .ok_or_else(|| error::MissingPropertyError("PrivateKey is missing".into()))?
-            .to_string();
+        let private_key = match (
+            properties.get("PrivateKey"),
+            properties.get("PrivateKeyFrom"),
+        ) {
+            (Some(_), Some(_)) => {
+                return Err(error::Error::InvalidFormat {
+                    message: "PrivateKey and PrivateKeyFrom cannot both be set".to_string(),
+                });
+            }
+            (Some(key), None) => PrivateKey::Inline(key.to_string()),
+            (None, Some(source)) => PrivateKey::Hardware(source.parse()?),
+            (None, None) => {
+                return Err(error::MissingPropertyError("PrivateKey is missing".into()).into());
+            }
+        };
 
+        let mut notes = Vec::new();
         let addresses = properties
             .get("Address")
             .ok_or_else(|| error::MissingPropertyError("Address is missing".into()))?
@@ -48,16 +130,15 @@ impl InterfaceConfig {
             .map(|s| s.trim())
             .filter(|s| !s.is_empty())
             .map(|s| {
-                if !s.contains('/') {
-                    return Err(error::Error::InvalidFormat {
-                        message: format!("Invalid Address format: {}", s),
-                    });
-                }
                 let parts: Vec<&str> = s.split('/').collect();
-                IpAddr::from_str(parts[0]).map_err(|_| error::Error::InvalidFormat {
+                let ip = IpAddr::from_str(parts[0]).map_err(|_| error::Error::InvalidFormat {
                     message: format!("Invalid IP in Address: {}", parts[0]),
                 })?;
-                Ok(s.to_string())
+                let address = with_host_prefix(s, ip);
+                if address != s {
+                    notes.push(format!("Address {} has no prefix, taken as {}", s, address));
+                }
+                Ok(address)
             })
             .collect::<Result<Vec<String>, error::Error>>()?;
 
@@ -81,11 +162,37 @@ impl InterfaceConfig {
             })
             .transpose()?;
 
+        let table = properties
+            .get("Table")
+            .map(RoutingTable::from_str)
+            .transpose()?
+            .unwrap_or(RoutingTable::Auto);
+
+        let mtu = properties
+            .get("MTU")
+            .map(|mtu| {
+                mtu.trim()
+                    .parse::<u32>()
+                    .map_err(|_| error::Error::InvalidFormat {
+                        message: format!("Invalid MTU: {}", mtu),
+                    })
+            })
+            .transpose()?;
+
         Ok(Self {
             private_key,
             addresses,
             dns,
             listen_port,
+            table,
+            metric: parse_metric(properties)?,
+            mtu,
+            fwmark: properties
+                .get("FwMark")
+                .map(parse_fwmark)
+                .transpose()?
+                .flatten(),
+            notes,
         })
     }
 }
@@ -93,8 +200,97 @@ impl InterfaceConfig {
 #[derive(Debug)]
 struct PeerConfig {
     public_key: PublicKey,
-    endpoint: Option<SocketAddr>,
+    preshared_key: Option<Key>,
+    /// `ip:port` or `host:port`, resolved when the tunnel comes up.
+    endpoint: Option<String>,
     allowed_ips: Vec<String>,
+    /// Seconds between keepalive packets, disabled when unset.
+    persistent_keepalive: Option<u16>,
+    /// `# Metric =` of the routes to its AllowedIPs.
+    metric: Option<u32>,
+    /// `# Name =` shown in place of its key.
+    name: Option<String>,
+    /// `# ExcludedIPs =` carved out of its AllowedIPs.
+    excluded_ips: Vec<IpAddrMask>,
+    /// `# Groups =` the peer is configured with when passed to `--groups`.
+    groups: Vec<String>,
+}
+
+/// Keys wg-waybar reads from comments, so that wg-quick still accepts the file.
+const COMMENT_KEYS: &[&str] = &["Metric", "Name", "ExcludedIPs", "Groups", "PrivateKeyFrom"];
+
+/// `conf` with the `# Metric = 50` comments turned into keys of their section.
+fn uncomment_keys(conf: &str) -> String {
+    conf.lines()
+        .map(|line| {
+            let key = line
+                .trim()
+                .strip_prefix('#')
+                .and_then(|comment| comment.split_once('='))
+                .and_then(|(name, value)| {
+                    COMMENT_KEYS
+                        .iter()
+                        .find(|key| name.trim().eq_ignore_ascii_case(key))
+                        .map(|key| format!("{} = {}", key, value.trim()))
+                });
+            key.unwrap_or_else(|| line.to_string())
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn parse_metric(properties: &Properties) -> Result<Option<u32>, error::Error> {
+    properties
+        .get("Metric")
+        .map(|metric| {
+            metric
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| error::Error::InvalidFormat {
+                    message: format!("Invalid Metric: {}", metric),
+                })
+        })
+        .transpose()
+}
+
+/// `FwMark` as wg-quick takes it: decimal, `0x` hexadecimal, or `off`.
+fn parse_fwmark(value: &str) -> Result<Option<u32>, error::Error> {
+    let value = value.trim();
+    let mark = match value.strip_prefix("0x") {
+        _ if value == "off" => Ok(0),
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse::<u32>(),
+    }
+    .map_err(|_| error::Error::InvalidFormat {
+        message: format!("Invalid FwMark: {}", value),
+    })?;
+    Ok((mark != 0).then_some(mark))
+}
+
+fn parse_endpoint(value: &str) -> Result<String, error::PeerConfigError> {
+    match SocketAddr::from_str(value) {
+        Ok(addr) => Ok(addr.to_string()),
+        Err(e) => match value.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(value.to_string())
+            }
+            _ => Err(error::PeerConfigError::EndPoint(e)),
+        },
+    }
+}
+
+fn resolve_endpoint(endpoint: &str) -> Result<SocketAddr, error::Error> {
+    resolve_endpoint_all(endpoint)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| error::Error::Resolve(format!("{}: no address found", endpoint)))
+}
+
+fn resolve_endpoint_all(endpoint: &str) -> Result<Vec<SocketAddr>, error::Error> {
+    Ok(endpoint
+        .to_socket_addrs()
+        .map_err(|e| error::Error::Resolve(format!("{}: {}", endpoint, e)))?
+        .collect())
 }
 
 impl PeerConfig {
@@ -115,11 +311,35 @@ impl PeerConfig {
 
         let public_key = PublicKey::from(public_key_array);
 
-        let endpoint = properties
-            .get("Endpoint")
-            .map(|e| SocketAddr::from_str(e).map_err(error::PeerConfigError::EndPoint))
+        let preshared_key = properties
+            .get("PresharedKey")
+            .map(|key| {
+                let bytes = BASE64_STANDARD
+                    .decode(key.trim())
+                    .map_err(error::Error::Base64)?;
+                let bytes: [u8; 32] =
+                    bytes.try_into().map_err(|_| error::Error::InvalidFormat {
+                        message: "PresharedKey must be 32 bytes".to_string(),
+                    })?;
+                Ok::<_, error::Error>(Key::new(bytes))
+            })
             .transpose()?;
 
+        let endpoint = properties.get("Endpoint").map(parse_endpoint).transpose()?;
+
+        let persistent_keepalive = match properties.get("PersistentKeepalive").map(str::trim) {
+            None | Some("off") => None,
+            Some(value) => match value.parse::<u16>() {
+                Ok(0) => None,
+                Ok(seconds) => Some(seconds),
+                Err(_) => {
+                    return Err(error::Error::InvalidFormat {
+                        message: format!("Invalid PersistentKeepalive: {}", value),
+                    });
+                }
+            },
+        };
+
         let allowed_ips = properties
             .get("AllowedIPs")
             .ok_or_else(|| error::MissingPropertyError("AllowedIPs is missing".into()))?
@@ -146,28 +366,373 @@ impl PeerConfig {
             )));
         }
 
+        let excluded_ips = properties
+            .get("ExcludedIPs")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(parse_ip_addr_mask)
+            .collect::<Result<Vec<IpAddrMask>, error::Error>>()?;
+
         Ok(Self {
             public_key,
+            preshared_key,
             endpoint,
             allowed_ips,
+            persistent_keepalive,
+            metric: parse_metric(properties)?,
+            name: properties
+                .get("Name")
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_string),
+            excluded_ips,
+            groups: properties
+                .get("Groups")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|group| !group.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+}
+
+impl PeerConfig {
+    fn public_key_base64(&self) -> String {
+        BASE64_STANDARD.encode(self.public_key.as_bytes())
+    }
+
+    fn routes_default(&self) -> bool {
+        self.allowed_ips.iter().any(|ip| is_default_route(ip))
+    }
+
+    /// Whether the peer is configured with `--groups`: every peer without
+    /// them, those without a group, and those in one of them.
+    fn in_groups(&self, groups: &[String]) -> bool {
+        groups.is_empty()
+            || self.groups.is_empty()
+            || self.groups.iter().any(|group| {
+                groups
+                    .iter()
+                    .any(|wanted| wanted.eq_ignore_ascii_case(group))
+            })
+    }
+}
+
+/// The configuration with only the peers of `groups`.
+fn select_groups(
+    mut wg_config: WireGuardConfig,
+    groups: &[String],
+) -> Result<WireGuardConfig, error::Error> {
+    wg_config.peers.retain(|peer| peer.in_groups(groups));
+    if wg_config.peers.is_empty() {
+        return Err(error::Error::InvalidFormat {
+            message: format!("No peer is in the groups {}", groups.join(", ")),
+        });
+    }
+    Ok(wg_config)
+}
+
+/// `--groups` the running interface was brought up with, kept from the connect
+/// so that later commands act on the same peers, or `requested` without one.
+fn groups_in_use(interface_name: &str, requested: &[String]) -> Vec<String> {
+    applied::load("groups", interface_name).unwrap_or_else(|| requested.to_vec())
+}
+
+fn is_default_route(allowed_ip: &str) -> bool {
+    matches!(allowed_ip.trim(), "0.0.0.0/0" | "::/0")
+}
+
+/// Public key of the peer carrying the default route, when several peers claim it.
+///
+/// The `selected` one wins if it is among them, otherwise the first one does.
+fn exit_node_key(peers: &[PeerConfig], selected: Option<&str>) -> Option<String> {
+    let candidates: Vec<String> = peers
+        .iter()
+        .filter(|peer| peer.routes_default())
+        .map(PeerConfig::public_key_base64)
+        .collect();
+    if candidates.len() < 2 {
+        return None;
+    }
+    selected
+        .filter(|key| candidates.iter().any(|candidate| candidate == key))
+        .map(str::to_string)
+        .or_else(|| candidates.into_iter().next())
+}
+
+/// Bits of an address, and how many its family has.
+fn address_bits(ip: IpAddr) -> (u128, u8) {
+    match ip {
+        IpAddr::V4(ip) => (u32::from(ip).into(), 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    }
+}
+
+/// `network` without the addresses of `excluded`, as the fewest networks.
+fn subtract(network: &IpAddrMask, excluded: &IpAddrMask) -> Vec<IpAddrMask> {
+    let (bits, width) = address_bits(network.ip);
+    let (excluded_bits, excluded_width) = address_bits(excluded.ip);
+    let mask = |cidr: u8| u128::MAX.checked_shl(u32::from(width - cidr)).unwrap_or(0);
+    let common = network.cidr.min(excluded.cidr);
+    if width != excluded_width || (bits ^ excluded_bits) & mask(common) != 0 {
+        return vec![network.clone()];
+    }
+    // Every half on the way down to `excluded` that does not contain it.
+    (network.cidr + 1..=excluded.cidr)
+        .map(|cidr| {
+            let sibling = (excluded_bits & mask(cidr)) ^ (1 << (width - cidr));
+            let ip = match network.ip {
+                IpAddr::V4(_) => IpAddr::from(std::net::Ipv4Addr::from(sibling as u32)),
+                IpAddr::V6(_) => IpAddr::from(std::net::Ipv6Addr::from(sibling)),
+            };
+            IpAddrMask::new(ip, cidr)
+        })
+        .collect()
+}
+
+/// AllowedIPs of a peer, without the default routes unless it is the exit node,
+/// and without its ExcludedIPs.
+///
+/// A default route carved up no longer goes through the fwmark rules keeping
+/// the endpoint out of the tunnel, so the `endpoints` the peer resolved to are
+/// excluded too.
+fn peer_allowed_ips(
+    peer: &PeerConfig,
+    exit_node: Option<&str>,
+    endpoints: &[SocketAddr],
+) -> Result<Vec<IpAddrMask>, error::Error> {
+    let is_exit_node = exit_node.is_none_or(|key| key == peer.public_key_base64());
+    let networks = peer
+        .allowed_ips
+        .iter()
+        .filter(|ip| is_exit_node || !is_default_route(ip))
+        .map(|ip| parse_ip_addr_mask(ip))
+        .collect::<Result<Vec<IpAddrMask>, error::Error>>()?;
+    if peer.excluded_ips.is_empty() {
+        return Ok(networks);
+    }
+    let mut excluded = peer.excluded_ips.clone();
+    if networks.iter().any(|network| network.cidr == 0) {
+        excluded.extend(endpoints.iter().map(|endpoint| {
+            let (_, width) = address_bits(endpoint.ip());
+            IpAddrMask::new(endpoint.ip(), width)
+        }));
+    }
+    Ok(excluded.iter().fold(networks, |networks, excluded| {
+        networks
+            .iter()
+            .flat_map(|network| subtract(network, excluded))
+            .collect()
+    }))
+}
+
+/// A peer of a configuration, as listed to pick an exit node.
+pub struct PeerSummary {
+    pub public_key: String,
+    /// `# Name =` of the peer, if it has one.
+    pub name: Option<String>,
+    pub endpoint: Option<String>,
+    /// Whether its AllowedIPs include a default route.
+    pub default_route: bool,
+}
+
+/// Peers of a profile, limited to the groups the interface is configured with.
+pub fn peers(
+    config_path: &Path,
+    interface_name: &str,
+    groups: &[String],
+) -> Result<Vec<PeerSummary>, error::Error> {
+    let groups = groups_in_use(interface_name, groups);
+    Ok(select_groups(parse_wg_config(config_path)?, &groups)?
+        .peers
+        .iter()
+        .map(|peer| PeerSummary {
+            public_key: peer.public_key_base64(),
+            name: peer.name.clone(),
+            endpoint: peer.endpoint.clone(),
+            default_route: peer.routes_default(),
         })
+        .collect())
+}
+
+fn static_secret(private_key: &str) -> Result<StaticSecret, error::Error> {
+    let private_key: [u8; 32] = BASE64_STANDARD
+        .decode(private_key)?
+        .try_into()
+        .map_err(|_| error::Error::InvalidFormat {
+            message: "PrivateKey must be 32 bytes".to_string(),
+        })?;
+    Ok(StaticSecret::from(private_key))
+}
+
+/// Public key of the interface of a profile, and its addresses.
+pub fn identity(config_path: &Path) -> Result<(String, Vec<String>), error::Error> {
+    let interface = parse_wg_config(config_path)?.interface;
+    let public_key = PublicKey::from(&static_secret(&interface.private_key.resolve()?)?);
+    Ok((
+        BASE64_STANDARD.encode(public_key.as_bytes()),
+        interface.addresses,
+    ))
+}
+
+/// What a handshake with the first peer having an endpoint takes, without
+/// bringing the profile up: the private key of the interface, the public key
+/// of the peer and its endpoint resolved.
+pub fn handshake_target(
+    config_path: &Path,
+) -> Result<(StaticSecret, PublicKey, SocketAddr), error::Error> {
+    let wg_config = parse_wg_config(config_path)?;
+    // Unsealing it could prompt for a PIN or a touch.
+    let PrivateKey::Inline(private_key) = &wg_config.interface.private_key else {
+        return Err(error::Error::Probe(
+            "the private key is only unsealed when the tunnel comes up".to_string(),
+        ));
+    };
+    let (peer, endpoint) = wg_config
+        .peers
+        .iter()
+        .find_map(|peer| Some((peer, peer.endpoint.as_deref()?)))
+        .ok_or_else(|| error::Error::Probe("no endpoint".to_string()))?;
+    Ok((
+        static_secret(private_key)?,
+        peer.public_key,
+        resolve_endpoint(endpoint)?,
+    ))
+}
+
+/// AllowedIPs of every peer, as written in the configuration.
+pub fn allowed_ips(config_path: &Path) -> Result<Vec<String>, error::Error> {
+    Ok(parse_wg_config(config_path)?
+        .peers
+        .into_iter()
+        .flat_map(|peer| peer.allowed_ips)
+        .collect())
+}
+
+/// DNS servers the configuration sets for the tunnel.
+pub fn dns_servers(config_path: &Path) -> Result<Vec<IpAddr>, error::Error> {
+    Ok(parse_wg_config(config_path)?
+        .interface
+        .dns
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|dns| IpAddr::from_str(dns).ok())
+        .collect())
+}
+
+/// Move the default routes of a running interface to the peer `exit_node`.
+///
+/// Routes point at the interface itself, so only the AllowedIPs of the peers change.
+pub fn apply_exit_node(
+    config_path: &Path,
+    interface_name: &str,
+    options: &TunnelOptions,
+    exit_node: &str,
+) -> Result<(), error::Error> {
+    let netns = options.netns;
+    let groups = groups_in_use(interface_name, options.groups);
+    let wg_config = select_groups(parse_wg_config(config_path)?, &groups)?;
+    // Routes are not simulated.
+    if mock::enabled() {
+        return Ok(());
     }
+    let exit_node = exit_node_key(&wg_config.peers, Some(exit_node));
+    let wg_api = trace::Api::new(interface_name)?;
+    // The endpoints in use rather than resolved again, possibly through the tunnel.
+    let host = read_host(interface_name, netns)?;
+    // Peers losing the default route go first, the kernel keeps an allowed IP on a single peer.
+    let mut peers: Vec<&PeerConfig> = wg_config.peers.iter().collect();
+    peers.sort_by_key(|peer| exit_node.as_deref() == Some(peer.public_key_base64().as_str()));
+    for peer in peers {
+        let key = Key::new(*peer.public_key.as_bytes());
+        let endpoints: Vec<SocketAddr> = host
+            .peers
+            .get(&key)
+            .and_then(|peer| peer.endpoint)
+            .into_iter()
+            .collect();
+        let mut peer_config = Peer::new(key);
+        peer_config.preshared_key = peer.preshared_key.clone();
+        peer_config.set_allowed_ips(peer_allowed_ips(peer, exit_node.as_deref(), &endpoints)?);
+        match netns {
+            Some(name) => netns::with(name, || wg_api.configure_peer(&peer_config))??,
+            None => wg_api.configure_peer(&peer_config)?,
+        }
+    }
+    Ok(())
 }
 
+/// Parse a profile, or the configuration fetched for it when it has a `FetchCommand`,
+/// rendered from it when it is a template, or decrypted from it when encrypted.
 fn parse_wg_config(file_path: &Path) -> Result<WireGuardConfig, error::Error> {
-    let conf_str = fs::read_to_string(file_path)?;
-    let conf = Ini::load_from_str(&conf_str)?;
+    if fetch::is_fetched(file_path) {
+        let fetched = fetch::fetched_path(file_path)?;
+        if !fetched.exists() {
+            return Err(error::Error::Fetch(format!(
+                "{} was not fetched yet",
+                file_path.display()
+            )));
+        }
+        return parse_wg_config_file(&fetched);
+    }
+    if template::is_template(file_path) {
+        let rendered = template::rendered_path(file_path)?;
+        if !rendered.exists() {
+            return Err(error::Error::InvalidFormat {
+                message: format!(
+                    "{} is a template, its placeholders are resolved when it comes up",
+                    file_path.display()
+                ),
+            });
+        }
+        return parse_wg_config_file(&rendered);
+    }
+    if encrypted::is_encrypted(file_path) {
+        let decrypted = encrypted::decrypted_path(file_path)?;
+        if !decrypted.exists() {
+            return Err(error::Error::InvalidFormat {
+                message: format!(
+                    "{} is encrypted, it is decrypted when it comes up",
+                    file_path.display()
+                ),
+            });
+        }
+        return parse_wg_config_file(&decrypted);
+    }
+    parse_wg_config_file(file_path)
+}
+
+fn parse_wg_config_file(file_path: &Path) -> Result<WireGuardConfig, error::Error> {
+    let path = file_path.display();
+    let mut conf_str =
+        fs::read_to_string(file_path).with_context(|| format!("reading {}", path))?;
+    if file_path.extension().is_some_and(|ext| ext == "toml") {
+        conf_str = toml_profile::to_ini(&conf_str).with_context(|| path.to_string())?;
+    }
+    let conf = Ini::load_from_str(&uncomment_keys(&conf_str)).with_context(|| path.to_string())?;
 
     let interface_section = conf
         .section(Some("Interface"))
-        .ok_or(error::MissingSectionError("Interface".into()))?;
+        .ok_or(error::MissingSectionError("Interface".into()))
+        .with_context(|| path.to_string())?;
 
-    let interface_config = InterfaceConfig::load(interface_section)?;
+    let mut interface_config = InterfaceConfig::load(interface_section)
+        .with_context(|| format!("{}: [Interface]", path))?;
+    // A header of the profile, like `# Tags`, before any section.
+    if interface_config.metric.is_none() {
+        interface_config.metric =
+            parse_metric(conf.general_section()).with_context(|| path.to_string())?;
+    }
 
     let mut peers = Vec::new();
     for (section_name, section) in conf.iter() {
         if section_name.unwrap_or_default().starts_with("Peer") {
-            let peer_config = PeerConfig::load(section)?;
+            let peer_config = PeerConfig::load(section)
+                .with_context(|| format!("{}: peer {}", path, peers.len() + 1))?;
             peers.push(peer_config);
         }
     }
@@ -178,7 +743,7 @@ fn parse_wg_config(file_path: &Path) -> Result<WireGuardConfig, error::Error> {
     })
 }
 
-fn parse_ip_addr_mask(addr: &str) -> Result<IpAddrMask, error::Error> {
+pub fn parse_ip_addr_mask(addr: &str) -> Result<IpAddrMask, error::Error> {
     let parts: Vec<&str> = addr.split('/').collect();
     if parts.len() != 2 {
         return Err(error::Error::InvalidFormat {
@@ -196,58 +761,1173 @@ fn parse_ip_addr_mask(addr: &str) -> Result<IpAddrMask, error::Error> {
     Ok(IpAddrMask::new(ip, cidr))
 }
 
-pub fn configure_wireguard(config_path: &Path, interface_name: &str, port: u32) -> Result<(), error::Error> {
-    let wg_config = parse_wg_config(config_path)?;
-    let wg_api = WGApi::<Kernel>::new(interface_name.to_string())?;
-    wg_api.create_interface()?;
+/// Endpoints of the peers of a configuration, resolved without bringing it up.
+pub fn configured_endpoints(config_path: &Path) -> Result<Vec<SocketAddr>, error::Error> {
+    parse_wg_config(config_path)?
+        .peers
+        .iter()
+        .filter_map(|peer| peer.endpoint.as_deref())
+        .map(resolve_endpoint)
+        .collect()
+}
 
-    let addresses = wg_config
-        .interface
+/// Options shared by bringing a tunnel up and tearing it down again.
+pub struct TunnelOptions<'a> {
+    pub port: u32,
+    pub route_mode: RouteMode,
+    pub netns: Option<&'a str>,
+    /// Cgroups to tunnel with [`RouteMode::Cgroup`].
+    pub split_tunnel: Option<&'a SplitTunnelConfig>,
+    /// Public key of the peer carrying the default route when several claim it.
+    pub exit_node: Option<String>,
+    /// Family tried first for endpoints resolving to both IPv4 and IPv6.
+    pub endpoint_family: Option<Family>,
+    /// Physical interface the encrypted traffic is pinned to.
+    pub bind_interface: Option<&'a str>,
+    /// Honor `PersistentKeepalive`, off on metered uplinks if configured.
+    pub keepalive: bool,
+    /// Values of the placeholders of a template profile.
+    pub variables: Option<&'a HashMap<String, String>>,
+    /// LAN routed through the tunnel, not supported inside a namespace.
+    pub gateway: Option<&'a GatewayConfig>,
+    /// Firewall the gateway and kill switch rules go through.
+    pub firewall_backend: FirewallBackend,
+    /// Drop what leaves outside of the tunnel while it is up.
+    pub kill_switch: bool,
+    /// Loosen strict `rp_filter` while the tunnel is up rather than warn about it.
+    pub relax_rp_filter: bool,
+    /// Groups of the peers to configure, every peer when empty.
+    pub groups: &'a [String],
+    /// Print the sysctls changed and put back.
+    pub debug: bool,
+}
+
+/// What the command line brings a tunnel up with when given no option.
+impl Default for TunnelOptions<'_> {
+    fn default() -> Self {
+        Self {
+            port: 40077,
+            route_mode: RouteMode::Default,
+            netns: None,
+            split_tunnel: None,
+            exit_node: None,
+            endpoint_family: None,
+            bind_interface: None,
+            keepalive: true,
+            variables: None,
+            gateway: None,
+            firewall_backend: FirewallBackend::Auto,
+            kill_switch: false,
+            relax_rp_filter: false,
+            debug: false,
+            groups: &[],
+        }
+    }
+}
+
+impl TunnelOptions<'_> {
+    fn split_tunnel(&self, interface_name: &str) -> Result<&SplitTunnelConfig, error::Error> {
+        self.split_tunnel
+            .ok_or_else(|| error::Error::InvalidFormat {
+                message: format!(
+                    "No [split_tunnel.{}] section in the app config",
+                    interface_name
+                ),
+            })
+    }
+}
+
+/// Attempts at each netlink call bringing the interface up before giving up.
+const BRING_UP_ATTEMPTS: u32 = 4;
+/// Delay before the first retry, doubled for each one after it.
+const BRING_UP_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Whether the kernel only refused for now, as it does for a while after resume.
+fn is_transient(error: &error::Error) -> bool {
+    matches!(
+        error.errno(),
+        Some(libc::EBUSY | libc::ENOBUFS | libc::EAGAIN)
+    )
+}
+
+/// Somewhere between half of `backoff` and all of it, so that commands racing
+/// for the kernel after resume do not retry in step.
+fn jittered(backoff: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    backoff / 2 + backoff.mul_f64((random % 1000) as f64 / 2000.0)
+}
+
+/// Run `step` again after a jittered, growing delay while it fails with a
+/// transient error, returning the last failure once the attempts are used up.
+fn retry<T>(
+    backoff: Duration,
+    mut step: impl FnMut() -> Result<T, error::Error>,
+) -> Result<T, error::Error> {
+    let mut delay = backoff;
+    for _ in 1..BRING_UP_ATTEMPTS {
+        match step() {
+            Err(e) if is_transient(&e) => {
+                std::thread::sleep(jittered(delay));
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    step()
+}
+
+/// Whether `peer` completed a handshake since the interface came up.
+fn has_handshake(wg_api: &trace::Api, peer: &Peer) -> bool {
+    wg_api
+        .read_interface_data()
+        .ok()
+        .and_then(|host| host.peers.get(&peer.public_key).cloned())
+        .and_then(|peer| peer.last_handshake)
+        .is_some_and(|time| time > UNIX_EPOCH)
+}
+
+/// Try the endpoints of dual-stack peers one family after the other.
+///
+/// The preferred family gets [`eyeballs::FALLBACK_DELAY`] to complete a
+/// handshake, which a short keepalive triggers without traffic, and the other
+/// one what is left of [`eyeballs::RACE_TIMEOUT`]. Returns the family of the
+/// first handshake, going back to the preferred family otherwise.
+fn race_families(
+    wg_api: &trace::Api,
+    peers: &mut [Peer],
+    candidates: &[Vec<SocketAddr>],
+    progress: &dyn Fn(Stage),
+) -> Result<Option<Family>, error::Error> {
+    let racing: Vec<usize> = (0..peers.len())
+        .filter(|index| candidates[*index].len() > 1)
+        .collect();
+    if racing.is_empty() {
+        return Ok(None);
+    }
+    progress(Stage::Handshaking);
+    let keepalives: Vec<Option<u16>> = racing
+        .iter()
+        .map(|index| peers[*index].persistent_keepalive_interval)
+        .collect();
+    // Candidates all list the preferred family first.
+    let preferred = Family::of(&candidates[racing[0]][0]);
+    let endpoint = |index: usize, family| {
+        candidates[index]
+            .iter()
+            .find(|addr| Family::of(addr) == family)
+            .expect("racing peers resolve to both families")
+            .to_string()
+    };
+    let mut winner = None;
+    let start = Instant::now();
+    'families: for family in [preferred, preferred.other()] {
+        for index in &racing {
+            let peer = &mut peers[*index];
+            peer.set_endpoint(&endpoint(*index, family))?;
+            peer.persistent_keepalive_interval = Some(1);
+            wg_api.configure_peer(peer)?;
+        }
+        let until = if family == preferred {
+            eyeballs::FALLBACK_DELAY
+        } else {
+            eyeballs::RACE_TIMEOUT
+        };
+        while start.elapsed() < until {
+            if racing
+                .iter()
+                .any(|index| has_handshake(wg_api, &peers[*index]))
+            {
+                winner = Some(family);
+                break 'families;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+    for (index, keepalive) in racing.iter().zip(keepalives) {
+        let peer = &mut peers[*index];
+        if winner.is_none() {
+            peer.set_endpoint(&endpoint(*index, preferred))?;
+        }
+        // Zero turns the racing keepalive off, `None` would leave it running.
+        peer.persistent_keepalive_interval = keepalive.or(Some(0));
+        wg_api.configure_peer(peer)?;
+    }
+    Ok(winner)
+}
+
+fn parse_addresses(interface: &InterfaceConfig) -> Result<Vec<IpAddrMask>, error::Error> {
+    interface
         .addresses
         .iter()
         .map(|addr| parse_ip_addr_mask(addr))
-        .collect::<Result<Vec<IpAddrMask>, error::Error>>()?;
+        .collect()
+}
 
-    let config = InterfaceConfiguration {
-        name: interface_name.to_string(),
-        prvkey: wg_config.interface.private_key,
-        addresses,
-        port: wg_config.interface.listen_port.unwrap_or(port),
-        peers: vec![],
-        mtu: None,
-    };
-    wg_api.configure_interface(&config)?;
+fn parse_dns(interface: &InterfaceConfig) -> Result<Vec<IpAddr>, error::Error> {
+    interface
+        .dns
+        .iter()
+        .flatten()
+        .map(|d| {
+            IpAddr::from_str(d).map_err(|e| error::Error::InvalidFormat {
+                message: format!("Invalid DNS IP: {}", e),
+            })
+        })
+        .collect()
+}
+
+/// Bringing a tunnel up in steps that each return a typed value, so that what
+/// would be done can be looked at before anything changes: [`parse`](Self::parse)
+/// reads the profile, [`plan`](Self::plan) resolves it without touching the
+/// system, [`apply`](Self::apply) configures it and [`teardown`](Self::teardown)
+/// undoes it, also after an `apply` that failed half-way.
+pub struct TunnelBuilder<'a> {
+    config_path: &'a Path,
+    interface_name: &'a str,
+    options: &'a TunnelOptions<'a>,
+}
+
+/// What [`TunnelBuilder::apply`] found while bringing the tunnel up.
+#[derive(Debug, Default)]
+pub struct BroughtUp {
+    /// Endpoint family that won when a peer resolved to both IPv4 and IPv6.
+    pub family: Option<Family>,
+    /// Strict `rp_filter` was left as it is and may drop the replies.
+    pub strict_rp_filter: bool,
+}
+
+/// Everything [`TunnelBuilder::apply`] configures, resolved and checked.
+pub struct TunnelPlan {
+    interface: InterfaceConfiguration,
+    /// DNS servers of the tunnel.
+    dns: Vec<IpAddr>,
+    /// Peers as configured before the handshake race, with their first candidate.
+    peers: Vec<Peer>,
+    /// Addresses the endpoint of each peer resolved to, preferred family first.
+    candidates: Vec<Vec<SocketAddr>>,
+    /// `# Metric =` of the routes to the AllowedIPs that have one.
+    metrics: Vec<(IpAddrMask, u32)>,
+    table: RoutingTable,
+    fwmark: Option<u32>,
+}
 
-    if let Some(dns) = wg_config.interface.dns {
-        let dns_ips = dns
+impl TunnelPlan {
+    /// What [`TunnelBuilder::apply`] would configure, one line per setting.
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![format!("interface {}", self.interface.name)];
+        lines.extend(
+            self.interface
+                .addresses
+                .iter()
+                .map(|address| format!("  address {}", address)),
+        );
+        lines.push(format!("  listen-port {}", self.interface.port));
+        if let Some(mtu) = self.interface.mtu {
+            lines.push(format!("  mtu {}", mtu));
+        }
+        if let Some(mark) = self.fwmark {
+            lines.push(format!("  fwmark {:#x}", mark));
+        }
+        lines.extend(self.dns.iter().map(|server| format!("  dns {}", server)));
+        lines.push(format!(
+            "  table {}",
+            match self.table {
+                RoutingTable::Auto => "auto".to_string(),
+                RoutingTable::Off => "off".to_string(),
+                RoutingTable::Id(id) => id.to_string(),
+            }
+        ));
+        for (peer, candidates) in self.peers.iter().zip(&self.candidates) {
+            lines.push(format!(
+                "peer {}",
+                BASE64_STANDARD.encode(peer.public_key.as_slice())
+            ));
+            lines.extend(
+                candidates
+                    .iter()
+                    .map(|endpoint| format!("  endpoint {}", endpoint)),
+            );
+            for network in &peer.allowed_ips {
+                match self.metric(network) {
+                    Some(metric) => {
+                        lines.push(format!("  allowed-ip {} metric {}", network, metric))
+                    }
+                    None => lines.push(format!("  allowed-ip {}", network)),
+                }
+            }
+            if let Some(interval) = peer.persistent_keepalive_interval {
+                lines.push(format!("  persistent-keepalive {}", interval));
+            }
+        }
+        lines
+    }
+
+    fn metric(&self, network: &IpAddrMask) -> Option<u32> {
+        self.metrics
             .iter()
-            .map(|d| {
-                IpAddr::from_str(d).map_err(|e| error::Error::InvalidFormat {
-                    message: format!("Invalid DNS IP: {}", e),
-                })
+            .find(|(routed, _)| routed == network)
+            .map(|(_, metric)| *metric)
+    }
+
+    /// How the running interface differs from the plan: `+` for what applying
+    /// would add, `-` for what it would remove and `~` for what it would change.
+    /// Everything is added when the interface is down.
+    pub fn diff(&self, host: Option<&Host>) -> Vec<String> {
+        let Some(host) = host else {
+            return self
+                .describe()
+                .into_iter()
+                .map(|line| format!("+ {}", line))
+                .collect();
+        };
+        let mut lines = Vec::new();
+        if u32::from(host.listen_port) != self.interface.port {
+            lines.push(format!(
+                "~ listen-port {} -> {}",
+                host.listen_port, self.interface.port
+            ));
+        }
+        for (peer, candidates) in self.peers.iter().zip(&self.candidates) {
+            let key = BASE64_STANDARD.encode(peer.public_key.as_slice());
+            let Some(running) = host.peers.get(&peer.public_key) else {
+                lines.push(format!("+ peer {}", key));
+                continue;
+            };
+            // The handshake race may have settled on any of the candidates.
+            if let Some(endpoint) = running.endpoint
+                && !candidates.is_empty()
+                && !candidates.contains(&endpoint)
+            {
+                lines.push(format!(
+                    "~ peer {} endpoint {} -> {}",
+                    key, endpoint, candidates[0]
+                ));
+            }
+            for network in &peer.allowed_ips {
+                if !running.allowed_ips.contains(network) {
+                    lines.push(format!("+ peer {} allowed-ip {}", key, network));
+                }
+            }
+            for network in &running.allowed_ips {
+                if !peer.allowed_ips.contains(network) {
+                    lines.push(format!("- peer {} allowed-ip {}", key, network));
+                }
+            }
+        }
+        let mut removed: Vec<String> = host
+            .peers
+            .keys()
+            .filter(|key| !self.peers.iter().any(|peer| &peer.public_key == *key))
+            .map(|key| format!("- peer {}", BASE64_STANDARD.encode(key.as_slice())))
+            .collect();
+        removed.sort();
+        lines.extend(removed);
+        lines
+    }
+}
+
+impl<'a> TunnelBuilder<'a> {
+    pub fn new(
+        config_path: &'a Path,
+        interface_name: &'a str,
+        options: &'a TunnelOptions<'a>,
+    ) -> Self {
+        Self {
+            config_path,
+            interface_name,
+            options,
+        }
+    }
+
+    /// Read the profile and check what can be checked without the network.
+    pub fn parse(&self) -> Result<WireGuardConfig, error::Error> {
+        let wg_config = select_groups(parse_wg_config(self.config_path)?, self.options.groups)?;
+        parse_addresses(&wg_config.interface)?;
+        parse_dns(&wg_config.interface)?;
+        Ok(wg_config)
+    }
+
+    /// Resolve the endpoints and build the interface and peers to configure.
+    pub fn plan(&self, progress: &dyn Fn(Stage)) -> Result<TunnelPlan, error::Error> {
+        let options = self.options;
+        let wg_config = self.parse()?;
+
+        progress(Stage::Resolving);
+        let candidates = wg_config
+            .peers
+            .iter()
+            .map(|peer| match peer.endpoint.as_deref() {
+                Some(endpoint) => {
+                    let candidates = eyeballs::candidates(
+                        &resolve_endpoint_all(endpoint)?,
+                        options.endpoint_family,
+                    );
+                    if candidates.is_empty() {
+                        return Err(error::Error::Resolve(format!(
+                            "{}: no address found",
+                            endpoint
+                        )));
+                    }
+                    Ok(candidates)
+                }
+                None => Ok(Vec::new()),
             })
-            .collect::<Result<Vec<IpAddr>, error::Error>>()?;
-        wg_api.configure_dns(&dns_ips, &[])?;
+            .collect::<Result<Vec<Vec<SocketAddr>>, error::Error>>()?;
+
+        let addresses = parse_addresses(&wg_config.interface)?;
+        let dns = parse_dns(&wg_config.interface)?;
+
+        let exit_node = exit_node_key(&wg_config.peers, options.exit_node.as_deref());
+        let mut peers = Vec::new();
+        let mut metrics = Vec::new();
+        for (peer, candidates) in wg_config.peers.iter().zip(&candidates) {
+            let public_key_bytes = *peer.public_key.as_bytes();
+            let key = Key::new(public_key_bytes);
+            let mut peer_config = Peer::new(key);
+            peer_config.preshared_key = peer.preshared_key.clone();
+
+            peer_config.set_allowed_ips(peer_allowed_ips(peer, exit_node.as_deref(), candidates)?);
+            if let Some(metric) = peer.metric.or(wg_config.interface.metric) {
+                metrics.extend(
+                    peer_config
+                        .allowed_ips
+                        .iter()
+                        .map(|network| (network.clone(), metric)),
+                );
+            }
+
+            if let Some(endpoint) = candidates.first() {
+                peer_config.set_endpoint(&endpoint.to_string())?;
+            }
+            if options.keepalive {
+                peer_config.persistent_keepalive_interval = peer.persistent_keepalive;
+            }
+            peers.push(peer_config);
+        }
+
+        Ok(TunnelPlan {
+            interface: InterfaceConfiguration {
+                name: self.interface_name.to_string(),
+                prvkey: wg_config.interface.private_key.resolve()?,
+                addresses,
+                port: wg_config.interface.listen_port.unwrap_or(options.port),
+                peers: vec![],
+                mtu: wg_config.interface.mtu,
+            },
+            fwmark: wg_config.interface.fwmark,
+            dns,
+            peers,
+            candidates,
+            metrics,
+            table: wg_config.interface.table,
+        })
     }
 
-    for peer in wg_config.peers {
-        let public_key_bytes = *peer.public_key.as_bytes();
-        let key = Key::new(public_key_bytes);
-        let mut peer_config = Peer::new(key);
+    /// Bring the interface up as planned.
+    pub fn apply(
+        &self,
+        plan: TunnelPlan,
+        progress: &dyn Fn(Stage),
+    ) -> Result<BroughtUp, error::Error> {
+        let (interface_name, options) = (self.interface_name, self.options);
+        let TunnelPlan {
+            interface,
+            dns,
+            mut peers,
+            candidates,
+            metrics,
+            table,
+            fwmark,
+        } = plan;
+
+        progress(Stage::Configuring);
+        if mock::enabled() {
+            mock::up(&interface, &peers, &candidates)?;
+            return Ok(BroughtUp::default());
+        }
+        let wg_api = trace::Api::new(interface_name)?;
+        retry(BRING_UP_BACKOFF, || wg_api.create_interface())
+            .with_context(|| format!("creating {}", interface_name))?;
+        applied::save("groups", interface_name, &options.groups)?;
+        retry(BRING_UP_BACKOFF, || wg_api.configure_interface(&interface))
+            .with_context(|| format!("configuring {}", interface_name))?;
+        if let Some(mark) = fwmark {
+            utils::run_command(
+                "wg",
+                &["set", interface_name, "fwmark", &mark.to_string()],
+                None,
+            )
+            .map_err(|e| error::Error::Routing(format!("setting FwMark {}: {}", mark, e)))?;
+        }
+        if !dns.is_empty() && options.netns.is_none() {
+            wg_api
+                .configure_dns(&dns, &[])
+                .with_context(|| format!("setting the DNS servers of {}", interface_name))?;
+        }
+        for peer in &peers {
+            retry(BRING_UP_BACKOFF, || wg_api.configure_peer(peer)).with_context(|| {
+                format!(
+                    "adding peer {}",
+                    BASE64_STANDARD.encode(peer.public_key.as_slice())
+                )
+            })?;
+        }
 
-        let allowed_ips = peer
-            .allowed_ips
+        let mut bound = None;
+        if let Some(device) = options.bind_interface {
+            let addrs: Vec<IpAddr> = candidates.iter().flatten().map(|addr| addr.ip()).collect();
+            let applied = AppliedBind {
+                device: device.to_string(),
+                endpoints: routing::apply_bind(device, &addrs)?,
+            };
+            applied::save("bind", interface_name, &applied)?;
+            bound = Some(applied);
+        }
+        // Before any route exists, so that the handshakes leave through the uplink.
+        let family = race_families(&wg_api, &mut peers, &candidates, progress)?;
+        if let Some(mut bound) = bound {
+            let (used, unused): (Vec<IpAddr>, Vec<IpAddr>) =
+                bound.endpoints.iter().partition(|ip| {
+                    peers
+                        .iter()
+                        .any(|peer| peer.endpoint.map(|e| e.ip()) == Some(**ip))
+                });
+            routing::teardown_bind(&bound.device, &unused);
+            bound.endpoints = used;
+            applied::save("bind", interface_name, &bound)?;
+        }
+
+        let addresses = &interface.addresses;
+        let (allowed_ips, endpoints) = routing_targets(&peers);
+        // A namespace is one already: only the tunnel leads out of it.
+        if options.kill_switch && options.netns.is_none() {
+            let endpoints: Vec<SocketAddr> =
+                peers.iter().filter_map(|peer| peer.endpoint).collect();
+            firewall::apply_kill_switch(interface_name, &endpoints, options.firewall_backend)?;
+        }
+        if let Some(name) = options.netns {
+            if options.gateway.is_some() {
+                return Err(error::Error::Gateway(
+                    "a LAN cannot be routed into a namespace".to_string(),
+                ));
+            }
+            // The UDP socket stays in the namespace the interface was created in,
+            // so only the tunnel side ends up inside `name`.
+            netns::ensure(name)?;
+            netns::move_interface(interface_name, name)?;
+            netns::configure(name, interface_name, addresses, &allowed_ips, &dns)?;
+            return Ok(BroughtUp {
+                family,
+                strict_rp_filter: false,
+            });
+        }
+        let fwmark = match options.route_mode {
+            RouteMode::Cgroup => Some(options.split_tunnel(interface_name)?.fwmark),
+            _ => None,
+        };
+        // Before any route, so that a failure halfway is cleaned up as well.
+        applied::save(
+            "routes",
+            interface_name,
+            &AppliedRoutes::new(
+                options.route_mode,
+                table,
+                &allowed_ips,
+                &endpoints,
+                addresses,
+                fwmark,
+            ),
+        )?;
+        match (options.route_mode, table) {
+            (_, RoutingTable::Off) | (RouteMode::Default, RoutingTable::Auto) => {}
+            (RouteMode::Default, RoutingTable::Id(table)) => {
+                routing::apply_table(interface_name, table, &allowed_ips, &metrics, &endpoints)?;
+            }
+            (RouteMode::Policy, table) => {
+                let table = policy_table(table);
+                routing::apply_policy(interface_name, table, &allowed_ips, &metrics, addresses)?;
+            }
+            (RouteMode::Cgroup, table) => {
+                let split_tunnel = options.split_tunnel(interface_name)?;
+                let table = policy_table(table);
+                routing::apply_fwmark(
+                    interface_name,
+                    table,
+                    split_tunnel.fwmark,
+                    &allowed_ips,
+                    &metrics,
+                )?;
+                split_tunnel::apply(interface_name, split_tunnel)?;
+            }
+        }
+        // Replies from anywhere arrive through the tunnel while the route back to
+        // them, without the mark or source address, goes through the uplink.
+        let full_tunnel = allowed_ips
             .iter()
-            .map(|ip| parse_ip_addr_mask(ip))
-            .collect::<Result<Vec<IpAddrMask>, error::Error>>()?;
-        peer_config.set_allowed_ips(allowed_ips);
+            .any(|network| network.ip.is_ipv4() && network.cidr == 0);
+        let routed = !matches!(
+            (options.route_mode, table),
+            (_, RoutingTable::Off) | (RouteMode::Default, RoutingTable::Auto)
+        );
+        let mut strict_rp_filter = (full_tunnel || options.route_mode != RouteMode::Default)
+            && routed
+            && sysctl::strict_rp_filter(interface_name);
+        if strict_rp_filter && options.relax_rp_filter {
+            sysctl::relax_rp_filter(interface_name, options.debug)?;
+            strict_rp_filter = false;
+        }
+        if let Some(gateway) = options.gateway {
+            gateway::apply(
+                interface_name,
+                gateway,
+                options.firewall_backend,
+                options.debug,
+            )?;
+        }
+
+        Ok(BroughtUp {
+            family,
+            strict_rp_filter,
+        })
+    }
 
-        if let Some(endpoint) = peer.endpoint {
-            peer_config.set_endpoint(&endpoint.to_string())?;
+    /// Remove the interface along with any routing state [`apply`](Self::apply)
+    /// set up outside of it. The interface is removed even if the configuration
+    /// no longer parses.
+    pub fn teardown(&self) -> Result<(), error::Error> {
+        let (interface_name, options) = (self.interface_name, self.options);
+        if mock::enabled() {
+            return mock::down(interface_name);
+        }
+        let wg_api = trace::Api::new(interface_name)?;
+        let _ = applied::take::<Vec<String>>("groups", interface_name);
+        if let Some(bound) = applied::take::<AppliedBind>("bind", interface_name) {
+            routing::teardown_bind(&bound.device, &bound.endpoints);
+        }
+        if let Some(name) = options.netns {
+            if netns::exists(name) {
+                netns::with(name, || wg_api.remove_interface())??;
+            } else {
+                wg_api.remove_interface()?;
+            }
+            netns::teardown(name);
+            return Ok(());
+        }
+        gateway::teardown(interface_name);
+        firewall::teardown_kill_switch(interface_name);
+        if let Some(routes) = applied::take::<AppliedRoutes>("routes", interface_name) {
+            let allowed_ips = parse_networks(&routes.allowed_ips);
+            let endpoints = &routes.endpoints;
+            match (routes.route_mode, routes.table) {
+                (_, RoutingTable::Off) | (RouteMode::Default, RoutingTable::Auto) => {}
+                (RouteMode::Default, RoutingTable::Id(table)) => {
+                    routing::teardown_table(table, &allowed_ips, endpoints);
+                }
+                (RouteMode::Policy, table) => {
+                    routing::teardown_policy(
+                        policy_table(table),
+                        &parse_networks(&routes.addresses),
+                    );
+                }
+                (RouteMode::Cgroup, table) => {
+                    split_tunnel::teardown(interface_name);
+                    if let Some(fwmark) = routes.fwmark {
+                        routing::teardown_fwmark(policy_table(table), fwmark, &allowed_ips);
+                    }
+                }
+            }
         }
+        // Before the interface goes, along with its own sysctls.
+        sysctl::restore(interface_name, self.options.debug);
+        wg_api.remove_interface()?;
+        Ok(())
+    }
+}
 
-        wg_api.configure_peer(&peer_config)?;
+/// Whether the interface currently exists, looking inside the namespace when one is used.
+pub fn is_active(interface_name: &str, netns: Option<&str>) -> Result<bool, error::Error> {
+    if mock::enabled() {
+        return Ok(mock::is_up(interface_name));
     }
+    let wg_api = trace::Api::new(interface_name)?;
+    match netns {
+        Some(name) if netns::exists(name) => {
+            netns::with(name, || wg_api.read_interface_data().is_ok())
+        }
+        Some(_) => Ok(false),
+        None => Ok(wg_api.read_interface_data().is_ok()),
+    }
+}
 
-    Ok(())
+/// Live data of the interface, read inside the namespace when one is used.
+pub fn read_host(interface_name: &str, netns: Option<&str>) -> Result<Host, error::Error> {
+    if mock::enabled() {
+        return mock::read_host(interface_name);
+    }
+    let wg_api = trace::Api::new(interface_name)?;
+    Ok(match netns {
+        Some(name) => netns::with(name, || wg_api.read_interface_data())??,
+        None => wg_api.read_interface_data()?,
+    })
+}
+
+/// Most recent handshake among the peers of the interface, if one happened yet.
+pub fn last_handshake(
+    interface_name: &str,
+    netns: Option<&str>,
+) -> Result<Option<SystemTime>, error::Error> {
+    // The kernel reports peers that never completed a handshake at the epoch.
+    Ok(read_host(interface_name, netns)?
+        .peers
+        .values()
+        .filter_map(|peer| peer.last_handshake)
+        .filter(|time| *time > UNIX_EPOCH)
+        .max())
+}
+
+/// Bytes received and sent through the interface, all peers combined.
+#[cfg(feature = "daemon")]
+pub fn transfer_bytes(interface_name: &str, netns: Option<&str>) -> Result<u64, error::Error> {
+    let counters = transfer(interface_name, netns)?;
+    Ok(counters.rx + counters.tx)
+}
+
+/// Bytes received and sent by all peers of the interface.
+pub fn transfer(interface_name: &str, netns: Option<&str>) -> Result<Counters, error::Error> {
+    Ok(read_host(interface_name, netns)?.peers.values().fold(
+        Counters::default(),
+        |counters, peer| Counters {
+            rx: counters.rx + peer.rx_bytes,
+            tx: counters.tx + peer.tx_bytes,
+        },
+    ))
+}
+
+/// Endpoint of the peer of the interface with the given base64 public key.
+pub fn peer_endpoint(
+    interface_name: &str,
+    netns: Option<&str>,
+    public_key: &str,
+) -> Result<Option<SocketAddr>, error::Error> {
+    Ok(read_host(interface_name, netns)?
+        .peers
+        .values()
+        .find(|peer| BASE64_STANDARD.encode(peer.public_key.as_slice()) == public_key)
+        .and_then(|peer| peer.endpoint))
+}
+
+/// What the kernel reports about a peer of a running interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Base64 public key.
+    pub public_key: String,
+    pub endpoint: Option<SocketAddr>,
+    /// `None` until a handshake completed.
+    pub last_handshake: Option<SystemTime>,
+    pub transfer: Counters,
+}
+
+pub fn peer_stats(
+    interface_name: &str,
+    netns: Option<&str>,
+) -> Result<Vec<PeerStats>, error::Error> {
+    Ok(read_host(interface_name, netns)?
+        .peers
+        .values()
+        .map(|peer| PeerStats {
+            public_key: BASE64_STANDARD.encode(peer.public_key.as_slice()),
+            endpoint: peer.endpoint,
+            last_handshake: peer.last_handshake.filter(|time| *time > UNIX_EPOCH),
+            transfer: Counters {
+                rx: peer.rx_bytes,
+                tx: peer.tx_bytes,
+            },
+        })
+        .collect())
+}
+
+/// Endpoints the peers of the interface are currently talking to.
+pub fn peer_endpoints(
+    interface_name: &str,
+    netns: Option<&str>,
+) -> Result<Vec<SocketAddr>, error::Error> {
+    Ok(read_host(interface_name, netns)?
+        .peers
+        .values()
+        .filter_map(|peer| peer.endpoint)
+        .collect())
+}
+
+fn policy_table(table: RoutingTable) -> u32 {
+    match table {
+        RoutingTable::Id(table) => table,
+        _ => routing::DEFAULT_POLICY_TABLE,
+    }
+}
+
+/// Routing [`TunnelBuilder::apply`] set up outside the interface, kept until
+/// the teardown so that it removes exactly that, without the profile or DNS.
+#[derive(Serialize, Deserialize)]
+struct AppliedRoutes {
+    route_mode: RouteMode,
+    table: RoutingTable,
+    allowed_ips: Vec<String>,
+    endpoints: Vec<IpAddr>,
+    addresses: Vec<String>,
+    /// Mark of the split tunnel, in cgroup mode.
+    fwmark: Option<u32>,
+}
+
+impl AppliedRoutes {
+    fn new(
+        route_mode: RouteMode,
+        table: RoutingTable,
+        allowed_ips: &[IpAddrMask],
+        endpoints: &[IpAddr],
+        addresses: &[IpAddrMask],
+        fwmark: Option<u32>,
+    ) -> Self {
+        let networks = |networks: &[IpAddrMask]| {
+            networks
+                .iter()
+                .map(|network| format!("{}/{}", network.ip, network.cidr))
+                .collect()
+        };
+        Self {
+            route_mode,
+            table,
+            allowed_ips: networks(allowed_ips),
+            endpoints: endpoints.to_vec(),
+            addresses: networks(addresses),
+            fwmark,
+        }
+    }
+}
+
+/// Host routes [`routing::apply_bind`] added for the endpoints in use, removed
+/// by the teardown whatever `--bind-interface` says then.
+#[derive(Serialize, Deserialize)]
+struct AppliedBind {
+    device: String,
+    endpoints: Vec<IpAddr>,
+}
+
+fn parse_networks(networks: &[String]) -> Vec<IpAddrMask> {
+    networks
+        .iter()
+        .filter_map(|network| parse_ip_addr_mask(network).ok())
+        .collect()
+}
+
+fn routing_targets(peers: &[Peer]) -> (Vec<IpAddrMask>, Vec<IpAddr>) {
+    let allowed_ips = peers
+        .iter()
+        .flat_map(|peer| peer.allowed_ips.iter().cloned())
+        .collect();
+    let endpoints = peers
+        .iter()
+        .filter_map(|peer| peer.endpoint.map(|endpoint| endpoint.ip()))
+        .collect();
+    (allowed_ips, endpoints)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(byte: u8, allowed_ips: &[&str]) -> PeerConfig {
+        PeerConfig {
+            public_key: PublicKey::from([byte; 32]),
+            preshared_key: None,
+            endpoint: None,
+            allowed_ips: allowed_ips.iter().map(|ip| ip.to_string()).collect(),
+            persistent_keepalive: None,
+            metric: None,
+            name: None,
+            excluded_ips: Vec::new(),
+            groups: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn reads_metrics_from_comments_of_their_section() {
+        let conf = "# Metric = 50\n[Interface]\n# metric = 100\nPrivateKey = key\n\n[Peer]\n# Metric = 20\n# Name = office\n";
+        let conf = Ini::load_from_str(&uncomment_keys(conf)).unwrap();
+        let metric = |section| parse_metric(conf.section(Some(section)).unwrap()).unwrap();
+        assert_eq!(metric("Interface"), Some(100));
+        assert_eq!(metric("Peer"), Some(20));
+        assert_eq!(parse_metric(conf.general_section()).unwrap(), Some(50));
+    }
+
+    #[test]
+    fn plans_the_tunnel_without_touching_the_system() {
+        let path = std::env::temp_dir().join(format!("wg-waybar-plan-{}.conf", std::process::id()));
+        let key = BASE64_STANDARD.encode([7u8; 32]);
+        let psk = BASE64_STANDARD.encode([9u8; 32]);
+        fs::write(
+            &path,
+            format!(
+                "[Interface]\nPrivateKey = {key}\nAddress = 10.2.0.2\nDNS = 10.2.0.1\nMTU = 1380\nFwMark = 0xca6c\n\n[Peer]\n# Metric = 20\nPublicKey = {key}\nPresharedKey = {psk}\nEndpoint = 192.0.2.1:51820\nAllowedIPs = 10.20.0.0/16\nPersistentKeepalive = 25\n"
+            ),
+        )
+        .unwrap();
+        let options = TunnelOptions {
+            keepalive: false,
+            ..Default::default()
+        };
+        let plan = TunnelBuilder::new(&path, "wg-test", &options)
+            .plan(&|_| {})
+            .unwrap();
+        assert_eq!(
+            plan.interface.addresses,
+            [IpAddrMask::new([10, 2, 0, 2].into(), 32)]
+        );
+        assert_eq!(plan.dns, [IpAddr::from([10, 2, 0, 1])]);
+        assert_eq!(plan.interface.mtu, Some(1380));
+        assert_eq!(plan.fwmark, Some(0xca6c));
+        assert_eq!(plan.peers[0].preshared_key, Some(Key::new([9u8; 32])));
+        assert_eq!(plan.candidates, [vec!["192.0.2.1:51820".parse().unwrap()]]);
+        assert_eq!(plan.peers[0].persistent_keepalive_interval, None);
+        assert_eq!(
+            plan.metrics,
+            [(IpAddrMask::new([10, 20, 0, 0].into(), 16), 20)]
+        );
+        assert!(
+            plan.describe()
+                .contains(&"  allowed-ip 10.20.0.0/16 metric 20".to_string())
+        );
+        assert_eq!(parse_fwmark("51820").unwrap(), Some(51820));
+        assert_eq!(parse_fwmark("off").unwrap(), None);
+        assert!(parse_fwmark("0xzz").is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn diffs_the_plan_against_the_running_interface() {
+        let path = std::env::temp_dir().join(format!("wg-waybar-diff-{}.conf", std::process::id()));
+        let key = BASE64_STANDARD.encode([7u8; 32]);
+        fs::write(
+            &path,
+            format!(
+                "[Interface]\nPrivateKey = {key}\nAddress = 10.2.0.2/32\n\n[Peer]\nPublicKey = {key}\nEndpoint = 192.0.2.1:51820\nAllowedIPs = 10.20.0.0/16\n"
+            ),
+        )
+        .unwrap();
+        let options = TunnelOptions::default();
+        let plan = TunnelBuilder::new(&path, "wg-test", &options)
+            .plan(&|_| {})
+            .unwrap();
+        fs::remove_file(path).unwrap();
+        assert!(plan.diff(None).iter().all(|line| line.starts_with("+ ")));
+
+        let planned = Key::new([7u8; 32]);
+        let gone = Key::new([8u8; 32]);
+        let mut running = Peer::new(planned.clone());
+        running.endpoint = Some("192.0.2.1:51820".parse().unwrap());
+        running.allowed_ips = vec![IpAddrMask::new([10, 30, 0, 0].into(), 16)];
+        let host = Host {
+            listen_port: 40077,
+            peers: HashMap::from([(planned, running), (gone.clone(), Peer::new(gone))]),
+            ..Host::default()
+        };
+        assert_eq!(
+            plan.diff(Some(&host)),
+            [
+                format!("+ peer {} allowed-ip 10.20.0.0/16", key),
+                format!("- peer {} allowed-ip 10.30.0.0/16", key),
+                format!("- peer {}", BASE64_STANDARD.encode([8u8; 32])),
+            ]
+        );
+    }
+
+    #[test]
+    fn retries_transient_failures_only() {
+        let busy = || error::Error::WireGuardApi {
+            kind: error::ErrorKind::Other,
+            message: "Netlink error: Device or resource busy (os error 16)".to_string(),
+        };
+        let mut attempts = 0;
+        let result = retry(Duration::ZERO, || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(busy())
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        attempts = 0;
+        let result: Result<(), _> = retry(Duration::ZERO, || {
+            attempts += 1;
+            Err(busy())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, BRING_UP_ATTEMPTS);
+
+        attempts = 0;
+        let result: Result<(), _> = retry(Duration::ZERO, || {
+            attempts += 1;
+            Err(error::Error::Routing("Operation not permitted".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        let jitter = jittered(Duration::from_millis(100));
+        assert!(jitter >= Duration::from_millis(50) && jitter <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn tells_transient_errors_by_errno() {
+        let netlink = |errno: i32| error::Error::WireGuardApi {
+            kind: error::ErrorKind::Other,
+            message: format!("Netlink error: whatever (os error {errno})"),
+        };
+        assert!(is_transient(&netlink(libc::EBUSY)));
+        assert!(is_transient(&netlink(libc::ENOBUFS)));
+        assert!(is_transient(
+            &std::io::Error::from_raw_os_error(libc::EAGAIN).into()
+        ));
+        assert!(!is_transient(&netlink(libc::EPERM)));
+        assert!(!is_transient(&error::Error::WireGuardApi {
+            kind: error::ErrorKind::Other,
+            message: "Device or resource busy, try again".to_string(),
+        }));
+    }
+
+    #[test]
+    fn defaults_bare_addresses_to_a_single_host() {
+        let mut properties = Properties::new();
+        properties.insert("PrivateKey", "key");
+        properties.insert("Address", "10.2.0.2, fd00::2, 10.3.0.1/24");
+        let interface = InterfaceConfig::load(&properties).unwrap();
+        assert_eq!(
+            interface.addresses,
+            ["10.2.0.2/32", "fd00::2/128", "10.3.0.1/24"]
+        );
+        assert_eq!(
+            interface.notes,
+            [
+                "Address 10.2.0.2 has no prefix, taken as 10.2.0.2/32",
+                "Address fd00::2 has no prefix, taken as fd00::2/128"
+            ]
+        );
+    }
+
+    #[test]
+    fn picks_exit_node_among_peers_claiming_the_default_route() {
+        let peers = [
+            peer(1, &["10.0.0.0/24"]),
+            peer(2, &["0.0.0.0/0", "::/0"]),
+            peer(3, &["0.0.0.0/0"]),
+        ];
+        let second = peers[1].public_key_base64();
+        let third = peers[2].public_key_base64();
+        assert_eq!(exit_node_key(&peers, None), Some(second.clone()));
+        assert_eq!(exit_node_key(&peers, Some(&third)), Some(third.clone()));
+        assert_eq!(exit_node_key(&peers, Some("unknown")), Some(second));
+        assert_eq!(exit_node_key(&peers[..2], Some(&third)), None);
+
+        let allowed_ips = peer_allowed_ips(&peers[1], Some(&third), &[]).unwrap();
+        assert!(allowed_ips.is_empty());
+    }
+
+    #[test]
+    fn keeps_the_peers_of_the_selected_groups() {
+        let grouped = |byte, groups: &[&str]| PeerConfig {
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+            ..peer(byte, &["10.0.0.0/16"])
+        };
+        let config = || WireGuardConfig {
+            interface: InterfaceConfig {
+                private_key: PrivateKey::Inline(String::new()),
+                addresses: Vec::new(),
+                dns: None,
+                listen_port: None,
+                table: RoutingTable::Auto,
+                metric: None,
+                mtu: None,
+                fwmark: None,
+                notes: Vec::new(),
+            },
+            peers: vec![
+                grouped(1, &["infra"]),
+                grouped(2, &["offices", "eu"]),
+                grouped(3, &[]),
+            ],
+        };
+        let kept = |groups: &[&str]| {
+            let groups: Vec<String> = groups.iter().map(|group| group.to_string()).collect();
+            select_groups(config(), &groups).map(|config| {
+                config
+                    .peers
+                    .iter()
+                    .map(|peer| peer.public_key.as_bytes()[0])
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(kept(&[]).unwrap(), [1, 2, 3]);
+        assert_eq!(kept(&["Infra"]).unwrap(), [1, 3]);
+        assert_eq!(kept(&["eu", "infra"]).unwrap(), [1, 2, 3]);
+
+        let mut config = config();
+        config.peers.pop();
+        assert!(select_groups(config, &["spokes".to_string()]).is_err());
+    }
+
+    #[test]
+    fn carves_excluded_ips_out_of_allowed_ips() {
+        let network = |s: &str| parse_ip_addr_mask(s).unwrap();
+        let mut office = peer(1, &["10.0.0.0/8", "fd00::/64"]);
+        office.excluded_ips = vec![network("10.128.0.0/9"), network("10.1.2.3/32")];
+        let allowed_ips: Vec<String> = peer_allowed_ips(&office, None, &[])
+            .unwrap()
+            .iter()
+            .map(|network| format!("{}/{}", network.ip, network.cidr))
+            .collect();
+        assert_eq!(allowed_ips.len(), 24);
+        assert_eq!(
+            allowed_ips[..3],
+            ["10.64.0.0/10", "10.32.0.0/11", "10.16.0.0/12"]
+        );
+        assert!(!allowed_ips.contains(&"10.1.2.3/32".to_string()));
+        assert!(allowed_ips.contains(&"10.1.2.2/32".to_string()));
+        assert_eq!(allowed_ips.last().unwrap(), "fd00::/64");
+
+        assert!(subtract(&network("10.1.0.0/16"), &network("10.0.0.0/8")).is_empty());
+        assert_eq!(
+            subtract(&network("10.1.0.0/16"), &network("192.168.0.0/16")),
+            [network("10.1.0.0/16")]
+        );
+        assert_eq!(
+            subtract(&network("0.0.0.0/0"), &network("128.0.0.0/1")),
+            [network("0.0.0.0/1")]
+        );
+
+        // A carved up default route leaves out the endpoints it was given.
+        let mut full = peer(2, &["0.0.0.0/0"]);
+        full.excluded_ips = vec![network("192.168.0.0/16")];
+        let endpoint: SocketAddr = "198.51.100.7:51820".parse().unwrap();
+        let allowed_ips = peer_allowed_ips(&full, None, &[endpoint]).unwrap();
+        assert!(allowed_ips.contains(&network("198.51.100.6/32")));
+        assert!(!allowed_ips.contains(&network("198.51.100.7/32")));
+    }
+
+    #[test]
+    fn keeps_the_applied_routes_for_the_teardown() {
+        let network = |s: &str| parse_ip_addr_mask(s).unwrap();
+        let routes = AppliedRoutes::new(
+            RouteMode::Default,
+            RoutingTable::Id(1234),
+            &[network("10.0.0.0/8")],
+            &["198.51.100.7".parse().unwrap()],
+            &[network("10.2.0.2/32")],
+            None,
+        );
+        let routes: AppliedRoutes =
+            serde_json::from_str(&serde_json::to_string(&routes).unwrap()).unwrap();
+        assert_eq!(routes.table, RoutingTable::Id(1234));
+        assert_eq!(parse_networks(&routes.allowed_ips), [network("10.0.0.0/8")]);
+        assert_eq!(parse_networks(&routes.addresses), [network("10.2.0.2/32")]);
+    }
 }
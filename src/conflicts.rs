@@ -0,0 +1,125 @@
+use std::path::Path;
+use std::process::Command;
+
+const NET_DIR: &str = "/sys/class/net";
+
+/// `ARPHRD_NONE`, the link type of tun and WireGuard devices.
+const ARPHRD_NONE: &str = "65534";
+
+/// Destinations routing all traffic, including the halves used by OpenVPN's `def1`.
+const DEFAULT_DESTINATIONS: [&str; 5] = ["default", "0.0.0.0/1", "128.0.0.0/1", "::/1", "8000::/1"];
+
+/// Another VPN interface that is up.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Conflict {
+    pub interface: String,
+    /// Whether it routes all traffic, in any table.
+    pub default_route: bool,
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.default_route {
+            write!(f, "{} (default route)", self.interface)
+        } else {
+            write!(f, "{}", self.interface)
+        }
+    }
+}
+
+fn read(dir: &Path, name: &str) -> String {
+    std::fs::read_to_string(dir.join(name))
+        .map(|value| value.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Whether the link is a tunnel (tun, tap, WireGuard, ...) that is administratively up.
+fn is_vpn(dir: &Path) -> bool {
+    let up = u32::from_str_radix(read(dir, "flags").trim_start_matches("0x"), 16)
+        .is_ok_and(|flags| flags & libc::IFF_UP as u32 != 0);
+    let tunnel = read(dir, "type") == ARPHRD_NONE
+        || dir.join("tun_flags").exists()
+        || read(dir, "uevent").contains("DEVTYPE=wireguard");
+    up && tunnel
+}
+
+/// Devices owning a route to a default destination in `ip -j route show table all`.
+fn parse_default_route_devices(output: &str) -> Vec<String> {
+    let routes: Vec<serde_json::Value> = serde_json::from_str(output).unwrap_or_default();
+    routes
+        .iter()
+        .filter(|route| {
+            route
+                .get("dst")
+                .and_then(|dst| dst.as_str())
+                .is_some_and(|dst| DEFAULT_DESTINATIONS.contains(&dst))
+        })
+        .filter_map(|route| route.get("dev")?.as_str().map(str::to_string))
+        .collect()
+}
+
+fn default_route_devices() -> Vec<String> {
+    ["-4", "-6"]
+        .iter()
+        .filter_map(|family| {
+            Command::new("ip")
+                .args([family, "-j", "route", "show", "table", "all"])
+                .output()
+                .ok()
+        })
+        .flat_map(|output| parse_default_route_devices(&String::from_utf8_lossy(&output.stdout)))
+        .collect()
+}
+
+/// VPN interfaces other than `interface_name` that are up, e.g. `tun0` or `tailscale0`.
+pub fn detect(interface_name: &str) -> Vec<Conflict> {
+    let Ok(entries) = std::fs::read_dir(NET_DIR) else {
+        return Vec::new();
+    };
+    let mut interfaces: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| is_vpn(&entry.path()))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name != interface_name)
+        .collect();
+    if interfaces.is_empty() {
+        return Vec::new();
+    }
+    interfaces.sort();
+    let default_devices = default_route_devices();
+    interfaces
+        .into_iter()
+        .map(|interface| Conflict {
+            default_route: default_devices.contains(&interface),
+            interface,
+        })
+        .collect()
+}
+
+pub fn describe(conflicts: &[Conflict]) -> String {
+    conflicts
+        .iter()
+        .map(Conflict::to_string)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_devices_owning_default_routes() {
+        let routes = r#"[
+            {"dst":"default","gateway":"192.168.1.1","dev":"wlan0"},
+            {"dst":"0.0.0.0/1","gateway":"10.8.0.1","dev":"tun0"},
+            {"dst":"default","dev":"tailscale0","table":"52"},
+            {"dst":"10.8.0.0/24","dev":"tun0"}
+        ]"#;
+        assert_eq!(
+            parse_default_route_devices(routes),
+            vec!["wlan0", "tun0", "tailscale0"]
+        );
+        assert!(parse_default_route_devices("").is_empty());
+    }
+}
@@ -0,0 +1,431 @@
+use crate::actions::{self, Context};
+use crate::app_config::{AppConfig, FocusRule, PublishConfig, ScheduleConfig};
+use crate::battery::BatteryWatch;
+use crate::compositor::{self, Focus, FocusRules};
+use crate::config;
+use crate::error;
+use crate::events::Trackers;
+use crate::hooks;
+use crate::idle::{self, IdleTracker};
+use crate::profiles::{self, Profile};
+use crate::publish;
+use crate::refresh::{self, Registration};
+use crate::schedule::{self, Scheduler};
+use crate::status::{self, Status, StatusReport};
+#[cfg(feature = "tray")]
+use crate::tray::{Tray, TrayEvent};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::task;
+
+/// What can wake the daemon before its next poll.
+enum Event {
+    #[cfg(feature = "tray")]
+    Tray(TrayEvent),
+    Focus(Focus),
+}
+
+/// Hand each value sent to `handle` on the blocking pool, one after the
+/// other and in order, without the sender waiting for it.
+fn outbox<T: Send + 'static>(
+    handle: impl Fn(T) + Send + Sync + 'static,
+) -> mpsc::UnboundedSender<T> {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<T>();
+    let handle = Arc::new(handle);
+    tokio::spawn(async move {
+        while let Some(value) = receiver.recv().await {
+            let handle = handle.clone();
+            // A handler that panicked loses its value, not the outbox.
+            let _ = task::spawn_blocking(move || handle(value)).await;
+        }
+    });
+    sender
+}
+
+/// Publish what changed about the profiles to the running `events`, if any.
+/// Without a reader the profiles are forgotten, so that one starting later is
+/// not told about the state it started in.
+fn publish_events(ctx: &Context, trackers: &mut Trackers, profiles: &[Profile]) {
+    if !refresh::is_registered(refresh::EVENT_READERS, "events") {
+        trackers.clear();
+        return;
+    }
+    let result = trackers.check(ctx, profiles).and_then(|lines| {
+        for line in lines {
+            refresh::publish(refresh::EVENT_READERS, line.as_bytes())?;
+        }
+        Ok(())
+    });
+    if let Err(e) = result
+        && ctx.debug
+    {
+        println!("Failed to publish events: {}", e);
+    }
+}
+
+/// Publish and dispatch status changes in the background: an unreachable
+/// broker or a slow hook must not delay polling or the tray.
+fn notifier(
+    publish: &PublishConfig,
+    debug: bool,
+) -> mpsc::UnboundedSender<(String, serde_json::Value)> {
+    let publish = publish.clone();
+    outbox(
+        move |(interface_name, event): (String, serde_json::Value)| {
+            publish::publish(&publish, &event, debug);
+            hooks::dispatch("status-change", &interface_name, event, debug);
+        },
+    )
+}
+
+fn event(
+    interface_name: &str,
+    report: &StatusReport,
+    previous: Option<Status>,
+) -> serde_json::Value {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    json!({
+        "interface": interface_name,
+        "status": report.status.as_str(),
+        "previous": previous.map(|status| status.as_str()),
+        "text": report.text,
+        "tooltip": report.tooltip,
+        "timestamp": timestamp,
+    })
+}
+
+/// Open or close the scheduled windows that crossed a boundary.
+fn enforce_schedules(
+    ctx: &Context,
+    scheduler: &mut Scheduler,
+    schedules: &[ScheduleConfig],
+    profiles: &[Profile],
+) {
+    for (index, inside) in scheduler.boundaries(schedules, &schedule::local_now()) {
+        let schedule = &schedules[index];
+        if ctx.debug {
+            println!(
+                "Schedule of {} {}",
+                schedule.profile,
+                if inside { "opens" } else { "closes" }
+            );
+        }
+        let result = profiles::find(profiles, &schedule.profile)
+            .and_then(|profile| actions::ensure(ctx, profile, inside));
+        if let Err(e) = result
+            && ctx.debug
+        {
+            println!("Failed to apply schedule of {}: {}", schedule.profile, e);
+        }
+    }
+}
+
+/// Take down the profiles that are up outside of their allowed hours.
+fn enforce_allowed_hours(ctx: &Context, profiles: &[Profile]) {
+    for profile in profiles
+        .iter()
+        .filter(|profile| !ctx.is_allowed_now(profile))
+    {
+        if let Err(e) = actions::ensure(ctx, profile, false)
+            && ctx.debug
+        {
+            println!("Failed to enforce allowed hours of {}: {}", profile.name, e);
+        }
+    }
+}
+
+/// Connect the profiles toggled while offline if the network is back.
+fn complete_pending(ctx: &Context, profiles: &[Profile]) {
+    for profile in profiles {
+        if let Err(e) = actions::complete_pending(ctx, profile)
+            && ctx.debug
+        {
+            println!("Failed to connect {}: {}", profile.name, e);
+        }
+    }
+}
+
+/// Bring profiles up or down for the focus rules that started or stopped matching.
+fn apply_focus_rules(
+    ctx: &Context,
+    state: &mut FocusRules,
+    rules: &[FocusRule],
+    focus: &Focus,
+    profiles: &[Profile],
+) {
+    for (name, up) in state.changes(rules, focus) {
+        if ctx.debug {
+            println!(
+                "Focus on {:?} brings {} {}",
+                focus,
+                name,
+                if up { "up" } else { "down" }
+            );
+        }
+        let result =
+            profiles::find(profiles, name).and_then(|profile| actions::ensure(ctx, profile, up));
+        if let Err(e) = actions::ignore_missing_waybar(result)
+            && ctx.debug
+        {
+            println!("Failed to apply focus rule of {}: {}", name, e);
+        }
+    }
+}
+
+/// Carry out a request made from the tray icon.
+#[cfg(feature = "tray")]
+fn handle_tray_event(ctx: &Context, event: TrayEvent, interface_name: &str, profiles: &[Profile]) {
+    let result = match &event {
+        TrayEvent::Toggle => profiles::find(profiles, interface_name)
+            .and_then(|profile| actions::toggle(ctx, profile)),
+        TrayEvent::Switch(name) => actions::switch(ctx, profiles, name),
+    };
+    if let Err(e) = actions::ignore_missing_waybar(result)
+        && ctx.debug
+    {
+        println!("Failed to handle tray request {:?}: {}", event, e);
+    }
+}
+
+/// Mirror the status in the tray icon, along with the profiles that are up.
+#[cfg(feature = "tray")]
+fn update_tray(ctx: &Context, tray: &Tray, report: &StatusReport, profiles: &[Profile]) {
+    let active = profiles
+        .iter()
+        .filter(|profile| {
+            config::is_active(&profile.name, (ctx.options_for)(&profile.name).netns)
+                .unwrap_or(false)
+        })
+        .map(|profile| profile.name.clone())
+        .collect();
+    if let Err(e) = tray.update(report, active)
+        && ctx.debug
+    {
+        println!("Failed to update the tray icon: {}", e);
+    }
+}
+
+/// Poll the interface every `interval` and react to status changes.
+///
+/// Changes made outside of the toggle command (e.g. `ip link del`) are picked
+/// up too, so Waybar is signaled whenever the displayed status would differ.
+/// Scheduled windows of the app config are enforced at their boundaries,
+/// profiles up outside of their allowed hours are taken down, and the
+/// interface is disconnected when idle or on critical battery if configured.
+/// With `tray`, an icon is shown and its requests are handled as soon as they
+/// arrive, as are focus changes in sway or Hyprland when focus rules are set.
+///
+/// Runs on the tokio runtime, events and the poll timer being awaited together.
+/// Checks and the actions they trigger block in `block_in_place`, leaving the
+/// other worker to timers and background tasks, and status changes are
+/// published on the blocking pool, so that neither holds the other up.
+pub async fn run(
+    ctx: &Context<'_>,
+    interface_name: &str,
+    options: &config::TunnelOptions<'_>,
+    interval: Duration,
+    app_config: &AppConfig,
+    profiles: &[Profile],
+    tray: bool,
+) -> Result<(), error::Error> {
+    for schedule in &app_config.schedule {
+        profiles::find(profiles, &schedule.profile)?;
+    }
+    for rule in &app_config.focus_rule {
+        profiles::find(profiles, &rule.profile)?;
+        if rule.workspace.is_none() && rule.app_id.is_none() {
+            return Err(error::Error::InvalidFormat {
+                message: format!(
+                    "focus rule of {} needs a workspace or an app_id",
+                    rule.profile
+                ),
+            });
+        }
+    }
+    actions::collect_garbage(ctx);
+    // Kept until the loop ends, telling `events` that the daemon publishes.
+    let _registration = match Registration::bind(refresh::DAEMONS) {
+        Ok(registration) => Some(registration),
+        Err(e) => {
+            if ctx.debug {
+                println!("Not registering the daemon: {}", e);
+            }
+            None
+        }
+    };
+    let mut event_trackers = Trackers::default();
+    // Kept alive so that waiting for events never fails, even without sources.
+    let (sender, mut events) = mpsc::unbounded_channel();
+    #[cfg(not(feature = "tray"))]
+    if tray {
+        return Err(error::Error::Tray(
+            "wg-waybar was built without the tray feature".to_string(),
+        ));
+    }
+    #[cfg(feature = "tray")]
+    let tray = if tray {
+        let sender = sender.clone();
+        let notify = move |event| {
+            let _ = sender.send(Event::Tray(event));
+        };
+        Some(Tray::start(profiles, notify)?)
+    } else {
+        None
+    };
+    if !app_config.focus_rule.is_empty() {
+        let sender = sender.clone();
+        compositor::listen(
+            move |focus| {
+                let _ = sender.send(Event::Focus(focus));
+            },
+            ctx.debug,
+        )?;
+    }
+    let mut focus_rules = FocusRules::default();
+    let idle = app_config
+        .idle
+        .get(interface_name)
+        .map(|idle| profiles::find(profiles, interface_name).map(|profile| (idle, profile)))
+        .transpose()?;
+    let battery = app_config
+        .battery
+        .get(interface_name)
+        .map(|battery| profiles::find(profiles, interface_name).map(|profile| (battery, profile)))
+        .transpose()?;
+    let mut battery_watch = BatteryWatch::default();
+    let mut scheduler = Scheduler::default();
+    let mut idle_tracker = IdleTracker::default();
+    let mut previous: Option<Status> = None;
+    let notifier = notifier(&app_config.publish, ctx.debug);
+    loop {
+        let sleep = task::block_in_place(|| {
+            enforce_schedules(ctx, &mut scheduler, &app_config.schedule, profiles);
+            enforce_allowed_hours(ctx, profiles);
+            complete_pending(ctx, profiles);
+            publish_events(ctx, &mut event_trackers, profiles);
+            if let Some((idle, profile)) = idle
+                && let Err(e) = idle::check(ctx, &mut idle_tracker, idle, profile, options.netns)
+                && ctx.debug
+            {
+                println!("Failed to check idleness of {}: {}", interface_name, e);
+            }
+            let mut sleep = interval;
+            if let Some((battery, profile)) = battery {
+                match battery_watch.check(ctx, battery, profile, options.netns) {
+                    Ok(Some(seconds)) => sleep = Duration::from_secs(seconds),
+                    Ok(None) => {}
+                    Err(e) => {
+                        if ctx.debug {
+                            println!("Failed to check battery for {}: {}", interface_name, e);
+                        }
+                    }
+                }
+            }
+            let report = status::report(interface_name, ctx.store, options).unwrap_or_else(|e| {
+                StatusReport::error(format!("Failed to check VPN status: {}", e))
+            });
+            if previous != Some(report.status) {
+                if ctx.debug {
+                    println!(
+                        "{}: {} -> {}",
+                        interface_name,
+                        previous.map(|status| status.as_str()).unwrap_or("none"),
+                        report.status.as_str()
+                    );
+                }
+                if previous.is_some()
+                    && let Err(e) = ctx.refresh.refresh()
+                    && ctx.debug
+                {
+                    println!("Failed to refresh: {}", e);
+                }
+                let event = event(interface_name, &report, previous);
+                let _ = notifier.send((interface_name.to_string(), event));
+                previous = Some(report.status);
+            }
+            #[cfg(feature = "tray")]
+            if let Some(tray) = &tray {
+                update_tray(ctx, tray, &report, profiles);
+            }
+            sleep
+        });
+        match tokio::time::timeout(sleep, events.recv()).await {
+            #[cfg(feature = "tray")]
+            Ok(Some(Event::Tray(event))) => {
+                task::block_in_place(|| handle_tray_event(ctx, event, interface_name, profiles))
+            }
+            Ok(Some(Event::Focus(focus))) => task::block_in_place(|| {
+                apply_focus_rules(
+                    ctx,
+                    &mut focus_rules,
+                    &app_config.focus_rule,
+                    &focus,
+                    profiles,
+                )
+            }),
+            Ok(None) | Err(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_time()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn publishes_in_order_without_holding_up_the_loop() {
+        runtime().block_on(async {
+            let published = Arc::new(Mutex::new(Vec::new()));
+            let sink = published.clone();
+            let sender = outbox(move |value: u32| {
+                std::thread::sleep(Duration::from_millis(50));
+                sink.lock().unwrap().push(value);
+            });
+            let start = Instant::now();
+            for value in 1..=3 {
+                sender.send(value).unwrap();
+            }
+            assert!(start.elapsed() < Duration::from_millis(50));
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            assert_eq!(*published.lock().unwrap(), [1, 2, 3]);
+        });
+    }
+
+    #[test]
+    fn timers_keep_running_while_a_poll_blocks() {
+        runtime().block_on(async {
+            let (sender, mut ticks) = mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_millis(10));
+                loop {
+                    interval.tick().await;
+                    if sender.send(()).is_err() {
+                        break;
+                    }
+                }
+            });
+            // Stands for a poll blocking on netlink or a connect.
+            task::block_in_place(|| std::thread::sleep(Duration::from_millis(200)));
+            let mut count = 0;
+            while ticks.try_recv().is_ok() {
+                count += 1;
+            }
+            assert!(count >= 5, "only {} ticks while blocked", count);
+        });
+    }
+}
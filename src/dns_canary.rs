@@ -0,0 +1,140 @@
+use crate::netns;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Outcome of resolving the canary name through the DNS server of the tunnel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DnsCheck {
+    pub name: String,
+    pub server: IpAddr,
+    /// Milliseconds the answer took, if one came.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl DnsCheck {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    pub fn describe(&self) -> String {
+        match (&self.error, self.latency_ms) {
+            (Some(error), _) => format!("DNS through {} fails: {}", self.server, error),
+            (None, Some(latency)) => format!(
+                "DNS: {} resolved by {} in {} ms",
+                self.name, self.server, latency
+            ),
+            (None, None) => format!("DNS: {} resolved by {}", self.name, self.server),
+        }
+    }
+}
+
+/// A recursive `A` query for `name`.
+fn query(id: u16, name: &str) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(name.len() + 18);
+    packet.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question.
+    packet.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    // Type A, class IN.
+    packet.extend_from_slice(&[0, 1, 0, 1]);
+    packet
+}
+
+/// Whether `response` answers query `id` successfully, reading only the header.
+fn check_response(id: u16, response: &[u8]) -> Result<(), String> {
+    if response.len() < 12 || response[..2] != id.to_be_bytes() || response[2] & 0x80 == 0 {
+        return Err("malformed answer".to_string());
+    }
+    match response[3] & 0x0f {
+        0 => Ok(()),
+        2 => Err("server failure".to_string()),
+        3 => Err("no such name".to_string()),
+        5 => Err("refused".to_string()),
+        code => Err(format!("error code {}", code)),
+    }
+}
+
+fn resolve(server: IpAddr, name: &str, timeout: Duration) -> Result<Duration, String> {
+    let bind: SocketAddr = match server {
+        IpAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        IpAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let socket = UdpSocket::bind(bind).map_err(|e| e.to_string())?;
+    socket
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| e.to_string())?;
+    let id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos() as u16)
+        .unwrap_or_default();
+    let start = Instant::now();
+    socket
+        .send_to(&query(id, name), SocketAddr::new(server, 53))
+        .map_err(|e| e.to_string())?;
+    let mut response = [0u8; 512];
+    let length = socket.recv(&mut response).map_err(|e| match e.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+            format!("no answer within {} ms", timeout.as_millis())
+        }
+        _ => e.to_string(),
+    })?;
+    check_response(id, &response[..length])?;
+    Ok(start.elapsed())
+}
+
+/// Resolve `name` with `server`, from inside the namespace of the tunnel if any.
+pub fn check(server: IpAddr, name: &str, netns: Option<&str>, timeout: Duration) -> DnsCheck {
+    let result = match netns {
+        Some(namespace) => netns::with(namespace, || resolve(server, name, timeout))
+            .unwrap_or_else(|e| Err(e.to_string())),
+        None => resolve(server, name, timeout),
+    };
+    match result {
+        Ok(latency) => DnsCheck {
+            name: name.to_string(),
+            server,
+            latency_ms: Some(latency.as_millis() as u64),
+            error: None,
+        },
+        Err(error) => DnsCheck {
+            name: name.to_string(),
+            server,
+            latency_ms: None,
+            error: Some(error),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_queries_and_reads_answer_codes() {
+        let packet = query(0x1234, "example.com.");
+        assert_eq!(&packet[..4], &[0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(&packet[12..25], b"\x07example\x03com\x00");
+        assert_eq!(&packet[25..], &[0, 1, 0, 1]);
+
+        let mut answer = packet.clone();
+        answer[2] |= 0x80;
+        assert_eq!(check_response(0x1234, &answer), Ok(()));
+        answer[3] = 0x82;
+        assert_eq!(
+            check_response(0x1234, &answer),
+            Err("server failure".to_string())
+        );
+        // Not an answer to this query.
+        assert!(check_response(0x4321, &answer).is_err());
+        assert!(check_response(0x1234, &packet).is_err());
+    }
+}
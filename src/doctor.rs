@@ -0,0 +1,103 @@
+use crate::clock;
+use crate::config;
+use crate::precheck;
+use crate::profiles::Profile;
+use std::time::{Duration, SystemTime};
+
+/// Handshakes are renewed every two minutes; an older one means the peer stopped answering.
+const STALE_HANDSHAKE: Duration = Duration::from_secs(180);
+/// Time given to the NTP server to answer.
+const CLOCK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Outcome of one check.
+#[derive(Debug, PartialEq)]
+enum Finding {
+    Ok(String),
+    Problem(String),
+    /// The check could not be made, e.g. the tunnel is down.
+    Unknown(String),
+}
+
+fn endpoints(profile: &Profile) -> Finding {
+    match precheck::endpoints(&profile.path) {
+        Ok(0) => Finding::Ok("none, the peers connect to this profile".to_string()),
+        Ok(_) => Finding::Ok("reachable".to_string()),
+        Err(e) => Finding::Problem(e.to_string()),
+    }
+}
+
+fn handshake(profile: &Profile, netns: Option<&str>) -> Finding {
+    if !config::is_active(&profile.name, netns).unwrap_or(false) {
+        return Finding::Unknown("the tunnel is down".to_string());
+    }
+    match config::last_handshake(&profile.name, netns) {
+        Ok(Some(time)) => handshake_age(SystemTime::now().duration_since(time).unwrap_or_default()),
+        Ok(None) => Finding::Problem("no handshake with any peer".to_string()),
+        Err(e) => Finding::Unknown(e.to_string()),
+    }
+}
+
+fn handshake_age(age: Duration) -> Finding {
+    let text = format!("latest {} s ago", age.as_secs());
+    if age > STALE_HANDSHAKE {
+        Finding::Problem(text)
+    } else {
+        Finding::Ok(text)
+    }
+}
+
+fn clock() -> Finding {
+    match clock::skew(CLOCK_TIMEOUT) {
+        Ok(skew) => clock_skew(skew.round() as i64),
+        Err(e) => Finding::Unknown(format!("cannot reach NTP: {}", e)),
+    }
+}
+
+fn clock_skew(skew: i64) -> Finding {
+    if skew.abs() >= clock::SKEW_THRESHOLD {
+        Finding::Problem(clock::describe(skew))
+    } else {
+        Finding::Ok(format!("{} s off NTP", skew))
+    }
+}
+
+/// Check the usual causes of a tunnel that carries no traffic, printing one
+/// line per check, and return whether no problem was found.
+pub fn run(profile: &Profile, netns: Option<&str>) -> bool {
+    let findings = [
+        ("endpoints", endpoints(profile)),
+        ("handshake", handshake(profile, netns)),
+        ("clock", clock()),
+    ];
+    let mut healthy = true;
+    for (check, finding) in findings {
+        match finding {
+            Finding::Ok(text) => println!("ok       {}: {}", check, text),
+            Finding::Problem(text) => {
+                healthy = false;
+                println!("problem  {}: {}", check, text);
+            }
+            Finding::Unknown(text) => println!("unknown  {}: {}", check, text),
+        }
+    }
+    healthy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_stale_handshakes_and_skewed_clocks() {
+        assert!(matches!(
+            handshake_age(Duration::from_secs(30)),
+            Finding::Ok(_)
+        ));
+        assert!(matches!(
+            handshake_age(Duration::from_secs(600)),
+            Finding::Problem(_)
+        ));
+        assert_eq!(clock_skew(-4), Finding::Ok("-4 s off NTP".to_string()));
+        assert!(matches!(clock_skew(90), Finding::Problem(text) if text.contains("90 s behind")));
+    }
+}
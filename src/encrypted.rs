@@ -0,0 +1,156 @@
+use crate::error;
+use crate::hooks;
+use crate::paths;
+use crate::utils;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Suffix of profiles encrypted with age.
+pub const SUFFIX: &str = ".conf.age";
+/// Identity decrypting them, in the config directory of the invoking user.
+const IDENTITY: &str = "age-identity.txt";
+
+/// Whether the profile is encrypted with age, to decrypt when it comes up.
+pub fn is_encrypted(config_path: &Path) -> bool {
+    config_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(SUFFIX))
+}
+
+/// Where the decrypted configuration is kept while the profile is in use.
+pub fn decrypted_path(config_path: &Path) -> Result<PathBuf, error::Error> {
+    let name = config_path
+        .file_stem()
+        .ok_or_else(|| error::Error::InvalidFormat {
+            message: "profile without a file name".to_string(),
+        })?;
+    let dir = match paths::runtime_dir() {
+        Some(dir) => dir,
+        None => paths::state_dir()?,
+    };
+    Ok(dir.join("decrypted").join(name))
+}
+
+fn identity() -> Result<PathBuf, error::Error> {
+    if let Some(path) = utils::get_environ("WG_WAYBAR_AGE_IDENTITY") {
+        return Ok(path.into());
+    }
+    utils::get_config_home("wg-waybar")
+        .map(|dir| dir.join(IDENTITY))
+        .map_err(|e| error::Error::UnCaught(error::UnCaughtError(e.to_string())))
+}
+
+/// Decrypt `ciphertext` with `identity`, `cmd` running age.
+///
+/// The profile is read here and given on stdin, since it may only be readable
+/// by root while age runs as the invoking user, who owns the identity.
+fn run_age(mut cmd: Command, identity: &Path, ciphertext: Vec<u8>) -> Result<Vec<u8>, String> {
+    cmd.arg("--decrypt")
+        .arg("--identity")
+        .arg(identity)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    hooks::as_invoking_user(&mut cmd);
+    let mut child = cmd.spawn().map_err(|e| format!("cannot run age: {}", e))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // Written aside so that a large profile cannot fill both pipes at once.
+    let writer = std::thread::spawn(move || stdin.write_all(&ciphertext));
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    let _ = writer.join();
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(output.stdout)
+}
+
+/// Decrypt the profile with `age` and the identity of the invoking user, keeping
+/// the result as the configuration used for the tunnel.
+pub fn decrypt(config_path: &Path) -> Result<(), error::Error> {
+    let identity = identity()?;
+    let plaintext =
+        run_age(Command::new("age"), &identity, std::fs::read(config_path)?).map_err(|e| {
+            error::Error::InvalidFormat {
+                message: format!(
+                    "decrypting {} with {} failed: {}",
+                    config_path.display(),
+                    identity.display(),
+                    e
+                ),
+            }
+        })?;
+    let path = decrypted_path(config_path)?;
+    if let Some(dir) = path.parent() {
+        if let Some(base) = dir.parent() {
+            paths::ensure(base.to_path_buf())?;
+        }
+        paths::ensure(dir.to_path_buf())?;
+    }
+    // Created private before the private key gets written to it.
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)?;
+    utils::fs_write(path, plaintext)
+}
+
+/// Forget the decrypted configuration once the profile is down.
+pub fn discard(config_path: &Path) {
+    if is_encrypted(config_path)
+        && let Ok(path) = decrypted_path(config_path)
+    {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_encrypted_profiles() {
+        assert!(is_encrypted(Path::new("/etc/wireguard/home.conf.age")));
+        assert!(!is_encrypted(Path::new("/etc/wireguard/home.conf")));
+        assert!(!is_encrypted(Path::new("/etc/wireguard/home.age")));
+        assert_eq!(
+            decrypted_path(Path::new("/etc/wireguard/home.conf.age"))
+                .unwrap()
+                .file_name()
+                .unwrap(),
+            "home.conf"
+        );
+    }
+
+    #[test]
+    fn pipes_the_profile_through_age() {
+        // Stands in for age, its arguments becoming `$0` and on.
+        let age = |script: &str| {
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c").arg(script);
+            cmd
+        };
+        let working = age(r#"[ "$0 $1 $2" = "--decrypt --identity id.txt" ] && tr a-z A-Z"#);
+        assert_eq!(
+            run_age(working, Path::new("id.txt"), b"[interface]\n".to_vec()).unwrap(),
+            b"[INTERFACE]\n"
+        );
+        let failing = age("echo 'no identity matched' >&2; exit 1");
+        assert_eq!(
+            run_age(failing, Path::new("id.txt"), Vec::new()).unwrap_err(),
+            "no identity matched"
+        );
+        assert!(
+            run_age(
+                Command::new("/nonexistent/age"),
+                Path::new("id.txt"),
+                Vec::new()
+            )
+            .is_err()
+        );
+    }
+}
@@ -1,231 +1,377 @@
 use ini::ParseError;
+use serde::{Deserialize, Serialize};
 use std::net::AddrParseError;
-#[derive(Debug)]
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("Missing section {0} in WireGuard configuration file")]
 pub struct MissingSectionError(pub String);
 
-#[derive(Debug)]
+#[derive(Debug, Error)]
+#[error("Missing property: {0}")]
 pub struct MissingPropertyError(pub String);
 
-impl std::error::Error for MissingPropertyError {}
-
-impl std::fmt::Display for MissingPropertyError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Missing property: {}", self.0)
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum Error {
-    IO(std::io::Error),
-    Ini(ParseError),
-    MissingSection(MissingSectionError),
-    MissingProperty(MissingPropertyError),
-    PeerConfig(PeerConfigError),
-    Signal(SignalError),
+    #[error("I/O error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("INI parsing error: {0}")]
+    Ini(#[from] ParseError),
+    #[error(transparent)]
+    MissingSection(#[from] MissingSectionError),
+    #[error(transparent)]
+    MissingProperty(#[from] MissingPropertyError),
+    #[error(transparent)]
+    PeerConfig(#[from] PeerConfigError),
+    #[error("SignalError: {0}")]
+    Signal(#[from] SignalError),
+    #[error("Invalid format: {message}")]
     InvalidFormat { message: String },
-    WireGuardApi(String),
-    Base64(base64::DecodeError),
+    #[error("WireGuard API error: {message}")]
+    WireGuardApi { kind: ErrorKind, message: String },
+    #[error("Routing error: {0}")]
+    Routing(String),
+    #[error("Network namespace error: {0}")]
+    Netns(String),
+    #[error("Split tunnel error: {0}")]
+    SplitTunnel(String),
+    #[error("Gateway error: {0}")]
+    Gateway(String),
+    #[error("Firewall error: {0}")]
+    Firewall(String),
+    #[cfg(feature = "daemon")]
+    #[error("Publish error: {0}")]
+    Publish(String),
+    #[error("Refresh error: {0}")]
+    Refresh(String),
+    #[error("Failed to resolve endpoint {0}")]
+    Resolve(String),
+    #[error("Probe error: {0}")]
+    Probe(String),
+    #[error("Other VPN active: {0}")]
+    Conflict(String),
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    #[cfg(feature = "daemon")]
+    #[error("Tray error: {0}")]
+    Tray(String),
+    #[cfg(feature = "daemon")]
+    #[error("Compositor IPC error: {0}")]
+    Compositor(String),
+    #[error("Metered connection: {0}")]
+    Metered(String),
+    #[error("Outside allowed hours: {0}")]
+    Restricted(String),
+    #[error("In use by another user: {0}")]
+    InUse(String),
+    #[error("Profile expired: {0}")]
+    Expired(String),
+    #[error("Control socket error: {0}")]
+    Control(String),
+    #[error("Traffic accounting failed: {0}")]
+    Accounting(String),
+    #[cfg(feature = "providers")]
+    #[error("VPN provider error: {0}")]
+    Provider(String),
+    #[error("Failed to fetch the configuration: {0}")]
+    Fetch(String),
+    #[cfg(feature = "self-update")]
+    #[error("Self-update failed: {0}")]
+    Update(String),
+    #[error("Address pool error: {0}")]
+    Pool(String),
+    #[error("Hardware key error: {0}")]
+    HardwareKey(String),
+    #[error("Run error: {0}")]
+    Run(String),
+    #[error("Base64 decoding error: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("UserNotFound error: {0}")]
     UserNotFound(String),
-    Serde(serde_json::error::Error),
-    UnCaught(UnCaughtError),
+    #[error("SerdeError: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("Config error: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("UnCaughtError: {0}")]
+    UnCaught(#[from] UnCaughtError),
+    /// What was being done when `inner` happened: the file, peer or step involved.
+    #[error("{context}: {inner}")]
+    Context { context: String, inner: Box<Error> },
 }
 
-#[derive(Debug)]
-pub struct UnCaughtError(pub String);
-impl std::error::Error for UnCaughtError {}
-
-impl std::fmt::Display for UnCaughtError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
-    }
+/// Class of a failure, shown to scripts and CSS so they can react to each differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    /// Not running as root, or missing CAP_NET_ADMIN.
+    Permission,
+    /// The kernel has no WireGuard support loaded.
+    ModuleMissing,
+    /// An endpoint could not be resolved.
+    Dns,
+    /// The peer did not answer in time.
+    HandshakeTimeout,
+    /// A profile or the app config is invalid.
+    Parse,
+    Other,
 }
 
-impl From<UnCaughtError> for Error {
-    fn from(value: UnCaughtError) -> Self {
-        Self::UnCaught(value)
-    }
-}
-
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl ErrorKind {
+    pub const ALL: [ErrorKind; 6] = [
+        ErrorKind::Permission,
+        ErrorKind::ModuleMissing,
+        ErrorKind::Dns,
+        ErrorKind::HandshakeTimeout,
+        ErrorKind::Parse,
+        ErrorKind::Other,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
         match self {
-            Error::IO(err) => write!(f, "I/O error: {}", err),
-            Error::Ini(err) => write!(f, "INI parsing error: {}", err),
-            Error::MissingSection(err) => write!(f, "{}", err),
-            Error::MissingProperty(err) => write!(f, "{}", err),
-            Error::PeerConfig(err) => write!(f, "{}", err),
-            Error::InvalidFormat { message } => write!(f, "Invalid format: {}", message),
-            Error::WireGuardApi(err) => write!(f, "WireGuard API error: {}", err),
-            Error::Base64(err) => write!(f, "Base64 decoding error: {}", err),
-            Error::UserNotFound(err) => write!(f, "UserNotFound error: {}", err),
-            Error::Serde(err) => write!(f, "SerdeError: {}", err),
-            Error::Signal(err) => write!(f, "SignalError: {}", err),
-            Error::UnCaught(err) => write!(f, "UnCaughtError: {}", err),
+            ErrorKind::Permission => "permission",
+            ErrorKind::ModuleMissing => "module-missing",
+            ErrorKind::Dns => "dns",
+            ErrorKind::HandshakeTimeout => "handshake-timeout",
+            ErrorKind::Parse => "parse",
+            ErrorKind::Other => "other",
         }
     }
-}
 
-impl std::error::Error for Error {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        match self {
-            Error::IO(err) => Some(err),
-            Error::Ini(err) => Some(err),
-            Error::MissingSection(err) => Some(err),
-            Error::MissingProperty(err) => Some(err),
-            Error::PeerConfig(err) => Some(err),
-            Error::Base64(err) => Some(err),
+    /// The kind of a failure the kernel reported with `errno`, if it tells one.
+    fn from_errno(errno: i32) -> Option<Self> {
+        match errno {
+            libc::EPERM | libc::EACCES => Some(ErrorKind::Permission),
+            // Netlink's answer to a link kind or family no module provides.
+            libc::EOPNOTSUPP | libc::EAFNOSUPPORT | libc::EPROTONOSUPPORT => {
+                Some(ErrorKind::ModuleMissing)
+            }
             _ => None,
         }
     }
-}
 
-impl From<std::io::Error> for Error {
-    fn from(value: std::io::Error) -> Self {
-        Self::IO(value)
+    /// Classify a failure reported by the kernel or `ip` from its message: by
+    /// the errno of an `(os error N)`, or by the `strerror` text of one, as in
+    /// `RTNETLINK answers: Operation not permitted`.
+    pub fn classify(message: &str) -> Self {
+        if let Some(kind) = os_error(message).and_then(ErrorKind::from_errno) {
+            return kind;
+        }
+        let described = [
+            libc::EPERM,
+            libc::EACCES,
+            libc::EOPNOTSUPP,
+            libc::EAFNOSUPPORT,
+            libc::EPROTONOSUPPORT,
+        ]
+        .into_iter()
+        .find(|errno| message.contains(&strerror(*errno)));
+        match described.and_then(ErrorKind::from_errno) {
+            Some(kind) => kind,
+            // What `ip link add type wireguard` says without the module.
+            None if message.contains("Unknown device type") => ErrorKind::ModuleMissing,
+            None => ErrorKind::Other,
+        }
     }
 }
 
-impl From<SignalError> for Error {
-    fn from(value: SignalError) -> Self {
-        Self::Signal(value)
-    }
+/// The errno of a Rust I/O error in `message`, from its `(os error N)`.
+fn os_error(message: &str) -> Option<i32> {
+    let (_, rest) = message.split_once("(os error ")?;
+    rest.split_once(')')?.0.parse().ok()
 }
 
-impl From<ParseError> for Error {
-    fn from(value: ParseError) -> Self {
-        Self::Ini(value)
+/// The text of `errno`, as `ip` and the kernel tools print it.
+fn strerror(errno: i32) -> String {
+    let text = std::io::Error::from_raw_os_error(errno).to_string();
+    match text.split_once(" (os error") {
+        Some((description, _)) => description.to_string(),
+        None => text,
     }
 }
 
-impl From<MissingSectionError> for Error {
-    fn from(value: MissingSectionError) -> Self {
-        Self::MissingSection(value)
+impl std::str::FromStr for ErrorKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ErrorKind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or_else(|| {
+                let kinds: Vec<&str> = ErrorKind::ALL.iter().map(ErrorKind::as_str).collect();
+                format!(
+                    "unknown error kind {:?}, expected one of {}",
+                    s,
+                    kinds.join(", ")
+                )
+            })
     }
 }
 
-impl From<MissingPropertyError> for Error {
-    fn from(value: MissingPropertyError) -> Self {
-        Self::MissingProperty(value)
+impl Error {
+    /// The error underneath any context added on the way up.
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Context { inner, .. } => inner.root(),
+            err => err,
+        }
     }
-}
 
-impl From<PeerConfigError> for Error {
-    fn from(value: PeerConfigError) -> Self {
-        Self::PeerConfig(value)
+    /// The errno the kernel failed with, when the error tells it.
+    pub fn errno(&self) -> Option<i32> {
+        match self.root() {
+            Error::IO(err) => err.raw_os_error(),
+            Error::WireGuardApi { message, .. } => os_error(message),
+            _ => None,
+        }
     }
-}
 
-impl From<serde_json::Error> for Error {
-    fn from(value: serde_json::Error) -> Self {
-        Self::Serde(value)
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::IO(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                ErrorKind::Permission
+            }
+            Error::IO(err) => err
+                .raw_os_error()
+                .and_then(ErrorKind::from_errno)
+                .unwrap_or(ErrorKind::Other),
+            Error::Ini(_)
+            | Error::MissingSection(_)
+            | Error::MissingProperty(_)
+            | Error::PeerConfig(_)
+            | Error::InvalidFormat { .. }
+            | Error::Base64(_)
+            | Error::Serde(_)
+            | Error::Toml(_) => ErrorKind::Parse,
+            Error::WireGuardApi { kind, .. } => *kind,
+            Error::Context { inner, .. } => inner.kind(),
+            Error::Resolve(_) => ErrorKind::Dns,
+            Error::Timeout(_) => ErrorKind::HandshakeTimeout,
+            Error::InUse(_) => ErrorKind::Permission,
+            Error::Routing(message)
+            | Error::Netns(message)
+            | Error::SplitTunnel(message)
+            | Error::Gateway(message)
+            | Error::Firewall(message) => ErrorKind::classify(message),
+            _ => ErrorKind::Other,
+        }
+    }
+
+    /// Whether a policy declined the action before it was tried, rather than
+    /// the tunnel failing.
+    pub fn is_refusal(&self) -> bool {
+        matches!(
+            self.root(),
+            Error::Metered(_)
+                | Error::Restricted(_)
+                | Error::InUse(_)
+                | Error::Expired(_)
+                | Error::Conflict(_)
+        )
     }
 }
 
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct UnCaughtError(pub String);
+
 impl From<defguard_wireguard_rs::error::WireguardInterfaceError> for Error {
     fn from(value: defguard_wireguard_rs::error::WireguardInterfaceError) -> Self {
-        Self::WireGuardApi(value.to_string())
+        let message = value.to_string();
+        Self::WireGuardApi {
+            kind: ErrorKind::classify(&message),
+            message,
+        }
     }
 }
 
-impl From<base64::DecodeError> for Error {
-    fn from(value: base64::DecodeError) -> Self {
-        Self::Base64(value)
-    }
+/// Say which file, peer or step an error comes from.
+pub trait WithContext<T> {
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T, Error>;
 }
 
-impl std::fmt::Display for MissingSectionError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Missing section {} in WireGuard configuration file",
-            self.0
-        )
+impl<T, E: Into<Error>> WithContext<T> for Result<T, E> {
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T, Error> {
+        self.map_err(|err| Error::Context {
+            context: context().into(),
+            inner: Box::new(err.into()),
+        })
     }
 }
 
-impl std::error::Error for MissingSectionError {}
-
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum PeerConfigError {
-    EndPoint(AddrParseError),
-    MissingProperty(MissingPropertyError),
+    #[error("Endpoint parsing error: {0}")]
+    EndPoint(#[source] AddrParseError),
+    #[error(transparent)]
+    MissingProperty(#[from] MissingPropertyError),
+    #[error("Invalid public key: {message}")]
     InvalidPublicKey { message: String },
 }
 
-impl std::error::Error for PeerConfigError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        match self {
-            PeerConfigError::EndPoint(err) => Some(err),
-            PeerConfigError::MissingProperty(err) => Some(err),
-            _ => None,
-        }
-    }
-}
-
-impl std::fmt::Display for PeerConfigError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            PeerConfigError::EndPoint(err) => write!(f, "Endpoint parsing error: {}", err),
-            PeerConfigError::MissingProperty(err) => write!(f, "{}", err),
-            PeerConfigError::InvalidPublicKey { message } => {
-                write!(f, "Invalid public key: {}", message)
-            }
-        }
-    }
-}
-
-impl From<MissingPropertyError> for PeerConfigError {
-    fn from(value: MissingPropertyError) -> Self {
-        Self::MissingProperty(value)
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum SignalError {
-    OutOfRange(SignalOutOfRangeError),
-    ProcessNotFound(ProcessNotFoundError),
+    #[error(transparent)]
+    OutOfRange(#[from] SignalOutOfRangeError),
+    #[error(transparent)]
+    ProcessNotFound(#[from] ProcessNotFoundError),
+    #[error("OS error: {0}")]
     OS(String),
 }
 
-impl std::error::Error for SignalError {}
-
-#[derive(Debug)]
+#[derive(Debug, Error)]
+#[error("Signal out of allowed range: {0}")]
 pub struct SignalOutOfRangeError(pub String);
-#[derive(Debug)]
-pub struct ProcessNotFoundError(pub String);
-
-impl std::fmt::Display for SignalOutOfRangeError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Signal out of allowed range: {}", self.0)
-    }
-}
-impl std::fmt::Display for ProcessNotFoundError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Process not found: {}", self.0)
-    }
-}
-
-impl std::error::Error for SignalOutOfRangeError {}
-impl std::error::Error for ProcessNotFoundError {}
 
-impl std::fmt::Display for SignalError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            SignalError::OutOfRange(v) => write!(f, "{}", v),
-            SignalError::ProcessNotFound(v) => write!(f, "{}", v),
-            SignalError::OS(v) => write!(f, "OS error: {}", v),
-        }
-    }
-}
-
-impl From<ProcessNotFoundError> for SignalError {
-    fn from(value: ProcessNotFoundError) -> Self {
-        Self::ProcessNotFound(value)
-    }
-}
+#[derive(Debug, Error)]
+#[error("Process not found: {0}")]
+pub struct ProcessNotFoundError(pub String);
 
-impl From<SignalOutOfRangeError> for SignalError {
-    fn from(value: SignalOutOfRangeError) -> Self {
-        Self::OutOfRange(value)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_failures() {
+        let routing = Error::Routing(
+            "`ip link add` failed: RTNETLINK answers: Operation not permitted".into(),
+        );
+        assert_eq!(routing.kind(), ErrorKind::Permission);
+        let netns = Error::Netns("Error: Unknown device type.".into());
+        assert_eq!(netns.kind(), ErrorKind::ModuleMissing);
+        assert_eq!(
+            ErrorKind::classify("netlink: Operation not supported (os error 95)"),
+            ErrorKind::ModuleMissing
+        );
+        assert_eq!(
+            Error::IO(std::io::Error::from_raw_os_error(libc::EPERM)).kind(),
+            ErrorKind::Permission
+        );
+        // Naming a module is not failing for want of one.
+        assert_eq!(
+            ErrorKind::classify("Cannot find device \"module0\""),
+            ErrorKind::Other
+        );
+        assert_eq!(
+            Error::Resolve("vpn.example.com".into()).kind(),
+            ErrorKind::Dns
+        );
+        let missing = Error::MissingProperty(MissingPropertyError("PrivateKey".into()));
+        assert_eq!(missing.kind(), ErrorKind::Parse);
+        let wrapped: Result<(), Error> = Err(netns).with_context(|| "moving wg0 to vpn");
+        let wrapped = wrapped.unwrap_err();
+        assert_eq!(wrapped.kind(), ErrorKind::ModuleMissing);
+        assert_eq!(
+            wrapped.to_string(),
+            "moving wg0 to vpn: Network namespace error: Error: Unknown device type."
+        );
+        assert_eq!(
+            serde_json::to_value(ErrorKind::HandshakeTimeout).unwrap(),
+            "handshake-timeout"
+        );
+        assert!(!wrapped.is_refusal());
+        let metered: Result<(), Error> =
+            Err(Error::Metered("wlan0".into())).with_context(|| "connecting wg0");
+        assert!(metered.unwrap_err().is_refusal());
     }
 }
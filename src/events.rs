@@ -0,0 +1,240 @@
+use crate::actions::Context;
+use crate::config;
+use crate::error;
+use crate::profiles::Profile;
+use crate::refresh::{self, Registration};
+use crate::status::{self, Status, StatusReport};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// What changed about a profile between two checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum EventKind {
+    Connect,
+    Disconnect,
+    Error,
+    /// Up again after going through `reconnecting`.
+    Reconnect,
+    /// Up all along, but a peer answers from another address.
+    EndpointRoam,
+}
+
+/// One line of the `events` stream.
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Event<'a> {
+    pub event: EventKind,
+    pub interface: &'a str,
+    pub status: Status,
+    /// Peer endpoints, empty when the tunnel is not up.
+    pub endpoints: Vec<String>,
+    /// Unix timestamp of the check that saw the change.
+    pub timestamp: u64,
+    /// Tooltip of the status, for `error` events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'a str>,
+}
+
+/// Follows one profile; transitional statuses do not count as up or down.
+#[derive(Debug, Default)]
+struct Tracker {
+    status: Option<Status>,
+    /// Whether the last settled status was up, unknown before the first check.
+    up: Option<bool>,
+    reconnecting: bool,
+    endpoints: Vec<SocketAddr>,
+}
+
+impl Tracker {
+    /// Record the status and peer endpoints of a check and return what changed.
+    fn observe(&mut self, status: Status, endpoints: Vec<SocketAddr>) -> Vec<EventKind> {
+        let mut events = Vec::new();
+        let settled = match status {
+            Status::Error | Status::Disconnected => Some(false),
+            status if status.is_up() => Some(true),
+            _ => None,
+        };
+        if status == Status::Reconnecting {
+            self.reconnecting = true;
+        }
+        if status == Status::Error
+            && self
+                .status
+                .is_some_and(|previous| previous != Status::Error)
+        {
+            events.push(EventKind::Error);
+        }
+        match (self.up, settled) {
+            (Some(_), Some(true)) if self.reconnecting => events.push(EventKind::Reconnect),
+            (Some(false), Some(true)) => events.push(EventKind::Connect),
+            (Some(true), Some(false)) if status == Status::Disconnected => {
+                events.push(EventKind::Disconnect)
+            }
+            (Some(true), Some(true))
+                if !self.endpoints.is_empty()
+                    && !endpoints.is_empty()
+                    && self.endpoints != endpoints =>
+            {
+                events.push(EventKind::EndpointRoam)
+            }
+            _ => {}
+        }
+        if settled.is_some() {
+            self.up = settled;
+            self.reconnecting = false;
+        }
+        self.status = Some(status);
+        self.endpoints = endpoints;
+        events
+    }
+}
+
+fn message<'a>(
+    kind: EventKind,
+    profile: &'a Profile,
+    report: &'a StatusReport,
+    endpoints: &[SocketAddr],
+) -> Event<'a> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    Event {
+        event: kind,
+        interface: &profile.name,
+        status: report.status,
+        endpoints: endpoints.iter().map(ToString::to_string).collect(),
+        timestamp,
+        error: (kind == EventKind::Error).then_some(report.tooltip.as_str()),
+    }
+}
+
+/// What changed about the profiles since the previous check, for the
+/// `events` reading them or the daemon publishing them.
+#[derive(Default)]
+pub struct Trackers(HashMap<String, Tracker>);
+
+impl Trackers {
+    /// Check `profiles` and return the events since the previous check, one
+    /// JSON line each. The first check of a profile reports nothing.
+    pub fn check(
+        &mut self,
+        ctx: &Context<'_>,
+        profiles: &[Profile],
+    ) -> Result<Vec<String>, error::Error> {
+        let mut lines = Vec::new();
+        for profile in profiles {
+            let options = (ctx.options_for)(&profile.name);
+            let report = status::report_profile(profile, ctx.store, &options).unwrap_or_else(|e| {
+                StatusReport::error(format!("Failed to check VPN status: {}", e))
+            });
+            let endpoints = if report.status.is_up() {
+                config::peer_endpoints(&profile.name, options.netns).unwrap_or_default()
+            } else {
+                Vec::new()
+            };
+            let tracker = self.0.entry(profile.name.clone()).or_default();
+            for kind in tracker.observe(report.status, endpoints.clone()) {
+                lines.push(serde_json::to_string(&message(
+                    kind, profile, &report, &endpoints,
+                ))?);
+            }
+        }
+        Ok(lines)
+    }
+
+    /// Forget the profiles, e.g. while another process tracks them.
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// Print `line`, returning false once stdout is closed, e.g. by `| head`.
+fn emit(line: &str) -> bool {
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "{}", line.trim_end())
+        .and_then(|_| stdout.flush())
+        .is_ok()
+}
+
+/// Print the changes of the profiles as newline-delimited JSON until stdout is closed.
+///
+/// Events are `connect`, `disconnect`, `error`, `reconnect` and `endpoint-roam`;
+/// the state at start is not reported. While a `daemon` runs, they are those it
+/// publishes to the socket this command registers; otherwise the profiles are
+/// checked every `interval`.
+pub async fn run(
+    ctx: &Context<'_>,
+    profiles: &[Profile],
+    interval: Duration,
+) -> Result<(), error::Error> {
+    // Kept until the loop ends, which removes the socket.
+    let registration = Registration::bind(refresh::EVENT_READERS)?;
+    let socket = registration.try_clone()?;
+    let (sender, mut published) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        let mut buffer = vec![0; 65536];
+        while let Ok(len) = socket.recv(&mut buffer) {
+            let line = String::from_utf8_lossy(&buffer[..len]).into_owned();
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    let mut trackers = Trackers::default();
+    loop {
+        let lines = if refresh::is_registered(refresh::DAEMONS, "daemon") {
+            trackers.clear();
+            Vec::new()
+        } else {
+            tokio::task::block_in_place(|| trackers.check(ctx, profiles))?
+        };
+        for line in lines {
+            if !emit(&line) {
+                return Ok(());
+            }
+        }
+        let deadline = Instant::now() + interval;
+        while let Ok(Some(line)) = tokio::time::timeout_at(deadline, published.recv()).await {
+            if !emit(&line) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_transitions() {
+        let first: SocketAddr = "198.51.100.1:51820".parse().unwrap();
+        let second: SocketAddr = "198.51.100.2:51820".parse().unwrap();
+        let mut tracker = Tracker::default();
+        let mut observe =
+            |status, endpoints: &[SocketAddr]| tracker.observe(status, endpoints.to_vec());
+        assert_eq!(observe(Status::Disconnected, &[]), []);
+        assert_eq!(observe(Status::Connecting, &[]), []);
+        assert_eq!(observe(Status::Connected, &[first]), [EventKind::Connect]);
+        assert_eq!(
+            observe(Status::Warning, &[second]),
+            [EventKind::EndpointRoam]
+        );
+        assert_eq!(observe(Status::Reconnecting, &[]), []);
+        assert_eq!(
+            observe(Status::Connected, &[second]),
+            [EventKind::Reconnect]
+        );
+        assert_eq!(observe(Status::Disconnecting, &[]), []);
+        assert_eq!(observe(Status::Disconnected, &[]), [EventKind::Disconnect]);
+        assert_eq!(observe(Status::Error, &[]), [EventKind::Error]);
+    }
+}
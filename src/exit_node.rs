@@ -0,0 +1,131 @@
+use crate::config::{self, PeerSummary, TunnelOptions};
+use crate::error;
+use crate::profiles::Profile;
+use crate::state::StateStore;
+
+/// Whether `query` designates the peer: its 1-based position in the
+/// configuration, its name, a prefix of its public key, or its endpoint.
+fn matches(index: usize, peer: &PeerSummary, query: &str) -> bool {
+    query == (index + 1).to_string()
+        || peer
+            .name
+            .as_deref()
+            .is_some_and(|name| name.eq_ignore_ascii_case(query))
+        || peer.public_key.starts_with(query)
+        || peer.endpoint.as_deref().is_some_and(|endpoint| {
+            endpoint == query
+                || endpoint
+                    .rsplit_once(':')
+                    .is_some_and(|(host, _)| host == query)
+        })
+}
+
+fn resolve<'a>(peers: &'a [PeerSummary], query: &str) -> Result<&'a PeerSummary, error::Error> {
+    let candidates: Vec<&PeerSummary> = peers
+        .iter()
+        .enumerate()
+        .filter(|(index, peer)| peer.default_route && matches(*index, peer, query))
+        .map(|(_, peer)| peer)
+        .collect();
+    match candidates.as_slice() {
+        [peer] => Ok(peer),
+        [] => Err(error::Error::InvalidFormat {
+            message: format!("No peer with a default route matches {}", query),
+        }),
+        _ => Err(error::Error::InvalidFormat {
+            message: format!("Several peers match {}", query),
+        }),
+    }
+}
+
+fn label(peer: &PeerSummary) -> String {
+    match (&peer.name, &peer.endpoint) {
+        (Some(name), Some(endpoint)) => format!("{} ({})", name, endpoint),
+        (Some(name), None) => name.clone(),
+        (None, Some(endpoint)) => format!("{} ({})", endpoint, &peer.public_key[..8]),
+        (None, None) => peer.public_key.clone(),
+    }
+}
+
+/// Select the peer carrying the default route, applying it right away if the
+/// profile is up.
+pub fn set(
+    store: &StateStore,
+    profile: &Profile,
+    options: &TunnelOptions,
+    query: &str,
+) -> Result<(), error::Error> {
+    let peers = config::peers(&profile.path, &profile.name, options.groups)?;
+    let peer = resolve(&peers, query)?;
+    store.update(&profile.name, |state| {
+        state.exit_node = Some(peer.public_key.clone())
+    })?;
+    if config::is_active(&profile.name, options.netns)? {
+        config::apply_exit_node(&profile.path, &profile.name, options, &peer.public_key)?;
+    }
+    println!("Exit node of {}: {}", profile.name, label(peer));
+    Ok(())
+}
+
+/// Print the peers able to act as exit node, marking the selected one.
+pub fn list(
+    store: &StateStore,
+    profile: &Profile,
+    options: &TunnelOptions,
+) -> Result<(), error::Error> {
+    let peers = config::peers(&profile.path, &profile.name, options.groups)?;
+    let selected = store.load(&profile.name)?.exit_node;
+    let first = peers.iter().position(|peer| peer.default_route);
+    for (index, peer) in peers.iter().enumerate() {
+        if !peer.default_route {
+            continue;
+        }
+        let active = match &selected {
+            Some(key) if peers.iter().any(|peer| &peer.public_key == key) => {
+                &peer.public_key == key
+            }
+            _ => Some(index) == first,
+        };
+        println!(
+            "{} {}. {}",
+            if active { "*" } else { " " },
+            index + 1,
+            label(peer)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(public_key: &str, endpoint: &str, default_route: bool) -> PeerSummary {
+        PeerSummary {
+            public_key: public_key.to_string(),
+            name: None,
+            endpoint: Some(endpoint.to_string()),
+            default_route,
+        }
+    }
+
+    #[test]
+    fn resolves_peers_by_position_key_or_endpoint() {
+        let peers = [
+            peer("AAAA", "office.example.org:51820", false),
+            peer("BBBB", "se.example.net:51820", true),
+            peer("CCCC", "198.51.100.1:51820", true),
+        ];
+        assert_eq!(resolve(&peers, "2").unwrap().public_key, "BBBB");
+        assert_eq!(resolve(&peers, "CC").unwrap().public_key, "CCCC");
+        assert_eq!(
+            resolve(&peers, "se.example.net").unwrap().public_key,
+            "BBBB"
+        );
+        assert!(resolve(&peers, "1").is_err());
+
+        let mut named = peer("DDDD", "203.0.113.1:51820", true);
+        named.name = Some("Stockholm".to_string());
+        assert_eq!(resolve(&[named], "stockholm").unwrap().public_key, "DDDD");
+    }
+}
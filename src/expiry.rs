@@ -0,0 +1,195 @@
+use crate::error;
+use crate::hooks;
+use crate::profiles::Profile;
+use crate::toml_profile;
+use crate::utils;
+use std::fmt;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+/// Days before the expiry from which the tooltip warns about it.
+const WARN_DAYS: i64 = 14;
+
+/// Calendar date of a `# Expires = 2025-07-01` comment, from which the
+/// profile is no longer valid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Date {
+    year: i64,
+    month: u32,
+    day: u32,
+}
+
+impl FromStr for Date {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid date {:?}, expected YYYY-MM-DD", s);
+        let mut parts = s.trim().splitn(3, '-');
+        let mut next = || parts.next().ok_or_else(invalid);
+        let year = next()?.parse().map_err(|_| invalid())?;
+        let month = next()?.parse().map_err(|_| invalid())?;
+        let day = next()?.parse().map_err(|_| invalid())?;
+        if !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+            return Err(invalid());
+        }
+        Ok(Date { year, month, day })
+    }
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+impl Date {
+    /// Days since 1970-01-01, after Howard Hinnant's `days_from_civil`.
+    fn days(&self) -> i64 {
+        let year = if self.month <= 2 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = i64::from(self.month);
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + i64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146097 + day_of_era - 719468
+    }
+
+    /// Today in the local timezone.
+    fn today() -> Date {
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        unsafe {
+            let now = libc::time(std::ptr::null_mut());
+            libc::localtime_r(&now, &mut tm);
+        }
+        Date {
+            year: i64::from(tm.tm_year) + 1900,
+            month: (tm.tm_mon + 1) as u32,
+            day: tm.tm_mday as u32,
+        }
+    }
+}
+
+/// Days from `today` until `expiry`, zero or less once expired.
+fn days_left(expiry: Date, today: Date) -> i64 {
+    expiry.days() - today.days()
+}
+
+fn describe_on(expiry: Date, today: Date) -> Option<String> {
+    match days_left(expiry, today) {
+        left if left <= 0 => Some(format!("Warning: profile expired on {}", expiry)),
+        1 => Some(format!("Warning: profile expires tomorrow, on {}", expiry)),
+        left if left <= WARN_DAYS => Some(format!(
+            "Warning: profile expires in {} days, on {}",
+            left, expiry
+        )),
+        _ => None,
+    }
+}
+
+/// Tooltip line for a profile expired or about to, or whose expiry is invalid.
+pub fn describe(profile: &Profile) -> Option<String> {
+    match profile.expires() {
+        Ok(expiry) => describe_on(expiry?, Date::today()),
+        Err(e) => Some(format!("Warning: ignoring Expires: {}", e)),
+    }
+}
+
+/// Run the `# RenewCommand` of the profile as the invoking user, its output
+/// replacing the profile like the output of a `FetchCommand`. A TOML profile
+/// is only replaced by another one.
+fn renew(profile: &Profile, command: &str) -> Result<(), error::Error> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .env("WG_WAYBAR_INTERFACE", &profile.name)
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped());
+    hooks::as_invoking_user(&mut cmd);
+    let output = cmd.output()?;
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(error::Error::Expired(format!(
+            "renewing {} with `{}` failed: {}",
+            profile.name,
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    if profile.path.extension().is_some_and(|ext| ext == "toml") {
+        let renewed = String::from_utf8_lossy(&output.stdout);
+        toml_profile::to_ini(&renewed).map_err(|e| {
+            error::Error::Expired(format!(
+                "renewing {} with `{}` did not print a TOML profile: {}",
+                profile.name, command, e
+            ))
+        })?;
+    }
+    utils::replace_file(&profile.path, &output.stdout, 0o600)
+}
+
+/// Refuse to connect an expired profile, renewing it first if it says how.
+pub fn check(profile: &Profile) -> Result<(), error::Error> {
+    // An invalid expiry is shown in the tooltip rather than refused.
+    let Ok(Some(expiry)) = profile.expires() else {
+        return Ok(());
+    };
+    if days_left(expiry, Date::today()) > 0 {
+        return Ok(());
+    }
+    if let Some(command) = profile.renew_command() {
+        renew(profile, &command)?;
+        if profile
+            .expires()
+            .is_ok_and(|expiry| expiry.is_none_or(|expiry| days_left(expiry, Date::today()) > 0))
+        {
+            return Ok(());
+        }
+    }
+    Err(error::Error::Expired(format!(
+        "{} expired on {}, ask for a new configuration",
+        profile.name, expiry
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warns_as_the_expiry_approaches() {
+        let date = |s: &str| s.parse::<Date>().unwrap();
+        assert_eq!(date("1970-01-01").days(), 0);
+        assert_eq!(date("2024-03-01").days() - date("2024-02-28").days(), 2);
+        assert!("2025-13-01".parse::<Date>().is_err());
+        assert!("2025-02-31".parse::<Date>().is_err());
+        assert!("2025-02-29".parse::<Date>().is_err());
+        assert_eq!(date("2024-02-29").to_string(), "2024-02-29");
+        assert_eq!(date("2000-02-29").to_string(), "2000-02-29");
+        assert!("1900-02-29".parse::<Date>().is_err());
+        assert_eq!(date("2025-7-1").to_string(), "2025-07-01");
+
+        let expiry = date("2025-07-01");
+        assert_eq!(describe_on(expiry, date("2025-06-01")), None);
+        assert_eq!(
+            describe_on(expiry, date("2025-06-21")).unwrap(),
+            "Warning: profile expires in 10 days, on 2025-07-01"
+        );
+        assert_eq!(
+            describe_on(expiry, date("2025-07-01")).unwrap(),
+            "Warning: profile expired on 2025-07-01"
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use crate::error::ErrorKind;
+
+/// What an error kind means, why it happens and what to do about it.
+struct Explanation {
+    summary: &'static str,
+    causes: &'static [&'static str],
+    fixes: &'static [&'static str],
+}
+
+fn explanation(kind: ErrorKind) -> Explanation {
+    match kind {
+        ErrorKind::Permission => Explanation {
+            summary: "wg-waybar was not allowed to change the network configuration.",
+            causes: &[
+                "The command did not run as root, e.g. `sudo` missing from the Waybar `exec` or `on-click`",
+                "The binary lacks CAP_NET_ADMIN when run without sudo",
+                "The tunnel was brought up by another user",
+            ],
+            fixes: &[
+                "Prefix the commands with `sudo` and allow them without a password in sudoers",
+                "Or run daemon mode as root and point the module at it with `--connect`",
+            ],
+        },
+        ErrorKind::ModuleMissing => Explanation {
+            summary: "The kernel cannot create WireGuard interfaces.",
+            causes: &[
+                "The `wireguard` module is not loaded, or not built for the running kernel",
+                "The kernel was updated and the machine not rebooted yet",
+            ],
+            fixes: &[
+                "Load it with `sudo modprobe wireguard`",
+                "Reboot after a kernel update, or install the module package of your kernel",
+            ],
+        },
+        ErrorKind::Dns => Explanation {
+            summary: "The endpoint of a peer could not be resolved.",
+            causes: &[
+                "No network, or a captive portal not logged into yet",
+                "A typo in the `Endpoint` host name",
+                "DNS still pointing at a tunnel that is down",
+            ],
+            fixes: &[
+                "Check the uplink and resolve the host with `getent hosts <host>`",
+                "Fix the `Endpoint` of the profile, or use an IP address",
+            ],
+        },
+        ErrorKind::HandshakeTimeout => Explanation {
+            summary: "The tunnel is up but the server never answered the handshake.",
+            causes: &[
+                "The endpoint is unreachable, or UDP is blocked by the network",
+                "The server does not know the public key of this peer",
+                "The system clock is far off, see the tooltip",
+            ],
+            fixes: &[
+                "Check the endpoint and port with the server administrator",
+                "Compare the peer keys on both sides with `wg show`",
+                "Enable time sync with `timedatectl set-ntp true`",
+            ],
+        },
+        ErrorKind::Parse => Explanation {
+            summary: "A profile or the app config could not be read.",
+            causes: &[
+                "A syntax error or a misspelled key in the `.conf` file",
+                "An invalid key, address or port",
+                "An unknown key in `~/.config/wg-waybar/config.toml`",
+            ],
+            fixes: &[
+                "Read the error in the tooltip, which names the file and key",
+                "Compare the profile with `wg-quick`'s format",
+            ],
+        },
+        ErrorKind::Other => Explanation {
+            summary: "The failure did not match a known kind.",
+            causes: &["See the message in the tooltip"],
+            fixes: &["Run the command again with `--debug` for details"],
+        },
+    }
+}
+
+/// Causes and fixes of `kind`, as printed by `explain`.
+pub fn render(kind: ErrorKind) -> String {
+    let explanation = explanation(kind);
+    let mut text = format!("{}: {}\n\nCauses:\n", kind.as_str(), explanation.summary);
+    for cause in explanation.causes {
+        text.push_str(&format!("  - {}\n", cause));
+    }
+    text.push_str("\nFixes:\n");
+    for fix in explanation.fixes {
+        text.push_str(&format!("  - {}\n", fix));
+    }
+    text
+}
+
+/// Tooltip line pointing to `explain`, for the kinds it says something about.
+pub fn hint(kind: ErrorKind) -> Option<String> {
+    (kind != ErrorKind::Other).then(|| {
+        format!(
+            "Run `wg-waybar explain {}` for causes and fixes",
+            kind.as_str()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explains_every_kind_but_hints_only_known_ones() {
+        let text = render(ErrorKind::ModuleMissing);
+        assert!(text.starts_with("module-missing: The kernel cannot create"));
+        assert!(text.contains("\nFixes:\n  - Load it with `sudo modprobe wireguard`\n"));
+        assert_eq!(
+            hint(ErrorKind::Dns).unwrap(),
+            "Run `wg-waybar explain dns` for causes and fixes"
+        );
+        assert_eq!(hint(ErrorKind::Other), None);
+        for kind in ErrorKind::ALL {
+            assert_eq!(kind.as_str().parse::<ErrorKind>(), Ok(kind));
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use crate::app_config::ExportPeerConfig;
+use crate::config;
+use crate::error;
+use crate::paths;
+use crate::profiles::Profile;
+use crate::utils;
+use base64::prelude::*;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Addresses handed out by `export-peer`, by profile then peer name.
+type Allocations = BTreeMap<String, BTreeMap<String, IpAddr>>;
+
+/// The allocation database, kept across reboots so that addresses stay unique.
+fn database_path() -> Result<PathBuf, error::Error> {
+    Ok(paths::ensure(paths::state_dir()?)?.join("address-pool.json"))
+}
+
+fn load(path: &Path) -> Result<Allocations, error::Error> {
+    if !path.exists() {
+        return Ok(Allocations::new());
+    }
+    Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+/// Network and prefix length of a pool like `10.8.0.0/24`.
+fn parse_pool(pool: &str) -> Result<(IpAddr, u8), error::Error> {
+    let invalid = || {
+        error::Error::Pool(format!(
+            "invalid pool {:?}, expected e.g. 10.8.0.0/24",
+            pool
+        ))
+    };
+    let (ip, cidr) = pool.split_once('/').ok_or_else(invalid)?;
+    let ip: IpAddr = ip.trim().parse().map_err(|_| invalid())?;
+    let cidr: u8 = cidr.trim().parse().map_err(|_| invalid())?;
+    let width = if ip.is_ipv4() { 32 } else { 128 };
+    if cidr > width {
+        return Err(invalid());
+    }
+    Ok((ip, cidr))
+}
+
+/// First host address of `pool` not in `taken`.
+///
+/// The network address is never handed out, nor the broadcast address of an
+/// IPv4 pool.
+fn allocate(pool: &str, taken: &[IpAddr]) -> Result<IpAddr, error::Error> {
+    let (network, cidr) = parse_pool(pool)?;
+    let (bits, width) = match network {
+        IpAddr::V4(ip) => (u128::from(u32::from(ip)), 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    };
+    let host_bits = u32::from(width - cidr);
+    let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+    let size = 1u128.checked_shl(host_bits).unwrap_or(u128::MAX);
+    let last = if network.is_ipv4() { size - 1 } else { size };
+    (1..last)
+        .map(|offset| {
+            let address = (bits & mask) | offset;
+            match network {
+                IpAddr::V4(_) => IpAddr::from(Ipv4Addr::from(address as u32)),
+                IpAddr::V6(_) => IpAddr::from(Ipv6Addr::from(address)),
+            }
+        })
+        .find(|address| !taken.contains(address))
+        .ok_or_else(|| error::Error::Pool(format!("{} has no address left", pool)))
+}
+
+/// A new key pair, clamped as `wg genkey` does, encoded in base64.
+fn key_pair() -> Result<(String, String), error::Error> {
+    let mut secret = [0u8; 32];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut secret)?;
+    secret[0] &= 248;
+    secret[31] = (secret[31] & 127) | 64;
+    let public_key = PublicKey::from(&StaticSecret::from(secret));
+    Ok((
+        BASE64_STANDARD.encode(secret),
+        BASE64_STANDARD.encode(public_key.as_bytes()),
+    ))
+}
+
+/// Addresses of a list like `AllowedIPs`, without their prefix length.
+fn hosts(networks: &[String]) -> impl Iterator<Item = IpAddr> + '_ {
+    networks.iter().filter_map(|network| {
+        network
+            .split('/')
+            .next()
+            .and_then(|ip| ip.trim().parse().ok())
+    })
+}
+
+/// Give `name` the next free address of the pool, add it as a peer of the
+/// profile and print the configuration of its side.
+pub fn run(profile: &Profile, config: &ExportPeerConfig, name: &str) -> Result<(), error::Error> {
+    if profile.path.extension().is_none_or(|ext| ext != "conf") {
+        return Err(error::Error::Pool(format!(
+            "{} is not a .conf profile, peers can only be added to those",
+            profile.path.display()
+        )));
+    }
+    let database = database_path()?;
+    // Held until the peer is in both the profile and the database.
+    let _lock = utils::lock_file(&database.with_extension("lock"))?;
+    let mut allocations = load(&database)?;
+    let allocated = allocations.entry(profile.name.clone()).or_default();
+    if let Some(address) = allocated.get(name) {
+        return Err(error::Error::Pool(format!(
+            "{} already has {} in the pool of {}",
+            name, address, profile.name
+        )));
+    }
+    let (server_key, server_addresses) = config::identity(&profile.path)?;
+    // Addresses given out by hand count as taken as well.
+    let mut taken: Vec<IpAddr> = allocated.values().copied().collect();
+    taken.extend(hosts(&server_addresses));
+    taken.extend(hosts(&config::allowed_ips(&profile.path)?));
+    let address = allocate(&config.pool, &taken)?;
+    let (_, cidr) = parse_pool(&config.pool)?;
+    let host_cidr = if address.is_ipv4() { 32 } else { 128 };
+    let (private_key, public_key) = key_pair()?;
+
+    let mut profile_conf = std::fs::read_to_string(&profile.path)?;
+    profile_conf.push_str(&format!(
+        "\n[Peer]\n# Name = {}\nPublicKey = {}\nAllowedIPs = {}/{}\n",
+        name, public_key, address, host_cidr
+    ));
+    utils::replace_file(&profile.path, profile_conf, 0o600)?;
+    allocated.insert(name.to_string(), address);
+    utils::replace_file(
+        &database,
+        serde_json::to_string_pretty(&allocations)?,
+        0o644,
+    )?;
+
+    let mut client = format!(
+        "[Interface]\nPrivateKey = {}\nAddress = {}/{}\n",
+        private_key, address, cidr
+    );
+    if !config.dns.is_empty() {
+        client.push_str(&format!("DNS = {}\n", config.dns.join(", ")));
+    }
+    let allowed_ips = if config.allowed_ips.is_empty() {
+        vec![config.pool.clone()]
+    } else {
+        config.allowed_ips.clone()
+    };
+    client.push_str(&format!(
+        "\n[Peer]\n# Name = {}\nPublicKey = {}\nEndpoint = {}\nAllowedIPs = {}\n",
+        profile.name,
+        server_key,
+        config.endpoint,
+        allowed_ips.join(", ")
+    ));
+    print!("{}", client);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hands_out_free_host_addresses_of_the_pool() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(allocate("10.8.0.0/24", &[]).unwrap(), ip("10.8.0.1"));
+        assert_eq!(
+            allocate("10.8.0.7/24", &[ip("10.8.0.1"), ip("10.8.0.3")]).unwrap(),
+            ip("10.8.0.2")
+        );
+        let full: Vec<IpAddr> = (1..3).map(|i| ip(&format!("10.8.0.{}", i))).collect();
+        assert!(allocate("10.8.0.0/30", &full).is_err());
+        assert_eq!(
+            allocate("fd00::/64", &[ip("fd00::1")]).unwrap(),
+            ip("fd00::2")
+        );
+        assert!(allocate("10.8.0.0/33", &[]).is_err());
+    }
+}
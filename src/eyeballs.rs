@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// How long a handshake over the preferred family may take before the other is tried.
+pub const FALLBACK_DELAY: Duration = Duration::from_secs(2);
+/// How long both families together may take before the preferred one is kept.
+pub const RACE_TIMEOUT: Duration = Duration::from_secs(4);
+
+/// Address family of an endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn of(addr: &SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(_) => Family::V4,
+            SocketAddr::V6(_) => Family::V6,
+        }
+    }
+
+    pub fn other(&self) -> Self {
+        match self {
+            Family::V4 => Family::V6,
+            Family::V6 => Family::V4,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Family::V4 => "IPv4",
+            Family::V6 => "IPv6",
+        }
+    }
+}
+
+/// Addresses to try for an endpoint: the first one of the preferred family,
+/// then the first one of the other family if it resolved to both.
+///
+/// IPv6 is preferred unless `prefer` says otherwise, as in RFC 8305.
+pub fn candidates(addrs: &[SocketAddr], prefer: Option<Family>) -> Vec<SocketAddr> {
+    let prefer = prefer.unwrap_or(Family::V6);
+    let preferred = addrs.iter().find(|addr| Family::of(addr) == prefer);
+    let other = addrs.iter().find(|addr| Family::of(addr) != prefer);
+    preferred.into_iter().chain(other).copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_candidates_by_preferred_family() {
+        let v4: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+        let other_v4: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let addrs = [v4, other_v4, v6];
+        assert_eq!(candidates(&addrs, None), vec![v6, v4]);
+        assert_eq!(candidates(&addrs, Some(Family::V4)), vec![v4, v6]);
+        assert_eq!(candidates(&[v4, other_v4], None), vec![v4]);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::error;
+use crate::hooks;
+use crate::paths;
+use crate::utils;
+use ini::Ini;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// `[Interface]` key of a profile whose configuration is printed by a command.
+const FETCH_COMMAND: &str = "FetchCommand";
+/// Comma-separated names of the secrets asked for before running the command.
+const FETCH_SECRET: &str = "FetchSecret";
+
+fn fetch_error(e: impl std::fmt::Display) -> error::Error {
+    error::Error::Fetch(e.to_string())
+}
+
+/// `FetchCommand` and `FetchSecret` names of a profile, if it is fetched.
+fn fetch_keys(config_path: &Path) -> Option<(String, Vec<String>)> {
+    let config = Ini::load_from_file(config_path).ok()?;
+    let interface = config.section(Some("Interface"))?;
+    let command = interface.get(FETCH_COMMAND)?.to_string();
+    let secrets = interface
+        .get(FETCH_SECRET)
+        .map(|names| {
+            names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    Some((command, secrets))
+}
+
+pub fn is_fetched(config_path: &Path) -> bool {
+    fetch_keys(config_path).is_some()
+}
+
+/// Where the configuration fetched for a profile is kept while it is in use.
+pub fn fetched_path(config_path: &Path) -> Result<PathBuf, error::Error> {
+    let name = config_path
+        .file_name()
+        .ok_or_else(|| fetch_error("profile without a file name"))?;
+    let dir = match paths::runtime_dir() {
+        Some(dir) => dir,
+        None => paths::state_dir()?,
+    };
+    Ok(dir.join("fetched").join(name))
+}
+
+/// Undo the percent-encoding of Assuan data lines.
+fn percent_decode(value: &str) -> String {
+    let mut decoded = Vec::new();
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%'
+            && let (Some(high), Some(low)) = (bytes.next(), bytes.next())
+            && let Ok(value) = u8::from_str_radix(&format!("{}{}", high as char, low as char), 16)
+        {
+            decoded.push(value);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// The PIN from pinentry's answer to `GETPIN`, `None` once it reports an error.
+fn parse_pin(lines: impl Iterator<Item = String>) -> Result<Option<String>, String> {
+    let mut pin = None;
+    for line in lines {
+        if let Some(data) = line.strip_prefix("D ") {
+            pin = Some(percent_decode(data));
+        } else if line == "OK" {
+            return Ok(pin);
+        } else if let Some(error) = line.strip_prefix("ERR ") {
+            return Err(error.to_string());
+        }
+    }
+    Err("pinentry exited early".to_string())
+}
+
+/// X display served by one of the sockets `names` of `/tmp/.X11-unix`, the lowest.
+fn x_display(names: impl Iterator<Item = String>) -> Option<String> {
+    names
+        .filter_map(|name| name.strip_prefix('X')?.parse::<u32>().ok())
+        .min()
+        .map(|display| format!(":{}", display))
+}
+
+/// X display of the invoking user, which sudo may not keep.
+fn display() -> Option<String> {
+    if let Some(display) = utils::get_environ("DISPLAY") {
+        return display.into_string().ok();
+    }
+    x_display(
+        std::fs::read_dir("/tmp/.X11-unix")
+            .ok()?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok()),
+    )
+}
+
+/// Wayland display of the invoking user, lost when going through sudo.
+fn wayland_display() -> Option<String> {
+    if let Some(display) = utils::get_environ("WAYLAND_DISPLAY") {
+        return display.into_string().ok();
+    }
+    std::fs::read_dir(utils::user_runtime_dir()?)
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .find(|name| name.starts_with("wayland-") && !name.ends_with(".lock"))
+}
+
+/// Ask the invoking user for a secret through `pinentry`.
+fn ask(interface_name: &str, name: &str) -> Result<String, error::Error> {
+    let mut cmd = Command::new("pinentry");
+    cmd.stdin(Stdio::piped()).stdout(Stdio::piped());
+    hooks::as_invoking_user(&mut cmd);
+    // So that a graphical pinentry finds the session, under sudo too.
+    if let Some(display) = wayland_display() {
+        cmd.env("WAYLAND_DISPLAY", display);
+    }
+    if let Some(display) = display() {
+        cmd.env("DISPLAY", display);
+    }
+    if let Some(xauthority) = utils::get_environ("XAUTHORITY") {
+        cmd.env("XAUTHORITY", xauthority);
+    }
+    let mut child = cmd.spawn().map_err(fetch_error)?;
+    let mut stdin = child.stdin.take().expect("piped stdin");
+    let stdout = BufReader::new(child.stdout.take().expect("piped stdout"));
+    let mut lines = stdout.lines().map_while(Result::ok);
+    // Greeting.
+    lines.next();
+    let mut request = |line: String| -> Result<(), error::Error> {
+        writeln!(stdin, "{}", line).map_err(fetch_error)
+    };
+    for line in [
+        "SETTITLE wg-waybar".to_string(),
+        format!(
+            "SETDESC {} needed to fetch the configuration of {}",
+            name, interface_name
+        ),
+        format!("SETPROMPT {}:", name),
+    ] {
+        request(line)?;
+        parse_pin(&mut lines).map_err(fetch_error)?;
+    }
+    request("GETPIN".to_string())?;
+    let pin =
+        parse_pin(&mut lines).map_err(|e| fetch_error(format!("{} not given: {}", name, e)))?;
+    let _ = request("BYE".to_string());
+    let _ = child.wait();
+    Ok(pin.unwrap_or_default())
+}
+
+/// Environment variable carrying a secret to the fetch command.
+fn secret_variable(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("WG_WAYBAR_SECRET_{}", name)
+}
+
+/// Run the `FetchCommand` of a profile as the invoking user and keep its output
+/// as the configuration used for the tunnel.
+///
+/// Secrets listed in `FetchSecret` are asked for with pinentry and passed in
+/// `WG_WAYBAR_SECRET_<NAME>`, never on the command line.
+pub fn fetch(config_path: &Path, interface_name: &str) -> Result<(), error::Error> {
+    let (command, secrets) = fetch_keys(config_path)
+        .ok_or_else(|| fetch_error(format!("no {} in {}", FETCH_COMMAND, config_path.display())))?;
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(&command)
+        .env("WG_WAYBAR_INTERFACE", interface_name)
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped());
+    for name in &secrets {
+        cmd.env(secret_variable(name), ask(interface_name, name)?);
+    }
+    hooks::as_invoking_user(&mut cmd);
+    let output = cmd.output().map_err(fetch_error)?;
+    if !output.status.success() {
+        return Err(fetch_error(format!(
+            "`{}` failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let path = fetched_path(config_path)?;
+    if let Some(dir) = path.parent() {
+        if let Some(base) = dir.parent() {
+            paths::ensure(base.to_path_buf())?;
+        }
+        paths::ensure(dir.to_path_buf())?;
+    }
+    // Created private before the private key gets written to it.
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)?;
+    utils::fs_write(path, &output.stdout)
+}
+
+/// Forget the configuration fetched for a profile once it is down.
+pub fn discard(config_path: &Path) {
+    if is_fetched(config_path)
+        && let Ok(path) = fetched_path(config_path)
+    {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_x_display() {
+        let sockets = ["X1", "X0", "ICE", "Xfoo"].map(String::from);
+        assert_eq!(x_display(sockets.into_iter()), Some(":0".to_string()));
+        assert_eq!(x_display(std::iter::empty()), None);
+    }
+
+    #[test]
+    fn reads_pinentry_answers() {
+        let lines = ["D s3cr%25t%0A", "OK"].map(str::to_string);
+        assert_eq!(
+            parse_pin(lines.into_iter()),
+            Ok(Some("s3cr%t\n".to_string()))
+        );
+        let lines = ["ERR 83886179 Operation cancelled".to_string()];
+        assert!(parse_pin(lines.into_iter()).is_err());
+        assert_eq!(
+            secret_variable("one-time code"),
+            "WG_WAYBAR_SECRET_ONE_TIME_CODE"
+        );
+    }
+}
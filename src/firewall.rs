@@ -0,0 +1,214 @@
+use crate::applied;
+use crate::cli::FirewallBackend;
+use crate::error;
+use crate::utils;
+use std::net::SocketAddr;
+
+fn firewall_error(e: impl std::fmt::Display) -> error::Error {
+    error::Error::Firewall(e.to_string())
+}
+
+/// Run a firewall tool and return its output.
+pub fn run(program: &str, args: &[&str]) -> Result<String, error::Error> {
+    utils::run_command(program, args, None).map_err(firewall_error)
+}
+
+fn ufw_active(status: &str) -> bool {
+    status.lines().any(|line| line.trim() == "Status: active")
+}
+
+/// Whether `iptables -S FORWARD` shows rules or a policy that would drop what
+/// a separate nftables table accepts.
+fn iptables_in_use(forward: &str) -> bool {
+    forward
+        .lines()
+        .any(|line| line.starts_with("-A ") || line.trim() == "-P FORWARD DROP")
+}
+
+/// Which firewall owns the ruleset: firewalld and ufw drop forwarded traffic
+/// unless told otherwise, and so do iptables rules left by e.g. Docker.
+pub fn detect() -> FirewallBackend {
+    if run("firewall-cmd", &["--state"]).is_ok() {
+        FirewallBackend::Firewalld
+    } else if run("ufw", &["status"]).is_ok_and(|status| ufw_active(&status)) {
+        FirewallBackend::Ufw
+    } else if run("iptables", &["-S", "FORWARD"]).is_ok_and(|forward| iptables_in_use(&forward)) {
+        FirewallBackend::Iptables
+    } else {
+        FirewallBackend::Nftables
+    }
+}
+
+/// The backend to go through, detected when left to `auto`.
+pub fn resolve(backend: FirewallBackend) -> FirewallBackend {
+    match backend {
+        FirewallBackend::Auto => detect(),
+        backend => backend,
+    }
+}
+
+fn kill_switch_name(interface_name: &str) -> String {
+    format!("wg-waybar-ks-{}", interface_name)
+}
+
+/// What a kill switch lets out besides the tunnel: loopback, the encrypted
+/// traffic to the endpoints, DHCP and IPv6 neighbor discovery, which keep the
+/// uplink itself working.
+fn kill_switch_ruleset(interface_name: &str, endpoints: &[SocketAddr]) -> String {
+    let mut rules = format!(
+        "    oifname \"lo\" accept\n    oifname \"{}\" accept\n    udp sport 68 udp dport 67 accept\n    udp sport 546 udp dport 547 accept\n    icmpv6 type {{ nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert }} accept\n",
+        interface_name
+    );
+    for endpoint in endpoints {
+        let family = if endpoint.is_ipv6() { "ip6" } else { "ip" };
+        rules.push_str(&format!(
+            "    {} daddr {} udp dport {} accept\n",
+            family,
+            endpoint.ip(),
+            endpoint.port()
+        ));
+    }
+    format!(
+        "table inet {name} {{\n  chain output {{\n    type filter hook output priority filter; policy drop;\n{rules}  }}\n}}\n",
+        name = kill_switch_name(interface_name),
+    )
+}
+
+/// The rules of [`kill_switch_ruleset`] for iptables or ip6tables, in a chain of their own.
+fn kill_switch_rules(interface_name: &str, endpoints: &[SocketAddr], ipv6: bool) -> Vec<String> {
+    let mut rules = vec![
+        "-o lo -j ACCEPT".to_string(),
+        format!("-o {} -j ACCEPT", interface_name),
+    ];
+    rules.push(if ipv6 {
+        "-p udp --sport 546 --dport 547 -j ACCEPT".to_string()
+    } else {
+        "-p udp --sport 68 --dport 67 -j ACCEPT".to_string()
+    });
+    if ipv6 {
+        for kind in [
+            "router-solicitation",
+            "neighbour-solicitation",
+            "neighbour-advertisement",
+        ] {
+            rules.push(format!("-p icmpv6 --icmpv6-type {} -j ACCEPT", kind));
+        }
+    }
+    for endpoint in endpoints
+        .iter()
+        .filter(|endpoint| endpoint.is_ipv6() == ipv6)
+    {
+        rules.push(format!(
+            "-d {} -p udp --dport {} -j ACCEPT",
+            endpoint.ip(),
+            endpoint.port()
+        ));
+    }
+    rules.push("-j DROP".to_string());
+    rules
+}
+
+fn iptables(ipv6: bool) -> &'static str {
+    if ipv6 { "ip6tables" } else { "iptables" }
+}
+
+fn split(rule: &str) -> Vec<&str> {
+    rule.split(' ').collect()
+}
+
+/// Drop whatever would leave the machine outside of the tunnel while it is
+/// up, the encrypted traffic to `endpoints` aside.
+///
+/// A drop cannot be undone by the accepts of another table or firewall, so an
+/// nftables table of its own works next to firewalld and ufw; only the
+/// `iptables` backend gets iptables chains, for systems without nftables.
+pub fn apply_kill_switch(
+    interface_name: &str,
+    endpoints: &[SocketAddr],
+    backend: FirewallBackend,
+) -> Result<(), error::Error> {
+    let backend = resolve(backend);
+    applied::save("kill-switch", interface_name, &backend)?;
+    match backend {
+        FirewallBackend::Iptables => {
+            let chain = kill_switch_name(interface_name);
+            for ipv6 in [false, true] {
+                run(iptables(ipv6), &["-N", &chain])?;
+                for rule in kill_switch_rules(interface_name, endpoints, ipv6) {
+                    let mut args = vec!["-A", &chain];
+                    args.extend(split(&rule));
+                    run(iptables(ipv6), &args)?;
+                }
+                run(iptables(ipv6), &["-I", "OUTPUT", "-j", &chain])?;
+            }
+            Ok(())
+        }
+        _ => utils::run_command(
+            "nft",
+            &["-f", "-"],
+            Some(&kill_switch_ruleset(interface_name, endpoints)),
+        )
+        .map(|_| ())
+        .map_err(firewall_error),
+    }
+}
+
+/// Remove the kill switch of the tunnel, through the firewall it went through.
+pub fn teardown_kill_switch(interface_name: &str) {
+    let Some(backend) = applied::take::<FirewallBackend>("kill-switch", interface_name) else {
+        return;
+    };
+    let name = kill_switch_name(interface_name);
+    match backend {
+        FirewallBackend::Iptables => {
+            for ipv6 in [false, true] {
+                let _ = run(iptables(ipv6), &["-D", "OUTPUT", "-j", &name]);
+                let _ = run(iptables(ipv6), &["-F", &name]);
+                let _ = run(iptables(ipv6), &["-X", &name]);
+            }
+        }
+        _ => {
+            let _ = utils::run_command("nft", &["delete", "table", "inet", &name], None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_rulesets_owned_by_other_tools() {
+        assert!(ufw_active("Status: active\n\nTo  Action  From\n"));
+        assert!(!ufw_active("Status: inactive\n"));
+        assert!(iptables_in_use("-P FORWARD DROP\n"));
+        assert!(iptables_in_use(
+            "-P FORWARD ACCEPT\n-A FORWARD -j DOCKER-USER\n"
+        ));
+        assert!(!iptables_in_use("-P FORWARD ACCEPT\n"));
+    }
+
+    #[test]
+    fn lets_only_the_tunnel_and_its_endpoints_out() {
+        let endpoints: Vec<SocketAddr> = vec![
+            "198.51.100.1:51820".parse().unwrap(),
+            "[2001:db8::1]:51820".parse().unwrap(),
+        ];
+        let rules = kill_switch_ruleset("wg0", &endpoints);
+        assert!(rules.contains("table inet wg-waybar-ks-wg0 {"));
+        assert!(rules.contains("policy drop;"));
+        assert!(rules.contains("    oifname \"wg0\" accept\n"));
+        assert!(rules.contains("    ip daddr 198.51.100.1 udp dport 51820 accept\n"));
+        assert!(rules.contains("    ip6 daddr 2001:db8::1 udp dport 51820 accept\n"));
+
+        let rules = kill_switch_rules("wg0", &endpoints, false);
+        assert_eq!(rules.last().unwrap(), "-j DROP");
+        assert!(rules.contains(&"-d 198.51.100.1 -p udp --dport 51820 -j ACCEPT".to_string()));
+        assert!(!rules.iter().any(|rule| rule.contains("2001:db8::1")));
+        assert!(
+            kill_switch_rules("wg0", &endpoints, true)
+                .iter()
+                .any(|rule| rule.contains("neighbour-solicitation"))
+        );
+    }
+}
@@ -0,0 +1,505 @@
+use crate::cli::{OutputFormat, Style, Theme};
+use crate::config::PeerStats;
+use crate::status::{Status, StatusReport, escape_markup, format_bytes};
+use crate::theme::{self, Palette};
+use std::io::{self, Write};
+use std::time::{Duration, SystemTime};
+
+/// Turns a status report into what a frontend prints.
+#[derive(Default)]
+pub struct Formatter {
+    pub output_format: OutputFormat,
+    pub style: Style,
+    /// Accessibility preset: colors of markup tooltips, classes and shapes.
+    pub theme: Theme,
+    /// Text template, the report text itself when unset.
+    pub template: Option<String>,
+    /// Characters of text kept, the last one replaced by `…` when cut.
+    pub max_length: Option<usize>,
+    /// Line appended to the tooltip, e.g. that the tunnel is managed externally.
+    pub note: Option<String>,
+    /// Render the Waybar tooltip as Pango markup instead of escaping it whole.
+    pub tooltip_markup: bool,
+    /// Show the peers of an up tunnel as a table in the Waybar tooltip, as markup.
+    pub peer_table: bool,
+}
+
+/// Age after which WireGuard drops the session of a silent peer.
+const STALE_HANDSHAKE: Duration = Duration::from_secs(180);
+
+/// A dot colored by status, or its shape without palette.
+fn dot(palette: Option<&Palette>, status: Status) -> String {
+    match palette {
+        Some(palette) => format!("<span foreground=\"{}\">●</span>", palette.color(status)),
+        None => theme::shape(status).to_string(),
+    }
+}
+
+/// A row of the peer table and the status its handshake age stands for.
+fn peer_row(peer: &PeerStats, now: SystemTime) -> (String, Status) {
+    let key: String = peer.public_key.chars().take(8).collect();
+    let age = peer
+        .last_handshake
+        .and_then(|time| now.duration_since(time).ok());
+    let (handshake, color) = match age {
+        Some(age) if age < STALE_HANDSHAKE => (format!("{} s", age.as_secs()), Status::Connected),
+        Some(age) => (format!("{} s", age.as_secs()), Status::Warning),
+        None => ("never".to_string(), Status::Error),
+    };
+    let endpoint = peer
+        .endpoint
+        .map(|endpoint| endpoint.to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let row = format!(
+        "{:<9} {:<22} {:>9} {:>10} {:>10}",
+        format!("{}…", key),
+        endpoint,
+        handshake,
+        format_bytes(peer.transfer.rx),
+        format_bytes(peer.transfer.tx)
+    );
+    (row, color)
+}
+
+/// Tooltip as Pango markup with the peers in aligned columns: a dot colored by
+/// status before the bold first line, then a monospace table with a dot per
+/// peer colored by the age of its handshake.
+fn table_tooltip(
+    palette: Option<&Palette>,
+    status: Status,
+    tooltip: &str,
+    peers: &[PeerStats],
+    now: SystemTime,
+) -> String {
+    let (heading, details) = tooltip.split_once('\n').unwrap_or((tooltip, ""));
+    let mut markup = format!("{} <b>{}</b>", dot(palette, status), escape_markup(heading));
+    if !peers.is_empty() {
+        let header = format!(
+            "{:<9} {:<22} {:>9} {:>10} {:>10}",
+            "PEER", "ENDPOINT", "HANDSHAKE", "↓", "↑"
+        );
+        markup.push_str(&format!("\n<tt>  <b>{}</b>", header));
+        for peer in peers {
+            let (row, health) = peer_row(peer, now);
+            markup.push_str(&format!(
+                "\n{} {}",
+                dot(palette, health),
+                escape_markup(&row)
+            ));
+        }
+        markup.push_str("</tt>");
+    }
+    if !details.is_empty() {
+        markup.push_str(&format!("\n{}", escape_markup(details)));
+    }
+    markup
+}
+
+/// Tooltip as Pango markup: the first line in bold, the rest in red for errors,
+/// or in italics without palette.
+///
+/// The lines themselves are escaped, error messages being arbitrary text.
+fn markup_tooltip(palette: Option<&Palette>, status: Status, tooltip: &str) -> String {
+    let (heading, details) = tooltip.split_once('\n').unwrap_or((tooltip, ""));
+    let mut markup = format!("<b>{}</b>", escape_markup(heading));
+    if !details.is_empty() {
+        let details = escape_markup(details);
+        match (status, palette) {
+            (Status::Error, Some(palette)) => markup.push_str(&format!(
+                "\n<span foreground=\"{}\">{}</span>",
+                palette.error, details
+            )),
+            (Status::Error, None) => markup.push_str(&format!("\n<i>{}</i>", details)),
+            _ => markup.push_str(&format!("\n{}", details)),
+        }
+    }
+    markup
+}
+
+/// Abbreviate dash-separated names, keeping the first and last parts.
+///
+/// `wg-company-amsterdam-3` becomes `wg-c…-3`; names of three parts or less
+/// are kept whole.
+pub fn short_name(name: &str) -> String {
+    let parts: Vec<&str> = name.split('-').collect();
+    match parts.as_slice() {
+        [first, second, _, .., last] => {
+            let initial: String = second.chars().take(1).collect();
+            format!("{}-{}…-{}", first, initial, last)
+        }
+        _ => name.to_string(),
+    }
+}
+
+/// Cut `text` to `max_length` characters, ending with `…` like Waybar does.
+pub fn truncate(text: &str, max_length: usize) -> String {
+    if text.chars().count() <= max_length {
+        return text.to_string();
+    }
+    if max_length == 0 {
+        return String::new();
+    }
+    let mut truncated: String = text.chars().take(max_length.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Split Pango markup into its tags and the characters it shows, one token
+/// each, an entity like `&amp;` being a single character.
+fn markup_tokens(markup: &str) -> Vec<(bool, &str)> {
+    let mut tokens = Vec::new();
+    let mut rest = markup;
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '<' => rest.find('>').map_or(rest.len(), |end| end + 1),
+            '&' => rest.find(';').map_or(1, |end| end + 1),
+            c => c.len_utf8(),
+        };
+        tokens.push((c == '<', &rest[..len]));
+        rest = &rest[len..];
+    }
+    tokens
+}
+
+/// [`truncate`] for Pango markup, counting only the characters shown and
+/// keeping every tag so that they stay balanced.
+fn truncate_markup(markup: &str, max_length: usize) -> String {
+    let tokens = markup_tokens(markup);
+    if tokens.iter().filter(|(tag, _)| !tag).count() <= max_length {
+        return markup.to_string();
+    }
+    let mut kept = max_length.saturating_sub(1);
+    let mut truncated = String::with_capacity(markup.len());
+    let mut cut = false;
+    for (tag, token) in tokens {
+        if tag {
+            truncated.push_str(token);
+        } else if kept > 0 {
+            kept -= 1;
+            truncated.push_str(token);
+        } else if !cut && max_length > 0 {
+            cut = true;
+            truncated.push('…');
+        }
+    }
+    truncated
+}
+
+/// Single glyph standing for a status.
+fn glyph(status: Status, style: Style) -> &'static str {
+    match (style, status) {
+        (Style::Ascii, Status::Connected | Status::Idle | Status::Confirm | Status::InUse) => "[+]",
+        (Style::Ascii, Status::Disconnected) => "[-]",
+        (Style::Ascii, Status::Error) => "[!]",
+        (Style::Ascii, Status::Warning) => "[?]",
+        (Style::Ascii, _) => "[~]",
+        (_, Status::Connected | Status::Idle | Status::Confirm | Status::InUse) => "🔒",
+        (_, Status::Disconnected) => "🔓",
+        (_, Status::Error | Status::Warning) => "⚠",
+        (_, _) => "⋯",
+    }
+}
+
+impl Formatter {
+    /// Text shown for the report of `interface_name`.
+    ///
+    /// For Waybar the template is Pango markup: the values put into it are
+    /// escaped, the template itself is not.
+    pub fn text(&self, interface_name: &str, report: &StatusReport) -> String {
+        let markup = self.output_format == OutputFormat::Waybar;
+        let value = |value: &str| {
+            if markup {
+                escape_markup(value)
+            } else {
+                value.to_string()
+            }
+        };
+        let base = match (self.style, self.theme) {
+            (Style::Full, Theme::Default) => report.text.clone(),
+            // Told apart by shape rather than color alone.
+            (Style::Full, _) => format!("{} {}", theme::shape(report.status), report.text),
+            (style, _) => glyph(report.status, style).to_string(),
+        };
+        let text = match &self.template {
+            Some(template) => template
+                .replace("{text}", &value(&base))
+                .replace("{interface_short}", &value(&short_name(interface_name)))
+                .replace("{interface}", &value(interface_name))
+                .replace("{status}", report.status.as_str()),
+            None => value(&base),
+        };
+        match self.max_length {
+            Some(max_length) if markup => truncate_markup(&text, max_length),
+            Some(max_length) => truncate(&text, max_length),
+            None => text,
+        }
+    }
+
+    pub fn render(&self, interface_name: &str, report: &StatusReport) -> serde_json::Value {
+        // Compact styles move the full text to the tooltip.
+        let mut tooltip = match self.style {
+            Style::Full => report.tooltip.clone(),
+            _ => format!("{}\n{}", report.text, report.tooltip),
+        };
+        if let Some(note) = &self.note {
+            tooltip.push_str(&format!("\n{}", note));
+        }
+        let mut report = StatusReport {
+            text: self.text(interface_name, report),
+            tooltip,
+            ..report.clone()
+        };
+        // Only Waybar reads markup; the other frontends show the text as is.
+        if self.output_format == OutputFormat::Waybar {
+            let palette = theme::palette(self.theme);
+            report.tooltip = if self.peer_table {
+                table_tooltip(
+                    palette,
+                    report.status,
+                    &report.tooltip,
+                    &report.peers,
+                    SystemTime::now(),
+                )
+            } else if self.tooltip_markup {
+                markup_tooltip(palette, report.status, &report.tooltip)
+            } else {
+                escape_markup(&report.tooltip)
+            };
+        }
+        match self.output_format {
+            OutputFormat::Waybar => {
+                let mut json = report.to_json();
+                theme::decorate(self.theme, report.status, &mut json);
+                json
+            }
+            OutputFormat::Eww => report.to_eww_json(interface_name),
+            OutputFormat::I3bar => report.to_i3bar_json(interface_name),
+        }
+    }
+
+    pub fn output(&self, interface_name: &str, report: &StatusReport) -> Result<(), io::Error> {
+        println!("{}", self.render(interface_name, report));
+        io::stdout().flush()
+    }
+
+    /// Start the output of `watch`: for i3bar, its protocol header and the
+    /// endless array holding one list of blocks per update.
+    #[cfg(feature = "daemon")]
+    pub fn begin_stream(&self) -> Result<(), io::Error> {
+        if self.output_format == OutputFormat::I3bar {
+            println!("{{\"version\":1}}\n[");
+            io::stdout().flush()?;
+        }
+        Ok(())
+    }
+
+    /// One update of `watch`, the `first` after [`Formatter::begin_stream`] or not.
+    #[cfg(feature = "daemon")]
+    pub fn output_update(
+        &self,
+        interface_name: &str,
+        report: &StatusReport,
+        first: bool,
+    ) -> Result<(), io::Error> {
+        println!(
+            "{}",
+            self.update_line(self.render(interface_name, report), first)
+        );
+        io::stdout().flush()
+    }
+
+    #[cfg(feature = "daemon")]
+    fn update_line(&self, json: serde_json::Value, first: bool) -> String {
+        match self.output_format {
+            OutputFormat::I3bar if first => format!("[{}]", json),
+            OutputFormat::I3bar => format!(",[{}]", json),
+            _ => json.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abbreviates_and_truncates() {
+        assert_eq!(short_name("wg-company-amsterdam-3"), "wg-c…-3");
+        assert_eq!(short_name("wg-home"), "wg-home");
+        assert_eq!(truncate("VPN: wg-company", 8), "VPN: wg…");
+        assert_eq!(truncate("VPN: wg0", 8), "VPN: wg0");
+        assert_eq!(truncate("VPN: wg0", 0), "");
+
+        let formatter = Formatter {
+            template: Some("{interface_short} ({status})".to_string()),
+            ..Formatter::default()
+        };
+        let report = StatusReport {
+            percentage: 100,
+            ..StatusReport::new(
+                "VPN: wg-company-amsterdam-3".to_string(),
+                Status::Connected,
+                String::new(),
+            )
+        };
+        assert_eq!(
+            formatter.text("wg-company-amsterdam-3", &report),
+            "wg-c…-3 (connected)"
+        );
+    }
+
+    #[test]
+    fn compact_style_moves_text_to_tooltip() {
+        let formatter = Formatter {
+            style: Style::Compact,
+            ..Formatter::default()
+        };
+        let report = StatusReport::new(
+            "VPN: wg0".to_string(),
+            Status::Disconnected,
+            "VPN is disconnected".to_string(),
+        );
+        let json = formatter.render("wg0", &report);
+        assert_eq!(json["text"], "🔓");
+        assert_eq!(json["tooltip"], "VPN: wg0\nVPN is disconnected");
+
+        let formatter = Formatter {
+            note: Some("Managed externally".to_string()),
+            ..formatter
+        };
+        let json = formatter.render("wg0", &report);
+        assert_eq!(
+            json["tooltip"],
+            "VPN: wg0\nVPN is disconnected\nManaged externally"
+        );
+    }
+
+    #[cfg(feature = "daemon")]
+    #[test]
+    fn frames_i3bar_updates_in_an_array() {
+        let formatter = Formatter {
+            output_format: OutputFormat::I3bar,
+            ..Formatter::default()
+        };
+        let block = serde_json::json!({"full_text": "VPN: wg0"});
+        assert_eq!(
+            formatter.update_line(block.clone(), true),
+            r#"[{"full_text":"VPN: wg0"}]"#
+        );
+        assert_eq!(
+            formatter.update_line(block.clone(), false),
+            r#",[{"full_text":"VPN: wg0"}]"#
+        );
+        let formatter = Formatter {
+            output_format: OutputFormat::Eww,
+            ..formatter
+        };
+        assert_eq!(
+            formatter.update_line(block, false),
+            r#"{"full_text":"VPN: wg0"}"#
+        );
+    }
+
+    #[test]
+    fn escapes_waybar_tooltips_unless_markup_is_asked_for() {
+        let formatter = Formatter::default();
+        let report = StatusReport::error("Failed to resolve <vpn.example> & retry".to_string());
+        let json = formatter.render("wg0", &report);
+        assert_eq!(
+            json["tooltip"],
+            "Failed to resolve &lt;vpn.example&gt; &amp; retry"
+        );
+
+        let formatter = Formatter {
+            tooltip_markup: true,
+            ..formatter
+        };
+        let report = StatusReport {
+            tooltip: "VPN: wg0\n<peer> unreachable".to_string(),
+            ..report
+        };
+        assert_eq!(
+            formatter.render("wg0", &report)["tooltip"],
+            "<b>VPN: wg0</b>\n<span foreground=\"#eb6f92\">&lt;peer&gt; unreachable</span>"
+        );
+    }
+
+    #[test]
+    fn escapes_the_values_of_the_template_only() {
+        let formatter = Formatter {
+            template: Some("<b>{interface}</b> {text}".to_string()),
+            ..Formatter::default()
+        };
+        let report = StatusReport {
+            percentage: 100,
+            ..StatusReport::new("R&D".to_string(), Status::Connected, String::new())
+        };
+        assert_eq!(
+            formatter.text("wg<0>", &report),
+            "<b>wg&lt;0&gt;</b> R&amp;D"
+        );
+
+        let formatter = Formatter {
+            max_length: Some(8),
+            ..formatter
+        };
+        assert_eq!(formatter.text("wg<0>", &report), "<b>wg&lt;0&gt;</b> R…");
+        assert_eq!(truncate_markup("<i>abc</i>", 3), "<i>abc</i>");
+        assert_eq!(truncate_markup("<i>abcd</i>", 3), "<i>ab…</i>");
+        assert_eq!(truncate_markup("<i>abcd</i>", 0), "<i></i>");
+    }
+
+    #[test]
+    fn renders_peers_as_aligned_table() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let peers = [
+            PeerStats {
+                public_key: "AbCdEfGhIjKlMnOp=".to_string(),
+                endpoint: Some("198.51.100.1:51820".parse().unwrap()),
+                last_handshake: Some(now - Duration::from_secs(12)),
+                transfer: crate::state::Counters { rx: 2048, tx: 512 },
+            },
+            PeerStats {
+                public_key: "QrStUvWxYz012345=".to_string(),
+                endpoint: None,
+                last_handshake: None,
+                transfer: crate::state::Counters::default(),
+            },
+        ];
+        let markup = table_tooltip(
+            theme::palette(Theme::Default),
+            Status::Connected,
+            "VPN is connected\nVPN over wlan0",
+            &peers,
+            now,
+        );
+        let lines: Vec<&str> = markup.lines().collect();
+        assert_eq!(
+            lines[0],
+            "<span foreground=\"#9ccfd8\">●</span> <b>VPN is connected</b>"
+        );
+        assert_eq!(
+            lines[2],
+            "<span foreground=\"#9ccfd8\">●</span> AbCdEfGh… 198.51.100.1:51820          12 s    2.0 KiB      512 B"
+        );
+        assert!(lines[3].starts_with("<span foreground=\"#eb6f92\">●</span> QrStUvWx… -     "));
+        assert!(lines[3].ends_with("0 B</tt>"));
+        assert_eq!(lines[4], "VPN over wlan0");
+
+        // The peers come with the report, the formatter reads nothing itself.
+        let formatter = Formatter {
+            peer_table: true,
+            ..Formatter::default()
+        };
+        let report = StatusReport {
+            peers: peers.to_vec(),
+            ..StatusReport::new(
+                "VPN: wg0".to_string(),
+                Status::Connected,
+                "VPN is connected".to_string(),
+            )
+        };
+        let tooltip = formatter.render("wg0", &report)["tooltip"].to_string();
+        assert!(tooltip.contains("AbCdEfGh…") && tooltip.contains("QrStUvWx…"));
+    }
+}
@@ -0,0 +1,428 @@
+use crate::app_config::GatewayConfig;
+use crate::applied;
+use crate::cli::FirewallBackend;
+use crate::error;
+use crate::firewall;
+use crate::hotspot;
+use crate::sysctl;
+use crate::utils;
+use serde::{Deserialize, Serialize};
+
+const IPV4_FORWARD: &str = "net/ipv4/ip_forward";
+const IPV6_FORWARD: &str = "net/ipv6/conf/all/forwarding";
+
+fn table_name(interface_name: &str) -> String {
+    format!("wg-waybar-gw-{}", interface_name)
+}
+
+fn gateway_error(e: impl std::fmt::Display) -> error::Error {
+    error::Error::Gateway(e.to_string())
+}
+
+fn nft(args: &[&str], stdin: Option<&str>) -> Result<(), error::Error> {
+    utils::run_command("nft", args, stdin)
+        .map(|_| ())
+        .map_err(gateway_error)
+}
+
+fn is_ipv6(subnet: &str) -> bool {
+    subnet.contains(':')
+}
+
+/// Forward the LAN to the tunnel and the replies back, the LAN hiding behind
+/// the address of the tunnel so that the peers need no route to it.
+///
+/// Without `forward_rules`, only the masquerade: another firewall decides what is forwarded.
+fn ruleset(interface_name: &str, config: &GatewayConfig, forward_rules: bool) -> String {
+    let mut forward = String::new();
+    let mut masquerade = String::new();
+    for subnet in &config.lan {
+        let family = if is_ipv6(subnet) { "ip6" } else { "ip" };
+        if forward_rules {
+            forward.push_str(&format!(
+                "    oifname \"{interface}\" {family} saddr {subnet} accept\n    iifname \"{interface}\" {family} daddr {subnet} ct state established,related accept\n",
+                interface = interface_name,
+            ));
+        }
+        masquerade.push_str(&format!(
+            "    oifname \"{interface}\" {family} saddr {subnet} masquerade\n",
+            interface = interface_name,
+        ));
+    }
+    format!(
+        "table inet {table} {{\n  chain forward {{\n    type filter hook forward priority filter; policy accept;\n{forward}  }}\n  chain postrouting {{\n    type nat hook postrouting priority srcnat; policy accept;\n{masquerade}  }}\n}}\n",
+        table = table_name(interface_name),
+    )
+}
+
+/// One iptables rule: the program of its family, its table, chain and the rest.
+#[derive(Debug, PartialEq)]
+struct IptablesRule {
+    program: &'static str,
+    table: &'static str,
+    chain: &'static str,
+    spec: Vec<String>,
+}
+
+impl IptablesRule {
+    /// Arguments to insert (`-I`) or delete (`-D`) the rule.
+    fn args<'a>(&'a self, operation: &'a str) -> Vec<&'a str> {
+        let mut args = vec!["-t", self.table, operation, self.chain];
+        args.extend(self.spec.iter().map(String::as_str));
+        args
+    }
+}
+
+/// The rules of [`ruleset`] for iptables, commented with the table name.
+fn iptables_rules(interface_name: &str, config: &GatewayConfig) -> Vec<IptablesRule> {
+    let comment = table_name(interface_name);
+    let rule = |program, table, chain, matches: String, target: &str| IptablesRule {
+        program,
+        table,
+        chain,
+        spec: format!("{} -m comment --comment {} -j {}", matches, comment, target)
+            .split(' ')
+            .map(String::from)
+            .collect(),
+    };
+    config
+        .lan
+        .iter()
+        .flat_map(|subnet| {
+            let program = if is_ipv6(subnet) {
+                "ip6tables"
+            } else {
+                "iptables"
+            };
+            [
+                rule(
+                    program,
+                    "filter",
+                    "FORWARD",
+                    format!("-s {} -o {}", subnet, interface_name),
+                    "ACCEPT",
+                ),
+                rule(
+                    program,
+                    "filter",
+                    "FORWARD",
+                    format!(
+                        "-d {} -i {} -m conntrack --ctstate RELATED,ESTABLISHED",
+                        subnet, interface_name
+                    ),
+                    "ACCEPT",
+                ),
+                rule(
+                    program,
+                    "nat",
+                    "POSTROUTING",
+                    format!("-s {} -o {}", subnet, interface_name),
+                    "MASQUERADE",
+                ),
+            ]
+        })
+        .collect()
+}
+
+/// firewalld zone of the tunnels, kept apart from the LAN so that the peers get
+/// none of the services the LAN zone opens.
+const FIREWALLD_ZONE: &str = "wg-waybar";
+/// firewalld policy forwarding the LAN zone into [`FIREWALLD_ZONE`].
+const FIREWALLD_POLICY: &str = "wg-waybar-gateway";
+
+/// Zone of the LAN: the configured one, or firewalld's default zone.
+fn firewalld_zone(config: &GatewayConfig) -> Result<String, error::Error> {
+    match &config.zone {
+        Some(zone) => Ok(zone.clone()),
+        None => Ok(firewall::run("firewall-cmd", &["--get-default-zone"])?
+            .trim()
+            .to_string()),
+    }
+}
+
+/// Rich rules of the policy for `subnet`: forward it, masqueraded.
+fn rich_rules(subnet: &str) -> [String; 2] {
+    let family = if is_ipv6(subnet) { "ipv6" } else { "ipv4" };
+    ["masquerade", "accept"].map(|action| {
+        format!(
+            "rule family=\"{}\" source address=\"{}\" {}",
+            family, subnet, action
+        )
+    })
+}
+
+/// Create the zone and policy, which firewalld only allows permanently
+/// followed by a reload, so only the first time.
+fn ensure_firewalld_objects() -> Result<(), error::Error> {
+    let has = |list: &str, name: &str| -> Result<bool, error::Error> {
+        Ok(firewall::run("firewall-cmd", &[list])?
+            .split_whitespace()
+            .any(|item| item == name))
+    };
+    let mut created = false;
+    if !has("--get-zones", FIREWALLD_ZONE)? {
+        firewall::run(
+            "firewall-cmd",
+            &["--permanent", &format!("--new-zone={}", FIREWALLD_ZONE)],
+        )?;
+        created = true;
+    }
+    if !has("--get-policies", FIREWALLD_POLICY)? {
+        let policy = format!("--policy={}", FIREWALLD_POLICY);
+        firewall::run(
+            "firewall-cmd",
+            &["--permanent", &format!("--new-policy={}", FIREWALLD_POLICY)],
+        )?;
+        firewall::run(
+            "firewall-cmd",
+            &[
+                "--permanent",
+                &policy,
+                &format!("--add-egress-zone={}", FIREWALLD_ZONE),
+            ],
+        )?;
+        created = true;
+    }
+    if created {
+        firewall::run("firewall-cmd", &["--reload"])?;
+    }
+    Ok(())
+}
+
+/// Runtime-only changes, which a `firewall-cmd --reload` drops.
+fn apply_firewalld(
+    interface_name: &str,
+    config: &GatewayConfig,
+    lan_zone: &str,
+) -> Result<(), error::Error> {
+    ensure_firewalld_objects()?;
+    let policy = format!("--policy={}", FIREWALLD_POLICY);
+    firewall::run(
+        "firewall-cmd",
+        &[
+            &format!("--zone={}", FIREWALLD_ZONE),
+            &format!("--change-interface={}", interface_name),
+        ],
+    )?;
+    firewall::run(
+        "firewall-cmd",
+        &[&policy, &format!("--add-ingress-zone={}", lan_zone)],
+    )?;
+    for subnet in &config.lan {
+        for rule in rich_rules(subnet) {
+            firewall::run(
+                "firewall-cmd",
+                &[&policy, &format!("--add-rich-rule={}", rule)],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn teardown_firewalld(interface_name: &str, lan: &[String], lan_zone: &str) {
+    let policy = format!("--policy={}", FIREWALLD_POLICY);
+    for subnet in lan {
+        for rule in rich_rules(subnet) {
+            let _ = firewall::run(
+                "firewall-cmd",
+                &[&policy, &format!("--remove-rich-rule={}", rule)],
+            );
+        }
+    }
+    let zone = format!("--zone={}", FIREWALLD_ZONE);
+    let _ = firewall::run(
+        "firewall-cmd",
+        &[&zone, &format!("--remove-interface={}", interface_name)],
+    );
+    // Still forwarded to the gateway of another tunnel.
+    let others = firewall::run("firewall-cmd", &[&zone, "--list-interfaces"]);
+    if others.is_ok_and(|others| others.trim().is_empty()) {
+        let _ = firewall::run(
+            "firewall-cmd",
+            &[&policy, &format!("--remove-ingress-zone={}", lan_zone)],
+        );
+    }
+}
+
+/// What [`apply`] set up, for [`teardown`] to remove the same.
+#[derive(Serialize, Deserialize)]
+struct Applied {
+    backend: FirewallBackend,
+    /// Subnets forwarded, the hotspot's included.
+    lan: Vec<String>,
+    /// firewalld zone of the LAN.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lan_zone: Option<String>,
+}
+
+/// `ufw route allow` (or `route delete allow`) for the traffic of `subnet` to the tunnel.
+fn ufw_route(operation: &[&'static str], interface_name: &str, subnet: &str) -> Vec<String> {
+    operation
+        .iter()
+        .copied()
+        .chain(["from", subnet, "out", "on", interface_name])
+        .map(String::from)
+        .collect()
+}
+
+/// `config` with the subnet of the hotspot added to the LAN when it asks for it.
+///
+/// The subnet found is kept in [`Applied`] with the others, so that teardown
+/// removes its rules even once the hotspot stopped.
+fn with_hotspot(config: &GatewayConfig) -> GatewayConfig {
+    let mut config = config.clone();
+    if config.hotspot
+        && let Some(hotspot) = hotspot::detect()
+    {
+        let subnet = hotspot.subnet();
+        if !config.lan.contains(&subnet) {
+            config.lan.push(subnet);
+        }
+    }
+    config
+}
+
+/// Forwarding switches the LAN subnets need, by family.
+fn forward_sysctls(config: &GatewayConfig) -> Vec<&'static str> {
+    let mut sysctls = Vec::new();
+    if config.lan.iter().any(|subnet| !is_ipv6(subnet)) {
+        sysctls.push(IPV4_FORWARD);
+    }
+    if config.lan.iter().any(|subnet| is_ipv6(subnet)) {
+        sysctls.push(IPV6_FORWARD);
+    }
+    sysctls
+}
+
+/// Let the machine route the configured LAN through the tunnel: enable IP
+/// forwarding and masquerade the LAN behind the tunnel address, through the
+/// firewall that owns the ruleset since a separate table cannot accept what
+/// another one drops.
+///
+/// Forwarding is put back by [`sysctl::restore`] once the tunnel is down.
+pub fn apply(
+    interface_name: &str,
+    config: &GatewayConfig,
+    backend: FirewallBackend,
+    debug: bool,
+) -> Result<(), error::Error> {
+    let config = &with_hotspot(config);
+    if config.lan.is_empty() {
+        return Err(gateway_error(format!(
+            "[gateway.{}] has no lan subnet and no hotspot is up",
+            interface_name
+        )));
+    }
+    for key in forward_sysctls(config) {
+        sysctl::set(interface_name, key, "1", debug)?;
+    }
+    let backend = firewall::resolve(backend);
+    let lan_zone = match backend {
+        FirewallBackend::Firewalld => Some(firewalld_zone(config)?),
+        _ => None,
+    };
+    // Saved first, so that what a failure leaves behind is removed too.
+    applied::save(
+        "gateway",
+        interface_name,
+        &Applied {
+            backend,
+            lan: config.lan.clone(),
+            lan_zone: lan_zone.clone(),
+        },
+    )?;
+    match backend {
+        FirewallBackend::Firewalld => apply_firewalld(
+            interface_name,
+            config,
+            lan_zone.as_deref().unwrap_or_default(),
+        ),
+        FirewallBackend::Ufw => {
+            // ufw cannot masquerade from the command line, only its forward policy is in the way.
+            nft(&["-f", "-"], Some(&ruleset(interface_name, config, false)))?;
+            for subnet in &config.lan {
+                let mut args = ufw_route(&["route", "allow"], interface_name, subnet);
+                args.extend(["comment".to_string(), table_name(interface_name)]);
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                firewall::run("ufw", &args)?;
+            }
+            Ok(())
+        }
+        FirewallBackend::Iptables => {
+            for rule in iptables_rules(interface_name, config) {
+                firewall::run(rule.program, &rule.args("-I"))?;
+            }
+            Ok(())
+        }
+        FirewallBackend::Nftables | FirewallBackend::Auto => {
+            nft(&["-f", "-"], Some(&ruleset(interface_name, config, true)))
+        }
+    }
+}
+
+/// Remove what [`apply`] set up, through the firewall it went through.
+pub fn teardown(interface_name: &str) {
+    let Some(applied) = applied::take::<Applied>("gateway", interface_name) else {
+        return;
+    };
+    let lan = &applied.lan;
+    match applied.backend {
+        FirewallBackend::Firewalld => {
+            if let Some(lan_zone) = &applied.lan_zone {
+                teardown_firewalld(interface_name, lan, lan_zone);
+            }
+        }
+        FirewallBackend::Ufw => {
+            for subnet in lan {
+                let args = ufw_route(&["route", "delete", "allow"], interface_name, subnet);
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                let _ = firewall::run("ufw", &args);
+            }
+        }
+        FirewallBackend::Iptables => {
+            let config = GatewayConfig {
+                lan: lan.clone(),
+                hotspot: false,
+                zone: None,
+            };
+            for rule in iptables_rules(interface_name, &config) {
+                let _ = firewall::run(rule.program, &rule.args("-D"));
+            }
+        }
+        FirewallBackend::Nftables | FirewallBackend::Auto => {}
+    }
+    let _ = nft(
+        &["delete", "table", "inet", &table_name(interface_name)],
+        None,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masquerades_lan_by_family() {
+        let config = GatewayConfig {
+            lan: vec!["192.168.50.0/24".to_string(), "fd00:50::/64".to_string()],
+            zone: None,
+            hotspot: false,
+        };
+        let rules = ruleset("wg0", &config, true);
+        assert!(rules.contains("table inet wg-waybar-gw-wg0 {"));
+        assert!(rules.contains("    oifname \"wg0\" ip saddr 192.168.50.0/24 masquerade\n"));
+        assert!(rules.contains(
+            "    iifname \"wg0\" ip6 daddr fd00:50::/64 ct state established,related accept\n"
+        ));
+        assert!(!ruleset("wg0", &config, false).contains("accept\n"));
+        assert_eq!(forward_sysctls(&config), [IPV4_FORWARD, IPV6_FORWARD]);
+
+        let rules = iptables_rules("wg0", &config);
+        assert_eq!(rules.len(), 6);
+        assert_eq!(
+            rules[2].args("-I").join(" "),
+            "-t nat -I POSTROUTING -s 192.168.50.0/24 -o wg0 -m comment --comment wg-waybar-gw-wg0 -j MASQUERADE"
+        );
+        assert_eq!(rules[3].program, "ip6tables");
+    }
+}
@@ -0,0 +1,252 @@
+use blake2::digest::{FixedOutput, KeyInit, Update};
+use blake2::{Blake2s256, Blake2sMac, Digest, digest::consts::U16};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key};
+use std::io::{ErrorKind, Read};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const CONSTRUCTION: &[u8] = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+const IDENTIFIER: &[u8] = b"WireGuard v1 zx2c4 Jason@zx2c4.com";
+const LABEL_MAC1: &[u8] = b"mac1----";
+
+const HANDSHAKE_INITIATION: u8 = 1;
+const HANDSHAKE_RESPONSE: u8 = 2;
+/// Sent instead of a response by a peer under load, as good a sign of life.
+const COOKIE_REPLY: u8 = 3;
+
+/// Length of a handshake initiation, and where its MACs start.
+const INITIATION_LEN: usize = 148;
+const MAC1_OFFSET: usize = 116;
+
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Blake2s256::new();
+    for part in parts {
+        Digest::update(&mut hasher, part);
+    }
+    hasher.finalize().into()
+}
+
+/// HMAC-BLAKE2s, as the key derivation of the Noise protocol uses it.
+fn hmac(key: &[u8; 32], parts: &[&[u8]]) -> [u8; 32] {
+    let mut ipad = [0x36u8; 64];
+    let mut opad = [0x5cu8; 64];
+    for (i, byte) in key.iter().enumerate() {
+        ipad[i] ^= byte;
+        opad[i] ^= byte;
+    }
+    let mut inner = vec![&ipad[..]];
+    inner.extend_from_slice(parts);
+    hash(&[&opad, &hash(&inner)])
+}
+
+/// The first two keys derived from the chaining key and `input`.
+fn kdf2(chaining_key: &[u8; 32], input: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let secret = hmac(chaining_key, &[input]);
+    let first = hmac(&secret, &[&[1]]);
+    let second = hmac(&secret, &[&first, &[2]]);
+    (first, second)
+}
+
+fn seal(key: &[u8; 32], plaintext: &[u8], associated_data: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(
+            &[0u8; 12].into(),
+            Payload {
+                msg: plaintext,
+                aad: associated_data,
+            },
+        )
+        .expect("a handshake field fits in one message")
+}
+
+fn mac(key: &[u8; 32], message: &[u8]) -> [u8; 16] {
+    let mut mac = <Blake2sMac<U16> as KeyInit>::new_from_slice(key).expect("a 32-byte key");
+    Update::update(&mut mac, message);
+    mac.finalize_fixed().into()
+}
+
+/// TAI64N label of `time`, which a peer requires to grow from one handshake to the next.
+fn tai64n(time: SystemTime) -> [u8; 12] {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut label = [0u8; 12];
+    label[..8].copy_from_slice(&(0x4000_0000_0000_000a + since_epoch.as_secs()).to_be_bytes());
+    label[8..].copy_from_slice(&since_epoch.subsec_nanos().to_be_bytes());
+    label
+}
+
+/// The first message of a WireGuard handshake from `private_key` to `peer`,
+/// sent by `sender`.
+fn initiation(
+    private_key: &StaticSecret,
+    peer: &PublicKey,
+    ephemeral: &StaticSecret,
+    sender: u32,
+    time: SystemTime,
+) -> [u8; INITIATION_LEN] {
+    let chaining_key = hash(&[CONSTRUCTION]);
+    let h = hash(&[&hash(&[&chaining_key, IDENTIFIER]), peer.as_bytes()]);
+
+    let ephemeral_public = PublicKey::from(ephemeral);
+    let (chaining_key, _) = kdf2(&chaining_key, ephemeral_public.as_bytes());
+    let h = hash(&[&h, ephemeral_public.as_bytes()]);
+
+    let (chaining_key, key) = kdf2(&chaining_key, ephemeral.diffie_hellman(peer).as_bytes());
+    let encrypted_static = seal(&key, PublicKey::from(private_key).as_bytes(), &h);
+    let h = hash(&[&h, &encrypted_static]);
+
+    let (_, key) = kdf2(&chaining_key, private_key.diffie_hellman(peer).as_bytes());
+    let encrypted_timestamp = seal(&key, &tai64n(time), &h);
+
+    let mut message = [0u8; INITIATION_LEN];
+    message[0] = HANDSHAKE_INITIATION;
+    message[4..8].copy_from_slice(&sender.to_le_bytes());
+    message[8..40].copy_from_slice(ephemeral_public.as_bytes());
+    message[40..88].copy_from_slice(&encrypted_static);
+    message[88..116].copy_from_slice(&encrypted_timestamp);
+    let mac1 = mac(
+        &hash(&[LABEL_MAC1, peer.as_bytes()]),
+        &message[..MAC1_OFFSET],
+    );
+    message[MAC1_OFFSET..MAC1_OFFSET + 16].copy_from_slice(&mac1);
+    message
+}
+
+/// Whether `reply` answers the initiation sent by `sender`.
+fn answers(reply: &[u8], sender: u32) -> bool {
+    reply.len() >= 12
+        && matches!(reply[0], HANDSHAKE_RESPONSE | COOKIE_REPLY)
+        && match reply[0] {
+            // Sender of the response, then the receiver it answers.
+            HANDSHAKE_RESPONSE => reply[8..12] == sender.to_le_bytes(),
+            _ => reply[4..8] == sender.to_le_bytes(),
+        }
+}
+
+/// Round-trip time of a handshake with `peer` at `endpoint`, as `private_key`.
+///
+/// Unlike a ping, only a WireGuard server that knows the key answers, and
+/// through the same port and firewalls the tunnel would go.
+pub fn probe(
+    private_key: &StaticSecret,
+    peer: &PublicKey,
+    endpoint: SocketAddr,
+    timeout: Duration,
+) -> Result<Duration, String> {
+    let bind: SocketAddr = match endpoint {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let socket = UdpSocket::bind(bind).map_err(|e| e.to_string())?;
+    socket.connect(endpoint).map_err(|e| e.to_string())?;
+    let mut random = [0u8; 36];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut random))
+        .map_err(|e| e.to_string())?;
+    let sender = u32::from_le_bytes([random[0], random[1], random[2], random[3]]);
+    let ephemeral = StaticSecret::from(<[u8; 32]>::try_from(&random[4..]).unwrap());
+    let message = initiation(private_key, peer, &ephemeral, sender, SystemTime::now());
+
+    let start = Instant::now();
+    socket.send(&message).map_err(|e| e.to_string())?;
+    let mut reply = [0u8; 128];
+    loop {
+        let left = timeout.saturating_sub(start.elapsed());
+        if left.is_zero() {
+            return Err("no handshake response".to_string());
+        }
+        socket
+            .set_read_timeout(Some(left))
+            .map_err(|e| e.to_string())?;
+        match socket.recv(&mut reply) {
+            Ok(len) if answers(&reply[..len], sender) => return Ok(start.elapsed()),
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                return Err("nothing listens on this port".to_string());
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err("no handshake response".to_string());
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(key: &[u8; 32], ciphertext: &[u8], associated_data: &[u8]) -> Vec<u8> {
+        ChaCha20Poly1305::new(Key::from_slice(key))
+            .decrypt(
+                &[0u8; 12].into(),
+                Payload {
+                    msg: ciphertext,
+                    aad: associated_data,
+                },
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn builds_an_initiation_the_responder_accepts() {
+        assert_eq!(
+            hash(&[CONSTRUCTION])[..4],
+            [96, 226, 109, 174],
+            "initial chaining key of WireGuard"
+        );
+        let initiator = StaticSecret::from([1u8; 32]);
+        let responder = StaticSecret::from([2u8; 32]);
+        let ephemeral = StaticSecret::from([3u8; 32]);
+        let time = UNIX_EPOCH + Duration::new(1_700_000_000, 5);
+        let message = initiation(
+            &initiator,
+            &PublicKey::from(&responder),
+            &ephemeral,
+            7,
+            time,
+        );
+        assert_eq!(message[0], HANDSHAKE_INITIATION);
+        assert_eq!(message[4..8], 7u32.to_le_bytes());
+
+        // What the responder does with it, from its own keys.
+        let responder_public = PublicKey::from(&responder);
+        let mac1 = mac(
+            &hash(&[LABEL_MAC1, responder_public.as_bytes()]),
+            &message[..MAC1_OFFSET],
+        );
+        assert_eq!(message[MAC1_OFFSET..MAC1_OFFSET + 16], mac1);
+        let chaining_key = hash(&[CONSTRUCTION]);
+        let h = hash(&[
+            &hash(&[&chaining_key, IDENTIFIER]),
+            responder_public.as_bytes(),
+        ]);
+        let ephemeral_public: [u8; 32] = message[8..40].try_into().unwrap();
+        let (chaining_key, _) = kdf2(&chaining_key, &ephemeral_public);
+        let h = hash(&[&h, &ephemeral_public]);
+        let shared = responder.diffie_hellman(&PublicKey::from(ephemeral_public));
+        let (chaining_key, key) = kdf2(&chaining_key, shared.as_bytes());
+        let initiator_public: [u8; 32] = open(&key, &message[40..88], &h).try_into().unwrap();
+        assert_eq!(initiator_public, *PublicKey::from(&initiator).as_bytes());
+        let h = hash(&[&h, &message[40..88]]);
+        let shared = responder.diffie_hellman(&PublicKey::from(initiator_public));
+        let (_, key) = kdf2(&chaining_key, shared.as_bytes());
+        assert_eq!(open(&key, &message[88..116], &h), tai64n(time));
+    }
+
+    #[test]
+    fn takes_only_replies_to_its_initiation() {
+        let mut response = [0u8; 92];
+        response[0] = HANDSHAKE_RESPONSE;
+        response[4..8].copy_from_slice(&9u32.to_le_bytes());
+        response[8..12].copy_from_slice(&7u32.to_le_bytes());
+        assert!(answers(&response, 7));
+        assert!(!answers(&response, 9));
+        let mut cookie = [0u8; 64];
+        cookie[0] = COOKIE_REPLY;
+        cookie[4..8].copy_from_slice(&7u32.to_le_bytes());
+        assert!(answers(&cookie, 7));
+        assert!(!answers(&[4, 0, 0, 0], 7));
+    }
+}
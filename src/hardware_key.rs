@@ -0,0 +1,260 @@
+use crate::error;
+#[cfg(feature = "hardware-keys")]
+use base64::prelude::*;
+#[cfg(feature = "hardware-keys")]
+use std::io::Write;
+#[cfg(feature = "hardware-keys")]
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+#[cfg(feature = "hardware-keys")]
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Relying party of the FIDO2 credentials keys are derived from.
+#[cfg(feature = "hardware-keys")]
+const FIDO2_RP: &str = "wg-waybar";
+/// Salt of the hmac-secret, the secret itself being unique to the credential.
+#[cfg(feature = "hardware-keys")]
+const FIDO2_SALT: &[u8; 32] = b"wg-waybar private key derivation";
+
+/// Where the private key of a profile comes from instead of its `PrivateKey`,
+/// after `# PrivateKeyFrom =` in its `[Interface]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    /// `tpm2:0x81000001`, an object sealed in the TPM, unsealed with `tpm2_unseal`.
+    Tpm2 { handle: String },
+    /// `fido2:/dev/hidraw0:<credential id>`, the hmac-secret of a credential
+    /// of the `wg-waybar` relying party, asserted with `fido2-assert`.
+    Fido2 { device: String, credential: String },
+}
+
+impl FromStr for KeySource {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || error::Error::InvalidFormat {
+            message: format!(
+                "Invalid PrivateKeyFrom: {}, expected tpm2:<handle> or fido2:<device>:<credential id>",
+                s
+            ),
+        };
+        match s.trim().split_once(':').ok_or_else(invalid)? {
+            ("tpm2", handle) if !handle.is_empty() => Ok(KeySource::Tpm2 {
+                handle: handle.to_string(),
+            }),
+            ("fido2", rest) => {
+                let (device, credential) = rest.rsplit_once(':').ok_or_else(invalid)?;
+                if device.is_empty() || credential.is_empty() {
+                    return Err(invalid());
+                }
+                Ok(KeySource::Fido2 {
+                    device: device.to_string(),
+                    credential: credential.to_string(),
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl std::fmt::Display for KeySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeySource::Tpm2 { handle } => write!(f, "tpm2:{}", handle),
+            KeySource::Fido2 { device, credential } => {
+                write!(f, "fido2:{}:{}", device, credential)
+            }
+        }
+    }
+}
+
+/// Output of a hardware tool, or why it could not give it.
+#[cfg(feature = "hardware-keys")]
+fn run(mut cmd: Command, input: Option<&str>) -> Result<Vec<u8>, error::Error> {
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    cmd.stdin(if input.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    })
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped());
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| error::Error::HardwareKey(format!("cannot run {}: {}", program, e)))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(error::Error::HardwareKey(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output.stdout)
+}
+
+/// The key as `wg genkey` writes it, from the 32 bytes or base64 text unsealed.
+#[cfg(feature = "hardware-keys")]
+fn encode_key(mut secret: Vec<u8>) -> Result<String, error::Error> {
+    if secret.len() != 32 {
+        let text = String::from_utf8_lossy(&secret).trim().to_string();
+        secret = BASE64_STANDARD.decode(text)?;
+    }
+    let mut secret: [u8; 32] = secret
+        .try_into()
+        .map_err(|_| error::Error::HardwareKey("the unsealed key is not 32 bytes".to_string()))?;
+    secret[0] &= 248;
+    secret[31] = (secret[31] & 127) | 64;
+    Ok(BASE64_STANDARD.encode(secret))
+}
+
+/// The hmac-secret in the output of `fido2-assert -G -h`, its last line.
+#[cfg(feature = "hardware-keys")]
+fn hmac_secret(output: &[u8]) -> Result<Vec<u8>, error::Error> {
+    let output = String::from_utf8_lossy(output);
+    let line = output.lines().last().unwrap_or_default();
+    Ok(BASE64_STANDARD.decode(line.trim())?)
+}
+
+/// The credential id in the output of `fido2-cred -M`, its fifth line.
+#[cfg(feature = "hardware-keys")]
+fn credential_id(output: &[u8]) -> Result<String, error::Error> {
+    let output = String::from_utf8_lossy(output);
+    output
+        .lines()
+        .nth(4)
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .ok_or_else(|| error::Error::HardwareKey("fido2-cred gave no credential id".to_string()))
+}
+
+/// Make a credential of the `wg-waybar` relying party with the hmac-secret
+/// extension on the security key at `device`, asking for a touch.
+#[cfg(feature = "hardware-keys")]
+fn make_credential(device: &str) -> Result<KeySource, error::Error> {
+    // Client data hash, relying party, user name and user id, one per line.
+    let input = format!(
+        "{}\n{}\n{}\n{}\n",
+        BASE64_STANDARD.encode([0u8; 32]),
+        FIDO2_RP,
+        FIDO2_RP,
+        BASE64_STANDARD.encode(FIDO2_RP)
+    );
+    let mut cmd = Command::new("fido2-cred");
+    cmd.args(["-M", "-h", "-i", "-"]).arg(device);
+    eprintln!("Touch the security key at {} to enroll it", device);
+    Ok(KeySource::Fido2 {
+        device: device.to_string(),
+        credential: credential_id(&run(cmd, Some(&input))?)?,
+    })
+}
+
+/// The source of `source`, making the FIDO2 credential when only a device is
+/// given, and the public key of the private key it gives.
+#[cfg(feature = "hardware-keys")]
+pub fn enroll(source: &str) -> Result<(String, String), error::Error> {
+    let source = match source.trim().strip_prefix("fido2:") {
+        Some(device) if !device.is_empty() && !device.contains(':') => make_credential(device)?,
+        _ => source.parse()?,
+    };
+    let secret: [u8; 32] = BASE64_STANDARD
+        .decode(private_key(&source)?)?
+        .try_into()
+        .map_err(|_| error::Error::HardwareKey("the key is not 32 bytes".to_string()))?;
+    let public_key = PublicKey::from(&StaticSecret::from(secret));
+    Ok((
+        source.to_string(),
+        BASE64_STANDARD.encode(public_key.as_bytes()),
+    ))
+}
+
+/// Unseal or derive the private key, in base64.
+///
+/// There is no fallback to a key in a file: without the device, or in a build
+/// without the `hardware-keys` feature, the profile does not come up.
+#[cfg(feature = "hardware-keys")]
+pub fn private_key(source: &KeySource) -> Result<String, error::Error> {
+    match source {
+        KeySource::Tpm2 { handle } => {
+            let mut cmd = Command::new("tpm2_unseal");
+            cmd.arg("--object-context").arg(handle);
+            encode_key(run(cmd, None)?)
+        }
+        KeySource::Fido2 { device, credential } => {
+            // Client data hash, relying party, credential and salt, one per line.
+            let input = format!(
+                "{}\n{}\n{}\n{}\n",
+                BASE64_STANDARD.encode([0u8; 32]),
+                FIDO2_RP,
+                credential,
+                BASE64_STANDARD.encode(FIDO2_SALT)
+            );
+            let mut cmd = Command::new("fido2-assert");
+            cmd.args(["-G", "-h", "-i", "-"]).arg(device);
+            eprintln!("Touch the security key at {} to unlock the profile", device);
+            encode_key(hmac_secret(&run(cmd, Some(&input))?)?)
+        }
+    }
+}
+
+#[cfg(not(feature = "hardware-keys"))]
+pub fn private_key(source: &KeySource) -> Result<String, error::Error> {
+    Err(error::Error::HardwareKey(format!(
+        "the key comes from {:?} but wg-waybar was built without the hardware-keys feature",
+        source
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_key_sources() {
+        assert_eq!(
+            "tpm2:0x81000001".parse::<KeySource>().unwrap(),
+            KeySource::Tpm2 {
+                handle: "0x81000001".to_string()
+            }
+        );
+        assert_eq!(
+            "fido2:/dev/hidraw0:Y3JlZA==".parse::<KeySource>().unwrap(),
+            KeySource::Fido2 {
+                device: "/dev/hidraw0".to_string(),
+                credential: "Y3JlZA==".to_string()
+            }
+        );
+        assert_eq!(
+            "fido2:/dev/hidraw0:Y3JlZA=="
+                .parse::<KeySource>()
+                .unwrap()
+                .to_string(),
+            "fido2:/dev/hidraw0:Y3JlZA=="
+        );
+        assert!("tpm2:".parse::<KeySource>().is_err());
+        assert!("yubikey:1".parse::<KeySource>().is_err());
+    }
+
+    #[cfg(feature = "hardware-keys")]
+    #[test]
+    fn derives_a_wireguard_key_from_the_hmac_secret() {
+        let output = format!(
+            "hash\nwg-waybar\nauthdata\nsig\n{}\n",
+            BASE64_STANDARD.encode([255u8; 32])
+        );
+        let key = encode_key(hmac_secret(output.as_bytes()).unwrap()).unwrap();
+        let bytes = BASE64_STANDARD.decode(key).unwrap();
+        assert_eq!((bytes[0], bytes[31]), (248, 127));
+        assert!(encode_key(b"too short".to_vec()).is_err());
+    }
+
+    #[cfg(feature = "hardware-keys")]
+    #[test]
+    fn reads_the_credential_id_of_a_new_credential() {
+        let output = "hash\nwg-waybar\npacked\nauthdata\nY3JlZA==\nsig\ncert\n";
+        assert_eq!(credential_id(output.as_bytes()).unwrap(), "Y3JlZA==");
+        assert!(credential_id(b"hash\nwg-waybar\n").is_err());
+    }
+}
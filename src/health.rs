@@ -0,0 +1,120 @@
+use crate::config;
+use crate::error;
+#[cfg(feature = "http")]
+use crate::http;
+#[cfg(feature = "http")]
+use crate::netns;
+use std::time::{Duration, Instant, SystemTime};
+
+const POLL: Duration = Duration::from_millis(250);
+
+/// Handshakes older than this no longer carry traffic (WireGuard's `REJECT_AFTER_TIME`).
+const HANDSHAKE_MAX_AGE: Duration = Duration::from_secs(180);
+
+/// Handshakes are renewed this often while traffic flows (WireGuard's `REKEY_AFTER_TIME`).
+const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+
+/// Link quality in percent from the age of the last handshake.
+///
+/// Full up to the rekey interval, then falling linearly to 0 once the session
+/// can no longer carry traffic. No handshake at all is 0.
+pub fn quality(age: Option<Duration>) -> u8 {
+    let Some(age) = age else {
+        return 0;
+    };
+    if age <= REKEY_AFTER_TIME {
+        return 100;
+    }
+    let left = HANDSHAKE_MAX_AGE.saturating_sub(age).as_secs_f64();
+    let window = (HANDSHAKE_MAX_AGE - REKEY_AFTER_TIME).as_secs_f64();
+    (left / window * 100.0).round() as u8
+}
+
+/// Age of the most recent handshake of the interface.
+pub fn handshake_age(interface_name: &str, netns: Option<&str>) -> Option<Duration> {
+    let time = config::last_handshake(interface_name, netns).ok()??;
+    Some(SystemTime::now().duration_since(time).unwrap_or_default())
+}
+
+/// Request `url` through the tunnel, from inside the namespace if any.
+///
+/// Any HTTP response counts as success, only transport failures are errors.
+#[cfg(feature = "http")]
+pub fn request(url: &str, netns: Option<&str>, timeout: Duration) -> Result<(), String> {
+    let request = || {
+        let agent: ureq::Agent = http::config(timeout)
+            .http_status_as_error(false)
+            .build()
+            .into();
+        agent.get(url).call().map(|_| ())
+    };
+    let result = match netns {
+        Some(name) => netns::with(name, request).map_err(|e| e.to_string())?,
+        None => request(),
+    };
+    result.map_err(|e| format!("Request to {} failed: {}", url, e))
+}
+
+#[cfg(not(feature = "http"))]
+pub fn request(url: &str, _netns: Option<&str>, _timeout: Duration) -> Result<(), String> {
+    Err(format!(
+        "Cannot request {}: wg-waybar was built without the http feature",
+        url
+    ))
+}
+
+fn has_recent_handshake(interface_name: &str, netns: Option<&str>) -> bool {
+    handshake_age(interface_name, netns).is_some_and(|age| age < HANDSHAKE_MAX_AGE)
+}
+
+/// Block until the tunnel has a recent handshake and, if given, `url` answers.
+pub fn wait_online(
+    interface_name: &str,
+    netns: Option<&str>,
+    url: Option<&str>,
+    timeout: Duration,
+    debug: bool,
+) -> Result<(), error::Error> {
+    let start = Instant::now();
+    let mut handshake = false;
+    while start.elapsed() < timeout {
+        handshake = handshake || has_recent_handshake(interface_name, netns);
+        if handshake {
+            let remaining = timeout.saturating_sub(start.elapsed());
+            match url.map(|url| request(url, netns, remaining.min(Duration::from_secs(5)))) {
+                None | Some(Ok(())) => return Ok(()),
+                Some(Err(e)) => {
+                    if debug {
+                        println!("{}", e);
+                    }
+                }
+            }
+        }
+        std::thread::sleep(POLL);
+    }
+    let reason = if handshake {
+        format!("{} did not respond", url.unwrap_or_default())
+    } else {
+        "no recent handshake".to_string()
+    };
+    Err(error::Error::Timeout(format!(
+        "{} is not online after {} s: {}",
+        interface_name,
+        timeout.as_secs(),
+        reason
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_handshake_age_to_quality() {
+        assert_eq!(quality(None), 0);
+        assert_eq!(quality(Some(Duration::from_secs(5))), 100);
+        assert_eq!(quality(Some(REKEY_AFTER_TIME)), 100);
+        assert_eq!(quality(Some(Duration::from_secs(150))), 50);
+        assert_eq!(quality(Some(Duration::from_secs(600))), 0);
+    }
+}
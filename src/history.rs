@@ -0,0 +1,66 @@
+use crate::error;
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// Throughput measured through a tunnel by `speedtest`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpeedTest {
+    /// Unix timestamp of the measurement.
+    pub timestamp: u64,
+    pub interface: String,
+    /// Peer endpoints at the time, identifying the exit node.
+    pub endpoints: Vec<String>,
+    /// Bits per second, `None` if the transfer failed.
+    pub download_bps: Option<f64>,
+    pub upload_bps: Option<f64>,
+}
+
+/// One line of the usage history file.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Entry {
+    SpeedTest(SpeedTest),
+}
+
+/// Append an entry to the history, a file of JSON lines.
+pub fn append(path: &Path, entry: &Entry) -> Result<(), error::Error> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    utils::fs_append(path.to_path_buf(), line)
+}
+
+/// Entries of the history, oldest first. Lines that cannot be parsed are skipped.
+pub fn load(path: &Path) -> Result<Vec<Entry>, error::Error> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let file = std::fs::File::open(path)?;
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        if let Ok(entry) = serde_json::from_str(&line?) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_test_entries_are_tagged_by_kind() {
+        let entry = Entry::SpeedTest(SpeedTest {
+            timestamp: 1,
+            interface: "wg0".into(),
+            endpoints: vec!["198.51.100.1:51820".into()],
+            download_bps: Some(8e6),
+            upload_bps: None,
+        });
+        let line = serde_json::to_string(&entry).unwrap();
+        assert!(line.starts_with(r#"{"kind":"speedtest","#));
+        assert_eq!(serde_json::from_str::<Entry>(&line).unwrap(), entry);
+    }
+}
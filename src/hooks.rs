@@ -0,0 +1,201 @@
+use crate::state;
+use crate::utils;
+use serde_json::json;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use uzers::os::unix::UserExt;
+
+/// Run `cmd` as the invoking user when running through sudo, with the
+/// variables needed to reach their session bus (notifications, mounts, ...).
+pub fn as_invoking_user(cmd: &mut Command) {
+    if let Some(user) =
+        utils::get_environ("SUDO_USER").and_then(|name| uzers::get_user_by_name(&name))
+    {
+        let runtime_dir = format!("/run/user/{}", user.uid());
+        cmd.uid(user.uid())
+            .gid(user.primary_group_id())
+            .env("HOME", user.home_dir())
+            .env("USER", user.name())
+            .env(
+                "DBUS_SESSION_BUS_ADDRESS",
+                format!("unix:path={}/bus", runtime_dir),
+            )
+            .env("XDG_RUNTIME_DIR", runtime_dir);
+    }
+}
+
+/// Start `cmd` and hand it to `feed`, e.g. to write its stdin, without waiting
+/// for it to exit.
+///
+/// It is reaped once it exits, not left a zombie of the daemon; one that
+/// outlives a short-lived command is reaped by init instead.
+fn spawn_reaped(
+    cmd: &mut Command,
+    feed: impl FnOnce(&mut Child) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let mut child = cmd.spawn()?;
+    let fed = feed(&mut child);
+    std::thread::spawn(move || child.wait());
+    fed
+}
+
+/// Start `command` through `sh -c` without waiting for it.
+pub fn run(command: &str, interface_name: &str, error: Option<&str>, debug: bool) {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .env("WG_WAYBAR_INTERFACE", interface_name)
+        .stdin(Stdio::null());
+    if let Some(error) = error {
+        cmd.env("WG_WAYBAR_ERROR", error);
+    }
+    as_invoking_user(&mut cmd);
+    if let Err(e) = spawn_reaped(&mut cmd, |_| Ok(()))
+        && debug
+    {
+        println!("Failed to run hook `{}`: {}", command, e);
+    }
+}
+
+/// Show a desktop notification through `notify-send`, without waiting for it.
+pub fn notify(summary: &str, body: &str, debug: bool) {
+    send_notification(summary, body, "normal", debug);
+}
+
+/// Like [`notify`], for problems that keep coming back and need the user.
+pub fn notify_critical(summary: &str, body: &str, debug: bool) {
+    send_notification(summary, body, "critical", debug);
+}
+
+fn send_notification(summary: &str, body: &str, urgency: &str, debug: bool) {
+    if !cfg!(feature = "notifications") {
+        return;
+    }
+    let mut cmd = Command::new("notify-send");
+    cmd.args([
+        "--app-name",
+        "wg-waybar",
+        "--urgency",
+        urgency,
+        summary,
+        body,
+    ])
+    .stdin(Stdio::null());
+    as_invoking_user(&mut cmd);
+    if let Err(e) = spawn_reaped(&mut cmd, |_| Ok(()))
+        && debug
+    {
+        println!("Failed to send notification: {}", e);
+    }
+}
+
+/// Executables of `~/.config/wg-waybar/hooks.d`, sorted by name; hidden files are skipped.
+fn drop_ins() -> Vec<PathBuf> {
+    let Ok(dir) = utils::get_config_home("wg-waybar").map(|dir| dir.join("hooks.d")) else {
+        return Vec::new();
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut hooks: Vec<PathBuf> = entries
+        .flatten()
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .filter(|entry| {
+            entry
+                .metadata()
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        })
+        .map(|entry| entry.path())
+        .collect();
+    hooks.sort();
+    hooks
+}
+
+/// One JSON line: `details` with the `event` name, and `interface` and
+/// `timestamp` unless already given.
+fn message(event: &str, interface_name: &str, details: serde_json::Value) -> String {
+    let mut message = json!({
+        "event": event,
+        "interface": interface_name,
+        "timestamp": state::now(),
+    });
+    if let (Some(message), serde_json::Value::Object(details)) = (message.as_object_mut(), details)
+    {
+        message.extend(details);
+        message.insert("event".to_string(), event.into());
+    }
+    format!("{}\n", message)
+}
+
+/// Hand `event` to every drop-in of `hooks.d` on stdin, without waiting for them.
+///
+/// Events are `pre-up`, `post-up`, `status-change` and `error`.
+pub fn dispatch(event: &str, interface_name: &str, details: serde_json::Value, debug: bool) {
+    let hooks = drop_ins();
+    if hooks.is_empty() {
+        return;
+    }
+    let message = message(event, interface_name, details);
+    for hook in hooks {
+        let mut cmd = Command::new(&hook);
+        cmd.arg(event)
+            .env("WG_WAYBAR_INTERFACE", interface_name)
+            .stdin(Stdio::piped());
+        as_invoking_user(&mut cmd);
+        // The pipe buffer holds the line, so a hook not reading stdin does not block us.
+        let result = spawn_reaped(&mut cmd, |child| match child.stdin.take() {
+            Some(mut stdin) => stdin.write_all(message.as_bytes()),
+            None => Ok(()),
+        });
+        if let Err(e) = result
+            && debug
+        {
+            println!("Failed to run hook {}: {}", hook.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_one_line_per_event() {
+        let line = message(
+            "status-change",
+            "wg0",
+            json!({"status": "connected", "event": "spoofed"}),
+        );
+        assert!(line.ends_with('\n') && !line.trim_end().contains('\n'));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["event"], "status-change");
+        assert_eq!(value["interface"], "wg0");
+        assert_eq!(value["status"], "connected");
+        assert!(value["timestamp"].is_u64());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn leaves_no_zombie_behind() {
+        let mut pid = 0;
+        spawn_reaped(&mut Command::new("true"), |child| {
+            pid = child.id();
+            Ok(())
+        })
+        .unwrap();
+        let stat = format!("/proc/{}/stat", pid);
+        let reaped = (0..100).any(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            std::fs::read_to_string(&stat).is_err()
+        });
+        assert!(reaped);
+
+        let failed = spawn_reaped(&mut Command::new("true"), |_| {
+            Err(std::io::Error::other("no stdin"))
+        });
+        assert!(failed.is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::Ipv4Addr;
+
+/// Wireless interface serving an access point, e.g. NetworkManager's hotspot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hotspot {
+    pub device: String,
+    /// Address of the machine on the hotspot.
+    pub address: Ipv4Addr,
+    pub prefix_length: u8,
+}
+
+impl Hotspot {
+    fn network(&self) -> u32 {
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(self.prefix_length))
+            .unwrap_or(0);
+        u32::from(self.address) & mask
+    }
+
+    /// Network of the tethered clients, e.g. `10.42.0.0/24`.
+    pub fn subnet(&self) -> String {
+        format!("{}/{}", Ipv4Addr::from(self.network()), self.prefix_length)
+    }
+
+    /// An address a client could have: the first of the subnet other than the machine's.
+    fn client(&self) -> Ipv4Addr {
+        let network = self.network();
+        let first = Ipv4Addr::from(network + 1);
+        if first == self.address {
+            Ipv4Addr::from(network + 2)
+        } else {
+            first
+        }
+    }
+}
+
+impl fmt::Display for Hotspot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.device, self.subnet())
+    }
+}
+
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    utils::run_command(program, args, None).ok()
+}
+
+/// Interfaces of `iw dev` in AP mode.
+fn parse_access_points(output: &str) -> Vec<String> {
+    let mut devices = Vec::new();
+    let mut interface = None;
+    for line in output.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix("Interface ") {
+            interface = Some(name.to_string());
+        } else if line == "type AP"
+            && let Some(name) = interface.take()
+        {
+            devices.push(name);
+        }
+    }
+    devices
+}
+
+/// First IPv4 address and prefix of `ip -j -4 addr show dev <device>`.
+fn parse_address(output: &str) -> Option<(Ipv4Addr, u8)> {
+    let links: serde_json::Value = serde_json::from_str(output).ok()?;
+    let info = links.get(0)?.get("addr_info")?.get(0)?;
+    let address = info.get("local")?.as_str()?.parse().ok()?;
+    let prefix_length = info.get("prefixlen")?.as_u64()?;
+    Some((address, prefix_length.min(32) as u8))
+}
+
+/// The hotspot the machine serves, if any.
+pub fn detect() -> Option<Hotspot> {
+    let device = parse_access_points(&output("iw", &["dev"])?)
+        .into_iter()
+        .next()?;
+    let (address, prefix_length) = parse_address(&output(
+        "ip",
+        &["-j", "-4", "addr", "show", "dev", &device],
+    )?)?;
+    Some(Hotspot {
+        device,
+        address,
+        prefix_length,
+    })
+}
+
+/// Whether the internet traffic of the clients leaves through the tunnel,
+/// asking the kernel how it would route a packet from one of them.
+pub fn is_tunneled(hotspot: &Hotspot, interface_name: &str) -> bool {
+    let client = hotspot.client().to_string();
+    output(
+        "ip",
+        &[
+            "-j",
+            "route",
+            "get",
+            "1.1.1.1",
+            "from",
+            &client,
+            "iif",
+            &hotspot.device,
+        ],
+    )
+    .and_then(|output| {
+        let routes: serde_json::Value = serde_json::from_str(&output).ok()?;
+        Some(routes.get(0)?.get("dev")?.as_str()? == interface_name)
+    })
+    .unwrap_or(false)
+}
+
+/// How the clients of the hotspot were routed when the tunnel came up, kept
+/// in the state so that rendering the status runs neither `iw` nor `ip`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct HotspotRoute {
+    /// The hotspot as shown, e.g. `wlan0 (10.42.0.0/24)`.
+    pub hotspot: String,
+    /// Whether the internet traffic of the clients goes through the tunnel.
+    pub tunneled: bool,
+}
+
+impl HotspotRoute {
+    pub fn describe(&self) -> String {
+        let route = if self.tunneled {
+            "go through the tunnel"
+        } else {
+            "bypass the tunnel"
+        };
+        format!("Hotspot {}: clients {}", self.hotspot, route)
+    }
+}
+
+/// How the clients of the hotspot the machine serves are routed, if it serves one.
+pub fn check(interface_name: &str) -> Option<HotspotRoute> {
+    let hotspot = detect()?;
+    Some(HotspotRoute {
+        tunneled: is_tunneled(&hotspot, interface_name),
+        hotspot: hotspot.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_access_points_and_their_subnet() {
+        let iw = "phy#0\n\tInterface wlan0\n\t\tifindex 3\n\t\ttype AP\n\t\tchannel 6\nphy#1\n\tInterface wlan1\n\t\ttype managed\n";
+        assert_eq!(parse_access_points(iw), ["wlan0"]);
+
+        let ip = r#"[{"ifindex":3,"ifname":"wlan0","addr_info":[{"family":"inet","local":"10.42.0.1","prefixlen":24}]}]"#;
+        let (address, prefix_length) = parse_address(ip).unwrap();
+        let hotspot = Hotspot {
+            device: "wlan0".to_string(),
+            address,
+            prefix_length,
+        };
+        assert_eq!(hotspot.subnet(), "10.42.0.0/24");
+        assert_eq!(hotspot.client(), Ipv4Addr::new(10, 42, 0, 2));
+        assert_eq!(hotspot.to_string(), "wlan0 (10.42.0.0/24)");
+        let route = HotspotRoute {
+            hotspot: hotspot.to_string(),
+            tunneled: false,
+        };
+        assert_eq!(
+            route.describe(),
+            "Hotspot wlan0 (10.42.0.0/24): clients bypass the tunnel"
+        );
+    }
+}
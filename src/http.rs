@@ -0,0 +1,26 @@
+use std::time::Duration;
+
+// Without either, ureq would build but fail every HTTPS request at run time.
+#[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+compile_error!(
+    "`http` and the features using it need a TLS stack: enable `rustls` or `native-tls`"
+);
+use ureq::config::ConfigBuilder;
+use ureq::typestate::AgentScope;
+
+/// Agent configuration over the TLS stack chosen at build time: rustls by
+/// default, which links statically, or the system's with `native-tls` alone.
+pub fn config(timeout: Duration) -> ConfigBuilder<AgentScope> {
+    let builder = ureq::Agent::config_builder().timeout_global(Some(timeout));
+    #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+    let builder = builder.tls_config(
+        ureq::tls::TlsConfig::builder()
+            .provider(ureq::tls::TlsProvider::NativeTls)
+            .build(),
+    );
+    builder
+}
+
+pub fn agent(timeout: Duration) -> ureq::Agent {
+    config(timeout).build().into()
+}
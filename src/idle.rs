@@ -0,0 +1,115 @@
+use crate::actions::{self, Context};
+use crate::app_config::IdleConfig;
+use crate::config;
+use crate::error;
+use crate::hooks;
+use crate::profiles::Profile;
+use crate::state;
+use std::time::{Duration, Instant};
+
+/// Follows the transfer counter of a tunnel to tell how long it has been idle.
+#[derive(Default)]
+pub struct IdleTracker {
+    last: Option<(Instant, u64)>,
+    idle_since: Option<Instant>,
+}
+
+impl IdleTracker {
+    /// Record the counter at `now` and return for how long traffic has stayed
+    /// below `threshold` bytes per second.
+    fn observe(&mut self, now: Instant, bytes: u64, threshold: u64) -> Duration {
+        if let Some((at, previous)) = self.last {
+            let elapsed = now.duration_since(at).as_secs_f64();
+            let rate = bytes.saturating_sub(previous) as f64 / elapsed.max(f64::EPSILON);
+            if rate < threshold as f64 {
+                self.idle_since.get_or_insert(at);
+            } else {
+                self.idle_since = None;
+            }
+        }
+        self.last = Some((now, bytes));
+        self.idle_since
+            .map(|since| now.duration_since(since))
+            .unwrap_or_default()
+    }
+
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Warn about and then disconnect a tunnel whose traffic stayed low for too long.
+///
+/// Once idle for `minutes`, the interface enters the idle state for `grace`
+/// seconds and a notification is shown; traffic resuming in between cancels it.
+pub fn check(
+    ctx: &Context,
+    tracker: &mut IdleTracker,
+    idle: &IdleConfig,
+    profile: &Profile,
+    netns: Option<&str>,
+) -> Result<(), error::Error> {
+    let state = ctx.store.load(&profile.name)?;
+    if !config::is_active(&profile.name, netns)? {
+        tracker.reset();
+        if state.idle_deadline.is_some() {
+            ctx.store
+                .update(&profile.name, |state| state.idle_deadline = None)?;
+        }
+        return Ok(());
+    }
+    let bytes = config::transfer_bytes(&profile.name, netns)?;
+    let idle_for = tracker.observe(Instant::now(), bytes, idle.threshold);
+    match state.idle_deadline {
+        None if idle_for >= Duration::from_secs(idle.minutes * 60) => {
+            ctx.store.update(&profile.name, |state| {
+                state.idle_deadline = Some(state::now() + idle.grace)
+            })?;
+            hooks::notify(
+                "VPN idle",
+                &format!(
+                    "{} disconnects in {} s unless traffic resumes",
+                    profile.name, idle.grace
+                ),
+                ctx.debug,
+            );
+        }
+        Some(_) if idle_for.is_zero() => {
+            ctx.store
+                .update(&profile.name, |state| state.idle_deadline = None)?;
+        }
+        Some(deadline) if state::now() >= deadline => {
+            actions::ensure(ctx, profile, false)?;
+            tracker.reset();
+            hooks::notify(
+                "VPN disconnected",
+                &format!("{} was idle for {} minutes", profile.name, idle.minutes),
+                ctx.debug,
+            );
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_time_spent_below_threshold() {
+        let start = Instant::now();
+        let mut tracker = IdleTracker::default();
+        assert_eq!(tracker.observe(start, 0, 100), Duration::ZERO);
+        let after = |secs| start + Duration::from_secs(secs);
+        assert_eq!(
+            tracker.observe(after(10), 500, 100),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            tracker.observe(after(20), 1000, 100),
+            Duration::from_secs(20)
+        );
+        assert_eq!(tracker.observe(after(30), 100_000, 100), Duration::ZERO);
+    }
+}
@@ -0,0 +1,194 @@
+use crate::error;
+use crate::paths;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Which profile each templated interface name was given to, so that the name
+/// stays the same from one run to the next and is not handed out twice.
+const CLAIMS_DIR: &str = "interfaces";
+/// Longest interface name the kernel accepts (`IFNAMSIZ` without the NUL).
+const MAX_LEN: usize = 15;
+/// Instances tried before giving up on a template.
+const MAX_INSTANCES: u32 = 100;
+
+/// Whether the kernel and the tools handling `name` take it as an interface name.
+pub fn is_valid(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_LEN
+        && !name.contains(['/', ':', '{', '}'])
+        && !name.contains(char::is_whitespace)
+}
+
+/// Interface name from `template` for instance `n` of the profile `stem`,
+/// shortening the profile name when the result would not fit.
+///
+/// A template without `{n}` gets `-<n>` appended from the second instance on.
+fn render(template: &str, stem: &str, n: u32) -> Result<String, String> {
+    let template = if template.contains("{n}") || n == 0 {
+        template.to_string()
+    } else {
+        format!("{}-{{n}}", template)
+    };
+    let fixed = template
+        .replace("{profile}", "")
+        .replace("{n}", &n.to_string());
+    let room = MAX_LEN.saturating_sub(fixed.len());
+    let mut stem = stem;
+    while stem.len() > room {
+        stem = &stem[..stem.floor_char_boundary(stem.len() - 1)];
+    }
+    let name = template
+        .replace("{profile}", stem)
+        .replace("{n}", &n.to_string());
+    if !is_valid(&name) {
+        return Err(format!(
+            "interface name template {:?} gives {:?}, not a valid interface name",
+            template, name
+        ));
+    }
+    Ok(name)
+}
+
+fn claimant(dir: &Path, name: &str) -> Option<PathBuf> {
+    fs::read_to_string(dir.join(name)).ok().map(PathBuf::from)
+}
+
+fn netdev_exists(name: &str) -> bool {
+    Path::new("/sys/class/net").join(name).exists()
+}
+
+fn assign_in(
+    dir: &Path,
+    template: &str,
+    stem: &str,
+    profile_path: &Path,
+    taken: impl Fn(&str) -> bool,
+) -> Result<String, String> {
+    let profile_path = canonical(profile_path);
+    for n in 0..MAX_INSTANCES {
+        let name = render(template, stem, n)?;
+        match claimant(dir, &name) {
+            Some(path) if path == profile_path => return Ok(name),
+            // Claims of deleted profiles are free to take over.
+            Some(path) if path.exists() => continue,
+            _ if taken(&name) => continue,
+            _ => return Ok(name),
+        }
+    }
+    Err(format!(
+        "no free interface name from template {:?} for {}",
+        template, stem
+    ))
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn claim_in(dir: &Path, name: &str, profile_path: &Path) -> Result<(), String> {
+    let profile_path = canonical(profile_path);
+    let create = || {
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dir.join(name))?
+            .write_all(profile_path.as_os_str().as_encoded_bytes())
+    };
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    match create() {
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => match claimant(dir, name) {
+            Some(path) if path == profile_path => Ok(()),
+            Some(path) if path.exists() => Err(format!(
+                "interface name {} was just claimed by {}",
+                name,
+                path.display()
+            )),
+            _ => {
+                let _ = fs::remove_file(dir.join(name));
+                create().map_err(|e| e.to_string())
+            }
+        },
+        result => result.map_err(|e| e.to_string()),
+    }
+}
+
+/// Interface name of the profile at `profile_path` from `template`, e.g.
+/// `wg-{profile}-{n}`: its claim if it has one, otherwise the first instance
+/// `n` neither claimed by another profile, used by an interface wg-waybar did
+/// not create nor in `given`.
+pub fn assign(
+    template: &str,
+    stem: &str,
+    profile_path: &Path,
+    given: &[String],
+) -> Result<String, error::Error> {
+    assign_in(
+        &paths::shared_dir(CLAIMS_DIR),
+        template,
+        stem,
+        profile_path,
+        |name| given.iter().any(|given| given == name) || netdev_exists(name),
+    )
+    .map_err(|message| error::Error::InvalidFormat { message })
+}
+
+/// Claim `name` for the profile at `profile_path` as it connects, so that it
+/// keeps the name until reboot and no other profile gets it.
+pub fn claim(name: &str, profile_path: &Path) -> Result<(), error::Error> {
+    claim_in(&paths::shared_dir(CLAIMS_DIR), name, profile_path)
+        .map_err(|message| error::Error::InvalidFormat { message })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_names_that_fit() {
+        assert_eq!(render("wg-{profile}-{n}", "work", 0).unwrap(), "wg-work-0");
+        assert_eq!(
+            render("wg-{profile}-{n}", "mullvad-se-sto", 12).unwrap(),
+            "wg-mullvad-s-12"
+        );
+        assert_eq!(render("vpn", "work", 2).unwrap(), "vpn-2");
+        assert!(render("wg {profile}", "work", 0).is_err());
+    }
+
+    #[test]
+    fn avoids_names_claimed_or_in_use() {
+        let dir = std::env::temp_dir().join(format!("wg-waybar-ifname-{}", std::process::id()));
+        let profiles = dir.join("profiles");
+        fs::create_dir_all(&profiles).unwrap();
+        let claims = dir.join("claims");
+        let office = profiles.join("office.conf");
+        let home = profiles.join("home.conf");
+        fs::write(&office, "").unwrap();
+        fs::write(&home, "").unwrap();
+
+        let in_use = |name: &str| name == "wg0";
+        assert_eq!(
+            assign_in(&claims, "wg{n}", "office", &office, in_use).unwrap(),
+            "wg1"
+        );
+        // Only claimed once connected.
+        assert_eq!(
+            assign_in(&claims, "wg{n}", "home", &home, in_use).unwrap(),
+            "wg1"
+        );
+        claim_in(&claims, "wg1", &office).unwrap();
+        claim_in(&claims, "wg1", &office).unwrap();
+        assert!(claim_in(&claims, "wg1", &home).is_err());
+        assert_eq!(
+            assign_in(&claims, "wg{n}", "home", &home, in_use).unwrap(),
+            "wg2"
+        );
+        // Up now, but claimed by the same profile.
+        let up = |name: &str| name != "wg3";
+        assert_eq!(
+            assign_in(&claims, "wg{n}", "office", &office, up).unwrap(),
+            "wg1"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,353 @@
+use crate::cli::ImportFormat;
+use crate::error;
+use crate::ifname;
+use ini::Ini;
+use serde::Deserialize;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write as _;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// A profile converted from another tool's export.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Imported {
+    /// Interface name, used as the file stem.
+    pub name: String,
+    /// wg-quick configuration.
+    pub config: String,
+}
+
+/// Keys of an `[Interface]` or `[Peer]` section, in order, skipping unset ones.
+pub type Section = Vec<(&'static str, Option<String>)>;
+
+/// Render a wg-quick configuration from its sections.
+pub fn render(interface: Section, peers: Vec<Section>) -> String {
+    let mut config = String::new();
+    let sections = std::iter::once(("Interface", interface))
+        .chain(peers.into_iter().map(|peer| ("Peer", peer)));
+    for (index, (title, keys)) in sections.enumerate() {
+        if index > 0 {
+            config.push('\n');
+        }
+        let _ = writeln!(config, "[{}]", title);
+        for (key, value) in keys {
+            if let Some(value) = value {
+                let _ = writeln!(config, "{} = {}", key, value);
+            }
+        }
+    }
+    config
+}
+
+fn invalid(message: impl Into<String>) -> error::Error {
+    error::Error::InvalidFormat {
+        message: message.into(),
+    }
+}
+
+/// NetworkManager lists are `;`-separated with a trailing `;`.
+fn nm_list(value: &str) -> Option<String> {
+    let items: Vec<&str> = value
+        .split(';')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .collect();
+    (!items.is_empty()).then(|| items.join(", "))
+}
+
+/// A NetworkManager `.nmconnection` keyfile of type `wireguard`.
+fn from_nm_keyfile(content: &str) -> Result<Vec<Imported>, error::Error> {
+    let keyfile = Ini::load_from_str(content)?;
+    let connection = keyfile
+        .section(Some("connection"))
+        .ok_or_else(|| invalid("no [connection] section"))?;
+    if connection.get("type") != Some("wireguard") {
+        return Err(invalid("not a wireguard connection"));
+    }
+    let name = connection
+        .get("interface-name")
+        .or(connection.get("id"))
+        .ok_or_else(|| invalid("no interface-name or id"))?
+        .to_string();
+    let wireguard = keyfile
+        .section(Some("wireguard"))
+        .ok_or_else(|| invalid("no [wireguard] section"))?;
+    let private_key = wireguard
+        .get("private-key")
+        .ok_or_else(|| invalid("no private-key, it may be kept in a secret agent"))?;
+
+    let mut addresses = Vec::new();
+    let mut dns = Vec::new();
+    for family in ["ipv4", "ipv6"] {
+        let Some(section) = keyfile.section(Some(family)) else {
+            continue;
+        };
+        for (key, value) in section.iter() {
+            if key.starts_with("address") {
+                // `address1=10.0.0.2/32,10.0.0.1` may carry a gateway after the comma.
+                addresses.extend(value.split(',').next().map(str::to_string));
+            }
+        }
+        dns.extend(section.get("dns").and_then(nm_list));
+    }
+    let interface = vec![
+        ("PrivateKey", Some(private_key.to_string())),
+        (
+            "Address",
+            (!addresses.is_empty()).then(|| addresses.join(", ")),
+        ),
+        (
+            "ListenPort",
+            wireguard.get("listen-port").map(str::to_string),
+        ),
+        ("DNS", (!dns.is_empty()).then(|| dns.join(", "))),
+    ];
+
+    let peers = keyfile
+        .iter()
+        .filter_map(|(title, section)| {
+            let public_key = title?.strip_prefix("wireguard-peer.")?;
+            Some(vec![
+                ("PublicKey", Some(public_key.to_string())),
+                (
+                    "PresharedKey",
+                    section.get("preshared-key").map(str::to_string),
+                ),
+                ("Endpoint", section.get("endpoint").map(str::to_string)),
+                ("AllowedIPs", section.get("allowed-ips").and_then(nm_list)),
+                (
+                    "PersistentKeepalive",
+                    section.get("persistent-keepalive").map(str::to_string),
+                ),
+            ])
+        })
+        .collect();
+    Ok(vec![Imported {
+        name,
+        config: render(interface, peers),
+    }])
+}
+
+#[derive(Deserialize)]
+struct DefguardConfig {
+    network_name: String,
+    /// wg-quick configuration, as shown for manual setup.
+    config: String,
+}
+
+/// Device configurations from defguard: one object or a list of them.
+fn from_defguard(content: &str) -> Result<Vec<Imported>, error::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Export {
+        One(DefguardConfig),
+        Many(Vec<DefguardConfig>),
+        Device { configs: Vec<DefguardConfig> },
+    }
+    let configs = match serde_json::from_str(content)? {
+        Export::One(config) => vec![config],
+        Export::Many(configs) | Export::Device { configs } => configs,
+    };
+    Ok(configs
+        .into_iter()
+        .map(|config| Imported {
+            name: config.network_name,
+            config: config.config,
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct MullvadRelay {
+    hostname: String,
+    ipv4_addr_in: String,
+    public_key: String,
+    #[serde(default = "default_mullvad_port")]
+    port: u16,
+}
+
+fn default_mullvad_port() -> u16 {
+    51820
+}
+
+#[derive(Deserialize)]
+struct MullvadExport {
+    private_key: String,
+    addresses: Vec<String>,
+    #[serde(default)]
+    dns: Vec<String>,
+    relay: MullvadRelay,
+}
+
+/// A Mullvad device key and addresses along with one entry of the relay list.
+fn from_mullvad(content: &str) -> Result<Vec<Imported>, error::Error> {
+    let export: MullvadExport = serde_json::from_str(content)?;
+    let relay = export.relay;
+    let interface = vec![
+        ("PrivateKey", Some(export.private_key)),
+        ("Address", Some(export.addresses.join(", "))),
+        (
+            "DNS",
+            (!export.dns.is_empty()).then(|| export.dns.join(", ")),
+        ),
+    ];
+    let peer = vec![
+        ("PublicKey", Some(relay.public_key)),
+        ("AllowedIPs", Some("0.0.0.0/0, ::/0".to_string())),
+        (
+            "Endpoint",
+            Some(format!("{}:{}", relay.ipv4_addr_in, relay.port)),
+        ),
+    ];
+    Ok(vec![Imported {
+        name: relay.hostname,
+        config: render(interface, vec![peer]),
+    }])
+}
+
+pub fn convert(format: ImportFormat, content: &str) -> Result<Vec<Imported>, error::Error> {
+    match format {
+        ImportFormat::Defguard => from_defguard(content),
+        ImportFormat::MullvadJson => from_mullvad(content),
+        ImportFormat::NmKeyfile => from_nm_keyfile(content),
+    }
+}
+
+/// Write the profile as `<name>.conf` into `dir`, readable by root only.
+pub fn write(dir: &Path, imported: &Imported, force: bool) -> Result<PathBuf, error::Error> {
+    if !ifname::is_valid(&imported.name) {
+        return Err(invalid(format!(
+            "{:?} is not a valid interface name, pass --name",
+            imported.name
+        )));
+    }
+    let path = dir.join(format!("{}.conf", imported.name));
+    let mut options = fs::OpenOptions::new();
+    options.write(true).mode(0o600);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let mut file = options
+        .open(&path)
+        .map_err(|e| invalid(format!("cannot write {}: {}", path.display(), e)))?;
+    file.write_all(imported.config.as_bytes())?;
+    Ok(path)
+}
+
+/// Convert `file` and write the profiles it holds into `dir`.
+pub fn run(
+    dir: &Path,
+    file: &Path,
+    format: ImportFormat,
+    name: Option<&str>,
+    force: bool,
+) -> Result<(), error::Error> {
+    let mut profiles = convert(format, &fs::read_to_string(file)?)?;
+    if let Some(name) = name {
+        if profiles.len() > 1 {
+            return Err(invalid("--name needs an export holding a single profile"));
+        }
+        for profile in &mut profiles {
+            profile.name = name.to_string();
+        }
+    }
+    for profile in &profiles {
+        println!("{}", write(dir, profile, force)?.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_nm_keyfile() {
+        let keyfile = "\
+[connection]
+id=Office
+type=wireguard
+interface-name=wg-office
+
+[wireguard]
+private-key=cHJpdmF0ZQ==
+
+[wireguard-peer.cHVibGlj]
+endpoint=vpn.example.org:51820
+allowed-ips=10.0.0.0/8;192.168.10.0/24;
+
+[ipv4]
+address1=10.0.0.2/32
+dns=10.0.0.1;
+method=manual
+";
+        let imported = convert(ImportFormat::NmKeyfile, keyfile).unwrap();
+        assert_eq!(
+            imported,
+            vec![Imported {
+                name: "wg-office".to_string(),
+                config: "\
+[Interface]
+PrivateKey = cHJpdmF0ZQ==
+Address = 10.0.0.2/32
+DNS = 10.0.0.1
+
+[Peer]
+PublicKey = cHVibGlj
+Endpoint = vpn.example.org:51820
+AllowedIPs = 10.0.0.0/8, 192.168.10.0/24
+"
+                .to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn converts_json_exports() {
+        let defguard = r#"{"configs": [{"network_id": 1, "network_name": "office", "config": "[Interface]\n"}]}"#;
+        let imported = convert(ImportFormat::Defguard, defguard).unwrap();
+        assert_eq!(imported[0].name, "office");
+
+        let mullvad = r#"{"private_key": "cHJpdmF0ZQ==", "addresses": ["10.64.1.2/32"],
+            "relay": {"hostname": "se-sto-wg-001", "ipv4_addr_in": "185.65.135.1", "public_key": "cHVibGlj"}}"#;
+        let imported = convert(ImportFormat::MullvadJson, mullvad).unwrap();
+        assert_eq!(imported[0].name, "se-sto-wg-001");
+        assert!(
+            imported[0]
+                .config
+                .contains("Endpoint = 185.65.135.1:51820\n")
+        );
+    }
+
+    #[test]
+    fn refuses_names_that_are_not_interface_names() {
+        let dir = std::env::temp_dir().join(format!("wg-waybar-import-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "Office VPN",
+            "office:1",
+            "{office}",
+            "a-name-far-too-long",
+            "",
+        ] {
+            let imported = Imported {
+                name: name.to_string(),
+                config: "[Interface]\n".to_string(),
+            };
+            assert!(write(&dir, &imported, false).is_err(), "{:?}", name);
+        }
+        let imported = Imported {
+            name: "office".to_string(),
+            config: "[Interface]\n".to_string(),
+        };
+        assert_eq!(
+            write(&dir, &imported, false).unwrap(),
+            dir.join("office.conf")
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
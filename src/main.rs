@@ -1,186 +1,314 @@
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 
 use cli::Cli;
-use defguard_wireguard_rs::{Kernel, WGApi, WireguardInterfaceApi};
-use serde_json::json;
-use std::collections::HashMap;
-use std::io;
-use std::io::Write;
-use std::path::Path;
-use utils::send_signal_to_waybar;
+use status::StatusReport;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+mod actions;
+mod adopt;
+mod app_config;
+mod applied;
+mod audit;
+mod battery;
+#[cfg(feature = "http")]
+mod bench;
+mod capabilities;
 mod cli;
+mod client;
+mod clock;
+mod commands;
+#[cfg(feature = "daemon")]
+mod compositor;
 mod config;
+mod conflicts;
+#[cfg(feature = "daemon")]
+mod daemon;
+mod dns_canary;
+mod doctor;
+mod encrypted;
 mod error;
+#[cfg(feature = "daemon")]
+mod events;
+mod exit_node;
+mod expiry;
+mod explain;
+mod export_peer;
+mod eyeballs;
+mod fetch;
+mod firewall;
+mod format;
+mod gateway;
+mod handshake;
+mod hardware_key;
+mod health;
+#[cfg(feature = "http")]
+mod history;
+mod hooks;
+mod hotspot;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "daemon")]
+mod idle;
+mod ifname;
+mod import;
+mod menu;
+#[cfg(feature = "prometheus")]
+mod metrics;
+mod mock;
+mod netns;
+mod owner;
+mod paths;
+mod precheck;
+mod profiles;
+#[cfg(feature = "providers")]
+mod provider;
+#[cfg(feature = "daemon")]
+mod publish;
+mod rank;
+mod refresh;
+mod routing;
+mod runs;
+mod schedule;
+#[cfg(feature = "schema")]
+mod schema;
+#[cfg(feature = "self-update")]
+mod self_update;
+mod serve;
+#[cfg(feature = "http")]
+mod speedtest;
+mod split_tunnel;
+mod state;
+mod status;
+mod sysctl;
+mod template;
+mod theme;
+mod toml_profile;
+mod top;
+mod trace;
+#[cfg(feature = "tray")]
+mod tray;
+#[cfg(feature = "tui")]
+mod tui;
+mod uplink;
 mod utils;
+#[cfg(feature = "daemon")]
+mod watch;
+mod waybar_config;
 
-#[derive(Copy, Clone)]
-enum Status {
-    Connected,
-    Disconnected,
-    Error,
-}
-
-impl Status {
-    fn as_str(&self) -> &'static str {
-        match self {
-            Status::Connected => "connected",
-            Status::Disconnected => "disconnected",
-            Status::Error => "error",
-        }
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cli = Cli::parse();
+    if cli.backend == cli::Backend::Mock {
+        mock::enable();
     }
-    fn percentage(&self) -> u8 {
-        match self {
-            Status::Connected => 0,
-            Status::Disconnected => 50,
-            Status::Error => 100,
-        }
+    if let Some(path) = &cli.record {
+        trace::record(PathBuf::from(path));
+    } else if let Some(path) = &cli.replay {
+        trace::replay(Path::new(path))?;
     }
-}
-
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
-struct LastStateError {
-    error: Option<HashMap<String, String>>,
-}
-
-fn status(interface_name: &str, state_filepath: std::path::PathBuf) -> Result<(), error::Error> {
-    let bytes = std::fs::read(state_filepath)?;
-    let error: LastStateError = serde_json::from_slice(&bytes)?;
-
-    if let Some(e) = error.error {
-        for (key, value) in e.iter() {
-            if key == interface_name {
-                output_json(
-                    "VPN: Error",
-                    Status::Error,
-                    &format!("Toggle failed: {}", value),
-                )?;
-            }
-        }
+    if cli.capabilities {
+        println!("{}", capabilities::report());
+        return Ok(());
     }
-
-    match WGApi::<Kernel>::new(interface_name.to_string()) {
-        Ok(wg_api) => {
-            let status = if wg_api.read_interface_data().is_ok() {
-                Status::Connected
-            } else {
-                Status::Disconnected
-            };
-            output_json(
-                &format!("VPN: {}", interface_name),
-                status,
-                &format!("VPN is {}", status.as_str()),
-            )?;
-        }
-        Err(e) => {
-            let err = error::Error::WireGuardApi(e.to_string());
-            output_json(
-                "VPN: Error",
-                Status::Error,
-                &format!("Failed to check VPN status: {}", err),
-            )?;
+    if let Some(cli::Commands::Explain { kind }) = cli.command {
+        print!("{}", explain::render(kind));
+        return Ok(());
+    }
+    // Subcommands lift the requirement, for `explain` only.
+    if cli.config.is_empty() && !cli.capabilities {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "the following required arguments were not provided:\n  <CONFIG>",
+            )
+            .exit();
+    }
+    #[cfg(feature = "schema")]
+    if let Some(cli::Commands::Schema { kind }) = cli.command {
+        println!("{}", serde_json::to_string_pretty(&schema::generate(kind))?);
+        return Ok(());
+    }
+    #[cfg(feature = "hardware-keys")]
+    if let Some(cli::Commands::Enroll { source }) = &cli.command {
+        let (source, public_key) = hardware_key::enroll(source)?;
+        println!("# In the [Interface] of the profile:");
+        println!("# PrivateKeyFrom = {}", source);
+        println!("# In the [Peer] of the server:");
+        println!("PublicKey = {}", public_key);
+        return Ok(());
+    }
+    if let Some(path) = &cli.waybar_config {
+        match waybar_config::adopt_signal(Path::new(path), cli.signal) {
+            Ok(signal_num) => cli.signal = signal_num,
+            Err(e) => eprintln!("Cannot read the signal of {}: {}", path, e),
         }
     }
-    Ok(())
-}
-fn output_json(text: &str, status: Status, tooltip: &str) -> Result<(), std::io::Error> {
-    let output = json!({
-        "text": text,
-        "class": status.as_str(),
-        "tooltip": tooltip,
-        "percentage": status.percentage()
-    });
-    println!("{}", output);
-    io::stdout().flush()
-}
-
-fn toggle(
-    interface_name: &str,
-    config_path: &Path,
-    signal_num: i32,
-    state_filepath: std::path::PathBuf,
-    debug: bool,
-    port: u32,
-) -> Result<(), error::Error> {
-    let result = match WGApi::<Kernel>::new(interface_name.to_string()) {
-        Ok(wg_api) => {
-            let is_active = wg_api.read_interface_data().is_ok();
-            if is_active {
-                wg_api
-                    .remove_interface()
-                    .map_err(|e| error::Error::WireGuardApi(e.to_string()))
-            } else {
-                match config::configure_wireguard(config_path, interface_name, port) {
-                    Ok(_) => Ok(()),
-                    Err(e) => {
-                        if let error::Error::WireGuardApi(_) = e {
-                            wg_api.remove_interface()?;
-                            Err(error::Error::WireGuardApi(e.to_string()))
-                        } else {
-                            Err(e)
-                        }
-                    }
-                }
-            }
+    if let Some(socket) = &cli.connect {
+        return Ok(client::run(&cli, Path::new(socket))?);
+    }
+    let config_path = Path::new(&cli.config);
+    // The directory may hold no profile yet.
+    if let Some(cli::Commands::Adopt { interface, force }) = &cli.command {
+        if cli.observe {
+            hooks::notify(
+                "VPN managed externally",
+                &format!(
+                    "{} is not managed by wg-waybar, it cannot be adopted",
+                    interface
+                ),
+                cli.debug,
+            );
+            return Ok(());
         }
-        Err(e) => Err(error::Error::WireGuardApi(e.to_string())),
+        let (store, _) = state::open_store(&cli.state_filename, cli.debug)?;
+        let app_config = app_config::AppConfig::load()?;
+        let result = adopt::run(
+            &store,
+            profiles::directory(config_path),
+            interface,
+            cli.netns.as_deref(),
+            *force,
+        );
+        audit::record(
+            &app_config.audit,
+            &audit::Requester::invoking(),
+            "adopt",
+            interface,
+            &result,
+        );
+        result?;
+        return Ok(());
+    }
+    let formatter = format::Formatter {
+        output_format: cli.output_format,
+        style: cli.style,
+        theme: cli.theme,
+        template: cli.format.clone(),
+        max_length: cli.max_length,
+        tooltip_markup: cli.tooltip_markup,
+        peer_table: cli.tooltip_peers,
+        note: cli
+            .observe
+            .then(|| "Managed externally, wg-waybar only observes it".to_string()),
     };
-
-    match result {
-        Ok(_) => {
-            utils::fs_write(state_filepath, "{}")?;
-        }
+    // Errors before any status is read are shown like one, in the format asked for.
+    let fail = |interface_name: &str, message: String| {
+        formatter.output(interface_name, &StatusReport::error(message))
+    };
+    let filter = profiles::Filter {
+        pattern: cli.match_pattern.as_deref(),
+        tag: cli.tag.as_deref(),
+        interface_template: cli.interface_name.as_deref(),
+    };
+    let profile = match profiles::select(config_path, cli.netns.as_deref(), filter) {
+        Ok(profile) => profile,
         Err(e) => {
-            let json_str = serde_json::to_string(&json!({
-                "error": {interface_name: e.to_string()}
-            }))?;
-            utils::fs_write(state_filepath, json_str)?;
+            fail("", format!("Failed to parse interface name: {}", e))?;
+            return Err(Box::new(e));
         }
-    }
-    send_signal_to_waybar(signal_num, debug)?;
-    Ok(())
-}
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
-    let config_path = Path::new(&cli.config);
-    let interface_name = config_path
-        .file_stem()
-        .and_then(|stem| stem.to_str())
-        .ok_or_else(|| error::Error::InvalidFormat {
-            message: "Invalid config file name".to_string(),
-        });
-    let interface_name = match interface_name {
-        Ok(name) => name,
+    };
+    let interface_name = profile.name.as_str();
+    let (store, state_home) = match state::open_store(&cli.state_filename, cli.debug) {
+        Ok(opened) => opened,
         Err(e) => {
-            let err = e.to_string();
-            output_json(
-                "VPN: Error",
-                Status::Error,
-                &format!("Failed to parse interface name: {}", err),
+            fail(
+                interface_name,
+                format!("Failed to open the state directory: {}", e),
             )?;
             return Err(Box::new(e));
         }
     };
-    let state_home = utils::get_state_home("wg-waybar")?;
-    if !state_home.exists() {
-        utils::fs_create_dir(state_home.clone())?;
-    }
-    let state_filepath = state_home.join(cli.state_filename);
-    if !state_filepath.exists() {
-        utils::fs_write(state_filepath.clone(), "{}")?;
-    }
-    match &cli.command {
-        Some(cli::Commands::Toggle) => toggle(
-            interface_name,
-            config_path,
+    let (app_config, config_error) = match app_config::AppConfig::load() {
+        Ok(app_config) => (app_config, None),
+        // Showing the tunnel does not need the config: the defaults stand in for it.
+        Err(e) if matches!(cli.command, None | Some(cli::Commands::Status { .. })) => (
+            app_config::AppConfig::default(),
+            Some(format!("Invalid app config, using the defaults: {}", e)),
+        ),
+        Err(e) => {
+            fail(interface_name, format!("Failed to load app config: {}", e))?;
+            return Err(Box::new(e));
+        }
+    };
+    let formatter = match config_error {
+        Some(message) => {
+            eprintln!("{}", message);
+            format::Formatter {
+                note: Some(match formatter.note {
+                    Some(note) => format!("{}\n{}", note, message),
+                    None => message,
+                }),
+                ..formatter
+            }
+        }
+        None => formatter,
+    };
+    let options_for = |name: &str| {
+        let state = store.load(name).unwrap_or_default();
+        config::TunnelOptions {
+            port: cli.port,
+            route_mode: cli.route_mode,
+            netns: cli.netns.as_deref(),
+            split_tunnel: app_config.split_tunnel.get(name),
+            exit_node: state.exit_node,
+            endpoint_family: state.endpoint_family,
+            bind_interface: cli
+                .bind_interface
+                .as_deref()
+                .or_else(|| app_config.bind_interface.get(name).map(String::as_str)),
+            keepalive: true,
+            variables: app_config.variables.get(name),
+            gateway: app_config.gateway.get(name),
+            relax_rp_filter: app_config.sysctl.relax_rp_filter,
+            firewall_backend: cli.firewall_backend,
+            kill_switch: cli.kill_switch,
+            groups: &cli.groups,
+            debug: cli.debug,
+        }
+    };
+    let ctx = actions::Context {
+        store: &store,
+        refresh: refresh::Sinks::new(
+            &app_config.refresh,
             cli.signal,
-            state_filepath,
+            cli.wait_for_waybar.map(Duration::from_secs),
             cli.debug,
-            cli.port,
-        )?,
-
-        None => status(interface_name, state_filepath)?,
+        ),
+        debug: cli.debug,
+        hooks: &app_config.hooks,
+        metered: &app_config.metered,
+        audit: &app_config.audit,
+        requester: audit::Requester::invoking(),
+        dns_canary: &app_config.dns_canary,
+        allowed_hours: &app_config.allowed_hours,
+        exclusive: cli.exclusive,
+        toggle_interval: cli.min_toggle_interval,
+        confirm_disconnect: cli.confirm_disconnect.map(Duration::from_secs),
+        handshake_timeout: cli.handshake_timeout.map(Duration::from_secs),
+        attention_after: cli.attention_after,
+        profile_filter: filter,
+        profile_dir: profiles::directory(config_path),
+        options_for: &options_for,
     };
+    if cli.observe
+        && cli
+            .command
+            .as_ref()
+            .is_some_and(cli::Commands::changes_tunnel)
+    {
+        hooks::notify(
+            "VPN managed externally",
+            &format!(
+                "{} is not managed by wg-waybar, bring it up or down with the tool that manages it",
+                interface_name
+            ),
+            cli.debug,
+        );
+        return Ok(());
+    }
+    let result = commands::run(&cli, &ctx, &profile, &formatter, &app_config, &state_home)?;
+    if let Some(code) = result {
+        std::process::exit(code);
+    }
     Ok(())
 }
@@ -0,0 +1,176 @@
+use crate::cli::Elevate;
+use crate::error;
+use crate::profiles::Profile;
+use crate::rank;
+use crate::utils;
+use serde_json::json;
+use std::path::Path;
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A menu entry, greyed out unless `sensitive`.
+fn item(id: &str, label: &str, sensitive: bool) -> String {
+    let insensitive = if sensitive {
+        ""
+    } else {
+        "        <property name=\"sensitive\">False</property>\n"
+    };
+    format!(
+        "    <child>\n      <object class=\"GtkMenuItem\" id=\"{}\">\n        <property name=\"label\">{}</property>\n{}      </object>\n    </child>\n",
+        escape(id),
+        escape(label),
+        insensitive
+    )
+}
+
+fn action_id(profile: &Profile) -> String {
+    format!("profile-{}", profile.name)
+}
+
+/// Name of the profile, followed by its tags and note if any.
+fn label(profile: &Profile) -> String {
+    let tags = profile.tags();
+    let mut label = if tags.is_empty() {
+        profile.name.clone()
+    } else {
+        format!("{} ({})", profile.name, tags.join(", "))
+    };
+    if let Some(note) = profile.note() {
+        label.push_str(&format!(": {}", note));
+    }
+    label
+}
+
+/// With `probes`, each entry shows the round-trip time to its endpoint, and
+/// dead servers are greyed out.
+fn menu_xml(profiles: &[Profile], probes: Option<&[rank::Rtt]>) -> String {
+    let mut items: String = profiles
+        .iter()
+        .enumerate()
+        .map(
+            |(i, profile)| match probes.and_then(|probes| probes.get(i)) {
+                Some(rtt) => item(
+                    &action_id(profile),
+                    &format!("{} — {}", label(profile), rank::describe(rtt)),
+                    rtt.is_ok(),
+                ),
+                None => item(&action_id(profile), &label(profile), true),
+            },
+        )
+        .collect();
+    items.push_str(
+        "    <child>\n      <object class=\"GtkSeparatorMenuItem\" id=\"separator\"/>\n    </child>\n",
+    );
+    items.push_str(&item("toggle", "Toggle", true));
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<interface>\n  <object class=\"GtkMenu\" id=\"menu\">\n{}  </object>\n</interface>\n",
+        items
+    )
+}
+
+/// The command line of each entry, `base` followed by its subcommand.
+fn menu_actions(
+    profiles: &[Profile],
+    base: &[String],
+) -> serde_json::Map<String, serde_json::Value> {
+    let command = |args: &[&str]| {
+        let mut words = base.to_vec();
+        words.extend(args.iter().map(|arg| arg.to_string()));
+        json!(utils::command_line(&words))
+    };
+    let mut actions = serde_json::Map::new();
+    for profile in profiles {
+        actions.insert(action_id(profile), command(&["switch", &profile.name]));
+    }
+    actions.insert("toggle".to_string(), command(&["toggle"]));
+    actions
+}
+
+/// Write the GtkBuilder menu listing `profiles` to `output`, with the
+/// reachability of their endpoints if probed.
+///
+/// Returns the `menu`, `menu-file` and `menu-actions` keys to merge into the
+/// Waybar module: picking a profile switches to it through `elevate`, `Toggle`
+/// toggles the active one.
+pub fn generate(
+    profiles: &[Profile],
+    config_dir: &Path,
+    signal_num: i32,
+    output: &Path,
+    probes: Option<&[rank::Rtt]>,
+    elevate: Elevate,
+) -> Result<serde_json::Value, error::Error> {
+    utils::fs_write(output.to_path_buf(), menu_xml(profiles, probes))?;
+
+    let exe = std::env::current_exe()?;
+    let mut base: Vec<String> = elevate.program().map(str::to_string).into_iter().collect();
+    base.extend([
+        exe.display().to_string(),
+        "--signal".to_string(),
+        signal_num.to_string(),
+        config_dir.display().to_string(),
+    ]);
+    let actions = menu_actions(profiles, &base);
+
+    Ok(json!({
+        "menu": "on-click-right",
+        "menu-file": output,
+        "menu-actions": actions,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(name: &str) -> Profile {
+        Profile {
+            name: name.to_string(),
+            stem: name.to_string(),
+            path: std::path::PathBuf::from(format!("/nonexistent/{}.conf", name)),
+        }
+    }
+
+    #[test]
+    fn lists_the_profiles_and_greys_out_dead_ones() {
+        let profiles = [profile("wg-se"), profile("a&b")];
+        let xml = menu_xml(&profiles, None);
+        assert!(xml.contains("id=\"profile-wg-se\""));
+        assert!(xml.contains("<property name=\"label\">a&amp;b</property>"));
+        assert!(xml.contains("id=\"toggle\""));
+        assert!(!xml.contains("sensitive"));
+
+        let probes = [
+            Ok(Some(std::time::Duration::from_millis(12))),
+            Err("no handshake response".to_string()),
+        ];
+        let xml = menu_xml(&profiles, Some(&probes));
+        assert!(xml.contains("wg-se — 12.0 ms"));
+        assert!(xml.contains("a&amp;b — unreachable: no handshake response"));
+        assert_eq!(
+            xml.matches("<property name=\"sensitive\">False</property>")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn runs_each_entry_through_the_command_given() {
+        let base = ["doas", "/usr/bin/wg-waybar", "/etc/my wireguard"].map(str::to_string);
+        let actions = menu_actions(&[profile("wg-se")], &base);
+        assert_eq!(
+            actions["profile-wg-se"],
+            "doas /usr/bin/wg-waybar '/etc/my wireguard' switch wg-se"
+        );
+        assert_eq!(
+            actions["toggle"],
+            "doas /usr/bin/wg-waybar '/etc/my wireguard' toggle"
+        );
+    }
+}
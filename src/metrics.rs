@@ -0,0 +1,131 @@
+use crate::config;
+use crate::profiles::Profile;
+use crate::state::Counters;
+use std::fmt::Write;
+use std::time::UNIX_EPOCH;
+
+/// What is exported of one profile.
+#[derive(Debug, Default)]
+pub struct Sample<'a> {
+    pub profile: &'a str,
+    pub up: bool,
+    /// Transfer of all peers combined, while up.
+    pub counters: Option<Counters>,
+    /// Unix time of the latest handshake, while up.
+    pub handshake: Option<u64>,
+}
+
+impl<'a> Sample<'a> {
+    pub fn read(profile: &'a Profile, netns: Option<&str>) -> Self {
+        let up = config::is_active(&profile.name, netns).unwrap_or(false);
+        if !up {
+            return Sample {
+                profile: &profile.name,
+                ..Default::default()
+            };
+        }
+        Sample {
+            profile: &profile.name,
+            up,
+            counters: config::transfer(&profile.name, netns).ok(),
+            handshake: config::last_handshake(&profile.name, netns)
+                .ok()
+                .flatten()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|age| age.as_secs()),
+        }
+    }
+}
+
+/// Escape a label value of the text format.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+struct Metric {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&Sample) -> Option<u64>,
+}
+
+const METRICS: [Metric; 4] = [
+    Metric {
+        name: "wg_waybar_up",
+        kind: "gauge",
+        help: "Whether the tunnel is up.",
+        value: |sample| Some(sample.up.into()),
+    },
+    Metric {
+        name: "wg_waybar_receive_bytes_total",
+        kind: "counter",
+        help: "Bytes received from all peers.",
+        value: |sample| sample.counters.map(|counters| counters.rx),
+    },
+    Metric {
+        name: "wg_waybar_transmit_bytes_total",
+        kind: "counter",
+        help: "Bytes sent to all peers.",
+        value: |sample| sample.counters.map(|counters| counters.tx),
+    },
+    Metric {
+        name: "wg_waybar_latest_handshake_seconds",
+        kind: "gauge",
+        help: "Unix time of the latest handshake with any peer.",
+        value: |sample| sample.handshake,
+    },
+];
+
+/// The samples in Prometheus' text exposition format, e.g. for node_exporter's
+/// textfile collector.
+pub fn render(samples: &[Sample]) -> String {
+    let mut text = String::new();
+    for metric in &METRICS {
+        let _ = writeln!(text, "# HELP {} {}", metric.name, metric.help);
+        let _ = writeln!(text, "# TYPE {} {}", metric.name, metric.kind);
+        for sample in samples {
+            if let Some(value) = (metric.value)(sample) {
+                let _ = writeln!(
+                    text,
+                    "{}{{profile=\"{}\"}} {}",
+                    metric.name,
+                    label(sample.profile),
+                    value
+                );
+            }
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_text_format() {
+        let samples = [
+            Sample {
+                profile: "work",
+                up: true,
+                counters: Some(Counters { rx: 10, tx: 20 }),
+                handshake: Some(1_700_000_000),
+            },
+            Sample {
+                profile: "ho\"me",
+                ..Default::default()
+            },
+        ];
+        let text = render(&samples);
+        assert!(text.contains("# TYPE wg_waybar_up gauge\n"));
+        assert!(text.contains("wg_waybar_up{profile=\"work\"} 1\n"));
+        assert!(text.contains("wg_waybar_up{profile=\"ho\\\"me\"} 0\n"));
+        assert!(text.contains("wg_waybar_receive_bytes_total{profile=\"work\"} 10\n"));
+        assert!(text.contains("wg_waybar_latest_handshake_seconds{profile=\"work\"} 1700000000\n"));
+        // Down tunnels only report `up`.
+        assert_eq!(text.matches("ho\\\"me").count(), 1);
+    }
+}
@@ -0,0 +1,182 @@
+use crate::error;
+use crate::paths;
+use crate::utils;
+use base64::prelude::*;
+use defguard_wireguard_rs::InterfaceConfiguration;
+use defguard_wireguard_rs::host::{Host, Peer};
+use defguard_wireguard_rs::key::Key;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Whether `--backend mock` was passed. Chosen once at start for the whole
+/// process, as every command reaching the kernel would otherwise need it.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Seconds between simulated handshakes, WireGuard's `REKEY_AFTER_TIME`.
+const REKEY_SECS: u64 = 120;
+/// Simulated throughput of each peer, in bytes per second.
+const RX_RATE: u64 = 180_000;
+const TX_RATE: u64 = 40_000;
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether tunnels are simulated rather than brought up.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// A simulated interface, kept as a file while "up".
+#[derive(Debug, Serialize, Deserialize)]
+struct Interface {
+    /// Unix time it came up, in seconds.
+    since: u64,
+    /// Of the interface's private key, which is not kept.
+    public_key: Option<String>,
+    listen_port: u32,
+    peers: Vec<MockPeer>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MockPeer {
+    public_key: String,
+    endpoint: Option<SocketAddr>,
+    allowed_ips: Vec<String>,
+}
+
+/// Directory of the simulated interfaces, and of the records otherwise shared
+/// by all users under `/run/wg-waybar`.
+pub fn dir() -> Result<PathBuf, error::Error> {
+    let dir = match paths::runtime_dir() {
+        Some(dir) => dir,
+        None => paths::state_dir()?,
+    };
+    paths::ensure(paths::ensure(dir)?.join("mock"))
+}
+
+fn path(interface_name: &str) -> Result<PathBuf, error::Error> {
+    Ok(dir()?.join(format!("{}.json", interface_name)))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Bytes received and sent, and seconds since the last handshake, `elapsed`
+/// seconds after coming up. The first handshake takes a second.
+fn simulate(elapsed: u64) -> (u64, u64, Option<u64>) {
+    let handshake_age = elapsed.checked_sub(1).map(|since| since % REKEY_SECS);
+    let active = elapsed.saturating_sub(1);
+    (active * RX_RATE, active * TX_RATE, handshake_age)
+}
+
+/// The public key of a base64 private key.
+fn public_key(private_key: &str) -> Option<String> {
+    let secret: [u8; 32] = BASE64_STANDARD.decode(private_key).ok()?.try_into().ok()?;
+    Some(BASE64_STANDARD.encode(PublicKey::from(&StaticSecret::from(secret)).as_bytes()))
+}
+
+/// "Bring up" the interface: remember its configuration, nothing else.
+pub fn up(
+    interface: &InterfaceConfiguration,
+    peers: &[Peer],
+    candidates: &[Vec<SocketAddr>],
+) -> Result<(), error::Error> {
+    let simulated = Interface {
+        since: now(),
+        public_key: public_key(&interface.prvkey),
+        listen_port: interface.port,
+        peers: peers
+            .iter()
+            .zip(candidates)
+            .map(|(peer, candidates)| MockPeer {
+                public_key: BASE64_STANDARD.encode(peer.public_key.as_slice()),
+                endpoint: candidates.first().copied(),
+                allowed_ips: peer
+                    .allowed_ips
+                    .iter()
+                    .map(|network| format!("{}/{}", network.ip, network.cidr))
+                    .collect(),
+            })
+            .collect(),
+    };
+    utils::fs_write(
+        path(&interface.name)?,
+        serde_json::to_string_pretty(&simulated)?,
+    )
+}
+
+pub fn down(interface_name: &str) -> Result<(), error::Error> {
+    let path = path(interface_name)?;
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+pub fn is_up(interface_name: &str) -> bool {
+    path(interface_name).is_ok_and(|path| path.exists())
+}
+
+fn key(base64: &str) -> Option<Key> {
+    let bytes: [u8; 32] = BASE64_STANDARD.decode(base64).ok()?.try_into().ok()?;
+    Some(Key::new(bytes))
+}
+
+/// What the kernel would report about the interface, with made-up counters
+/// and handshakes.
+pub fn read_host(interface_name: &str) -> Result<Host, error::Error> {
+    let path = path(interface_name)?;
+    if !path.exists() {
+        return Err(error::Error::WireGuardApi {
+            kind: error::ErrorKind::Other,
+            message: format!("{} is not up (mock)", interface_name),
+        });
+    }
+    let simulated: Interface = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    let (rx, tx, handshake_age) = simulate(now().saturating_sub(simulated.since));
+    let mut host = Host {
+        listen_port: simulated.listen_port as u16,
+        ..Host::default()
+    };
+    for mock_peer in &simulated.peers {
+        let Some(public_key) = key(&mock_peer.public_key) else {
+            continue;
+        };
+        let peer = Peer {
+            endpoint: mock_peer.endpoint,
+            allowed_ips: mock_peer
+                .allowed_ips
+                .iter()
+                .filter_map(|network| network.parse().ok())
+                .collect(),
+            last_handshake: handshake_age.map(|age| SystemTime::now() - Duration::from_secs(age)),
+            rx_bytes: rx,
+            tx_bytes: tx,
+            ..Peer::new(public_key.clone())
+        };
+        host.peers.insert(public_key, peer);
+    }
+    Ok(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simulates_handshakes_and_traffic() {
+        assert_eq!(simulate(0), (0, 0, None));
+        assert_eq!(simulate(1), (0, 0, Some(0)));
+        assert_eq!(simulate(11), (10 * RX_RATE, 10 * TX_RATE, Some(10)));
+        assert_eq!(simulate(1 + REKEY_SECS + 5).2, Some(5));
+    }
+}
@@ -0,0 +1,225 @@
+use crate::error;
+use crate::trace;
+use crate::utils;
+use defguard_wireguard_rs::net::IpAddrMask;
+use std::ffi::OsString;
+use std::fs::File;
+use std::net::IpAddr;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const NETNS_DIR: &str = "/run/netns";
+const NETNS_ETC_DIR: &str = "/etc/netns";
+/// First line of the `resolv.conf` written for a namespace, so that teardown
+/// removes only that one.
+const RESOLV_CONF_HEADER: &str = "# Written by wg-waybar, removed with the tunnel";
+
+fn ip(args: &[&str]) -> Result<(), error::Error> {
+    utils::run_ip(args).map_err(error::Error::Netns)
+}
+
+fn path(name: &str) -> PathBuf {
+    Path::new(NETNS_DIR).join(name)
+}
+
+pub fn exists(name: &str) -> bool {
+    path(name).exists()
+}
+
+/// Create the named network namespace unless it already exists.
+pub fn ensure(name: &str) -> Result<(), error::Error> {
+    supported()?;
+    if !exists(name) {
+        ip(&["netns", "add", name])?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set(file: &File) -> Result<(), error::Error> {
+    if unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error().into())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set(_file: &File) -> Result<(), error::Error> {
+    supported()
+}
+
+/// Network namespaces, `/proc/self/ns` and `ip netns` are Linux's.
+fn supported() -> Result<(), error::Error> {
+    if cfg!(target_os = "linux") {
+        Ok(())
+    } else {
+        Err(error::Error::Netns(
+            "network namespaces are only supported on Linux".to_string(),
+        ))
+    }
+}
+
+/// Switches the thread back into the namespace it left, at the latest when
+/// dropped, so that a panic of the code run inside does not leave it there.
+struct Restore(Option<File>);
+
+impl Restore {
+    fn finish(mut self) -> Result<(), error::Error> {
+        match self.0.take() {
+            Some(file) => set(&file),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if let Some(file) = self.0.take() {
+            let _ = set(&file);
+        }
+    }
+}
+
+/// Run `f` with the current thread switched into the named network namespace.
+pub fn with<T>(name: &str, f: impl FnOnce() -> T) -> Result<T, error::Error> {
+    supported()?;
+    trace::untraced("switching network namespaces")?;
+    let restore = Restore(Some(File::open("/proc/self/ns/net")?));
+    let target = File::open(path(name))?;
+    set(&target)?;
+    let result = f();
+    restore.finish()?;
+    Ok(result)
+}
+
+pub fn move_interface(interface_name: &str, name: &str) -> Result<(), error::Error> {
+    ip(&["link", "set", "dev", interface_name, "netns", name])
+}
+
+/// Bring the moved interface up inside the namespace.
+///
+/// Moving a link drops its addresses, so they are assigned again here. Every
+/// AllowedIPs route goes through the tunnel since the namespace has no other uplink.
+pub fn configure(
+    name: &str,
+    interface_name: &str,
+    addresses: &[IpAddrMask],
+    allowed_ips: &[IpAddrMask],
+    dns: &[IpAddr],
+) -> Result<(), error::Error> {
+    ip(&["-n", name, "link", "set", "lo", "up"])?;
+    for address in addresses {
+        let address = format!("{}/{}", address.ip, address.cidr);
+        ip(&[
+            "-n",
+            name,
+            "address",
+            "add",
+            &address,
+            "dev",
+            interface_name,
+        ])?;
+    }
+    ip(&["-n", name, "link", "set", interface_name, "up"])?;
+    for allowed_ip in allowed_ips {
+        let destination = format!("{}/{}", allowed_ip.ip, allowed_ip.cidr);
+        ip(&[
+            "-n",
+            name,
+            "route",
+            "replace",
+            &destination,
+            "dev",
+            interface_name,
+        ])?;
+    }
+    if !dns.is_empty() {
+        trace::untraced("the DNS servers of a network namespace")?;
+        // `ip netns exec` bind-mounts this file over /etc/resolv.conf.
+        let dir = Path::new(NETNS_ETC_DIR).join(name);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("resolv.conf"), resolv_conf(dns))?;
+    }
+    Ok(())
+}
+
+fn resolv_conf(dns: &[IpAddr]) -> String {
+    let mut content = format!("{}\n", RESOLV_CONF_HEADER);
+    for server in dns {
+        content.push_str(&format!("nameserver {}\n", server));
+    }
+    content
+}
+
+/// Remove the `resolv.conf` [`configure`] wrote in `dir`, and `dir` once
+/// empty, leaving one written by someone else.
+fn remove_resolv_conf(dir: &Path) {
+    let path = dir.join("resolv.conf");
+    let ours = std::fs::read_to_string(&path)
+        .is_ok_and(|content| content.lines().next() == Some(RESOLV_CONF_HEADER));
+    if ours {
+        let _ = std::fs::remove_file(path);
+        // Fails while anything else is left in it.
+        let _ = std::fs::remove_dir(dir);
+    }
+}
+
+/// Undo what [`configure`] set up outside the namespace, once the interface is removed.
+pub fn teardown(name: &str) {
+    remove_resolv_conf(&Path::new(NETNS_ETC_DIR).join(name));
+}
+
+/// `command`, to run inside the namespace `name` when one is given.
+///
+/// When invoked through sudo, the command runs as the invoking user.
+pub fn command(name: Option<&str>, command: &[String]) -> Command {
+    let mut argv: Vec<OsString> = Vec::new();
+    if let Some(name) = name {
+        argv.extend(["ip", "netns", "exec", name].map(OsString::from));
+    }
+    if let Some(user) = utils::get_environ("SUDO_USER") {
+        argv.extend(["runuser".into(), "-u".into(), user, "--".into()]);
+    }
+    argv.extend(command.iter().map(OsString::from));
+    let mut cmd = Command::new(&argv[0]);
+    cmd.args(&argv[1..]);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_only_the_resolv_conf_it_wrote() {
+        let dir = std::env::temp_dir().join(format!("wg-waybar-netns-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dns = ["10.2.0.1".parse().unwrap()];
+        assert_eq!(
+            resolv_conf(&dns),
+            format!("{}\nnameserver 10.2.0.1\n", RESOLV_CONF_HEADER)
+        );
+
+        std::fs::write(dir.join("resolv.conf"), "nameserver 192.0.2.53\n").unwrap();
+        remove_resolv_conf(&dir);
+        assert!(dir.join("resolv.conf").exists());
+
+        std::fs::write(dir.join("resolv.conf"), resolv_conf(&dns)).unwrap();
+        remove_resolv_conf(&dir);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn runs_commands_inside_the_namespace() {
+        let command = command(
+            Some("vpn"),
+            &["curl".to_string(), "ifconfig.me".to_string()],
+        );
+        assert_eq!(command.get_program(), "ip");
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args[..3], ["netns", "exec", "vpn"]);
+        assert_eq!(args[args.len() - 2..], ["curl", "ifconfig.me"]);
+    }
+}
@@ -0,0 +1,79 @@
+use crate::audit::Requester;
+use crate::paths;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Who brought each interface up, shared by all users unlike the state
+/// directories, which live in each user's home.
+const OWNERS_DIR: &str = "owners";
+
+fn path(dir: &Path, interface_name: &str) -> PathBuf {
+    dir.join(interface_name)
+}
+
+/// Record the invoking user as the owner of the interface just brought up.
+///
+/// Tunnels root brings up, e.g. in daemon mode, are shared and get no record.
+pub fn claim(interface_name: &str) -> std::io::Result<()> {
+    let uid = Requester::invoking().uid;
+    if uid == 0 {
+        release(interface_name);
+        return Ok(());
+    }
+    let dir = paths::shared_dir(OWNERS_DIR);
+    fs::create_dir_all(&dir)?;
+    fs::write(path(&dir, interface_name), uid.to_string())
+}
+
+pub fn release(interface_name: &str) {
+    let _ = fs::remove_file(path(&paths::shared_dir(OWNERS_DIR), interface_name));
+}
+
+fn owner_uid(dir: &Path, interface_name: &str) -> Option<u32> {
+    fs::read_to_string(path(dir, interface_name))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn other_than(dir: &Path, interface_name: &str, uid: u32) -> Option<u32> {
+    // Root, e.g. daemon mode, acts for everyone.
+    if uid == 0 {
+        return None;
+    }
+    // Nor is a tunnel root brought up anyone's, should a record say so.
+    owner_uid(dir, interface_name).filter(|&owner| owner != uid && owner != 0)
+}
+
+/// The user who brought the interface up, if another than the invoking one.
+///
+/// Only meaningful while the interface is up, the record of a tunnel brought
+/// down by something else being left behind.
+pub fn other(interface_name: &str) -> Option<Requester> {
+    other_than(
+        &paths::shared_dir(OWNERS_DIR),
+        interface_name,
+        Requester::invoking().uid,
+    )
+    .map(Requester::from_uid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_tunnels_of_other_users() {
+        let dir = std::env::temp_dir().join(format!("wg-waybar-owners-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(path(&dir, "wg0"), "1000\n").unwrap();
+        assert_eq!(other_than(&dir, "wg0", 1001), Some(1000));
+        assert_eq!(other_than(&dir, "wg0", 1000), None);
+        assert_eq!(other_than(&dir, "wg0", 0), None);
+        assert_eq!(other_than(&dir, "wg1", 1001), None);
+        fs::write(path(&dir, "wg2"), "0\n").unwrap();
+        assert_eq!(other_than(&dir, "wg2", 1000), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
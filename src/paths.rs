@@ -0,0 +1,73 @@
+use crate::error;
+use crate::mock;
+use crate::utils;
+use std::path::{Path, PathBuf};
+use uzers::get_current_uid;
+
+const APP_NAME: &str = "wg-waybar";
+/// Records shared by all users: interface names, owners, runs.
+const SHARED_DIR: &str = "/run/wg-waybar";
+
+fn runtime_dir_impl(
+    user_runtime_dir: Option<PathBuf>,
+    uid: u32,
+    exists: impl Fn(&Path) -> bool,
+) -> Option<PathBuf> {
+    match user_runtime_dir {
+        Some(dir) if exists(&dir) => Some(dir.join(APP_NAME)),
+        // Root without a session, e.g. a systemd unit.
+        _ if uid == 0 => Some(Path::new("/run").join(APP_NAME)),
+        _ => None,
+    }
+}
+
+/// Directory for what only lasts as long as the session: transient state and
+/// fetched configurations. `$XDG_RUNTIME_DIR/wg-waybar` of the invoking user, or
+/// `/run/wg-waybar` for root without a session.
+pub fn runtime_dir() -> Option<PathBuf> {
+    runtime_dir_impl(utils::user_runtime_dir(), get_current_uid(), Path::exists)
+}
+
+/// Directory for what must survive a reboot: active profiles, history, accounts.
+pub fn state_dir() -> Result<PathBuf, error::Error> {
+    utils::get_state_home(APP_NAME)
+        .map_err(|e| error::Error::UnCaught(error::UnCaughtError(e.to_string())))
+}
+
+/// Directory `name` of the records shared by all users, under `/run/wg-waybar`,
+/// or of the simulated tunnels with `--backend mock`, which must not need root.
+pub fn shared_dir(name: &str) -> PathBuf {
+    if mock::enabled()
+        && let Ok(dir) = mock::dir()
+    {
+        return dir.join(name);
+    }
+    Path::new(SHARED_DIR).join(name)
+}
+
+/// Create `dir` if missing, owned by the invoking user, and return it.
+pub fn ensure(dir: PathBuf) -> Result<PathBuf, error::Error> {
+    if !dir.exists() {
+        utils::fs_create_dir(dir.clone())?;
+    }
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn places_runtime_dir_in_session_or_run() {
+        let session = Some(PathBuf::from("/run/user/1000"));
+        assert_eq!(
+            runtime_dir_impl(session.clone(), 1000, |_| true),
+            Some(PathBuf::from("/run/user/1000/wg-waybar"))
+        );
+        assert_eq!(
+            runtime_dir_impl(session, 0, |_| false),
+            Some(PathBuf::from("/run/wg-waybar"))
+        );
+        assert_eq!(runtime_dir_impl(None, 1000, |_| true), None);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::config;
+use crate::error;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::time::Duration;
+
+/// Time given to an ICMP port unreachable to come back.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Send a datagram to `endpoint` and wait briefly for an error.
+///
+/// WireGuard does not answer what is not a handshake, so silence is as good as
+/// it gets; what is caught is a missing route or a closed port, which the
+/// kernel reports on the connected socket.
+fn probe(endpoint: SocketAddr, timeout: Duration) -> Result<(), String> {
+    let bind: SocketAddr = match endpoint {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let socket = UdpSocket::bind(bind).map_err(|e| e.to_string())?;
+    socket.connect(endpoint).map_err(|e| e.to_string())?;
+    socket
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| e.to_string())?;
+    socket.send(&[0]).map_err(|e| e.to_string())?;
+    match socket.recv(&mut [0u8; 64]) {
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            Err("nothing listens on this port".to_string())
+        }
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(()),
+        Err(e) => Err(e.to_string()),
+        Ok(_) => Ok(()),
+    }
+}
+
+/// Check that the endpoints of a profile resolve and are reachable, before a
+/// switch tears down the tunnel in use, returning how many were checked: none
+/// for a profile that only listens, whose peers connect to it.
+pub fn endpoints(config_path: &Path) -> Result<usize, error::Error> {
+    let endpoints = config::configured_endpoints(config_path)?;
+    for endpoint in &endpoints {
+        probe(*endpoint, PROBE_TIMEOUT)
+            .map_err(|e| error::Error::Probe(format!("endpoint {}: {}", endpoint, e)))?;
+    }
+    Ok(endpoints.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_closed_ports_from_silent_ones() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert_eq!(probe(silent.local_addr().unwrap(), PROBE_TIMEOUT), Ok(()));
+
+        let closed = silent.local_addr().unwrap();
+        drop(silent);
+        assert!(probe(closed, PROBE_TIMEOUT).is_err());
+    }
+
+    #[test]
+    fn passes_profiles_that_only_listen() {
+        let path =
+            std::env::temp_dir().join(format!("wg-waybar-listen-{}.conf", std::process::id()));
+        std::fs::write(
+            &path,
+            "[Interface]\nPrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=\nAddress = 10.0.0.1/24\nListenPort = 51820\n\n[Peer]\nPublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\nAllowedIPs = 10.0.0.2/32\n",
+        )
+        .unwrap();
+        assert_eq!(endpoints(&path).unwrap(), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,380 @@
+use crate::config;
+use crate::encrypted;
+use crate::error;
+use crate::expiry;
+use crate::ifname;
+use crate::toml_profile;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Suffixes of the profile formats, in order of precedence when profiles of
+/// different formats share a name.
+const FORMATS: &[&str] = &[".conf", encrypted::SUFFIX, ".toml"];
+
+/// Name and precedence of a profile file, `None` for other files.
+fn format_of(path: &Path) -> Option<(&str, usize)> {
+    let file_name = path.file_name()?.to_str()?;
+    FORMATS.iter().enumerate().find_map(|(precedence, suffix)| {
+        file_name
+            .strip_suffix(suffix)
+            .filter(|name| !name.is_empty())
+            .map(|name| (name, precedence))
+    })
+}
+
+/// A WireGuard configuration file; its name without the format suffix names
+/// the interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    /// Name of the interface, the stem unless `--interface-name` gives another.
+    pub name: String,
+    /// File name without the format suffix.
+    pub stem: String,
+    pub path: PathBuf,
+}
+
+impl Profile {
+    pub fn from_path(path: &Path) -> Result<Self, error::Error> {
+        let name = format_of(path)
+            .map(|(name, _)| name)
+            .or_else(|| path.file_stem().and_then(|stem| stem.to_str()))
+            .ok_or_else(|| error::Error::InvalidFormat {
+                message: "Invalid config file name".to_string(),
+            })?;
+        Ok(Self {
+            name: name.to_string(),
+            stem: name.to_string(),
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// `# Tags = streaming, eu` of the profile, in the order given.
+    pub fn tags(&self) -> Vec<String> {
+        header(&self.path, "Tags")
+            .map(|tags| {
+                tags.split(',')
+                    .map(str::trim)
+                    .filter(|tag| !tag.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// `# Note = ...` of the profile, a line of free text about it.
+    pub fn note(&self) -> Option<String> {
+        header(&self.path, "Note")
+    }
+
+    /// `# Expires = 2025-07-01` of the profile, an error when it is no date.
+    pub fn expires(&self) -> Result<Option<expiry::Date>, String> {
+        header(&self.path, "Expires")
+            .map(|value| value.parse())
+            .transpose()
+    }
+
+    /// `# RenewCommand = ...` printing a new configuration once the profile expired.
+    pub fn renew_command(&self) -> Option<String> {
+        header(&self.path, "RenewCommand")
+    }
+
+    /// `# HealthCheck = <url>` requested through the tunnel once it is up.
+    pub fn health_check(&self) -> Option<String> {
+        header(&self.path, "HealthCheck")
+    }
+}
+
+/// Which profiles of a directory are considered, from `--match` and `--tag`,
+/// and how their interfaces are named.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Filter<'a> {
+    /// Glob the name must match.
+    pub pattern: Option<&'a str>,
+    /// Tag the profile must carry, compared case-insensitively.
+    pub tag: Option<&'a str>,
+    /// `--interface-name` template, the file stem naming the interface when unset.
+    pub interface_template: Option<&'a str>,
+}
+
+impl Filter<'_> {
+    fn accepts(&self, profile: &Profile) -> bool {
+        self.pattern
+            .is_none_or(|pattern| glob_match(pattern, &profile.name))
+            && self.tag.is_none_or(|tag| {
+                profile
+                    .tags()
+                    .iter()
+                    .any(|candidate| candidate.eq_ignore_ascii_case(tag))
+            })
+    }
+
+    /// Name the interface of `profile` after the template, once accepted, past
+    /// the names `given` to other profiles.
+    fn name(&self, mut profile: Profile, given: &[String]) -> Result<Profile, error::Error> {
+        if let Some(template) = self.interface_template {
+            profile.name = ifname::assign(template, &profile.stem, &profile.path, given)?;
+        }
+        Ok(profile)
+    }
+}
+
+/// Directory holding the profiles: `config` itself, or the directory of the given file.
+pub fn directory(config: &Path) -> &Path {
+    if config.is_dir() {
+        config
+    } else {
+        config.parent().unwrap_or(Path::new("."))
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any run of
+/// characters and `?` for a single one.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    // Position after the last `*` and the name position it was tried at.
+    let (mut p, mut n, mut star) = (0, 0, None);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((after, tried)) => {
+                    p = after;
+                    n = tried + 1;
+                    star = Some((after, tried + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Value of a `# <key> = <value>` comment of a profile.
+fn header(path: &Path, key: &str) -> Option<String> {
+    let content = std::fs::read_to_string(path).ok()?;
+    // Fields of a TOML profile, like `tags`, stand for headers.
+    if path.extension().is_some_and(|ext| ext == "toml")
+        && let Some(value) = toml_profile::header(&content, key)
+    {
+        return Some(value);
+    }
+    content
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix('#'))
+        .filter_map(|comment| comment.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case(key))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+/// `# Priority = <n>` of a profile, 0 without one.
+fn priority(path: &Path) -> i64 {
+    header(path, "Priority")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+/// The profile files among `paths` by name, in order of precedence.
+fn by_name(paths: Vec<PathBuf>) -> BTreeMap<String, Vec<PathBuf>> {
+    let mut by_name: BTreeMap<String, Vec<(usize, PathBuf)>> = BTreeMap::new();
+    for path in paths {
+        if let Some((name, precedence)) = format_of(&path) {
+            by_name
+                .entry(name.to_string())
+                .or_default()
+                .push((precedence, path));
+        }
+    }
+    by_name
+        .into_iter()
+        .map(|(name, mut candidates)| {
+            candidates.sort();
+            (name, candidates.into_iter().map(|(_, path)| path).collect())
+        })
+        .collect()
+}
+
+/// The file of each profile name among `paths`.
+fn by_precedence(paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    by_name(paths)
+        .into_values()
+        .map(|mut candidates| candidates.swap_remove(0))
+        .collect()
+}
+
+fn files(dir: &Path) -> Result<Vec<PathBuf>, error::Error> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// The names of `dir` several formats are given for, each with its files, the
+/// one used first.
+pub fn shadowed(dir: &Path) -> Result<Vec<(String, Vec<PathBuf>)>, error::Error> {
+    Ok(by_name(files(dir)?)
+        .into_iter()
+        .filter(|(_, candidates)| candidates.len() > 1)
+        .collect())
+}
+
+/// `*.conf`, `*.conf.age` and `*.toml` files of `dir` accepted by `filter`.
+///
+/// Profiles with a higher `# Priority =` come first, then by name.
+pub fn discover(dir: &Path, filter: Filter) -> Result<Vec<Profile>, error::Error> {
+    let mut profiles = Vec::new();
+    let mut given = Vec::new();
+    for path in by_precedence(files(dir)?) {
+        let profile = Profile::from_path(&path)?;
+        if filter.accepts(&profile) {
+            let profile = filter.name(profile, &given)?;
+            given.push(profile.name.clone());
+            profiles.push(profile);
+        }
+    }
+    profiles.sort_by_cached_key(|profile| {
+        (
+            std::cmp::Reverse(priority(&profile.path)),
+            profile.name.clone(),
+        )
+    });
+    Ok(profiles)
+}
+
+/// The profile the module is about.
+///
+/// A file is used as is. For a directory, the first profile accepted by `filter`
+/// whose interface is up is selected, falling back to the first one.
+pub fn select(config: &Path, netns: Option<&str>, filter: Filter) -> Result<Profile, error::Error> {
+    if !config.is_dir() {
+        return filter.name(Profile::from_path(config)?, &[]);
+    }
+    let profiles = discover(config, filter)?;
+    let active = profiles
+        .iter()
+        .find(|profile| config::is_active(&profile.name, netns).unwrap_or(false));
+    active
+        .or(profiles.first())
+        .cloned()
+        .ok_or_else(|| error::Error::InvalidFormat {
+            message: format!("No matching .conf profile in {}", config.display()),
+        })
+}
+
+/// The profile named `name`, or whose interface is.
+pub fn find<'a>(profiles: &'a [Profile], name: &str) -> Result<&'a Profile, error::Error> {
+    profiles
+        .iter()
+        .find(|profile| profile.stem == name)
+        .or_else(|| profiles.iter().find(|profile| profile.name == name))
+        .ok_or_else(|| error::Error::InvalidFormat {
+            message: format!("Unknown profile: {}", name),
+        })
+}
+
+/// The profile after `current` in order, wrapping around.
+pub fn next<'a>(profiles: &'a [Profile], current: &str) -> Option<&'a Profile> {
+    let position = profiles.iter().position(|profile| profile.name == current);
+    let index = position.map_or(0, |position| (position + 1) % profiles.len());
+    profiles.get(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("mullvad-*", "mullvad-se-sto"));
+        assert!(glob_match("*-se-*", "mullvad-se-sto"));
+        assert!(glob_match("wg?", "wg0"));
+        assert!(!glob_match("wg?", "wg10"));
+        assert!(!glob_match("mullvad-*", "proton-se"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn prefers_conf_over_other_formats_of_the_same_name() {
+        let paths = [
+            "office.toml",
+            "home.conf.age",
+            "office.conf",
+            "office.conf.age",
+            "notes.txt",
+        ];
+        let chosen = by_precedence(paths.iter().map(PathBuf::from).collect());
+        assert_eq!(
+            chosen,
+            [PathBuf::from("home.conf.age"), PathBuf::from("office.conf")]
+        );
+        assert_eq!(
+            by_name(paths.iter().map(PathBuf::from).collect())["office"],
+            [
+                PathBuf::from("office.conf"),
+                PathBuf::from("office.conf.age"),
+                PathBuf::from("office.toml")
+            ]
+        );
+        assert_eq!(
+            Profile::from_path(Path::new("/etc/wireguard/home.conf.age"))
+                .unwrap()
+                .name,
+            "home"
+        );
+    }
+
+    #[test]
+    fn finds_profiles_by_name_or_interface() {
+        let profiles = [Profile {
+            name: "wg-work-0".to_string(),
+            ..Profile::from_path(Path::new("/etc/wireguard/work.conf")).unwrap()
+        }];
+        assert_eq!(find(&profiles, "work").unwrap().name, "wg-work-0");
+        assert_eq!(find(&profiles, "wg-work-0").unwrap().stem, "work");
+        assert!(find(&profiles, "home").is_err());
+    }
+
+    #[test]
+    fn filters_profiles_by_tag() {
+        let dir = std::env::temp_dir().join(format!("wg-waybar-tags-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("wg-nl.conf"),
+            "# Tags = Streaming, eu\n# Note = Netflix NL only\n[Interface]\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("wg-us.conf"), "[Interface]\n").unwrap();
+
+        let filter = Filter {
+            pattern: None,
+            tag: Some("streaming"),
+            interface_template: None,
+        };
+        let profiles = discover(&dir, filter).unwrap();
+        assert_eq!(profiles.len(), 1);
+        assert_eq!(profiles[0].tags(), ["Streaming", "eu"]);
+        assert_eq!(profiles[0].note().as_deref(), Some("Netflix NL only"));
+
+        let toml = dir.join("wg-se.toml");
+        std::fs::write(
+            &toml,
+            "tags = [\"eu\"]\nnote = \"Backup\"\n[interface]\nprivate_key = \"key\"\naddress = []\n",
+        )
+        .unwrap();
+        let toml = Profile::from_path(&toml).unwrap();
+        assert_eq!(toml.tags(), ["eu"]);
+        assert_eq!(toml.note().as_deref(), Some("Backup"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
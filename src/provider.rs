@@ -0,0 +1,527 @@
+use crate::actions::{self, Context};
+use crate::error;
+use crate::http;
+use crate::import::{self, Section};
+use crate::profiles::{self, Profile};
+use crate::utils;
+use base64::prelude::*;
+use ini::Ini;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const MULLVAD_RELAYS_URL: &str = "https://api.mullvad.net/public/relays/wireguard/v2";
+const MULLVAD_KEYS_URL: &str = "https://api.mullvad.net/wg/";
+/// Interface name of the generated profile.
+pub const MULLVAD_PROFILE: &str = "mullvad";
+const PROTON_LOGICALS_URL: &str = "https://api.protonvpn.ch/vpn/logicals";
+pub const PROTON_PROFILE: &str = "protonvpn";
+/// What every ProtonVPN configuration has, the key being what tells devices apart.
+const PROTON_DNS: &str = "10.2.0.1";
+/// Port of the relays of both providers.
+const WIREGUARD_PORT: u16 = 51820;
+/// Comment in the generated profile naming the relay, read back to rotate.
+const RELAY_COMMENT: &str = "# Relay = ";
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+fn provider_error(e: impl std::fmt::Display) -> error::Error {
+    error::Error::Provider(e.to_string())
+}
+
+fn agent() -> ureq::Agent {
+    http::agent(TIMEOUT)
+}
+
+#[derive(Debug, Deserialize)]
+struct Location {
+    city: String,
+    country: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Relay {
+    hostname: String,
+    /// Location code, e.g. `se-sto`.
+    location: String,
+    #[serde(default = "default_active")]
+    active: bool,
+    ipv4_addr_in: String,
+    public_key: String,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+struct WireGuardRelays {
+    relays: Vec<Relay>,
+    ipv4_gateway: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RelayList {
+    locations: HashMap<String, Location>,
+    wireguard: WireGuardRelays,
+}
+
+impl RelayList {
+    fn fetch() -> Result<Self, error::Error> {
+        agent()
+            .get(MULLVAD_RELAYS_URL)
+            .call()
+            .map_err(provider_error)?
+            .body_mut()
+            .read_json()
+            .map_err(provider_error)
+    }
+
+    fn matching(&self, selector: &str) -> Vec<&Relay> {
+        matching(&self.wireguard.relays, selector)
+    }
+}
+
+/// Active relays of a location (`se`, `se-sto`) or the relay of that hostname.
+fn matching<'a>(relays: &'a [Relay], selector: &str) -> Vec<&'a Relay> {
+    relays
+        .iter()
+        .filter(|relay| relay.active)
+        .filter(|relay| {
+            relay.hostname.eq_ignore_ascii_case(selector)
+                || relay.location.eq_ignore_ascii_case(selector)
+                || relay
+                    .location
+                    .split('-')
+                    .next()
+                    .is_some_and(|country| country.eq_ignore_ascii_case(selector))
+        })
+        .collect()
+}
+
+/// A physical server of a ProtonVPN logical one.
+#[derive(Debug, Deserialize)]
+struct ProtonServer {
+    #[serde(rename = "EntryIP")]
+    entry_ip: String,
+    #[serde(rename = "X25519PublicKey")]
+    public_key: Option<String>,
+    #[serde(rename = "Status")]
+    status: u8,
+}
+
+/// A server as ProtonVPN lists it, e.g. `CH#1`.
+#[derive(Debug, Deserialize)]
+struct LogicalServer {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "ExitCountry")]
+    exit_country: String,
+    #[serde(rename = "City")]
+    city: Option<String>,
+    #[serde(rename = "Status")]
+    status: u8,
+    #[serde(rename = "Servers")]
+    servers: Vec<ProtonServer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Logicals {
+    #[serde(rename = "LogicalServers")]
+    logical_servers: Vec<LogicalServer>,
+}
+
+impl Logicals {
+    fn fetch() -> Result<Self, error::Error> {
+        agent()
+            .get(PROTON_LOGICALS_URL)
+            .call()
+            .map_err(provider_error)?
+            .body_mut()
+            .read_json()
+            .map_err(provider_error)
+    }
+
+    /// The servers as relays located by country (`ch`), through their first
+    /// physical server with a WireGuard key.
+    fn relays(&self) -> Vec<Relay> {
+        self.logical_servers
+            .iter()
+            .filter_map(|logical| {
+                let server = logical
+                    .servers
+                    .iter()
+                    .find(|server| server.public_key.is_some())?;
+                Some(Relay {
+                    hostname: logical.name.clone(),
+                    location: logical.exit_country.to_lowercase(),
+                    active: logical.status == 1 && server.status == 1,
+                    ipv4_addr_in: server.entry_ip.clone(),
+                    public_key: server.public_key.clone()?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Pick a relay among `relays`, avoiding `current` when there is another one.
+fn pick<'a>(relays: &[&'a Relay], current: Option<&str>, seed: u32) -> Option<&'a Relay> {
+    let others: Vec<&Relay> = relays
+        .iter()
+        .copied()
+        .filter(|relay| Some(relay.hostname.as_str()) != current)
+        .collect();
+    let candidates = if others.is_empty() { relays } else { &others };
+    candidates
+        .get(seed as usize % candidates.len().max(1))
+        .copied()
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N], error::Error> {
+    let mut bytes = [0u8; N];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Device key and tunnel addresses registered with the account.
+struct Device {
+    private_key: String,
+    addresses: String,
+}
+
+impl Device {
+    /// Reuse the key of the existing profile, so rotating does not use up devices.
+    fn from_profile(path: &Path) -> Option<Self> {
+        let config = Ini::load_from_file(path).ok()?;
+        let interface = config.section(Some("Interface"))?;
+        Some(Self {
+            private_key: interface.get("PrivateKey")?.to_string(),
+            addresses: interface.get("Address")?.to_string(),
+        })
+    }
+
+    fn register(account: &str) -> Result<Self, error::Error> {
+        let mut secret = random_bytes::<32>()?;
+        // Clamped as `wg genkey` does.
+        secret[0] &= 248;
+        secret[31] = (secret[31] & 127) | 64;
+        let public_key = PublicKey::from(&StaticSecret::from(secret));
+        let addresses = agent()
+            .post(MULLVAD_KEYS_URL)
+            .send_form([
+                ("account", account),
+                ("pubkey", &BASE64_STANDARD.encode(public_key.as_bytes())),
+            ])
+            .map_err(provider_error)?
+            .body_mut()
+            .read_to_string()
+            .map_err(provider_error)?;
+        Ok(Self {
+            private_key: BASE64_STANDARD.encode(secret),
+            addresses: addresses.trim().replace(',', ", "),
+        })
+    }
+}
+
+/// Where an account number is kept once saved with `--save-account`.
+fn account_path() -> Result<PathBuf, error::Error> {
+    Ok(utils::get_state_home("wg-waybar")
+        .map_err(|e| error::Error::UnCaught(error::UnCaughtError(e.to_string())))?
+        .join("mullvad-account"))
+}
+
+/// Account number from the command line, else from the saved file.
+fn account(account: Option<&str>, save: bool) -> Result<Option<String>, error::Error> {
+    let path = account_path()?;
+    if let Some(account) = account {
+        if save {
+            // Created 0600 rather than narrowed once written.
+            utils::replace_file(&path, account, 0o600)?;
+        }
+        return Ok(Some(account.to_string()));
+    }
+    Ok(fs::read_to_string(path)
+        .ok()
+        .map(|account| account.trim().to_string()))
+}
+
+fn current_relay(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix(RELAY_COMMENT))
+        .map(str::to_string)
+}
+
+fn render(device: &Device, relay: &Relay, dns: &str) -> String {
+    let interface: Section = vec![
+        ("PrivateKey", Some(device.private_key.clone())),
+        ("Address", Some(device.addresses.clone())),
+        ("DNS", Some(dns.to_string())),
+    ];
+    let peer: Section = vec![
+        ("PublicKey", Some(relay.public_key.clone())),
+        ("AllowedIPs", Some("0.0.0.0/0, ::/0".to_string())),
+        (
+            "Endpoint",
+            Some(format!("{}:{}", relay.ipv4_addr_in, WIREGUARD_PORT)),
+        ),
+    ];
+    format!(
+        "{}{}\n{}",
+        RELAY_COMMENT,
+        relay.hostname,
+        import::render(interface, vec![peer])
+    )
+}
+
+fn write_profile(path: &Path, content: &str) -> Result<(), error::Error> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+/// Print the relays matching `selector`, or every location without one.
+pub fn list(selector: Option<&str>) -> Result<(), error::Error> {
+    let relays = RelayList::fetch()?;
+    match selector {
+        Some(selector) => {
+            for relay in relays.matching(selector) {
+                println!("{}\t{}", relay.hostname, relay.ipv4_addr_in);
+            }
+        }
+        None => {
+            let mut locations: Vec<_> = relays.locations.iter().collect();
+            locations.sort_by_key(|(code, _)| code.as_str());
+            for (code, location) in locations {
+                println!("{}\t{}, {}", code, location.city, location.country);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write the `mullvad` profile for a relay of `selector` and (re)connect it.
+///
+/// The device key of an existing profile is kept; a new one is registered with
+/// the account otherwise.
+pub fn connect(
+    ctx: &Context,
+    dir: &Path,
+    selector: &str,
+    account_number: Option<&str>,
+    save_account: bool,
+) -> Result<(), error::Error> {
+    let path = dir.join(format!("{}.conf", MULLVAD_PROFILE));
+    let relays = RelayList::fetch()?;
+    let current = current_relay(&path);
+    let seed = u32::from_ne_bytes(random_bytes()?);
+    let relay = pick(&relays.matching(selector), current.as_deref(), seed)
+        .ok_or_else(|| provider_error(format!("no active relay matches {}", selector)))?;
+    let device = match Device::from_profile(&path) {
+        Some(device) => device,
+        None => {
+            let account = account(account_number, save_account)?.ok_or_else(|| {
+                provider_error("an account number is needed to register a key, pass --account")
+            })?;
+            Device::register(&account)?
+        }
+    };
+    switch_to(
+        ctx,
+        dir,
+        MULLVAD_PROFILE,
+        relay,
+        &render(&device, relay, &relays.wireguard.ipv4_gateway),
+    )
+}
+
+/// Write the profile `name` of `dir` for `relay` and (re)connect it.
+fn switch_to(
+    ctx: &Context,
+    dir: &Path,
+    name: &str,
+    relay: &Relay,
+    content: &str,
+) -> Result<(), error::Error> {
+    let path = dir.join(format!("{}.conf", name));
+    if ctx.debug {
+        println!("Using relay {}", relay.hostname);
+    }
+    let profile = Profile::from_path(&path)?;
+    // The peer changes, so a running tunnel is brought down first.
+    actions::ensure(ctx, &profile, false)?;
+    write_profile(&path, content)?;
+    let profiles = profiles::discover(dir, profiles::Filter::default())?;
+    actions::switch(ctx, &profiles, name)
+}
+
+/// Move to another relay of the location of the current one.
+pub fn rotate(ctx: &Context, dir: &Path) -> Result<(), error::Error> {
+    let path = dir.join(format!("{}.conf", MULLVAD_PROFILE));
+    let current = current_relay(&path)
+        .ok_or_else(|| provider_error("no Mullvad profile to rotate, run connect first"))?;
+    let location = current
+        .rsplit_once("-wg-")
+        .map(|(location, _)| location.to_string())
+        .unwrap_or(current);
+    connect(ctx, dir, &location, None, false)
+}
+
+/// Print the ProtonVPN servers of a country (`ch`), or every country without one.
+pub fn proton_list(country: Option<&str>) -> Result<(), error::Error> {
+    let logicals = Logicals::fetch()?;
+    match country {
+        Some(country) => {
+            let relays = logicals.relays();
+            for relay in matching(&relays, country) {
+                println!("{}\t{}", relay.hostname, relay.ipv4_addr_in);
+            }
+        }
+        None => {
+            let mut countries: Vec<(String, Option<&str>)> = logicals
+                .logical_servers
+                .iter()
+                .map(|logical| (logical.exit_country.to_lowercase(), logical.city.as_deref()))
+                .collect();
+            countries.sort();
+            countries.dedup_by(|a, b| a.0 == b.0);
+            for (country, city) in countries {
+                println!("{}\t{}", country, city.unwrap_or_default());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write the `protonvpn` profile for a server of a country (`ch`) or of that
+/// name (`CH#1`) and (re)connect it.
+///
+/// ProtonVPN hands out keys only to logged-in sessions, but the key of one of
+/// its configurations is accepted by every server: it is taken from `key_from`,
+/// a configuration downloaded from the account page, or from the existing profile.
+pub fn proton_connect(
+    ctx: &Context,
+    dir: &Path,
+    selector: &str,
+    key_from: Option<&Path>,
+) -> Result<(), error::Error> {
+    let path = dir.join(format!("{}.conf", PROTON_PROFILE));
+    let device = key_from
+        .map(|key_from| {
+            Device::from_profile(key_from).ok_or_else(|| {
+                provider_error(format!(
+                    "{} has no PrivateKey and Address in [Interface]",
+                    key_from.display()
+                ))
+            })
+        })
+        .transpose()?
+        .or_else(|| Device::from_profile(&path))
+        .ok_or_else(|| {
+            provider_error(
+                "a key is needed, pass --key-from with a WireGuard configuration downloaded from ProtonVPN",
+            )
+        })?;
+    let relays = Logicals::fetch()?.relays();
+    let current = current_relay(&path);
+    let seed = u32::from_ne_bytes(random_bytes()?);
+    let relay = pick(&matching(&relays, selector), current.as_deref(), seed)
+        .ok_or_else(|| provider_error(format!("no active server matches {}", selector)))?;
+    switch_to(
+        ctx,
+        dir,
+        PROTON_PROFILE,
+        relay,
+        &render(&device, relay, PROTON_DNS),
+    )
+}
+
+/// Move to another ProtonVPN server of the country of the current one.
+pub fn proton_rotate(ctx: &Context, dir: &Path) -> Result<(), error::Error> {
+    let path = dir.join(format!("{}.conf", PROTON_PROFILE));
+    let current = current_relay(&path)
+        .ok_or_else(|| provider_error("no ProtonVPN profile to rotate, run connect first"))?;
+    proton_connect(ctx, dir, &proton_country(&current), None)
+}
+
+/// Country of a server name: `ch` for `CH#1`, and for the Secure Core `IS-CH#1`.
+fn proton_country(name: &str) -> String {
+    let name = name.split('#').next().unwrap_or(name);
+    name.rsplit('-').next().unwrap_or(name).to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relay(hostname: &str, location: &str) -> Relay {
+        Relay {
+            hostname: hostname.to_string(),
+            location: location.to_string(),
+            active: true,
+            ipv4_addr_in: "192.0.2.1".to_string(),
+            public_key: "cHVibGlj".to_string(),
+        }
+    }
+
+    #[test]
+    fn picks_another_relay_of_the_location() {
+        let list = RelayList {
+            locations: HashMap::new(),
+            wireguard: WireGuardRelays {
+                relays: vec![
+                    relay("se-sto-wg-001", "se-sto"),
+                    relay("se-sto-wg-002", "se-sto"),
+                    relay("se-got-wg-001", "se-got"),
+                ],
+                ipv4_gateway: "10.64.0.1".to_string(),
+            },
+        };
+        assert_eq!(list.matching("se").len(), 3);
+        assert_eq!(list.matching("se-sto").len(), 2);
+        let relays = list.matching("se-sto");
+        for seed in 0..4 {
+            let picked = pick(&relays, Some("se-sto-wg-001"), seed).unwrap();
+            assert_eq!(picked.hostname, "se-sto-wg-002");
+        }
+        let picked = pick(&list.matching("se-got-wg-001"), Some("se-got-wg-001"), 7);
+        assert_eq!(picked.unwrap().hostname, "se-got-wg-001");
+    }
+
+    #[test]
+    fn reads_the_protonvpn_servers() {
+        let logicals: Logicals = serde_json::from_str(
+            r#"{"Code": 1000, "LogicalServers": [
+                {"Name": "CH#1", "ExitCountry": "CH", "City": "Zurich", "Status": 1, "Tier": 2,
+                 "Servers": [{"EntryIP": "192.0.2.1", "Status": 1, "X25519PublicKey": "cHVibGlj"}]},
+                {"Name": "CH#2", "ExitCountry": "CH", "City": "Zurich", "Status": 0,
+                 "Servers": [{"EntryIP": "192.0.2.2", "Status": 1, "X25519PublicKey": "cHVibGlj"}]},
+                {"Name": "IS-CH#1", "ExitCountry": "CH", "City": null, "Status": 1,
+                 "Servers": [{"EntryIP": "192.0.2.3", "Status": 1, "X25519PublicKey": "cHVibGlj"}]},
+                {"Name": "SE#1", "ExitCountry": "SE", "Status": 1,
+                 "Servers": [{"EntryIP": "192.0.2.4", "Status": 1}]}
+            ]}"#,
+        )
+        .unwrap();
+        let relays = logicals.relays();
+        assert_eq!(relays.len(), 3, "SE#1 has no WireGuard key");
+        let hostnames: Vec<&str> = matching(&relays, "ch")
+            .iter()
+            .map(|relay| relay.hostname.as_str())
+            .collect();
+        assert_eq!(hostnames, ["CH#1", "IS-CH#1"]);
+        assert_eq!(matching(&relays, "ch#1")[0].ipv4_addr_in, "192.0.2.1");
+        assert_eq!(proton_country("CH#1"), "ch");
+        assert_eq!(proton_country("IS-CH#1"), "ch");
+    }
+}
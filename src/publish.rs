@@ -0,0 +1,145 @@
+use crate::app_config::{MqttConfig, PublishConfig};
+use crate::error;
+#[cfg(feature = "http")]
+use crate::http;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Send `event` to every configured destination.
+///
+/// Failures are reported in debug mode only: an unreachable broker must not
+/// stop the daemon from tracking the tunnel.
+pub fn publish(config: &PublishConfig, event: &serde_json::Value, debug: bool) {
+    if let Some(url) = &config.webhook
+        && let Err(e) = post_webhook(url, event)
+        && debug
+    {
+        println!("Failed to post to webhook {}: {}", url, e);
+    }
+    if let Some(mqtt) = &config.mqtt
+        && let Err(e) = mqtt::publish(mqtt, event.to_string().as_bytes())
+        && debug
+    {
+        println!("Failed to publish to MQTT broker {}: {}", mqtt.host, e);
+    }
+}
+
+#[cfg(feature = "http")]
+fn post_webhook(url: &str, event: &serde_json::Value) -> Result<(), error::Error> {
+    http::agent(TIMEOUT)
+        .post(url)
+        .send_json(event)
+        .map_err(|e| error::Error::Publish(e.to_string()))?;
+    Ok(())
+}
+
+#[cfg(not(feature = "http"))]
+fn post_webhook(_url: &str, _event: &serde_json::Value) -> Result<(), error::Error> {
+    Err(error::Error::Publish(
+        "wg-waybar was built without the http feature".to_string(),
+    ))
+}
+
+/// Minimal MQTT 3.1.1 client: connect, publish with QoS 0, disconnect.
+mod mqtt {
+    use super::*;
+
+    const CONNECT: u8 = 0x10;
+    const CONNACK: u8 = 0x20;
+    const PUBLISH: u8 = 0x30;
+    const DISCONNECT: u8 = 0xE0;
+
+    fn push_str(buf: &mut Vec<u8>, value: &str) {
+        buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        buf.extend_from_slice(value.as_bytes());
+    }
+
+    pub(super) fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+        let mut packet = vec![header];
+        let mut remaining = body.len();
+        loop {
+            let mut byte = (remaining % 128) as u8;
+            remaining /= 128;
+            if remaining > 0 {
+                byte |= 0x80;
+            }
+            packet.push(byte);
+            if remaining == 0 {
+                break;
+            }
+        }
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    fn connect_packet(config: &MqttConfig) -> Vec<u8> {
+        let mut flags = 0x02; // clean session
+        let mut body = Vec::new();
+        push_str(&mut body, "MQTT");
+        body.push(4); // protocol level 3.1.1
+        if config.username.is_some() {
+            flags |= 0x80;
+        }
+        if config.password.is_some() {
+            flags |= 0x40;
+        }
+        body.push(flags);
+        body.extend_from_slice(&60u16.to_be_bytes()); // keep alive
+        push_str(&mut body, &config.client_id);
+        if let Some(username) = &config.username {
+            push_str(&mut body, username);
+        }
+        if let Some(password) = &config.password {
+            push_str(&mut body, password);
+        }
+        packet(CONNECT, &body)
+    }
+
+    pub fn publish(config: &MqttConfig, payload: &[u8]) -> Result<(), error::Error> {
+        let mut stream = TcpStream::connect((config.host.as_str(), config.port))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        stream.write_all(&connect_packet(config))?;
+        let mut connack = [0u8; 4];
+        stream.read_exact(&mut connack)?;
+        if connack[0] != CONNACK || connack[3] != 0 {
+            return Err(error::Error::Publish(format!(
+                "MQTT connection refused (return code {})",
+                connack[3]
+            )));
+        }
+
+        let mut body = Vec::new();
+        push_str(&mut body, &config.topic);
+        body.extend_from_slice(payload);
+        let header = if config.retain {
+            PUBLISH | 0x01
+        } else {
+            PUBLISH
+        };
+        stream.write_all(&packet(header, &body))?;
+        stream.write_all(&packet(DISCONNECT, &[]))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mqtt::packet;
+
+    #[test]
+    fn encodes_short_remaining_length_in_one_byte() {
+        assert_eq!(packet(0xE0, &[]), vec![0xE0, 0x00]);
+    }
+
+    #[test]
+    fn encodes_long_remaining_length_as_varint() {
+        let encoded = packet(0x30, &[0u8; 321]);
+        assert_eq!(&encoded[..3], &[0x30, 0xC1, 0x02]);
+        assert_eq!(encoded.len(), 3 + 321);
+    }
+}
@@ -0,0 +1,332 @@
+use crate::actions::{self, Context};
+use crate::app_config::{BatteryConfig, MeteredConfig};
+use crate::battery;
+use crate::config;
+use crate::error;
+use crate::handshake;
+use crate::profiles::Profile;
+use crate::uplink;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Endpoints probed at once, so that large directories do not open a socket each.
+const PARALLEL_PROBES: usize = 8;
+/// Time given to a peer to answer one handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Pause between two handshakes with the same peer, which drops initiations
+/// that come too fast.
+const HANDSHAKE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Round-trip time to the endpoint of a profile, `None` for a profile that is
+/// up, or why it is unreachable.
+pub type Rtt = Result<Option<Duration>, String>;
+
+/// Outcome of probing the endpoint of one profile.
+struct Probe<'a> {
+    profile: &'a Profile,
+    endpoint: Option<IpAddr>,
+    rtt: Rtt,
+}
+
+/// Profiles left unprobed, from the `[metered]` and `[battery.<interface>]` settings.
+pub struct Skip<'a> {
+    metered: bool,
+    battery: &'a HashMap<String, BatteryConfig>,
+    on_battery: bool,
+}
+
+impl<'a> Skip<'a> {
+    /// The power supply is only read if a profile skips its probe on battery.
+    pub fn new(metered: &MeteredConfig, battery: &'a HashMap<String, BatteryConfig>) -> Self {
+        Skip {
+            metered: metered.disable_probes,
+            battery,
+            on_battery: battery.values().any(|battery| battery.skip_probes)
+                && battery::read().on_battery,
+        }
+    }
+
+    /// Why the profile is not probed, if it is not.
+    fn reason(&self, profile: &Profile) -> Option<&'static str> {
+        if self.on_battery
+            && self
+                .battery
+                .get(&profile.name)
+                .is_some_and(|battery| battery.skip_probes)
+        {
+            return Some("not probed on battery");
+        }
+        if self.metered && uplink::for_profile(&profile.path).is_some_and(|uplink| uplink.metered) {
+            return Some("not probed over a metered uplink");
+        }
+        None
+    }
+}
+
+/// Average round-trip time of `count` handshakes, among those answered.
+fn handshakes(config_path: &std::path::Path, count: u32) -> (Option<IpAddr>, Rtt) {
+    let (private_key, peer, endpoint) = match config::handshake_target(config_path) {
+        Ok(target) => target,
+        Err(e) => return (None, Err(e.to_string())),
+    };
+    let mut answered = Vec::new();
+    let mut failure = String::new();
+    for i in 0..count.max(1) {
+        if i > 0 {
+            std::thread::sleep(HANDSHAKE_INTERVAL);
+        }
+        match handshake::probe(&private_key, &peer, endpoint, HANDSHAKE_TIMEOUT) {
+            Ok(rtt) => answered.push(rtt),
+            Err(e) => failure = e,
+        }
+    }
+    let rtt = match answered.len() {
+        0 => Err(failure),
+        n => Ok(Some(answered.iter().sum::<Duration>() / n as u32)),
+    };
+    (Some(endpoint.ip()), rtt)
+}
+
+/// A profile that is up is left alone: a handshake as its key would move the
+/// peer's idea of where the tunnel is to the probe. So is one `skip` says to.
+fn probe<'a>(profile: &'a Profile, count: u32, netns: Option<&str>, skip: &Skip) -> Probe<'a> {
+    if config::is_active(&profile.name, netns).unwrap_or(false) {
+        return Probe {
+            profile,
+            endpoint: None,
+            rtt: Ok(None),
+        };
+    }
+    if let Some(reason) = skip.reason(profile) {
+        return Probe {
+            profile,
+            endpoint: None,
+            rtt: Err(reason.to_string()),
+        };
+    }
+    let (endpoint, rtt) = handshakes(&profile.path, count);
+    Probe {
+        profile,
+        endpoint,
+        rtt,
+    }
+}
+
+/// `f` of every item, in the order given, from [`PARALLEL_PROBES`] workers
+/// each taking the next item once done.
+fn in_parallel<'a, T: Sync, R: Send>(items: &'a [T], f: impl Fn(&'a T) -> R + Sync) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, R)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..PARALLEL_PROBES.min(items.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            return results;
+                        };
+                        results.push((i, f(item)));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("probe thread panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Probe the endpoint of every profile, in the order given.
+fn probe_all<'a>(
+    profiles: &'a [Profile],
+    count: u32,
+    netns: Option<&str>,
+    skip: &Skip,
+) -> Vec<Probe<'a>> {
+    in_parallel(profiles, |profile| probe(profile, count, netns, skip))
+}
+
+/// Round-trip time to the endpoint of each profile, in the order given, or why
+/// there is none.
+pub fn reachability(profiles: &[Profile], netns: Option<&str>, skip: &Skip) -> Vec<Rtt> {
+    probe_all(profiles, 1, netns, skip)
+        .into_iter()
+        .map(|probe| probe.rtt)
+        .collect()
+}
+
+/// `24.5 ms`, `connected`, or `unreachable: <reason>`.
+pub fn describe(rtt: &Rtt) -> String {
+    match rtt {
+        Ok(Some(rtt)) => format!("{:.1} ms", rtt.as_secs_f64() * 1000.0),
+        Ok(None) => "connected".to_string(),
+        Err(e) => format!("unreachable: {}", e),
+    }
+}
+
+/// Profiles measured first, by increasing round-trip time, then those up,
+/// then the unreachable ones.
+fn sort(probes: &mut [Probe]) {
+    let rank = |probe: &Probe| match probe.rtt {
+        Ok(Some(rtt)) => (0, rtt),
+        Ok(None) => (1, Duration::ZERO),
+        Err(_) => (2, Duration::ZERO),
+    };
+    probes.sort_by(|a, b| {
+        rank(a)
+            .cmp(&rank(b))
+            .then_with(|| a.profile.name.cmp(&b.profile.name))
+    });
+}
+
+/// Probe the endpoint of every profile in parallel and print them ranked by latency.
+///
+/// With `connect_best`, switch to the fastest reachable profile afterwards.
+pub fn run(
+    ctx: &Context,
+    profiles: &[Profile],
+    count: u32,
+    connect_best: bool,
+    netns: Option<&str>,
+    skip: &Skip,
+) -> Result<(), error::Error> {
+    let mut probes = probe_all(profiles, count, netns, skip);
+    sort(&mut probes);
+
+    for (rank, probe) in probes.iter().enumerate() {
+        let endpoint = probe.endpoint.map(|ip| ip.to_string()).unwrap_or_default();
+        match &probe.rtt {
+            Ok(Some(rtt)) => println!(
+                "{:>2}. {:<20} {:>10.1} ms  {}",
+                rank + 1,
+                probe.profile.name,
+                rtt.as_secs_f64() * 1000.0,
+                endpoint
+            ),
+            Ok(None) => println!(
+                "{:>2}. {:<20} {:>13}",
+                rank + 1,
+                probe.profile.name,
+                "connected"
+            ),
+            Err(e) => println!(
+                "{:>2}. {:<20} {:>13}  {} ({})",
+                rank + 1,
+                probe.profile.name,
+                "-",
+                endpoint,
+                e
+            ),
+        }
+    }
+
+    if connect_best {
+        let best = probes
+            .iter()
+            .find(|probe| matches!(probe.rtt, Ok(Some(_))))
+            .ok_or_else(|| error::Error::Probe("no profile endpoint is reachable".to_string()))?;
+        actions::switch(ctx, profiles, &best.profile.name)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranks_measured_profiles_first() {
+        let profile = |name: &str| Profile {
+            name: name.to_string(),
+            stem: name.to_string(),
+            path: std::path::PathBuf::from(format!("/nonexistent/{}.conf", name)),
+        };
+        let profiles = [
+            profile("up"),
+            profile("slow"),
+            profile("dead"),
+            profile("fast"),
+        ];
+        let rtts = [
+            Ok(None),
+            Ok(Some(Duration::from_millis(80))),
+            Err("no handshake response".to_string()),
+            Ok(Some(Duration::from_millis(20))),
+        ];
+        let mut probes: Vec<Probe> = profiles
+            .iter()
+            .zip(rtts)
+            .map(|(profile, rtt)| Probe {
+                profile,
+                endpoint: None,
+                rtt,
+            })
+            .collect();
+        sort(&mut probes);
+        let names: Vec<&str> = probes
+            .iter()
+            .map(|probe| probe.profile.name.as_str())
+            .collect();
+        assert_eq!(names, ["fast", "slow", "up", "dead"]);
+
+        // Every profile is probed once, in the order given, by fewer workers.
+        let many: Vec<Profile> = (0..20).map(|i| profile(&format!("p{}", i))).collect();
+        let skip = Skip {
+            metered: false,
+            battery: &HashMap::new(),
+            on_battery: false,
+        };
+        let probes = probe_all(&many, 1, None, &skip);
+        assert!(
+            probes
+                .iter()
+                .map(|probe| &probe.profile.name)
+                .eq(many.iter().map(|profile| &profile.name))
+        );
+
+        // On battery, profiles whose `[battery]` section says so are not probed.
+        let battery = HashMap::from([(
+            "p0".to_string(),
+            BatteryConfig {
+                interval: None,
+                critical: None,
+                skip_probes: true,
+            },
+        )]);
+        let skip = Skip {
+            metered: false,
+            battery: &battery,
+            on_battery: true,
+        };
+        assert_eq!(skip.reason(&many[0]), Some("not probed on battery"));
+        assert_eq!(skip.reason(&many[1]), None);
+
+        // No more than PARALLEL_PROBES at once, however many profiles.
+        let running = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        let squares = in_parallel(&(0..50).collect::<Vec<usize>>(), |i| {
+            most.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(5));
+            running.fetch_sub(1, Ordering::SeqCst);
+            i * i
+        });
+        assert_eq!(squares, (0..50).map(|i| i * i).collect::<Vec<_>>());
+        assert!(most.load(Ordering::SeqCst) <= PARALLEL_PROBES);
+
+        assert_eq!(
+            describe(&Ok(Some(Duration::from_micros(24_500)))),
+            "24.5 ms"
+        );
+        assert_eq!(describe(&Ok(None)), "connected");
+        assert_eq!(
+            describe(&Err("no reply".to_string())),
+            "unreachable: no reply"
+        );
+    }
+}
@@ -0,0 +1,477 @@
+use crate::app_config::{RefreshConfig, SinkKind};
+use crate::error;
+use crate::hooks;
+use crate::paths;
+use crate::utils;
+use libc::{SIGUSR1, kill};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+#[cfg(feature = "daemon")]
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Time between two looks for Waybar while waiting for it.
+const WAYBAR_POLL: Duration = Duration::from_millis(250);
+
+/// Object path and member of the D-Bus signal, for `dbus-monitor` or Eww scripts.
+const DBUS_PATH: &str = "/org/wgwaybar/Status";
+const DBUS_SIGNAL: &str = "org.wgwaybar.Status.Changed";
+
+/// Something to tell that the status changed and should be read again.
+trait Sink: Send + Sync {
+    /// `wait` is false for intermediate updates, which must not hold up the
+    /// action waiting for a reader to show up.
+    fn refresh(&self, debug: bool, wait: bool) -> Result<(), error::Error>;
+}
+
+/// The realtime signal of the Waybar module.
+struct WaybarSignal {
+    signal_num: i32,
+    /// How long to wait for Waybar when it is not running yet, once per run.
+    wait: Option<Duration>,
+    waited: AtomicBool,
+}
+
+/// Whether Waybar showed up within `timeout`.
+fn wait_for_waybar(timeout: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        std::thread::sleep(WAYBAR_POLL);
+        if utils::find_waybar_pid().is_some() {
+            return true;
+        }
+    }
+    false
+}
+
+impl WaybarSignal {
+    /// How long to wait for Waybar after not finding it: once per run, and
+    /// only when the refresh may wait.
+    fn wait_for(&self, wait: bool) -> Option<Duration> {
+        self.wait
+            .filter(|_| wait && !self.waited.swap(true, Ordering::Relaxed))
+    }
+}
+
+impl Sink for WaybarSignal {
+    fn refresh(&self, debug: bool, wait: bool) -> Result<(), error::Error> {
+        match utils::send_signal_to_waybar(self.signal_num, debug) {
+            Err(error::SignalError::ProcessNotFound(_)) if let Some(wait) = self.wait_for(wait) => {
+                if debug {
+                    println!("Waiting up to {} s for Waybar", wait.as_secs());
+                }
+                // Whether it came or not, the second attempt reports it.
+                wait_for_waybar(wait);
+                Ok(utils::send_signal_to_waybar(self.signal_num, debug)?)
+            }
+            result => Ok(result?),
+        }
+    }
+}
+
+/// Each running `watch` binds `<pid>.sock` in this directory of the runtime
+/// directory, to be woken up.
+pub const WATCHES: &str = "watch";
+/// Each running `events` binds `<pid>.sock` in it, to read the events the
+/// daemon publishes.
+#[cfg(feature = "daemon")]
+pub const EVENT_READERS: &str = "events";
+/// Each running `daemon` binds `<pid>.sock` in it, so that `events` knows who
+/// publishes.
+#[cfg(feature = "daemon")]
+pub const DAEMONS: &str = "daemon";
+
+/// Directory of the registrations of `kind`, like [`WATCHES`].
+fn registration_dir(kind: &str) -> Option<PathBuf> {
+    paths::runtime_dir().map(|dir| dir.join(kind))
+}
+
+fn watch_dir() -> Option<PathBuf> {
+    registration_dir(WATCHES)
+}
+
+/// The commands registered in `dir`, by pid and socket.
+fn registrations(dir: &Path) -> Vec<(i32, PathBuf)> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension() == Some(OsStr::new("sock")))
+        .filter_map(|path| {
+            let pid = path.file_stem()?.to_str()?.parse().ok()?;
+            Some((pid, path))
+        })
+        .collect()
+}
+
+/// Whether `cmdline`, as in `/proc/<pid>/cmdline`, is the one of wg-waybar
+/// running `command`.
+fn is_command(cmdline: &[u8], command: &str) -> bool {
+    let mut args = cmdline.split(|byte| *byte == 0);
+    let program = args
+        .next()
+        .and_then(|arg| Path::new(OsStr::from_bytes(arg)).file_name())
+        .and_then(OsStr::to_str);
+    program.is_some_and(|name| name.starts_with("wg-waybar"))
+        && args.any(|arg| arg == command.as_bytes())
+}
+
+fn is_watch(cmdline: &[u8]) -> bool {
+    is_command(cmdline, "watch")
+}
+
+/// Arguments of the process `pid`, NUL-separated as in `/proc/<pid>/cmdline`.
+#[cfg(target_os = "linux")]
+fn cmdline(pid: i32) -> Option<Vec<u8>> {
+    fs::read(format!("/proc/{}/cmdline", pid)).ok()
+}
+
+/// Without `/proc`, as `ps` shows them, split at spaces.
+#[cfg(not(target_os = "linux"))]
+fn cmdline(pid: i32) -> Option<Vec<u8>> {
+    let output = Command::new("ps")
+        .args(["-ww", "-o", "args=", "-p", &pid.to_string()])
+        .output()
+        .ok()?;
+    let args = String::from_utf8_lossy(&output.stdout);
+    Some(args.trim().replace(' ', "\0").into_bytes())
+}
+
+/// The socket of a running `watch`, `events` or `daemon`, removed once it stops.
+///
+/// It is the pid file of the `watch` sink and what the `ipc` sink writes to.
+#[cfg(feature = "daemon")]
+pub struct Registration {
+    path: PathBuf,
+    socket: UnixDatagram,
+}
+
+#[cfg(feature = "daemon")]
+impl Registration {
+    /// Register this process as one of `kind`, like [`WATCHES`].
+    pub fn bind(kind: &str) -> Result<Self, error::Error> {
+        let dir = registration_dir(kind).ok_or_else(|| {
+            error::Error::Refresh(format!("no runtime directory to register the {} in", kind))
+        })?;
+        Self::bind_in(&paths::ensure(dir)?)
+    }
+
+    fn bind_in(dir: &Path) -> Result<Self, error::Error> {
+        let path = dir.join(format!("{}.sock", std::process::id()));
+        // Left behind by an earlier process with the same pid.
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path)?;
+        // Anyone may ask for a status they could read anyway.
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666))?;
+        Ok(Registration { path, socket })
+    }
+
+    /// The socket, to wait for the datagrams of the `ipc` sink on.
+    pub fn try_clone(&self) -> io::Result<UnixDatagram> {
+        self.socket.try_clone()
+    }
+}
+
+#[cfg(feature = "daemon")]
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// SIGUSR1 to the running `watch` commands, which print the status right away.
+///
+/// Waybar has no IPC reaching a custom module, so this is how a module with a
+/// continuous `exec` or an Eww `deflisten` is updated. Only the pids registered
+/// by a `watch` are signaled, once their command line confirms they still are one.
+struct Watch {
+    /// Added to every refresh rather than configured, and quiet about finding
+    /// no `watch`, the usual case.
+    implicit: bool,
+}
+
+impl Sink for Watch {
+    fn refresh(&self, debug: bool, _wait: bool) -> Result<(), error::Error> {
+        let Some(dir) = watch_dir() else {
+            return Ok(());
+        };
+        let mut signaled = false;
+        for (pid, path) in registrations(&dir) {
+            if !cmdline(pid).is_some_and(|cmdline| is_watch(&cmdline)) {
+                let _ = fs::remove_file(path);
+                continue;
+            }
+            if unsafe { kill(pid, SIGUSR1) } == 0 {
+                signaled = true;
+            } else if debug {
+                println!(
+                    "Failed to signal watch {}: {}",
+                    pid,
+                    io::Error::last_os_error()
+                );
+            }
+        }
+        if debug && !signaled && !self.implicit {
+            println!("No wg-waybar watch to refresh");
+        }
+        Ok(())
+    }
+}
+
+/// A datagram to the socket of every running `watch`.
+///
+/// Unlike `watch`, it needs no permission to signal, so it also reaches a `watch`
+/// of another user, and does not depend on `/proc`.
+struct Ipc;
+
+/// Send `message` to every command registered in `dir`, removing the sockets
+/// nobody reads. Returns how many were told.
+fn send_to_all(dir: &Path, message: &[u8]) -> Result<usize, error::Error> {
+    let socket = UnixDatagram::unbound()?;
+    socket.set_nonblocking(true)?;
+    let mut told = 0;
+    for (_, path) in registrations(dir) {
+        match socket.send_to(message, &path) {
+            Ok(_) => told += 1,
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                let _ = fs::remove_file(path);
+            }
+            // A full queue already holds a wake-up.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => told += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(told)
+}
+
+impl Sink for Ipc {
+    fn refresh(&self, debug: bool, _wait: bool) -> Result<(), error::Error> {
+        let told = match watch_dir() {
+            Some(dir) => send_to_all(&dir, b"\n")?,
+            None => 0,
+        };
+        if debug && told == 0 {
+            println!("No wg-waybar watch to refresh");
+        }
+        Ok(())
+    }
+}
+
+/// Send `message` to every command registered as `kind`, e.g. an event to
+/// the running `events`. Returns how many were told.
+#[cfg(feature = "daemon")]
+pub fn publish(kind: &str, message: &[u8]) -> Result<usize, error::Error> {
+    match registration_dir(kind) {
+        Some(dir) => send_to_all(&dir, message),
+        None => Ok(0),
+    }
+}
+
+/// Whether a wg-waybar `command` registered as `kind` is still running.
+#[cfg(feature = "daemon")]
+pub fn is_registered(kind: &str, command: &str) -> bool {
+    registration_dir(kind).is_some_and(|dir| {
+        registrations(&dir)
+            .into_iter()
+            .any(|(pid, _)| cmdline(pid).is_some_and(|cmdline| is_command(&cmdline, command)))
+    })
+}
+
+/// A file whose modification time changes, for `exec-if` or inotify setups.
+struct Touch {
+    path: PathBuf,
+}
+
+impl Sink for Touch {
+    fn refresh(&self, _debug: bool, _wait: bool) -> Result<(), error::Error> {
+        if !self.path.exists() {
+            utils::fs_write(self.path.clone(), "")?;
+        }
+        let file: File = OpenOptions::new().write(true).open(&self.path)?;
+        file.set_modified(SystemTime::now())?;
+        Ok(())
+    }
+}
+
+/// A signal on the session bus of the invoking user, sent with `gdbus` (GLib,
+/// which Waybar and Eww depend on).
+struct DBus;
+
+impl Sink for DBus {
+    fn refresh(&self, _debug: bool, _wait: bool) -> Result<(), error::Error> {
+        let mut cmd = Command::new("gdbus");
+        cmd.args([
+            "emit",
+            "--session",
+            "--object-path",
+            DBUS_PATH,
+            "--signal",
+            DBUS_SIGNAL,
+        ])
+        .stdin(Stdio::null());
+        hooks::as_invoking_user(&mut cmd);
+        let output = cmd.output()?;
+        if !output.status.success() {
+            return Err(error::Error::Refresh(format!(
+                "`gdbus emit` failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Default file of the `file` sink, in the runtime directory of the user.
+fn default_file() -> Option<PathBuf> {
+    utils::user_runtime_dir().map(|dir| dir.join("wg-waybar.stamp"))
+}
+
+/// Every sink told about a change, from `[refresh]` of the app config.
+#[derive(Clone)]
+pub struct Sinks {
+    sinks: Arc<[Box<dyn Sink>]>,
+    debug: bool,
+}
+
+impl Sinks {
+    pub fn new(
+        config: &RefreshConfig,
+        signal_num: i32,
+        wait_for_waybar: Option<Duration>,
+        debug: bool,
+    ) -> Self {
+        let mut sinks = config
+            .sinks
+            .iter()
+            .filter_map(|kind| -> Option<Box<dyn Sink>> {
+                match kind {
+                    SinkKind::Signal => Some(Box::new(WaybarSignal {
+                        signal_num,
+                        wait: wait_for_waybar,
+                        waited: AtomicBool::new(false),
+                    })),
+                    SinkKind::Watch => Some(Box::new(Watch { implicit: false })),
+                    SinkKind::Ipc => Some(Box::new(Ipc)),
+                    SinkKind::File => {
+                        let path = config.file.clone().or_else(default_file);
+                        if path.is_none() {
+                            eprintln!(
+                                "No runtime directory for the file sink, set file in [refresh]"
+                            );
+                        }
+                        path.map(|path| Box::new(Touch { path }) as Box<dyn Sink>)
+                    }
+                    SinkKind::Dbus => Some(Box::new(DBus)),
+                }
+            })
+            .collect::<Vec<_>>();
+        // A running `watch` is woken up by every toggle, whatever else is told.
+        if !config
+            .sinks
+            .iter()
+            .any(|kind| matches!(kind, SinkKind::Watch | SinkKind::Ipc))
+        {
+            sinks.push(Box::new(Watch { implicit: true }));
+        }
+        Self {
+            sinks: sinks.into(),
+            debug,
+        }
+    }
+
+    /// Tell every sink, returning the first failure once all were tried.
+    pub fn refresh(&self) -> Result<(), error::Error> {
+        self.notify(true)
+    }
+
+    /// Tell every sink about an intermediate update, e.g. the stage of a
+    /// connection, without waiting for Waybar to show up.
+    pub fn update(&self) -> Result<(), error::Error> {
+        self.notify(false)
+    }
+
+    fn notify(&self, wait: bool) -> Result<(), error::Error> {
+        let mut result = Ok(());
+        for sink in self.sinks.iter() {
+            if let Err(e) = sink.refresh(self.debug, wait)
+                && result.is_ok()
+            {
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn touches_the_file_of_every_refresh() {
+        let path = std::env::temp_dir().join(format!("wg-waybar-stamp-{}", std::process::id()));
+        let config = RefreshConfig {
+            sinks: vec![SinkKind::File, SinkKind::File],
+            file: Some(path.clone()),
+        };
+        let sinks = Sinks::new(&config, 8, None, false);
+        // And the implicit `watch`.
+        assert_eq!(sinks.sinks.len(), 3);
+        sinks.refresh().unwrap();
+        let first = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        sinks.refresh().unwrap();
+        assert!(std::fs::metadata(&path).unwrap().modified().unwrap() > first);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn waits_for_waybar_on_the_final_refresh_only() {
+        let signal = WaybarSignal {
+            signal_num: 8,
+            wait: Some(Duration::from_millis(600)),
+            waited: AtomicBool::new(false),
+        };
+        assert_eq!(signal.wait_for(false), None);
+        assert_eq!(signal.wait_for(true), Some(Duration::from_millis(600)));
+        assert_eq!(signal.wait_for(true), None);
+    }
+
+    #[test]
+    fn recognizes_watch_commands() {
+        assert!(is_watch(b"/usr/bin/wg-waybar\0/etc/wireguard\0watch\0"));
+        assert!(is_watch(b"wg-waybar\0--debug\0watch\0"));
+        assert!(!is_watch(b"/usr/bin/wg-waybar\0/etc/wireguard\0toggle\0"));
+        assert!(!is_watch(b"/usr/bin/vim\0wg-waybar\0watch\0"));
+        assert!(!is_watch(b""));
+        assert!(is_command(b"wg-waybar\0/etc/wireguard\0daemon\0", "daemon"));
+    }
+
+    #[cfg(feature = "daemon")]
+    #[test]
+    fn tells_registered_watches_and_forgets_stopped_ones() {
+        let dir = std::env::temp_dir().join(format!("wg-waybar-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let registration = Registration::bind_in(&dir).unwrap();
+        let stale = dir.join("1.sock");
+        drop(UnixDatagram::bind(&stale).unwrap());
+        assert_eq!(registrations(&dir).len(), 2);
+
+        assert_eq!(send_to_all(&dir, b"\n").unwrap(), 1);
+        registration.socket.recv(&mut [0; 1]).unwrap();
+        assert!(!stale.exists());
+
+        drop(registration);
+        assert!(registrations(&dir).is_empty());
+        fs::remove_dir(dir).unwrap();
+    }
+}
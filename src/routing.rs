@@ -0,0 +1,421 @@
+use crate::error;
+use crate::utils;
+use defguard_wireguard_rs::net::IpAddrMask;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Table used for policy routing when the configuration does not name one.
+pub const DEFAULT_POLICY_TABLE: u32 = 51820;
+
+/// Mark wg-quick puts on the encrypted traffic of a full tunnel with
+/// `Table = auto`.
+pub const TUNNEL_FWMARK: u32 = 51820;
+
+/// Routing table selection, mirroring wg-quick's `Table` key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RoutingTable {
+    /// Install no route of our own, leaving routing as it is (the default).
+    Auto,
+    /// Do not install any route.
+    Off,
+    /// Install routes into the given table and add the ip rules selecting it.
+    Id(u32),
+}
+
+impl FromStr for RoutingTable {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "auto" => Ok(RoutingTable::Auto),
+            "off" => Ok(RoutingTable::Off),
+            value => value.parse::<u32>().map(RoutingTable::Id).map_err(|_| {
+                error::Error::InvalidFormat {
+                    message: format!("Invalid Table: {}", value),
+                }
+            }),
+        }
+    }
+}
+
+fn family_flag(ip: &IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(_) => "-4",
+        IpAddr::V6(_) => "-6",
+    }
+}
+
+fn host_prefix(ip: &IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn ip(args: &[&str]) -> Result<(), error::Error> {
+    utils::run_ip(args).map_err(error::Error::Routing)
+}
+
+/// Rules installed for a custom table, in the order they must be added.
+///
+/// Later rules take precedence: the table lookup comes first, then the main
+/// table (ignoring its default route), then the peer endpoints so that the
+/// encrypted traffic itself never loops back into the tunnel.
+fn table_rules(table: u32, families: &[&'static str], endpoints: &[IpAddr]) -> Vec<Vec<String>> {
+    let table = table.to_string();
+    let mut rules = Vec::new();
+    for family in families {
+        rules.push(vec![
+            family.to_string(),
+            "rule".into(),
+            "add".into(),
+            "lookup".into(),
+            table.clone(),
+        ]);
+        rules.push(vec![
+            family.to_string(),
+            "rule".into(),
+            "add".into(),
+            "table".into(),
+            "main".into(),
+            "suppress_prefixlength".into(),
+            "0".into(),
+        ]);
+    }
+    for endpoint in endpoints {
+        let flag = family_flag(endpoint);
+        if !families.contains(&flag) {
+            continue;
+        }
+        rules.push(vec![
+            flag.to_string(),
+            "rule".into(),
+            "add".into(),
+            "to".into(),
+            format!("{}/{}", endpoint, host_prefix(endpoint)),
+            "lookup".into(),
+            "main".into(),
+        ]);
+    }
+    rules
+}
+
+/// Rules sending traffic sourced from the tunnel addresses to `table`.
+fn policy_rules(table: u32, sources: &[IpAddrMask]) -> Vec<Vec<String>> {
+    sources
+        .iter()
+        .map(|source| {
+            vec![
+                family_flag(&source.ip).to_string(),
+                "rule".into(),
+                "add".into(),
+                "from".into(),
+                format!("{}/{}", source.ip, host_prefix(&source.ip)),
+                "lookup".into(),
+                table.to_string(),
+            ]
+        })
+        .collect()
+}
+
+/// Rules sending marked traffic to `table`.
+fn fwmark_rules(table: u32, fwmark: u32, families: &[&'static str]) -> Vec<Vec<String>> {
+    families
+        .iter()
+        .map(|family| {
+            vec![
+                family.to_string(),
+                "rule".into(),
+                "add".into(),
+                "fwmark".into(),
+                format!("{:#x}", fwmark),
+                "lookup".into(),
+                table.to_string(),
+            ]
+        })
+        .collect()
+}
+
+fn families(allowed_ips: &[IpAddrMask]) -> Vec<&'static str> {
+    let mut families = Vec::new();
+    for allowed_ip in allowed_ips {
+        let flag = family_flag(&allowed_ip.ip);
+        if !families.contains(&flag) {
+            families.push(flag);
+        }
+    }
+    families
+}
+
+/// Metric of the route to `allowed_ip`, from the `# Metric =` of its peer or profile.
+fn metric(metrics: &[(IpAddrMask, u32)], allowed_ip: &IpAddrMask) -> Option<String> {
+    metrics
+        .iter()
+        .find(|(network, _)| network == allowed_ip)
+        .map(|(_, metric)| metric.to_string())
+}
+
+fn add_table_routes(
+    interface_name: &str,
+    table: u32,
+    allowed_ips: &[IpAddrMask],
+    metrics: &[(IpAddrMask, u32)],
+) -> Result<(), error::Error> {
+    let table_str = table.to_string();
+    for allowed_ip in allowed_ips {
+        let destination = format!("{}/{}", allowed_ip.ip, allowed_ip.cidr);
+        let mut args = vec![
+            family_flag(&allowed_ip.ip),
+            "route",
+            "replace",
+            &destination,
+            "dev",
+            interface_name,
+            "table",
+            &table_str,
+        ];
+        let metric = metric(metrics, allowed_ip);
+        if let Some(metric) = &metric {
+            args.extend(["metric", metric.as_str()]);
+        }
+        ip(&args)?;
+    }
+    Ok(())
+}
+
+fn add_rules(rules: Vec<Vec<String>>) -> Result<(), error::Error> {
+    for rule in rules {
+        let args: Vec<&str> = rule.iter().map(String::as_str).collect();
+        ip(&args)?;
+    }
+    Ok(())
+}
+
+fn delete_rules(rules: Vec<Vec<String>>) {
+    for mut rule in rules.into_iter().rev() {
+        rule[2] = "del".into();
+        let args: Vec<&str> = rule.iter().map(String::as_str).collect();
+        let _ = ip(&args);
+    }
+}
+
+/// Install `allowed_ips` routes into `table` and the rules selecting it.
+pub fn apply_table(
+    interface_name: &str,
+    table: u32,
+    allowed_ips: &[IpAddrMask],
+    metrics: &[(IpAddrMask, u32)],
+    endpoints: &[IpAddr],
+) -> Result<(), error::Error> {
+    add_table_routes(interface_name, table, allowed_ips, metrics)?;
+    add_rules(table_rules(table, &families(allowed_ips), endpoints))
+}
+
+/// Remove the rules added by [`apply_table`].
+///
+/// Routes are dropped by the kernel together with the interface, rules are not.
+/// Failures are ignored so that a partially applied setup can still be cleaned.
+pub fn teardown_table(table: u32, allowed_ips: &[IpAddrMask], endpoints: &[IpAddr]) {
+    delete_rules(table_rules(table, &families(allowed_ips), endpoints));
+}
+
+/// Install `allowed_ips` routes into `table` and select it only for traffic
+/// originating from one of the tunnel `addresses`.
+///
+/// The main table keeps the default route, so the tunnel acts as a secondary
+/// identity that applications opt into by binding to its address.
+pub fn apply_policy(
+    interface_name: &str,
+    table: u32,
+    allowed_ips: &[IpAddrMask],
+    metrics: &[(IpAddrMask, u32)],
+    addresses: &[IpAddrMask],
+) -> Result<(), error::Error> {
+    add_table_routes(interface_name, table, allowed_ips, metrics)?;
+    add_rules(policy_rules(table, addresses))
+}
+
+/// Remove the rules added by [`apply_policy`].
+pub fn teardown_policy(table: u32, addresses: &[IpAddrMask]) {
+    delete_rules(policy_rules(table, addresses));
+}
+
+/// Install `allowed_ips` routes into `table` and select it only for traffic
+/// carrying `fwmark`.
+pub fn apply_fwmark(
+    interface_name: &str,
+    table: u32,
+    fwmark: u32,
+    allowed_ips: &[IpAddrMask],
+    metrics: &[(IpAddrMask, u32)],
+) -> Result<(), error::Error> {
+    add_table_routes(interface_name, table, allowed_ips, metrics)?;
+    add_rules(fwmark_rules(table, fwmark, &families(allowed_ips)))
+}
+
+/// Remove the rules added by [`apply_fwmark`].
+pub fn teardown_fwmark(table: u32, fwmark: u32, allowed_ips: &[IpAddrMask]) {
+    delete_rules(fwmark_rules(table, fwmark, &families(allowed_ips)));
+}
+
+/// Gateway of the first route listed by `ip -j route show`.
+fn parse_gateway(output: &str) -> Option<IpAddr> {
+    let routes: serde_json::Value = serde_json::from_str(output).ok()?;
+    routes.get(0)?.get("gateway")?.as_str()?.parse().ok()
+}
+
+/// Gateway of the default route through `device` for the family of `ip`.
+fn default_gateway(device: &str, ip: &IpAddr) -> Option<IpAddr> {
+    let output = utils::run_command(
+        "ip",
+        &[
+            "-j",
+            family_flag(ip),
+            "route",
+            "show",
+            "default",
+            "dev",
+            device,
+        ],
+        None,
+    )
+    .ok()?;
+    parse_gateway(&output)
+}
+
+/// Whether `ip -j route show` listed any route.
+fn lists_routes(output: &str) -> bool {
+    matches!(
+        serde_json::from_str(output),
+        Ok(serde_json::Value::Array(routes)) if !routes.is_empty()
+    )
+}
+
+/// Whether the main table has a route to exactly `destination`.
+fn has_route(destination: &str, ip: &IpAddr) -> bool {
+    utils::run_command(
+        "ip",
+        &["-j", family_flag(ip), "route", "show", "exact", destination],
+        None,
+    )
+    .is_ok_and(|output| lists_routes(&output))
+}
+
+/// Pin the encrypted traffic to `device` with a host route to each endpoint,
+/// through the gateway of its default route if it has one, returning the
+/// endpoints routed.
+///
+/// Host routes win over any default route, so the tunnel does not follow the
+/// default route to another uplink. An endpoint that has a host route already
+/// keeps it, and is left out for [`teardown_bind`] to leave alone.
+pub fn apply_bind(device: &str, endpoints: &[IpAddr]) -> Result<Vec<IpAddr>, error::Error> {
+    let mut routed = Vec::new();
+    for endpoint in endpoints {
+        let destination = format!("{}/{}", endpoint, host_prefix(endpoint));
+        if routed.contains(endpoint) || has_route(&destination, endpoint) {
+            continue;
+        }
+        let mut args = vec![family_flag(endpoint), "route", "add", &destination];
+        let gateway = default_gateway(device, endpoint).map(|gateway| gateway.to_string());
+        if let Some(gateway) = &gateway {
+            args.extend(["via", gateway]);
+        }
+        args.extend(["dev", device]);
+        if let Err(e) = ip(&args) {
+            teardown_bind(device, &routed);
+            return Err(e);
+        }
+        routed.push(*endpoint);
+    }
+    Ok(routed)
+}
+
+/// Remove the routes added by [`apply_bind`], ignoring failures.
+pub fn teardown_bind(device: &str, endpoints: &[IpAddr]) {
+    for endpoint in endpoints {
+        let destination = format!("{}/{}", endpoint, host_prefix(endpoint));
+        let _ = ip(&[
+            family_flag(endpoint),
+            "route",
+            "del",
+            &destination,
+            "dev",
+            device,
+        ]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_gateway_of_the_default_route() {
+        let output = r#"[{"dst":"default","gateway":"192.168.1.1","dev":"eth0","flags":[]},{"dst":"default","gateway":"10.0.0.1","dev":"eth0","metric":600,"flags":[]}]"#;
+        assert_eq!(parse_gateway(output), "192.168.1.1".parse().ok());
+        let on_link = r#"[{"dst":"default","dev":"ppp0","scope":"link","flags":[]}]"#;
+        assert_eq!(parse_gateway(on_link), None);
+        assert_eq!(parse_gateway("[]"), None);
+        assert_eq!(parse_gateway(""), None);
+    }
+
+    #[test]
+    fn tells_whether_a_route_exists() {
+        assert!(lists_routes(
+            r#"[{"dst":"192.0.2.1","gateway":"192.168.1.1","dev":"eth0","flags":[]}]"#
+        ));
+        assert!(!lists_routes("[]"));
+        assert!(!lists_routes(""));
+    }
+
+    #[test]
+    fn selects_the_table_and_spares_the_endpoints() {
+        let endpoint: IpAddr = "192.0.2.1".parse().unwrap();
+        let rules = table_rules(1234, &["-4"], &[endpoint, "2001:db8::1".parse().unwrap()]);
+        let rules: Vec<String> = rules.iter().map(|rule| rule.join(" ")).collect();
+        assert_eq!(
+            rules,
+            [
+                "-4 rule add lookup 1234",
+                "-4 rule add table main suppress_prefixlength 0",
+                "-4 rule add to 192.0.2.1/32 lookup main",
+            ]
+        );
+    }
+
+    #[test]
+    fn adds_the_rules_of_every_family() {
+        let endpoint: IpAddr = "2001:db8::1".parse().unwrap();
+        let rules = table_rules(7, &["-4", "-6"], &[endpoint]);
+        assert_eq!(rules.len(), 5);
+        assert_eq!(rules[2].join(" "), "-6 rule add lookup 7");
+        assert_eq!(
+            rules[4].join(" "),
+            "-6 rule add to 2001:db8::1/128 lookup main"
+        );
+        assert!(table_rules(7, &[], &[endpoint]).is_empty());
+    }
+
+    #[test]
+    fn routes_traffic_from_each_tunnel_address() {
+        let sources = [
+            IpAddrMask::new("10.2.0.2".parse().unwrap(), 24),
+            IpAddrMask::new("fd00::2".parse().unwrap(), 64),
+        ];
+        let rules: Vec<String> = policy_rules(51820, &sources)
+            .iter()
+            .map(|rule| rule.join(" "))
+            .collect();
+        // Only the host itself, not the subnet of the address.
+        assert_eq!(
+            rules,
+            [
+                "-4 rule add from 10.2.0.2/32 lookup 51820",
+                "-6 rule add from fd00::2/128 lookup 51820",
+            ]
+        );
+        assert!(policy_rules(51820, &[]).is_empty());
+    }
+}
@@ -0,0 +1,184 @@
+use crate::error;
+use crate::paths;
+use crate::utils;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// The `run` commands going through each interface, one `<pid>` file per run,
+/// shared by all users like the interface owners.
+const RUNS_DIR: &str = "runs";
+/// Left in the directory of an interface a run brought up, so that the last
+/// run to end brings it down again.
+const BROUGHT_UP: &str = "up";
+
+/// The command of this run once started, 0 before.
+static CHILD: AtomicI32 = AtomicI32::new(0);
+/// The latest signal asking wg-waybar to stop.
+static STOP: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward(signal: libc::c_int) {
+    STOP.store(signal, Ordering::SeqCst);
+    let child = CHILD.load(Ordering::SeqCst);
+    // Ctrl-C reaches the command from the terminal already.
+    if child != 0 && signal != libc::SIGINT {
+        unsafe { libc::kill(child, signal) };
+    }
+}
+
+/// Keep SIGINT, SIGTERM and SIGHUP from stopping wg-waybar between bringing
+/// the tunnel up and down: they go to the command instead, once [`started`].
+///
+/// Handlers, unlike ignored signals, are reset for the command as it starts.
+pub fn guard_signals() {
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        unsafe {
+            libc::signal(signal, forward as *const () as libc::sighandler_t);
+        }
+    }
+}
+
+/// Give `child` the signals received since [`guard_signals`], and those to come.
+pub fn started(child: &Child) {
+    let pid = child.id() as i32;
+    CHILD.store(pid, Ordering::SeqCst);
+    let pending = STOP.swap(0, Ordering::SeqCst);
+    if pending != 0 {
+        unsafe { libc::kill(pid, pending) };
+    }
+}
+
+/// The exit code of a command as a shell gives it: 128 plus the signal that killed it.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
+
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = i32::try_from(pid) else {
+        return false;
+    };
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// The runs going through one interface.
+pub struct Runs {
+    dir: PathBuf,
+    lock: PathBuf,
+}
+
+impl Runs {
+    pub fn open(interface_name: &str) -> Result<Self, error::Error> {
+        Self::open_in(&paths::shared_dir(RUNS_DIR), interface_name)
+    }
+
+    fn open_in(dir: &Path, interface_name: &str) -> Result<Self, error::Error> {
+        let lock = dir.join(format!("{}.lock", interface_name));
+        let dir = dir.join(interface_name);
+        fs::create_dir_all(&dir)?;
+        Ok(Runs { dir, lock })
+    }
+
+    /// Exclusive lock on the runs of the interface, held until dropped, to
+    /// bring it up or down without another run deciding at the same time.
+    pub fn lock(&self) -> Result<fs::File, error::Error> {
+        utils::lock_file(&self.lock)
+    }
+
+    /// Runs other than `pid` still going, forgetting those whose process is gone.
+    fn others(&self, pid: u32) -> Result<usize, error::Error> {
+        let mut others = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let Some(other) = path
+                .file_name()
+                .and_then(|name| name.to_str()?.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            if other == pid {
+                continue;
+            }
+            if is_running(other) {
+                others += 1;
+            } else {
+                let _ = fs::remove_file(path);
+            }
+        }
+        Ok(others)
+    }
+
+    /// Register the run `pid` through the interface, `active` or not, returning
+    /// whether the run must bring it up.
+    pub fn join(&self, pid: u32, active: bool) -> Result<bool, error::Error> {
+        fs::write(self.dir.join(pid.to_string()), "")?;
+        if !active {
+            fs::write(self.dir.join(BROUGHT_UP), "")?;
+        }
+        Ok(!active)
+    }
+
+    /// Unregister the run `pid`, returning whether it must bring the interface
+    /// down: runs brought it up, and this one is the last of them.
+    pub fn leave(&self, pid: u32) -> Result<bool, error::Error> {
+        match fs::remove_file(self.dir.join(pid.to_string())) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let brought_up = self.dir.join(BROUGHT_UP);
+        if !brought_up.exists() || self.others(pid)? > 0 {
+            return Ok(false);
+        }
+        fs::remove_file(brought_up)?;
+        Ok(true)
+    }
+
+    /// Forget that runs brought the interface up, e.g. when that failed.
+    pub fn forget(&self) {
+        let _ = fs::remove_file(self.dir.join(BROUGHT_UP));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exits_like_a_shell() {
+        assert_eq!(exit_code(ExitStatus::from_raw(0)), 0);
+        assert_eq!(exit_code(ExitStatus::from_raw(3 << 8)), 3);
+        assert_eq!(exit_code(ExitStatus::from_raw(libc::SIGKILL)), 137);
+    }
+
+    /// Above `pid_max`, so never a running process.
+    const GONE: u32 = 99_999_999;
+
+    #[test]
+    fn brings_down_with_the_last_run() {
+        let dir = std::env::temp_dir().join(format!("wg-waybar-runs-{}", std::process::id()));
+        let runs = Runs::open_in(&dir, "wg0").unwrap();
+        let pid = std::process::id();
+        assert!(runs.join(GONE, false).unwrap());
+        // Another run is still going while this one joins an interface that is up.
+        assert!(!runs.join(pid, true).unwrap());
+        assert!(!runs.leave(GONE).unwrap());
+        assert!(runs.leave(pid).unwrap());
+
+        // Up before any run: left up.
+        assert!(!runs.join(pid, true).unwrap());
+        assert!(!runs.leave(pid).unwrap());
+
+        // A run that ended without leaving does not keep it up.
+        assert!(runs.join(pid, false).unwrap());
+        fs::write(runs.dir.join(GONE.to_string()), "").unwrap();
+        assert!(runs.leave(pid).unwrap());
+        assert!(!runs.dir.join(GONE.to_string()).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
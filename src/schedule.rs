@@ -0,0 +1,247 @@
+use crate::app_config::HoursConfig;
+#[cfg(feature = "daemon")]
+use crate::app_config::ScheduleConfig;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    const ALL: [Weekday; 7] = [
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ];
+
+    /// From `tm_wday`, where 0 is Sunday.
+    fn from_tm(wday: i32) -> Self {
+        Self::ALL[(wday + 6).rem_euclid(7) as usize]
+    }
+
+    fn previous(self) -> Self {
+        Self::ALL[(self as usize + 6) % 7]
+    }
+}
+
+/// Time of day in minutes since midnight, written `HH:MM` in the app config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay(u16);
+
+impl std::fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("Invalid time of day {:?}, expected HH:MM", value);
+        let (hours, minutes) = value.split_once(':').ok_or_else(invalid)?;
+        let hours: u16 = hours.parse().map_err(|_| invalid())?;
+        let minutes: u16 = minutes.parse().map_err(|_| invalid())?;
+        if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+            return Err(invalid());
+        }
+        Ok(TimeOfDay(hours * 60 + minutes))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    pub weekday: Weekday,
+    pub time: TimeOfDay,
+}
+
+/// Current weekday and time of day in the local timezone.
+pub fn local_now() -> LocalTime {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        libc::localtime_r(&now, &mut tm);
+    }
+    LocalTime {
+        weekday: Weekday::from_tm(tm.tm_wday),
+        time: TimeOfDay((tm.tm_hour * 60 + tm.tm_min) as u16),
+    }
+}
+
+/// Whether `now` falls within the window from `start` to `end` on `days`, or
+/// every day if empty.
+///
+/// A window ending before it starts spans midnight and belongs to the day
+/// it starts on.
+fn window_contains(days: &[Weekday], start: TimeOfDay, end: TimeOfDay, now: &LocalTime) -> bool {
+    let on = |weekday| days.is_empty() || days.contains(&weekday);
+    if start <= end {
+        on(now.weekday) && start <= now.time && now.time < end
+    } else {
+        (on(now.weekday) && now.time >= start) || (on(now.weekday.previous()) && now.time < end)
+    }
+}
+
+#[cfg(feature = "daemon")]
+impl ScheduleConfig {
+    /// Whether `now` falls within the window.
+    pub fn contains(&self, now: &LocalTime) -> bool {
+        window_contains(&self.days, self.start, self.end, now)
+    }
+}
+
+impl HoursConfig {
+    /// Whether the profile may be up at `now`.
+    pub fn allows(&self, now: &LocalTime) -> bool {
+        window_contains(&self.days, self.start, self.end, now)
+    }
+}
+
+impl std::fmt::Display for HoursConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}–{}", self.start, self.end)?;
+        if !self.days.is_empty() {
+            let days: Vec<String> = self
+                .days
+                .iter()
+                .map(|day| format!("{:?}", day).to_lowercase())
+                .collect();
+            write!(f, " on {}", days.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Tracks the schedules to act only when a window opens or closes.
+///
+/// Between two boundaries the profile is left alone, so a manual toggle holds
+/// until the next one.
+#[cfg(feature = "daemon")]
+#[derive(Default)]
+pub struct Scheduler {
+    inside: Vec<Option<bool>>,
+}
+
+#[cfg(feature = "daemon")]
+impl Scheduler {
+    /// Schedules that crossed a boundary since the last call, with whether
+    /// their window is now open. The first call only records where each
+    /// schedule stands, so that starting the daemon leaves the profiles alone.
+    pub fn boundaries(
+        &mut self,
+        schedules: &[ScheduleConfig],
+        now: &LocalTime,
+    ) -> Vec<(usize, bool)> {
+        self.inside.resize(schedules.len(), None);
+        let mut crossed = Vec::new();
+        for (index, schedule) in schedules.iter().enumerate() {
+            let inside = schedule.contains(now);
+            if self.inside[index].is_some_and(|was| was != inside) {
+                crossed.push((index, inside));
+            }
+            self.inside[index] = Some(inside);
+        }
+        crossed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(value: &str) -> TimeOfDay {
+        TimeOfDay::try_from(value.to_string()).unwrap()
+    }
+
+    #[cfg(feature = "daemon")]
+    fn schedule(days: Vec<Weekday>, start: &str, end: &str) -> ScheduleConfig {
+        ScheduleConfig {
+            profile: "work".to_string(),
+            days,
+            start: time(start),
+            end: time(end),
+        }
+    }
+
+    fn at(weekday: Weekday, value: &str) -> LocalTime {
+        LocalTime {
+            weekday,
+            time: time(value),
+        }
+    }
+
+    #[cfg(feature = "daemon")]
+    #[test]
+    fn contains_times_within_the_window_on_listed_days() {
+        let work = schedule(vec![Weekday::Mon, Weekday::Fri], "09:00", "17:00");
+        assert!(work.contains(&at(Weekday::Mon, "09:00")));
+        assert!(!work.contains(&at(Weekday::Mon, "17:00")));
+        assert!(!work.contains(&at(Weekday::Tue, "12:00")));
+    }
+
+    #[cfg(feature = "daemon")]
+    #[test]
+    fn windows_spanning_midnight_belong_to_their_start_day() {
+        let night = schedule(vec![Weekday::Fri], "22:00", "02:00");
+        assert!(night.contains(&at(Weekday::Fri, "23:00")));
+        assert!(night.contains(&at(Weekday::Sat, "01:00")));
+        assert!(!night.contains(&at(Weekday::Fri, "01:00")));
+    }
+
+    #[cfg(feature = "daemon")]
+    #[test]
+    fn reports_only_boundaries() {
+        let schedules = [schedule(vec![], "09:00", "17:00")];
+        let mut scheduler = Scheduler::default();
+        assert!(
+            scheduler
+                .boundaries(&schedules, &at(Weekday::Mon, "08:00"))
+                .is_empty()
+        );
+        assert!(
+            scheduler
+                .boundaries(&schedules, &at(Weekday::Mon, "08:30"))
+                .is_empty()
+        );
+        assert_eq!(
+            scheduler.boundaries(&schedules, &at(Weekday::Mon, "09:00")),
+            vec![(0, true)]
+        );
+        assert_eq!(
+            scheduler.boundaries(&schedules, &at(Weekday::Mon, "17:00")),
+            vec![(0, false)]
+        );
+    }
+
+    #[test]
+    fn allows_hours_within_the_window() {
+        let evenings = HoursConfig {
+            days: vec![],
+            start: time("18:00"),
+            end: time("23:00"),
+        };
+        assert!(evenings.allows(&at(Weekday::Wed, "20:30")));
+        assert!(!evenings.allows(&at(Weekday::Wed, "12:00")));
+        assert_eq!(evenings.to_string(), "18:00–23:00");
+    }
+
+    #[test]
+    fn rejects_invalid_times() {
+        assert!(TimeOfDay::try_from("9".to_string()).is_err());
+        assert!(TimeOfDay::try_from("25:00".to_string()).is_err());
+        assert_eq!(time("24:00"), TimeOfDay(24 * 60));
+    }
+}
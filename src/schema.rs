@@ -0,0 +1,48 @@
+use crate::cli::SchemaKind;
+use crate::state::InterfaceState;
+use crate::status::FullStatus;
+use schemars::{Schema, schema_for};
+
+/// JSON Schema of one of the machine-readable outputs, generated from the
+/// types that serialize it.
+pub fn generate(kind: SchemaKind) -> Schema {
+    match kind {
+        SchemaKind::Status => schema_for!(FullStatus<'static>),
+        SchemaKind::State => schema_for!(InterfaceState),
+        #[cfg(feature = "daemon")]
+        SchemaKind::Events => schema_for!(crate::events::Event<'static>),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_the_fields_of_each_output() {
+        let status = serde_json::to_value(generate(SchemaKind::Status)).unwrap();
+        assert_eq!(status["title"], "FullStatus");
+        assert!(status["properties"]["error_kind"].is_object());
+        assert!(
+            status["$defs"]["Status"]["enum"]
+                .as_array()
+                .unwrap()
+                .contains(&"in-use".into())
+        );
+        assert!(
+            status["required"]
+                .as_array()
+                .unwrap()
+                .contains(&"connected".into())
+        );
+
+        let state = serde_json::to_value(generate(SchemaKind::State)).unwrap();
+        assert!(state["properties"]["transition"].is_object());
+        assert!(
+            !state["required"]
+                .as_array()
+                .unwrap_or(&Vec::new())
+                .contains(&"error".into())
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use crate::error;
+use crate::http;
+use minisign_verify::{PublicKey, Signature};
+use serde::Deserialize;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::Duration;
+
+const RELEASES_URL: &str = "https://api.github.com/repos/sondalex/wg-waybar/releases/latest";
+/// Minisign public key the releases are signed with, as `minisign -G` writes it.
+const RELEASE_KEY: &str = include_str!("../minisign.pub");
+/// Key replacing [`RELEASE_KEY`], given at build time so that packagers
+/// building their own releases can bake in theirs.
+const BUILD_KEY: Option<&str> = option_env!("WG_WAYBAR_UPDATE_KEY");
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// Largest binary accepted, far above the size of a release.
+const MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+fn update_error(e: impl std::fmt::Display) -> error::Error {
+    error::Error::Update(e.to_string())
+}
+
+fn agent() -> ureq::Agent {
+    http::agent(TIMEOUT)
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+impl Release {
+    fn latest() -> Result<Self, error::Error> {
+        agent()
+            .get(RELEASES_URL)
+            .header("User-Agent", "wg-waybar")
+            .call()
+            .map_err(update_error)?
+            .body_mut()
+            .read_json()
+            .map_err(update_error)
+    }
+
+    fn asset(&self, name: &str) -> Result<&Asset, error::Error> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| update_error(format!("{} has no asset {}", self.tag_name, name)))
+    }
+}
+
+/// Release asset of the binary for this machine, e.g. `wg-waybar-x86_64-linux`.
+fn asset_name() -> String {
+    format!(
+        "wg-waybar-{}-{}",
+        std::env::consts::ARCH,
+        std::env::consts::OS
+    )
+}
+
+/// Numeric parts of a version tag, `v1.2.3` being `[1, 2, 3]`.
+fn version_parts(version: &str) -> Vec<u64> {
+    version
+        .trim_start_matches('v')
+        .split(['.', '-', '+'])
+        .map_while(|part| part.parse().ok())
+        .collect()
+}
+
+fn is_newer(tag: &str, current: &str) -> bool {
+    version_parts(tag) > version_parts(current)
+}
+
+fn download(url: &str) -> Result<Vec<u8>, error::Error> {
+    agent()
+        .get(url)
+        .header("User-Agent", "wg-waybar")
+        .call()
+        .map_err(update_error)?
+        .body_mut()
+        .with_config()
+        .limit(MAX_SIZE)
+        .read_to_vec()
+        .map_err(update_error)
+}
+
+fn public_key() -> Result<PublicKey, error::Error> {
+    match BUILD_KEY {
+        Some(key) => PublicKey::from_base64(key.trim()),
+        None => PublicKey::decode(RELEASE_KEY),
+    }
+    .map_err(|e| update_error(format!("this build has no valid update key: {}", e)))
+}
+
+pub fn has_key() -> bool {
+    public_key().is_ok()
+}
+
+/// `key:value` of a trusted comment, e.g. `version` in
+/// `timestamp:1760000000 file:wg-waybar-x86_64-linux version:v0.3.0`.
+fn comment_field<'a>(comment: &'a str, key: &str) -> Option<&'a str> {
+    comment
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix(key)?.strip_prefix(':'))
+}
+
+/// The version a signature vouches for, refused unless it is for `asset` and
+/// newer than `current`: the release tag comes unsigned from the API, so an
+/// older binary signed with the same key would be installed otherwise.
+fn signed_version(comment: &str, asset: &str, current: &str) -> Result<String, error::Error> {
+    if comment_field(comment, "file") != Some(asset) {
+        return Err(update_error(format!(
+            "the signature is not for {} (trusted comment: {})",
+            asset, comment
+        )));
+    }
+    let version = comment_field(comment, "version")
+        .ok_or_else(|| update_error("the signature does not name a version"))?;
+    if !is_newer(version, current) {
+        return Err(update_error(format!(
+            "refusing to downgrade {} to {}",
+            current, version
+        )));
+    }
+    Ok(version.to_string())
+}
+
+/// Check the signature of `binary`, returning its trusted comment.
+fn verify(key: &PublicKey, binary: &[u8], signature: &[u8]) -> Result<String, error::Error> {
+    let signature = std::str::from_utf8(signature)
+        .map_err(update_error)
+        .and_then(|signature| Signature::decode(signature).map_err(update_error))?;
+    key.verify(binary, &signature, false)
+        .map_err(|e| update_error(format!("bad signature: {}", e)))?;
+    Ok(signature.trusted_comment().to_string())
+}
+
+/// Write `binary` next to `exe` and rename it over, so the binary is never half written.
+fn replace(exe: &Path, binary: &[u8]) -> Result<(), error::Error> {
+    let staged = exe.with_extension("new");
+    let result = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o755)
+        .open(&staged)
+        .and_then(|mut file| {
+            file.write_all(binary)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&staged, exe));
+    if result.is_err() {
+        let _ = fs::remove_file(&staged);
+    }
+    Ok(result?)
+}
+
+/// Replace the running binary with the latest release once its signature checks out.
+///
+/// With `check`, only report whether an update is available.
+pub fn run(check: bool, debug: bool) -> Result<(), error::Error> {
+    let current = env!("CARGO_PKG_VERSION");
+    let release = Release::latest()?;
+    if !is_newer(&release.tag_name, current) {
+        println!("wg-waybar {} is up to date", current);
+        return Ok(());
+    }
+    if check {
+        println!(
+            "wg-waybar {} is available (installed: {})",
+            release.tag_name, current
+        );
+        return Ok(());
+    }
+    let key = public_key()?;
+    let name = asset_name();
+    let binary = download(&release.asset(&name)?.browser_download_url)?;
+    let signature = download(
+        &release
+            .asset(&format!("{}.minisig", name))?
+            .browser_download_url,
+    )?;
+    let comment = verify(&key, &binary, &signature)?;
+    let version = signed_version(&comment, &name, current)?;
+    let exe = std::env::current_exe()?;
+    if debug {
+        println!("Replacing {} with {}", exe.display(), version);
+    }
+    replace(&exe, &binary)?;
+    println!("Updated wg-waybar {} to {}", current, version);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_release_tags() {
+        assert!(is_newer("v0.2.0", "0.1.0"));
+        assert!(is_newer("v0.10.0", "0.9.3"));
+        assert!(!is_newer("v0.1.0", "0.1.0"));
+        assert!(!is_newer("0.1.0-rc1", "0.1.0"));
+    }
+
+    #[test]
+    fn takes_the_version_from_the_signature() {
+        let asset = "wg-waybar-x86_64-linux";
+        let comment = "timestamp:1760000000 file:wg-waybar-x86_64-linux version:v0.3.0";
+        assert_eq!(signed_version(comment, asset, "0.2.0").unwrap(), "v0.3.0");
+        // An older release signed with the same key.
+        assert!(signed_version(comment, asset, "0.3.0").is_err());
+        assert!(signed_version(comment, "wg-waybar-aarch64-linux", "0.2.0").is_err());
+        assert!(
+            signed_version(
+                "timestamp:1760000000 file:wg-waybar-x86_64-linux",
+                asset,
+                "0.2.0"
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,532 @@
+use crate::actions::{self, Context};
+use crate::app_config::AccessConfig;
+use crate::audit::Requester;
+use crate::config;
+use crate::error;
+use crate::profiles::{self, Profile};
+use crate::status;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Failure of the requested action itself.
+const SERVER_ERROR: i64 = -32000;
+/// The caller may not bring the profile up or down.
+const UNAUTHORIZED: i64 = -32001;
+
+/// Clients served at once by [`run_socket`], each holding a thread; further
+/// connections are closed right away.
+const MAX_CLIENTS: usize = 64;
+
+/// A JSON-RPC 2.0 request, one per line, or a batch of them in an array.
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    /// Absent for notifications, which get no response.
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+fn error_response(id: Value, code: i64, message: String) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+/// The response to one request, `None` for a notification.
+fn answer_one(
+    request: Value,
+    handle: &dyn Fn(&str, &Value) -> Result<Value, RpcError>,
+) -> Option<Value> {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let request = match serde_json::from_value::<Request>(request) {
+        Ok(request) if request.jsonrpc == "2.0" => request,
+        Ok(_) => {
+            return Some(error_response(
+                id,
+                INVALID_REQUEST,
+                "jsonrpc must be \"2.0\"".to_string(),
+            ));
+        }
+        Err(e) => return Some(error_response(id, INVALID_REQUEST, e.to_string())),
+    };
+    let result = handle(&request.method, &request.params);
+    let id = request.id?;
+    Some(match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(e) => error_response(id, e.code, e.message),
+    })
+}
+
+/// The response to a line holding a request or a batch of them, `None` when
+/// there is nothing to send back: only notifications.
+fn answer(line: &str, handle: &dyn Fn(&str, &Value) -> Result<Value, RpcError>) -> Option<Value> {
+    match serde_json::from_str(line) {
+        Ok(Value::Array(batch)) if batch.is_empty() => Some(error_response(
+            Value::Null,
+            INVALID_REQUEST,
+            "empty batch".to_string(),
+        )),
+        Ok(Value::Array(batch)) => {
+            let responses: Vec<Value> = batch
+                .into_iter()
+                .filter_map(|request| answer_one(request, handle))
+                .collect();
+            (!responses.is_empty()).then_some(Value::Array(responses))
+        }
+        Ok(request) => answer_one(request, handle),
+        Err(e) => Some(error_response(Value::Null, PARSE_ERROR, e.to_string())),
+    }
+}
+
+impl From<error::Error> for RpcError {
+    fn from(value: error::Error) -> Self {
+        Self {
+            code: SERVER_ERROR,
+            message: value.to_string(),
+        }
+    }
+}
+
+/// Answers the requests of one client, one JSON document per line.
+struct Server<'a, 'c> {
+    /// With the user at the other end as requester, for the audit log.
+    ctx: Context<'c>,
+    config_path: &'a Path,
+    netns: Option<&'a str>,
+    output: Mutex<Box<dyn Write + Send + 'a>>,
+    /// Globs of the profiles the client may bring up or down, any when `None`.
+    allowed: Option<&'a [String]>,
+    /// Held while an action runs, so that clients of the socket take turns.
+    busy: &'a Mutex<()>,
+    subscribed: AtomicBool,
+    stopped: AtomicBool,
+}
+
+impl Server<'_, '_> {
+    fn send(&self, message: &Value) {
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writeln!(output, "{}", message);
+        let _ = output.flush();
+    }
+
+    fn authorize(&self, action: &str, profile: &Profile) -> Result<(), RpcError> {
+        match self.allowed {
+            Some(globs)
+                if !globs
+                    .iter()
+                    .any(|glob| profiles::glob_match(glob, &profile.name)) =>
+            {
+                self.ctx.audit_denied(action, &profile.name);
+                Err(RpcError {
+                    code: UNAUTHORIZED,
+                    message: format!("Not allowed to bring {} up or down", profile.name),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The profile named in `params`, or the one the module shows.
+    fn profile(&self, params: &Value) -> Result<Profile, RpcError> {
+        match params.get("profile") {
+            Some(Value::String(name)) => {
+                let profiles = self.ctx.profiles()?;
+                Ok(profiles::find(&profiles, name)?.clone())
+            }
+            Some(_) => Err(RpcError {
+                code: INVALID_PARAMS,
+                message: "profile must be a string".to_string(),
+            }),
+            None => Ok(profiles::select(
+                self.config_path,
+                self.netns,
+                self.ctx.profile_filter,
+            )?),
+        }
+    }
+
+    fn status(&self, profile: &Profile) -> Result<Value, RpcError> {
+        let options = (self.ctx.options_for)(&profile.name);
+        let report = status::report_profile(profile, self.ctx.store, &options)?;
+        let mut value = report.to_json();
+        value["interface"] = json!(profile.name);
+        Ok(value)
+    }
+
+    fn handle(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "status" => self.status(&self.profile(params)?),
+            "toggle" => {
+                let profile = self.profile(params)?;
+                self.authorize("toggle", &profile)?;
+                let _busy = self.busy.lock().unwrap_or_else(|e| e.into_inner());
+                actions::ignore_missing_waybar(actions::toggle(&self.ctx, &profile))?;
+                self.status(&profile)
+            }
+            "switch" => {
+                let Some(Value::String(target)) = params.get("profile") else {
+                    return Err(RpcError {
+                        code: INVALID_PARAMS,
+                        message: "switch requires a profile".to_string(),
+                    });
+                };
+                let profiles = self.ctx.profiles()?;
+                // Switching takes down the others that are up, which needs their authorization too.
+                for profile in &profiles {
+                    if profile.name == *target
+                        || config::is_active(&profile.name, self.netns).unwrap_or(false)
+                    {
+                        self.authorize("switch", profile)?;
+                    }
+                }
+                let _busy = self.busy.lock().unwrap_or_else(|e| e.into_inner());
+                actions::ignore_missing_waybar(actions::switch(&self.ctx, &profiles, target))?;
+                self.status(profiles::find(&profiles, target)?)
+            }
+            "subscribe" => {
+                self.subscribed.store(true, Ordering::SeqCst);
+                Ok(json!(true))
+            }
+            _ => Err(RpcError {
+                code: METHOD_NOT_FOUND,
+                message: format!("Unknown method: {}", method),
+            }),
+        }
+    }
+
+    fn respond(&self, line: &str) {
+        if let Some(response) = answer(line, &|method, params| self.handle(method, params)) {
+            self.send(&response);
+        }
+    }
+
+    /// Push a `status` notification whenever the status of the shown profile changes.
+    fn notify_changes(&self, interval: Duration) {
+        let mut previous: Option<Value> = None;
+        while !self.stopped.load(Ordering::SeqCst) {
+            if self.subscribed.load(Ordering::SeqCst)
+                && let Ok(status) = self.profile(&Value::Null).and_then(|p| self.status(&p))
+                && previous.as_ref() != Some(&status)
+            {
+                self.send(&json!({"jsonrpc": "2.0", "method": "status", "params": status}));
+                previous = Some(status);
+            }
+            std::thread::sleep(interval);
+        }
+    }
+}
+
+/// Answer the lines of `input` until it is closed.
+fn serve_lines(
+    server: &Server,
+    input: impl BufRead,
+    interval: Duration,
+) -> Result<(), error::Error> {
+    std::thread::scope(|scope| {
+        scope.spawn(|| server.notify_changes(interval));
+        let result = (|| {
+            for line in input.lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    server.respond(&line);
+                }
+            }
+            Ok(())
+        })();
+        server.stopped.store(true, Ordering::SeqCst);
+        result
+    })
+}
+
+/// Serve JSON-RPC 2.0 over stdin/stdout until stdin is closed.
+///
+/// Methods: `status`, `toggle` and `switch` taking an optional (required for
+/// `switch`) `profile` parameter, and `subscribe`, after which `status`
+/// notifications are sent on every change. A line may hold a batch of
+/// requests, answered by one array.
+pub fn run_stdio(
+    ctx: &Context,
+    config_path: &Path,
+    netns: Option<&str>,
+    interval: Duration,
+) -> Result<(), error::Error> {
+    let busy = Mutex::new(());
+    let server = Server {
+        ctx: ctx.clone(),
+        config_path,
+        netns,
+        output: Mutex::new(Box::new(std::io::stdout())),
+        allowed: None,
+        busy: &busy,
+        subscribed: AtomicBool::new(false),
+        stopped: AtomicBool::new(false),
+    };
+    serve_lines(&server, std::io::stdin().lock(), interval)
+}
+
+/// User id of the process at the other end of `stream`.
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> std::io::Result<u32> {
+    let mut credentials: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut credentials as *mut libc::ucred).cast(),
+            &mut length,
+        )
+    };
+    if result == 0 {
+        Ok(credentials.uid)
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(stream: &UnixStream) -> std::io::Result<u32> {
+    let (mut uid, mut gid) = (0, 0);
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } == 0 {
+        Ok(uid)
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Globs of the profiles `uid` may bring up or down: any for root, those of
+/// its `[access.<user>]` entry otherwise, and none without one.
+fn allowed_profiles(uid: u32, access: &HashMap<String, AccessConfig>) -> Option<&[String]> {
+    if uid == 0 {
+        return None;
+    }
+    let name = uzers::get_user_by_uid(uid).map(|user| user.name().to_string_lossy().into_owned());
+    Some(
+        name.and_then(|name| access.get(&name))
+            .map(|access| access.profiles.as_slice())
+            .unwrap_or_default(),
+    )
+}
+
+/// Listen on `socket`, accessible to root and the members of `gid` only.
+///
+/// The socket is bound in a directory only root can enter and moved into place
+/// once restricted, so that no one can connect in between.
+fn bind_restricted(socket: &Path, gid: u32) -> Result<UnixListener, error::Error> {
+    let dir = socket.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(dir)?;
+    let private = dir.join(format!(".wg-waybar-bind-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&private);
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join("socket");
+    let result = (|| -> Result<UnixListener, error::Error> {
+        let listener = UnixListener::bind(&bound)?;
+        std::os::unix::fs::chown(&bound, None, Some(gid))?;
+        fs::set_permissions(&bound, fs::Permissions::from_mode(0o660))?;
+        fs::rename(&bound, socket)?;
+        Ok(listener)
+    })();
+    let _ = fs::remove_dir_all(&private);
+    result
+}
+
+/// Serve JSON-RPC 2.0 to every client of `socket`, e.g. the Waybar modules of
+/// several users while running as a system service.
+///
+/// The socket is only accessible to root and the members of `group`; status is
+/// open to all of them, toggling and switching limited by `access`.
+#[allow(clippy::too_many_arguments)]
+pub fn run_socket(
+    ctx: &Context,
+    config_path: &Path,
+    netns: Option<&str>,
+    interval: Duration,
+    socket: &Path,
+    group: &str,
+    access: &HashMap<String, AccessConfig>,
+) -> Result<(), error::Error> {
+    let gid = uzers::get_group_by_name(group)
+        .ok_or_else(|| error::Error::InvalidFormat {
+            message: format!("Unknown group: {}", group),
+        })?
+        .gid();
+    let listener = bind_restricted(socket, gid)?;
+    let busy = Mutex::new(());
+    let busy = &busy;
+    let clients = AtomicUsize::new(0);
+    let clients = &clients;
+    std::thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    if ctx.debug {
+                        println!("Failed to accept a client: {}", e);
+                    }
+                    continue;
+                }
+            };
+            if clients.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+                clients.fetch_sub(1, Ordering::SeqCst);
+                if ctx.debug {
+                    println!("Refused a client: {} already connected", MAX_CLIENTS);
+                }
+                continue;
+            }
+            scope.spawn(move || {
+                let result = (|| {
+                    let uid = peer_uid(&stream)?;
+                    let server = Server {
+                        ctx: Context {
+                            requester: Requester::from_uid(uid),
+                            ..ctx.clone()
+                        },
+                        config_path,
+                        netns,
+                        output: Mutex::new(Box::new(stream.try_clone()?)),
+                        allowed: allowed_profiles(uid, access),
+                        busy,
+                        subscribed: AtomicBool::new(false),
+                        stopped: AtomicBool::new(false),
+                    };
+                    serve_lines(&server, BufReader::new(stream), interval)
+                })();
+                if let Err(e) = result
+                    && ctx.debug
+                {
+                    println!("Client of {} failed: {}", socket.display(), e);
+                }
+                clients.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+        Ok(())
+    })
+}
+
+/// Send one request to the server listening on `socket` and return its result.
+pub fn call(socket: &Path, method: &str, params: Value) -> Result<Value, error::Error> {
+    let control_error =
+        |e: &dyn std::fmt::Display| error::Error::Control(format!("{}: {}", socket.display(), e));
+    let mut stream = UnixStream::connect(socket).map_err(|e| control_error(&e))?;
+    let request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+    writeln!(stream, "{}", request).map_err(|e| control_error(&e))?;
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .map_err(|e| control_error(&e))?;
+    let mut response: Value = serde_json::from_str(&line)?;
+    match response.get("error") {
+        Some(error) => Err(control_error(
+            &error["message"].as_str().unwrap_or("unknown error"),
+        )),
+        None => Ok(response["result"].take()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_users_to_their_access_entry() {
+        let access = HashMap::from([(
+            "root-less".to_string(),
+            AccessConfig {
+                profiles: vec!["wg-work".to_string()],
+            },
+        )]);
+        assert_eq!(allowed_profiles(0, &access), None);
+        // No user has this id, so no entry applies.
+        assert_eq!(allowed_profiles(u32::MAX - 1, &access), Some(&[][..]));
+    }
+
+    fn echo(method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "echo" => Ok(params.clone()),
+            _ => Err(RpcError {
+                code: METHOD_NOT_FOUND,
+                message: format!("Unknown method: {}", method),
+            }),
+        }
+    }
+
+    #[test]
+    fn answers_requests_and_batches() {
+        let response = answer(
+            r#"{"jsonrpc":"2.0","id":1,"method":"echo","params":[2]}"#,
+            &echo,
+        );
+        assert_eq!(
+            response,
+            Some(json!({"jsonrpc": "2.0", "id": 1, "result": [2]}))
+        );
+        // Notifications get no response.
+        assert_eq!(answer(r#"{"jsonrpc":"2.0","method":"echo"}"#, &echo), None);
+
+        let response = answer(
+            r#"[{"jsonrpc":"2.0","id":1,"method":"echo","params":1},
+                {"jsonrpc":"2.0","method":"echo"},
+                {"jsonrpc":"2.0","id":2,"method":"nope"}]"#,
+            &echo,
+        )
+        .unwrap();
+        assert_eq!(response[0]["result"], 1);
+        assert_eq!(response[1]["id"], 2);
+        assert_eq!(response[1]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(response.as_array().unwrap().len(), 2);
+        assert_eq!(
+            answer(r#"[{"jsonrpc":"2.0","method":"echo"}]"#, &echo),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        let code = |line: &str| answer(line, &echo).unwrap()["error"]["code"].clone();
+        assert_eq!(code("{"), PARSE_ERROR);
+        assert_eq!(code("[]"), INVALID_REQUEST);
+        assert_eq!(code(r#"{"id":1,"method":"echo"}"#), INVALID_REQUEST);
+        assert_eq!(
+            code(r#"{"jsonrpc":"1.0","id":1,"method":"echo"}"#),
+            INVALID_REQUEST
+        );
+        assert_eq!(code(r#"{"jsonrpc":"2.0","id":1}"#), INVALID_REQUEST);
+        let response = answer(r#"[1]"#, &echo).unwrap();
+        assert_eq!(response[0]["error"]["code"], INVALID_REQUEST);
+        assert_eq!(response[0]["id"], Value::Null);
+    }
+
+    #[test]
+    fn binds_the_socket_restricted() {
+        let dir = std::env::temp_dir().join(format!("wg-waybar-serve-{}", std::process::id()));
+        let socket = dir.join("control.sock");
+        let gid = unsafe { libc::getgid() };
+        let _listener = bind_restricted(&socket, gid).unwrap();
+        let mode = fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        // Only the socket is left behind.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
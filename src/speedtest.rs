@@ -0,0 +1,137 @@
+use crate::config::{self, TunnelOptions};
+use crate::error;
+use crate::history::{self, Entry, SpeedTest};
+use crate::http;
+use crate::netns;
+use crate::state;
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Endpoints used when none is configured.
+pub const DEFAULT_DOWNLOAD_URL: &str = "https://speed.cloudflare.com/__down?bytes={bytes}";
+pub const DEFAULT_UPLOAD_URL: &str = "https://speed.cloudflare.com/__up";
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+fn agent() -> ureq::Agent {
+    http::agent(TIMEOUT)
+}
+
+fn bits_per_second(bytes: u64, elapsed: Duration) -> f64 {
+    bytes as f64 * 8.0 / elapsed.as_secs_f64()
+}
+
+fn format_rate(bps: Option<f64>) -> String {
+    match bps {
+        Some(bps) => format!("{:.2} Mbit/s", bps / 1e6),
+        None => "failed".to_string(),
+    }
+}
+
+/// Download from `url`, `{bytes}` being replaced by the payload size.
+fn download(url: &str, bytes: u64) -> Result<f64, String> {
+    let url = url.replace("{bytes}", &bytes.to_string());
+    let start = Instant::now();
+    let response = agent().get(&url).call().map_err(|e| e.to_string())?;
+    let mut reader = response.into_body().into_reader();
+    let mut buffer = [0u8; 64 * 1024];
+    let mut received = 0u64;
+    loop {
+        let read = reader.read(&mut buffer).map_err(|e| e.to_string())?;
+        if read == 0 {
+            break;
+        }
+        received += read as u64;
+    }
+    Ok(bits_per_second(received, start.elapsed()))
+}
+
+fn upload(url: &str, bytes: u64) -> Result<f64, String> {
+    let payload = vec![0u8; bytes as usize];
+    let start = Instant::now();
+    agent()
+        .post(url)
+        .send(&payload[..])
+        .map_err(|e| e.to_string())?;
+    Ok(bits_per_second(bytes, start.elapsed()))
+}
+
+/// Measure throughput through the tunnel, print it and store it in the usage history.
+pub fn run(
+    interface_name: &str,
+    options: &TunnelOptions,
+    download_url: &str,
+    upload_url: &str,
+    bytes: u64,
+    history_path: &Path,
+    debug: bool,
+) -> Result<(), error::Error> {
+    if !config::is_active(interface_name, options.netns)? {
+        return Err(error::Error::InvalidFormat {
+            message: format!(
+                "{} is down, connect it before running a speed test",
+                interface_name
+            ),
+        });
+    }
+    let measure = || {
+        let download = download(download_url, bytes);
+        let upload = upload(upload_url, bytes);
+        (download, upload)
+    };
+    let (download, upload) = match options.netns {
+        Some(name) => netns::with(name, measure)?,
+        None => measure(),
+    };
+    for (direction, result) in [("Download", &download), ("Upload", &upload)] {
+        if let Err(e) = result
+            && debug
+        {
+            println!("{} failed: {}", direction, e);
+        }
+    }
+    let entry = SpeedTest {
+        timestamp: state::now(),
+        interface: interface_name.to_string(),
+        endpoints: config::peer_endpoints(interface_name, options.netns)?
+            .iter()
+            .map(|endpoint| endpoint.to_string())
+            .collect(),
+        download_bps: download.ok(),
+        upload_bps: upload.ok(),
+    };
+    println!("Download: {}", format_rate(entry.download_bps));
+    println!("Upload:   {}", format_rate(entry.upload_bps));
+    history::append(history_path, &Entry::SpeedTest(entry))
+}
+
+/// Print the speed tests of the interface recorded in the usage history.
+pub fn print_history(interface_name: &str, history_path: &Path) -> Result<(), error::Error> {
+    for entry in history::load(history_path)? {
+        let Entry::SpeedTest(test) = entry;
+        if test.interface != interface_name {
+            continue;
+        }
+        println!(
+            "{}  {:<24}  down {:>14}  up {:>14}",
+            test.timestamp,
+            test.endpoints.join(","),
+            format_rate(test.download_bps),
+            format_rate(test.upload_bps),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_bits_per_second() {
+        assert_eq!(bits_per_second(1_000_000, Duration::from_secs(2)), 4e6);
+        assert_eq!(format_rate(Some(4e6)), "4.00 Mbit/s");
+        assert_eq!(format_rate(None), "failed");
+    }
+}
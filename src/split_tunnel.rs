@@ -0,0 +1,70 @@
+use crate::app_config::SplitTunnelConfig;
+use crate::error;
+use crate::utils;
+use std::path::Path;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+fn table_name(interface_name: &str) -> String {
+    format!("wg-waybar-{}", interface_name)
+}
+
+fn nft(args: &[&str], stdin: Option<&str>) -> Result<(), error::Error> {
+    utils::run_command("nft", args, stdin)
+        .map(|_| ())
+        .map_err(error::Error::SplitTunnel)
+}
+
+/// Configured cgroups that currently exist.
+///
+/// nftables resolves cgroup paths when the rules are loaded, so cgroups that
+/// do not exist yet (application not started) cannot be matched and are skipped.
+pub fn existing_cgroups(config: &SplitTunnelConfig) -> Vec<&str> {
+    config
+        .cgroups
+        .iter()
+        .map(|cgroup| cgroup.trim_matches('/'))
+        .filter(|cgroup| Path::new(CGROUP_ROOT).join(cgroup).is_dir())
+        .collect()
+}
+
+fn ruleset(interface_name: &str, config: &SplitTunnelConfig) -> String {
+    let mut rules = String::new();
+    for cgroup in existing_cgroups(config) {
+        let level = cgroup.split('/').count();
+        rules.push_str(&format!(
+            "    socket cgroupv2 level {} \"{}\" meta mark set {:#x}\n",
+            level, cgroup, config.fwmark
+        ));
+    }
+    format!(
+        "table inet {table} {{\n  chain output {{\n    type route hook output priority mangle; policy accept;\n{rules}  }}\n  chain postrouting {{\n    type nat hook postrouting priority srcnat; policy accept;\n    oifname \"{interface}\" meta mark {mark:#x} masquerade\n  }}\n}}\n",
+        table = table_name(interface_name),
+        rules = rules,
+        interface = interface_name,
+        mark = config.fwmark,
+    )
+}
+
+/// Mark the traffic of the configured cgroups so that it is routed through the tunnel.
+///
+/// Marked packets are re-routed after the source address was picked from the
+/// regular uplink, hence the masquerade on the way out of the tunnel.
+pub fn apply(interface_name: &str, config: &SplitTunnelConfig) -> Result<(), error::Error> {
+    nft(&["-f", "-"], Some(&ruleset(interface_name, config)))
+}
+
+pub fn teardown(interface_name: &str) {
+    let _ = nft(
+        &["delete", "table", "inet", &table_name(interface_name)],
+        None,
+    );
+}
+
+pub fn is_active(interface_name: &str) -> bool {
+    nft(
+        &["list", "table", "inet", &table_name(interface_name)],
+        None,
+    )
+    .is_ok()
+}
@@ -0,0 +1,753 @@
+use crate::dns_canary::DnsCheck;
+use crate::error;
+use crate::eyeballs::Family;
+use crate::hotspot::HotspotRoute;
+use crate::paths;
+use crate::utils;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Transitions older than this are left-overs of an interrupted toggle.
+const TRANSITION_TIMEOUT_SECS: u64 = 120;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum TransitionKind {
+    Connecting,
+    Disconnecting,
+    Reconnecting,
+}
+
+/// Step of a connection in progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Fetching,
+    Resolving,
+    Configuring,
+    Handshaking,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Fetching => "fetching",
+            Stage::Resolving => "resolving",
+            Stage::Configuring => "configuring",
+            Stage::Handshaking => "handshaking",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Stage::Fetching => "fetching the configuration",
+            Stage::Resolving => "resolving endpoints",
+            Stage::Configuring => "configuring the interface",
+            Stage::Handshaking => "waiting for the first handshake",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Transition {
+    pub kind: TransitionKind,
+    /// Unix timestamp of the start of the transition.
+    pub since: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stage: Option<Stage>,
+}
+
+/// Bytes received and sent by the peers of an interface.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Counters {
+    pub rx: u64,
+    pub tx: u64,
+}
+
+/// State of one interface, stored in its own slot so that commands managing
+/// different interfaces never overwrite each other.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct InterfaceState {
+    /// Error of the last toggle.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Class of the error of the last toggle.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<error::ErrorKind>,
+    /// Toggle in progress.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transition: Option<Transition>,
+    /// Profile left up by wg-waybar, restored on start.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active: Option<PathBuf>,
+    /// Public key of the peer selected as exit node.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_node: Option<String>,
+    /// Unix timestamp at which daemon mode disconnects the idle tunnel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_deadline: Option<u64>,
+    /// Unix time in milliseconds of the last toggle, to ignore click storms.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_toggle: Option<u64>,
+    /// Unix time in milliseconds until which an ignored toggle is shown.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub busy_until: Option<u64>,
+    /// Unix time in milliseconds until which a second click disconnects.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confirm_until: Option<u64>,
+    /// Failed connections in a row, handshake timeouts included.
+    #[serde(default)]
+    pub failures: u32,
+    /// Whether failures kept coming, shown until a connection succeeds.
+    #[serde(default)]
+    pub attention: bool,
+    /// Peer counters when the tunnel was brought up, to tell this session's traffic.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_start: Option<Counters>,
+    /// Seconds waited in vain for the first handshake of the last connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handshake_timed_out: Option<u64>,
+    /// Seconds the clock was behind NTP (negative when ahead) after the last
+    /// handshake timeout, when enough to explain it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_skew: Option<i64>,
+    /// Endpoint family that completed the last handshake race.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint_family: Option<Family>,
+    /// Connection asked for while offline, made by daemon mode once a default route is back.
+    #[serde(default)]
+    pub pending: bool,
+    /// Resolution through the DNS server of the tunnel after the last connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns_check: Option<DnsCheck>,
+    /// Why the `# HealthCheck` URL did not answer after the last connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check_failure: Option<String>,
+    /// How the clients of the hotspot were routed after the last connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hotspot: Option<HotspotRoute>,
+    /// Strict `rp_filter` was left on by the last connection, which may drop the replies.
+    #[serde(default)]
+    pub strict_rp_filter: bool,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Parse a state file, moving it aside when it is corrupted (e.g. by a power loss
+/// mid-write) so that a clean one is written in its place.
+fn read_or_recover<T: DeserializeOwned + Default>(path: &Path) -> Result<T, error::Error> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    let bytes = std::fs::read(path)?;
+    match serde_json::from_slice(&bytes) {
+        Ok(value) => Ok(value),
+        Err(e) => {
+            let mut aside = path.as_os_str().to_owned();
+            aside.push(format!(".corrupt-{}", now()));
+            std::fs::rename(path, &aside)?;
+            // stderr, so that the status printed for Waybar stays valid.
+            eprintln!(
+                "{} was corrupted ({}), moved to {}",
+                path.display(),
+                e,
+                Path::new(&aside).display()
+            );
+            Ok(T::default())
+        }
+    }
+}
+
+/// Write through a temporary file of this writer only, so that an interrupted
+/// write leaves the previous content rather than half of the new one.
+fn write_atomic(path: &Path, content: String) -> Result<(), error::Error> {
+    utils::replace_file(path, content, 0o644)
+}
+
+impl InterfaceState {
+    /// Whether a toggle at `now` (in milliseconds) should be ignored: one is in
+    /// progress or the last one is less than `interval` ago, unless it opened a
+    /// confirmation this one gives.
+    pub fn is_busy(&self, now: u64, interval: Duration) -> bool {
+        let recent = self
+            .last_toggle
+            .is_some_and(|last| now.saturating_sub(last) < interval.as_millis() as u64);
+        let confirming = self.confirm_until.is_some_and(|until| until > now);
+        (recent && !confirming) || self.transition().is_some()
+    }
+
+    /// The transition in progress, unless it is stale.
+    pub fn transition(&self) -> Option<&Transition> {
+        self.transition
+            .as_ref()
+            .filter(|transition| now().saturating_sub(transition.since) < TRANSITION_TIMEOUT_SECS)
+    }
+
+    pub fn set_transition(&mut self, kind: TransitionKind) {
+        self.transition = Some(Transition {
+            kind,
+            since: now(),
+            stage: None,
+        });
+    }
+
+    pub fn set_stage(&mut self, stage: Stage) {
+        if let Some(transition) = &mut self.transition {
+            transition.stage = Some(stage);
+        }
+    }
+}
+
+/// Content of the state file: the interfaces having a slot.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    #[serde(default)]
+    interfaces: BTreeSet<String>,
+    /// Signal of the Waybar module, recorded by the status it runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signal: Option<i32>,
+    // Single-document layout used before slots, migrated when the store is opened.
+    #[serde(default, skip_serializing)]
+    error: Option<HashMap<String, String>>,
+    #[serde(default, skip_serializing)]
+    active: HashMap<String, PathBuf>,
+}
+
+/// Open the store in the state directory, or in the runtime directory when that
+/// cannot be written (read-only home of a live system, sandbox).
+///
+/// State kept in the runtime directory is lost on logout or reboot, so profiles
+/// are not restored from it, but the module keeps working.
+pub fn open_store(
+    index_filename: &str,
+    debug: bool,
+) -> Result<(StateStore, PathBuf), error::Error> {
+    let runtime_dir = paths::runtime_dir();
+    let candidates = [
+        paths::state_dir()
+            .ok()
+            .map(|dir| (dir, runtime_dir.clone())),
+        runtime_dir.map(|dir| (dir, None)),
+    ];
+    open_first(candidates.into_iter().flatten(), index_filename, debug)
+}
+
+/// Open the store in the first state directory of `candidates` that can be
+/// written, each with the runtime directory of its transient state.
+fn open_first(
+    candidates: impl IntoIterator<Item = (PathBuf, Option<PathBuf>)>,
+    index_filename: &str,
+    debug: bool,
+) -> Result<(StateStore, PathBuf), error::Error> {
+    let mut last_error = None;
+    for (state_home, runtime_dir) in candidates {
+        let result = paths::ensure(state_home.clone()).and_then(|state_home| {
+            StateStore::open(&state_home, runtime_dir.as_deref(), index_filename)
+        });
+        match result {
+            Ok(store) => return Ok((store, state_home)),
+            Err(e) => {
+                if debug {
+                    println!("Cannot keep state in {}: {}", state_home.display(), e);
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        error::Error::UnCaught(error::UnCaughtError(
+            "no state or runtime directory".to_string(),
+        ))
+    }))
+}
+
+/// Fields of [`InterfaceState`] that only make sense during the session, kept in
+/// the runtime directory so that a reboot never leaves a stale transition behind.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Transient {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    transition: Option<Transition>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idle_deadline: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_toggle: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    busy_until: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    confirm_until: Option<u64>,
+}
+
+impl Transient {
+    fn take(state: &mut InterfaceState) -> Self {
+        Self {
+            transition: state.transition.take(),
+            idle_deadline: state.idle_deadline.take(),
+            last_toggle: state.last_toggle.take(),
+            busy_until: state.busy_until.take(),
+            confirm_until: state.confirm_until.take(),
+        }
+    }
+
+    fn restore(self, state: &mut InterfaceState) {
+        state.transition = self.transition;
+        state.idle_deadline = self.idle_deadline;
+        state.last_toggle = self.last_toggle;
+        state.busy_until = self.busy_until;
+        state.confirm_until = self.confirm_until;
+    }
+}
+
+/// Per-interface state files under `interfaces/`, listed in a shared index file.
+pub struct StateStore {
+    index_path: PathBuf,
+    slots_dir: PathBuf,
+    /// Slots of the transient fields, when they are not kept with the rest.
+    transient_dir: Option<PathBuf>,
+}
+
+impl StateStore {
+    /// Open the store in `state_home`, creating its layout if needed.
+    ///
+    /// With `runtime_dir`, transient fields are kept there instead.
+    pub fn open(
+        state_home: &Path,
+        runtime_dir: Option<&Path>,
+        index_filename: &str,
+    ) -> Result<Self, error::Error> {
+        let transient_dir = runtime_dir.and_then(|dir| {
+            paths::ensure(dir.to_path_buf())
+                .and_then(|dir| paths::ensure(dir.join("interfaces")))
+                .ok()
+        });
+        let store = Self {
+            index_path: state_home.join(index_filename),
+            slots_dir: state_home.join("interfaces"),
+            transient_dir,
+        };
+        if !store.slots_dir.exists() {
+            utils::fs_create_dir(store.slots_dir.clone())?;
+        }
+        if !store.index_path.exists() {
+            store.write_index(&Index::default())?;
+        }
+        store.migrate()?;
+        Ok(store)
+    }
+
+    /// The index, rebuilt from the slots when it was lost.
+    fn read_index(&self) -> Result<Index, error::Error> {
+        let mut index: Index = read_or_recover(&self.index_path)?;
+        if index.interfaces.is_empty() {
+            for entry in std::fs::read_dir(&self.slots_dir)?.flatten() {
+                let path = entry.path();
+                if path
+                    .extension()
+                    .is_some_and(|extension| extension == "json")
+                    && let Some(name) = path.file_stem().and_then(|stem| stem.to_str())
+                {
+                    index.interfaces.insert(name.to_string());
+                }
+            }
+        }
+        Ok(index)
+    }
+
+    fn write_index(&self, index: &Index) -> Result<(), error::Error> {
+        write_atomic(&self.index_path, serde_json::to_string(index)?)
+    }
+
+    /// Move errors and active profiles of the single-document layout into slots.
+    fn migrate(&self) -> Result<(), error::Error> {
+        let _lock = self.lock_index()?;
+        let mut index = self.read_index()?;
+        if index.error.is_none() && index.active.is_empty() {
+            return Ok(());
+        }
+        for (name, message) in index.error.take().unwrap_or_default() {
+            let mut state = self.load(&name)?;
+            state.error = Some(message);
+            self.write_slot(&name, &state)?;
+            index.interfaces.insert(name);
+        }
+        for (name, path) in std::mem::take(&mut index.active) {
+            let mut state = self.load(&name)?;
+            state.active = Some(path);
+            self.write_slot(&name, &state)?;
+            index.interfaces.insert(name);
+        }
+        self.write_index(&index)
+    }
+
+    fn slot_path(&self, interface_name: &str) -> PathBuf {
+        self.slots_dir.join(format!("{}.json", interface_name))
+    }
+
+    fn transient_path(&self, interface_name: &str) -> Option<PathBuf> {
+        self.transient_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.json", interface_name)))
+    }
+
+    /// Exclusive lock on the index, held until dropped, around each of its
+    /// read-modify-writes so that concurrent invocations do not lose entries.
+    fn lock_index(&self) -> Result<std::fs::File, error::Error> {
+        let mut path = self.index_path.as_os_str().to_owned();
+        path.push(".lock");
+        utils::lock_file(Path::new(&path))
+    }
+
+    /// Exclusive lock on the state of the interface, held until dropped.
+    fn lock(&self, interface_name: &str) -> Result<std::fs::File, error::Error> {
+        let dir = self.transient_dir.as_ref().unwrap_or(&self.slots_dir);
+        utils::lock_file(&dir.join(format!("{}.lock", interface_name)))
+    }
+
+    /// Record a toggle of the interface unless it is busy, returning whether it
+    /// was; checked and recorded under a lock so that of two toggles racing,
+    /// only one gets through.
+    pub fn claim_toggle(
+        &self,
+        interface_name: &str,
+        interval: Duration,
+    ) -> Result<bool, error::Error> {
+        let _lock = self.lock(interface_name)?;
+        let mut state = self.load(interface_name)?;
+        let now = now_millis();
+        if state.is_busy(now, interval) {
+            return Ok(false);
+        }
+        state.last_toggle = Some(now);
+        self.save(interface_name, &state)?;
+        Ok(true)
+    }
+
+    /// Take the toggle made while offline, as a toggle of its own: returns
+    /// false, keeping it pending, while [`claim_toggle`](Self::claim_toggle)
+    /// would refuse one, and once another toggle canceled it.
+    #[cfg(feature = "daemon")]
+    pub fn claim_pending(
+        &self,
+        interface_name: &str,
+        interval: Duration,
+    ) -> Result<bool, error::Error> {
+        let _lock = self.lock(interface_name)?;
+        let mut state = self.load(interface_name)?;
+        let now = now_millis();
+        if !state.pending || state.is_busy(now, interval) {
+            return Ok(false);
+        }
+        state.pending = false;
+        state.last_toggle = Some(now);
+        self.save(interface_name, &state)?;
+        Ok(true)
+    }
+
+    fn write_slot(&self, interface_name: &str, state: &InterfaceState) -> Result<(), error::Error> {
+        let Some(transient_path) = self.transient_path(interface_name) else {
+            return write_atomic(
+                &self.slot_path(interface_name),
+                serde_json::to_string(state)?,
+            );
+        };
+        let mut state = state.clone();
+        let transient = Transient::take(&mut state);
+        write_atomic(&transient_path, serde_json::to_string(&transient)?)?;
+        write_atomic(
+            &self.slot_path(interface_name),
+            serde_json::to_string(&state)?,
+        )
+    }
+
+    /// State of the interface, empty if it never had a slot.
+    pub fn load(&self, interface_name: &str) -> Result<InterfaceState, error::Error> {
+        let mut state: InterfaceState = read_or_recover(&self.slot_path(interface_name))?;
+        if let Some(path) = self.transient_path(interface_name)
+            && path.exists()
+        {
+            read_or_recover::<Transient>(&path)?.restore(&mut state);
+        }
+        Ok(state)
+    }
+
+    pub fn save(&self, interface_name: &str, state: &InterfaceState) -> Result<(), error::Error> {
+        self.write_slot(interface_name, state)?;
+        let _lock = self.lock_index()?;
+        let mut index = self.read_index()?;
+        if index.interfaces.insert(interface_name.to_string()) {
+            self.write_index(&index)?;
+        }
+        Ok(())
+    }
+
+    /// Load, modify and save the state of the interface, under its lock.
+    pub fn update(
+        &self,
+        interface_name: &str,
+        f: impl FnOnce(&mut InterfaceState),
+    ) -> Result<(), error::Error> {
+        let _lock = self.lock(interface_name)?;
+        let mut state = self.load(interface_name)?;
+        f(&mut state);
+        self.save(interface_name, &state)
+    }
+
+    /// Signal the Waybar module was last seen with.
+    pub fn signal(&self) -> Result<Option<i32>, error::Error> {
+        Ok(self.read_index()?.signal)
+    }
+
+    pub fn record_signal(&self, signal_num: i32) -> Result<(), error::Error> {
+        let _lock = self.lock_index()?;
+        let mut index = self.read_index()?;
+        if index.signal != Some(signal_num) {
+            index.signal = Some(signal_num);
+            self.write_index(&index)?;
+        }
+        Ok(())
+    }
+
+    /// Interfaces having a slot.
+    pub fn interfaces(&self) -> Result<Vec<String>, error::Error> {
+        Ok(self.read_index()?.interfaces.into_iter().collect())
+    }
+
+    /// Remove the slots of profiles that were deleted, and of interfaces with
+    /// nothing left to remember, not even a pending connection, returning the
+    /// names removed.
+    ///
+    /// `is_up` tells whether an interface exists, whose slot is then kept, and
+    /// `has_profile` whether a profile of that name is still in the directory.
+    pub fn collect_garbage(
+        &self,
+        is_up: impl Fn(&str) -> bool,
+        has_profile: impl Fn(&str) -> bool,
+    ) -> Result<Vec<String>, error::Error> {
+        let _lock = self.lock_index()?;
+        let mut index = self.read_index()?;
+        let mut removed = Vec::new();
+        for name in &index.interfaces {
+            let state = self.load(name)?;
+            let stale = match &state.active {
+                Some(path) => !path.exists(),
+                None => {
+                    !has_profile(name)
+                        || (state.error.is_none()
+                            && state.exit_node.is_none()
+                            && state.endpoint_family.is_none()
+                            && !state.pending)
+                }
+            };
+            if stale && !is_up(name) {
+                removed.push(name.clone());
+            }
+        }
+        for name in &removed {
+            index.interfaces.remove(name);
+            for path in [Some(self.slot_path(name)), self.transient_path(name)]
+                .into_iter()
+                .flatten()
+            {
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+            }
+        }
+        if !removed.is_empty() {
+            self.write_index(&index)?;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_corrupted_state_aside() {
+        let dir = std::env::temp_dir().join(format!("wg-waybar-state-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wg0.json");
+        std::fs::write(&path, r#"{"error": "Toggle fa"#).unwrap();
+
+        let state: InterfaceState = read_or_recover(&path).unwrap();
+        assert!(state.error.is_none());
+        assert!(!path.exists());
+        let aside = std::fs::read_dir(&dir).unwrap().flatten().next().unwrap();
+        assert!(
+            aside
+                .file_name()
+                .to_string_lossy()
+                .starts_with("wg0.json.corrupt-")
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn falls_back_to_a_writable_directory() {
+        let dir = std::env::temp_dir().join(format!("wg-waybar-fallback-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // Not even root can create a directory under a file.
+        let file = dir.join("file");
+        std::fs::write(&file, "").unwrap();
+        let runtime = dir.join("runtime");
+        let candidates = [
+            (file.join("state"), Some(runtime.clone())),
+            (runtime.clone(), None),
+        ];
+        let (store, state_home) = open_first(candidates, "status.json", false).unwrap();
+        assert_eq!(state_home, runtime);
+        store.update("wg0", |state| state.pending = true).unwrap();
+        assert!(store.load("wg0").unwrap().pending);
+
+        assert!(open_first([(file.join("state"), None)], "status.json", false).is_err());
+        assert!(open_first([], "status.json", false).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ignores_toggles_too_close_together() {
+        let interval = Duration::from_secs(2);
+        let state = InterfaceState {
+            last_toggle: Some(10_000),
+            ..Default::default()
+        };
+        assert!(state.is_busy(11_000, interval));
+        assert!(!state.is_busy(12_000, interval));
+        let confirming = InterfaceState {
+            confirm_until: Some(15_000),
+            ..state
+        };
+        assert!(!confirming.is_busy(11_000, interval));
+    }
+
+    #[test]
+    fn lets_one_of_racing_toggles_through() {
+        let dir = std::env::temp_dir().join(format!("wg-waybar-toggle-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = StateStore::open(&dir, None, "status.json").unwrap();
+        let claimed = std::thread::scope(|scope| {
+            let toggles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| store.claim_toggle("wg0", Duration::from_secs(60))))
+                .collect();
+            toggles
+                .into_iter()
+                .map(|toggle| toggle.join().unwrap().unwrap())
+                .filter(|claimed| *claimed)
+                .count()
+        });
+        assert_eq!(claimed, 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "daemon")]
+    #[test]
+    fn completes_a_pending_toggle_once_debounced() {
+        let dir = std::env::temp_dir().join(format!("wg-waybar-pending-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = StateStore::open(&dir, None, "status.json").unwrap();
+        let interval = Duration::from_secs(60);
+        assert!(!store.claim_pending("wg0", interval).unwrap());
+
+        // Toggled while offline just now: too soon to connect.
+        assert!(store.claim_toggle("wg0", interval).unwrap());
+        store.update("wg0", |state| state.pending = true).unwrap();
+        assert!(!store.claim_pending("wg0", interval).unwrap());
+        assert!(store.load("wg0").unwrap().pending);
+
+        store
+            .update("wg0", |state| state.last_toggle = Some(0))
+            .unwrap();
+        assert!(store.claim_pending("wg0", interval).unwrap());
+        let state = store.load("wg0").unwrap();
+        assert!(!state.pending);
+        assert!(state.last_toggle.is_some_and(|last| last > 0));
+        assert!(!store.claim_pending("wg0", Duration::ZERO).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_every_interface_saved_at_the_same_time() {
+        let dir = std::env::temp_dir().join(format!("wg-waybar-index-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = StateStore::open(&dir, None, "status.json").unwrap();
+        std::thread::scope(|scope| {
+            for n in 0..8 {
+                let store = &store;
+                scope.spawn(move || {
+                    store
+                        .update(&format!("wg{}", n), |state| {
+                            state.exit_node = Some("a".into())
+                        })
+                        .unwrap();
+                    store.record_signal(8 + n).unwrap();
+                });
+            }
+        });
+        assert_eq!(store.interfaces().unwrap().len(), 8);
+        let leftovers = std::fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".tmp"))
+            .count();
+        assert_eq!(leftovers, 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn forgets_the_state_of_deleted_profiles() {
+        let dir = std::env::temp_dir().join(format!("wg-waybar-gc-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = StateStore::open(&dir, None, "status.json").unwrap();
+        for name in ["kept", "deleted", "up"] {
+            store
+                .update(name, |state| state.error = Some("failed".into()))
+                .unwrap();
+        }
+        store
+            .update("empty", |state| state.exit_node = None)
+            .unwrap();
+        // A connection queued while offline, waiting for the network.
+        store
+            .update("pending", |state| state.pending = true)
+            .unwrap();
+        let removed = store
+            .collect_garbage(|name| name == "up", |name| name != "deleted")
+            .unwrap();
+        assert_eq!(removed, ["deleted", "empty"]);
+        assert_eq!(store.interfaces().unwrap(), ["kept", "pending", "up"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn records_the_signal_of_the_module() {
+        let dir = std::env::temp_dir().join(format!("wg-waybar-signal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = StateStore::open(&dir, None, "status.json").unwrap();
+        assert_eq!(store.signal().unwrap(), None);
+        store.record_signal(8).unwrap();
+        let store = StateStore::open(&dir, None, "status.json").unwrap();
+        assert_eq!(store.signal().unwrap(), Some(8));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
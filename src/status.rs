@@ -0,0 +1,637 @@
+use crate::clock;
+use crate::config::{self, PeerStats};
+use crate::conflicts;
+use crate::error;
+use crate::expiry;
+use crate::explain;
+use crate::health;
+use crate::owner;
+use crate::profiles::Profile;
+use crate::split_tunnel;
+use crate::state::{self, Counters, InterfaceState, StateStore, TransitionKind};
+use crate::uplink;
+use serde::Serialize;
+use serde_json::json;
+use std::io;
+use std::io::Write;
+
+/// Serialized as [`Status::as_str`] names it, the CSS class of the module.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+    Connected,
+    Disconnected,
+    Error,
+    Connecting,
+    Disconnecting,
+    Reconnecting,
+    /// Connected, but about to be disconnected for inactivity.
+    Idle,
+    /// A toggle came too soon after the previous one and was ignored.
+    Busy,
+    /// Connected, waiting for a second click to disconnect.
+    Confirm,
+    /// Up, but the peer has not answered within `--handshake-timeout`.
+    Warning,
+    /// Toggled while offline, connecting once the network is back.
+    Pending,
+    /// Up, brought up by another user who alone may bring it down.
+    InUse,
+}
+
+impl From<TransitionKind> for Status {
+    fn from(value: TransitionKind) -> Self {
+        match value {
+            TransitionKind::Connecting => Status::Connecting,
+            TransitionKind::Disconnecting => Status::Disconnecting,
+            TransitionKind::Reconnecting => Status::Reconnecting,
+        }
+    }
+}
+
+impl Status {
+    /// Whether the tunnel is up, whatever the module shows on top of it.
+    pub fn is_up(&self) -> bool {
+        matches!(
+            self,
+            Status::Connected | Status::Idle | Status::Confirm | Status::Warning
+        )
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Connected => "connected",
+            Status::Disconnected => "disconnected",
+            Status::Error => "error",
+            Status::Connecting => "connecting",
+            Status::Disconnecting => "disconnecting",
+            Status::Reconnecting => "reconnecting",
+            Status::Idle => "idle",
+            Status::Busy => "busy",
+            Status::Confirm => "confirm",
+            Status::Warning => "warning",
+            Status::Pending => "pending",
+            Status::InUse => "in-use",
+        }
+    }
+}
+
+impl Serialize for Status {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "schema")]
+impl schemars::JsonSchema for Status {
+    fn schema_name() -> std::borrow::Cow<'static, str> {
+        "Status".into()
+    }
+
+    fn json_schema(_: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        let names: Vec<&str> = [
+            Status::Connected,
+            Status::Disconnected,
+            Status::Error,
+            Status::Connecting,
+            Status::Disconnecting,
+            Status::Reconnecting,
+            Status::Idle,
+            Status::Busy,
+            Status::Confirm,
+            Status::Warning,
+            Status::Pending,
+            Status::InUse,
+        ]
+        .iter()
+        .map(Status::as_str)
+        .collect();
+        schemars::json_schema!({ "type": "string", "enum": names })
+    }
+}
+
+/// What the module displays for an interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusReport {
+    pub text: String,
+    pub status: Status,
+    pub tooltip: String,
+    /// Link quality from the handshake age, 0 unless connected.
+    pub percentage: u8,
+    /// Class of the error, when the status is one.
+    pub error_kind: Option<error::ErrorKind>,
+    /// Bytes moved by the tunnel, when it is up.
+    pub transfer: Option<Transfer>,
+    /// Connections kept failing, see `--attention-after`.
+    pub attention: bool,
+    /// The peers as the kernel reports them, when the tunnel is up.
+    pub peers: Vec<PeerStats>,
+}
+
+/// Status of `--output-format eww`, the most complete one.
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct FullStatus<'a> {
+    pub interface: &'a str,
+    pub status: Status,
+    /// Whether the tunnel is up, whatever the details of the status.
+    pub connected: bool,
+    pub text: &'a str,
+    pub tooltip: &'a str,
+    /// Link quality from the age of the last handshake, 0 when down.
+    pub percentage: u8,
+    pub error_kind: Option<error::ErrorKind>,
+    pub transfer: Option<Transfer>,
+    pub attention: bool,
+}
+
+/// Counters of the peers, and how much of them the current session accounts for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Transfer {
+    /// Raw peer counters, kept by the kernel across reconnections of the same interface.
+    pub total: Counters,
+    /// Since wg-waybar brought the tunnel up.
+    pub session: Counters,
+}
+
+impl Transfer {
+    /// Counters since `start`, restarting from zero when the kernel reset them.
+    pub fn since(total: Counters, start: Option<Counters>) -> Self {
+        let session = match start {
+            Some(start) if total.rx >= start.rx && total.tx >= start.tx => Counters {
+                rx: total.rx - start.rx,
+                tx: total.tx - start.tx,
+            },
+            _ => total,
+        };
+        Self { total, session }
+    }
+}
+
+/// Size in bytes with a binary unit, e.g. `1.5 MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+impl StatusReport {
+    /// A report of `status` with nothing more to tell than its text and tooltip.
+    pub fn new(text: String, status: Status, tooltip: String) -> Self {
+        Self {
+            text,
+            status,
+            tooltip,
+            percentage: 0,
+            error_kind: None,
+            transfer: None,
+            attention: false,
+            peers: Vec::new(),
+        }
+    }
+
+    pub fn error(tooltip: String) -> Self {
+        Self::new("VPN: Error".to_string(), Status::Error, tooltip)
+    }
+
+    /// The Waybar module JSON.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut value = waybar_json(
+            &self.text,
+            self.status,
+            &self.tooltip,
+            self.percentage,
+            self.error_kind,
+        );
+        if self.attention {
+            let mut classes = match value["class"].take() {
+                serde_json::Value::Array(classes) => classes,
+                class => vec![class],
+            };
+            classes.push(json!("attention"));
+            value["class"] = json!(classes);
+        }
+        if let Some(transfer) = &self.transfer {
+            value["transfer"] = json!(transfer);
+        }
+        value
+    }
+
+    /// A block of the i3bar protocol, `urgent` on errors. i3bar has no tooltips,
+    /// the text is shortened instead when space runs out.
+    pub fn to_i3bar_json(&self, interface_name: &str) -> serde_json::Value {
+        json!({
+            "name": "wg-waybar",
+            "instance": interface_name,
+            "full_text": self.text,
+            "short_text": interface_name,
+            "urgent": self.status == Status::Error || self.attention,
+        })
+    }
+
+    /// Flat JSON with stable keys for Eww/AGS, without Waybar-specific fields.
+    pub fn to_eww_json(&self, interface_name: &str) -> serde_json::Value {
+        json!(FullStatus {
+            interface: interface_name,
+            status: self.status,
+            connected: self.status.is_up(),
+            text: &self.text,
+            tooltip: &self.tooltip,
+            percentage: self.percentage,
+            error_kind: self.error_kind,
+            transfer: self.transfer,
+            attention: self.attention,
+        })
+    }
+}
+
+pub fn report(
+    interface_name: &str,
+    store: &StateStore,
+    options: &config::TunnelOptions,
+) -> Result<StatusReport, error::Error> {
+    let state = store.load(interface_name)?;
+    let mut report = report_state(interface_name, &state, options)?;
+    report.attention = state.attention;
+    Ok(report)
+}
+
+/// [`report`] for `profile`, with its tags in the tooltip.
+pub fn report_profile(
+    profile: &Profile,
+    store: &StateStore,
+    options: &config::TunnelOptions,
+) -> Result<StatusReport, error::Error> {
+    let mut report = report(&profile.name, store, options)?;
+    let tags = profile.tags();
+    if !tags.is_empty() {
+        report
+            .tooltip
+            .push_str(&format!("\nTags: {}", tags.join(", ")));
+    }
+    if let Some(note) = profile.note() {
+        report.tooltip.push_str(&format!("\nNote: {}", note));
+    }
+    if let Some(warning) = expiry::describe(profile) {
+        report.tooltip.push_str(&format!("\n{}", warning));
+    }
+    Ok(report)
+}
+
+/// Warning for a tunnel that is up while no handshake came within
+/// `--handshake-timeout` of connecting, nor since.
+fn handshake_warning(
+    interface_name: &str,
+    state: &InterfaceState,
+    percentage: u8,
+) -> Option<StatusReport> {
+    let waited = state.handshake_timed_out.filter(|_| percentage == 0)?;
+    let mut tooltip = format!(
+        "VPN is up, but no handshake after {} s: the server may be unreachable",
+        waited
+    );
+    if let Some(skew) = state.clock_skew {
+        tooltip.push_str(&format!("\n{}", clock::describe(skew)));
+    }
+    if let Some(hint) = explain::hint(error::ErrorKind::HandshakeTimeout) {
+        tooltip.push_str(&format!("\n{}", hint));
+    }
+    Some(StatusReport {
+        percentage,
+        ..StatusReport::new(format!("VPN: {}", interface_name), Status::Warning, tooltip)
+    })
+}
+
+fn report_state(
+    interface_name: &str,
+    state: &InterfaceState,
+    options: &config::TunnelOptions,
+) -> Result<StatusReport, error::Error> {
+    if state
+        .busy_until
+        .is_some_and(|until| until > state::now_millis())
+    {
+        return Ok(StatusReport::new(
+            "VPN: busy".to_string(),
+            Status::Busy,
+            "Ignoring clicks while the VPN settles".to_string(),
+        ));
+    }
+
+    if let Some(transition) = state.transition() {
+        let status = Status::from(transition.kind);
+        let report = match transition.stage {
+            Some(stage) => StatusReport::new(
+                format!("VPN: {}…", stage.as_str()),
+                status,
+                format!("VPN is {} ({})", status.as_str(), stage.description()),
+            ),
+            None => StatusReport::new(
+                format!("VPN: {}", interface_name),
+                status,
+                format!("VPN is {}", status.as_str()),
+            ),
+        };
+        return Ok(report);
+    }
+
+    if state.pending {
+        return Ok(StatusReport::new(
+            "VPN: pending".to_string(),
+            Status::Pending,
+            format!(
+                "No network: {} connects once it is back, click again to cancel",
+                interface_name
+            ),
+        ));
+    }
+
+    if let Some(value) = &state.error {
+        let mut tooltip = format!("Toggle failed: {}", value);
+        if let Some(hint) = state.error_kind.and_then(explain::hint) {
+            tooltip.push_str(&format!("\n{}", hint));
+        }
+        return Ok(StatusReport {
+            error_kind: state.error_kind,
+            ..StatusReport::error(tooltip)
+        });
+    }
+
+    let report = match config::is_active(interface_name, options.netns) {
+        Ok(is_active) => {
+            let status = if is_active {
+                Status::Connected
+            } else {
+                Status::Disconnected
+            };
+            // Not offering a toggle that would fail half-way.
+            if is_active && let Some(owner) = owner::other(interface_name) {
+                return Ok(StatusReport::new(
+                    format!("VPN: in use by {}", owner.user),
+                    Status::InUse,
+                    format!(
+                        "{} was brought up by {}, only they can bring it down",
+                        interface_name, owner.user
+                    ),
+                ));
+            }
+            let mut tooltip = format!("VPN is {}", status.as_str());
+            let percentage = if is_active {
+                health::quality(health::handshake_age(interface_name, options.netns))
+            } else {
+                0
+            };
+            if state
+                .confirm_until
+                .is_some_and(|until| until > state::now_millis())
+                && is_active
+            {
+                return Ok(StatusReport {
+                    percentage,
+                    ..StatusReport::new(
+                        "VPN: click again to disconnect".to_string(),
+                        Status::Confirm,
+                        "Click again to disconnect".to_string(),
+                    )
+                });
+            }
+            if is_active && let Some(report) = handshake_warning(interface_name, state, percentage)
+            {
+                return Ok(report);
+            }
+            if let Some(deadline) = state.idle_deadline
+                && is_active
+            {
+                let remaining = deadline.saturating_sub(state::now());
+                return Ok(StatusReport {
+                    percentage,
+                    ..StatusReport::new(
+                        format!("VPN: {}", interface_name),
+                        Status::Idle,
+                        format!("VPN is idle, disconnecting in {} s", remaining),
+                    )
+                });
+            }
+            if let Some(split_tunnel) = options.split_tunnel
+                && is_active
+                && split_tunnel::is_active(interface_name)
+            {
+                let cgroups = split_tunnel::existing_cgroups(split_tunnel);
+                tooltip.push_str(&format!("\nTunneled: {}", cgroups.join(", ")));
+            }
+            if let Some(key) = &state.exit_node
+                && is_active
+                && let Ok(Some(endpoint)) =
+                    config::peer_endpoint(interface_name, options.netns, key)
+            {
+                tooltip.push_str(&format!("\nExit node: {}", endpoint));
+            }
+            let dns_broken =
+                is_active && state.dns_check.as_ref().is_some_and(|check| !check.is_ok());
+            let unhealthy = is_active && state.health_check_failure.is_some();
+            if let Some(check) = &state.dns_check
+                && is_active
+            {
+                let warning = if check.is_ok() { "" } else { "Warning: " };
+                tooltip.push_str(&format!("\n{}{}", warning, check.describe()));
+            }
+            if let Some(failure) = &state.health_check_failure
+                && is_active
+            {
+                tooltip.push_str(&format!("\nWarning: health check failed: {}", failure));
+            }
+            // Read once, for the transfer and the peer table of the tooltip.
+            let peers = is_active
+                .then(|| config::peer_stats(interface_name, options.netns).ok())
+                .flatten();
+            let transfer = peers.as_ref().map(|peers| {
+                let total = peers
+                    .iter()
+                    .fold(Counters::default(), |total, peer| Counters {
+                        rx: total.rx + peer.transfer.rx,
+                        tx: total.tx + peer.transfer.tx,
+                    });
+                Transfer::since(total, state.session_start)
+            });
+            if let Some(transfer) = &transfer {
+                tooltip.push_str(&format!(
+                    "\nThis session: ↓ {} ↑ {}",
+                    format_bytes(transfer.session.rx),
+                    format_bytes(transfer.session.tx)
+                ));
+            }
+            if is_active && let Some(uplink) = uplink::find(interface_name, options.netns) {
+                tooltip.push_str(&format!("\nVPN over {}", uplink));
+            }
+            if is_active && let Some(route) = &state.hotspot {
+                tooltip.push_str(&format!("\n{}", route.describe()));
+            }
+            if is_active && state.strict_rp_filter {
+                tooltip.push_str(
+                    "\nWarning: strict rp_filter may drop the replies, set relax_rp_filter = true in [sysctl] of the app config",
+                );
+            }
+            let conflicts = conflicts::detect(interface_name);
+            if !conflicts.is_empty() {
+                tooltip.push_str(&format!(
+                    "\nWarning: other VPN active: {}",
+                    conflicts::describe(&conflicts)
+                ));
+            }
+            StatusReport {
+                text: format!("VPN: {}", interface_name),
+                // Connected but unable to resolve is what breaks browsing most often.
+                status: if dns_broken || unhealthy {
+                    Status::Warning
+                } else {
+                    status
+                },
+                tooltip,
+                percentage,
+                error_kind: None,
+                transfer,
+                attention: false,
+                peers: peers.unwrap_or_default(),
+            }
+        }
+        Err(e) => StatusReport::error(format!("Failed to check VPN status: {}", e)),
+    };
+    Ok(report)
+}
+
+/// `alt` selects the icon of an object `format-icons`, `percentage` Waybar's `states`.
+///
+/// Errors of a known kind get it as a second class, e.g. `.error.dns`.
+fn waybar_json(
+    text: &str,
+    status: Status,
+    tooltip: &str,
+    percentage: u8,
+    error_kind: Option<error::ErrorKind>,
+) -> serde_json::Value {
+    let class = match error_kind {
+        Some(kind) => json!([status.as_str(), kind.as_str()]),
+        None => json!(status.as_str()),
+    };
+    json!({
+        "text": text,
+        "alt": status.as_str(),
+        "class": class,
+        "tooltip": tooltip,
+        "percentage": percentage,
+        "error_kind": error_kind,
+    })
+}
+
+/// Escape `text` for Pango markup, which Waybar parses `text` and `tooltip` as.
+///
+/// Control characters other than newlines and tabs are dropped, Pango refusing them.
+pub fn escape_markup(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            '\n' | '\t' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn output_json(text: &str, status: Status, tooltip: &str) -> Result<(), std::io::Error> {
+    let (text, tooltip) = (escape_markup(text), escape_markup(tooltip));
+    println!("{}", waybar_json(&text, status, &tooltip, 0, None));
+    io::stdout().flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_the_session_from_the_snapshot() {
+        let total = Counters { rx: 5000, tx: 300 };
+        let transfer = Transfer::since(total, Some(Counters { rx: 1000, tx: 100 }));
+        assert_eq!(transfer.session, Counters { rx: 4000, tx: 200 });
+        // Counters reset by the kernel since the snapshot.
+        let transfer = Transfer::since(total, Some(Counters { rx: 9000, tx: 100 }));
+        assert_eq!(transfer.session, total);
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536 * 1024), "1.5 MiB");
+    }
+
+    #[test]
+    fn escapes_pango_markup() {
+        assert_eq!(
+            escape_markup("<peer> \"a\" & 'b'\n\u{1b}[0m"),
+            "&lt;peer&gt; &quot;a&quot; &amp; &#39;b&#39;\n[0m"
+        );
+    }
+
+    #[test]
+    fn shows_the_stage_of_a_toggle() {
+        let mut state = InterfaceState::default();
+        state.set_transition(TransitionKind::Connecting);
+        state.set_stage(state::Stage::Handshaking);
+        let report = report_state("wg0", &state, &config::TunnelOptions::default()).unwrap();
+        assert_eq!(report.status, Status::Connecting);
+        assert_eq!(report.text, "VPN: handshaking…");
+        assert_eq!(
+            report.tooltip,
+            "VPN is connecting (waiting for the first handshake)"
+        );
+    }
+
+    #[test]
+    fn shows_a_toggle_waiting_for_the_network() {
+        let state = InterfaceState {
+            pending: true,
+            ..Default::default()
+        };
+        let report = report_state("wg0", &state, &config::TunnelOptions::default()).unwrap();
+        assert_eq!(report.status, Status::Pending);
+        assert_eq!(report.text, "VPN: pending");
+        assert!(report.tooltip.contains("click again to cancel"));
+    }
+
+    #[test]
+    fn warns_of_a_peer_that_never_answered() {
+        let state = InterfaceState {
+            handshake_timed_out: Some(10),
+            ..Default::default()
+        };
+        let report = handshake_warning("wg0", &state, 0).unwrap();
+        assert_eq!(report.status, Status::Warning);
+        assert!(
+            report
+                .tooltip
+                .starts_with("VPN is up, but no handshake after 10 s")
+        );
+        assert_eq!(report.to_json()["class"], "warning");
+        // A handshake since then clears it.
+        assert!(handshake_warning("wg0", &state, 80).is_none());
+        assert!(handshake_warning("wg0", &InterfaceState::default(), 0).is_none());
+    }
+
+    #[test]
+    fn marks_errors_urgent_in_i3bar_blocks() {
+        let block = StatusReport::error("down".to_string()).to_i3bar_json("wg0");
+        assert_eq!(block["instance"], "wg0");
+        assert_eq!(block["full_text"], "VPN: Error");
+        assert_eq!(block["urgent"], true);
+    }
+}
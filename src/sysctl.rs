@@ -0,0 +1,136 @@
+use crate::applied;
+use crate::error::{self, WithContext};
+use crate::trace;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const PROC_SYS: &str = "/proc/sys";
+
+/// `net/ipv4/conf/wg0/rp_filter` as `sysctl` prints it, dots and slashes
+/// swapped; keys are kept as paths so that interface names with dots need no escaping.
+fn display(key: &str) -> String {
+    key.chars()
+        .map(|c| match c {
+            '/' => '.',
+            '.' => '/',
+            c => c,
+        })
+        .collect()
+}
+
+pub fn read(key: &str) -> Result<String, error::Error> {
+    let path = Path::new(PROC_SYS).join(key);
+    Ok(
+        trace::read_setting(&path, || std::fs::read_to_string(&path))?
+            .trim()
+            .to_string(),
+    )
+}
+
+fn write(key: &str, value: &str) -> std::io::Result<()> {
+    let path = Path::new(PROC_SYS).join(key);
+    trace::write_setting(&path, value, || std::fs::write(&path, value))
+}
+
+/// Values of the sysctls a tunnel changed, as they were before any tunnel
+/// changed them, kept with the rest of what it set up.
+type Saved = BTreeMap<String, String>;
+
+/// The value `key` had before any tunnel changed it: another tunnel may have
+/// saved it already, in which case `current` is what that tunnel set.
+fn original<'a>(others: &'a [Saved], key: &str, current: &'a str) -> &'a str {
+    others
+        .iter()
+        .find_map(|saved| saved.get(key))
+        .map_or(current, String::as_str)
+}
+
+/// Set `key` to `value` for as long as the tunnel `owner` is up, remembering
+/// what it was so that [`restore`] can put it back.
+pub fn set(owner: &str, key: &str, value: &str, debug: bool) -> Result<(), error::Error> {
+    let current = read(key)?;
+    let mut saved: Saved = applied::load("sysctl", owner).unwrap_or_default();
+    // Saved already when the tunnel comes up again without going down first.
+    if !saved.contains_key(key) {
+        let others: Vec<Saved> = applied::others("sysctl", owner);
+        let original = original(&others, key, &current).to_string();
+        saved.insert(key.to_string(), original);
+        applied::save("sysctl", owner, &saved)?;
+    }
+    if current != value {
+        write(key, value).with_context(|| format!("setting {}", display(key)))?;
+        if debug {
+            println!(
+                "{}: set {} from {} to {}",
+                owner,
+                display(key),
+                current,
+                value
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Put back the sysctls `owner` changed, except those another tunnel that is
+/// still up changed too.
+pub fn restore(owner: &str, debug: bool) {
+    let Some(saved) = applied::take::<Saved>("sysctl", owner) else {
+        return;
+    };
+    let others: Vec<Saved> = applied::others("sysctl", owner);
+    for (key, value) in saved {
+        let shared = others.iter().any(|other| other.contains_key(&key));
+        if shared || read(&key).is_ok_and(|current| current == value) {
+            continue;
+        }
+        let result = write(&key, &value);
+        if debug {
+            match result {
+                Ok(()) => println!("{}: restored {} to {}", owner, display(&key), value),
+                Err(e) => println!("{}: failed to restore {}: {}", owner, display(&key), e),
+            }
+        }
+    }
+}
+
+/// Whether reverse path filtering drops the replies coming through
+/// `interface_name`: the kernel applies the higher of `all` and the interface,
+/// and only strict mode (1) checks the route back.
+pub fn strict_rp_filter(interface_name: &str) -> bool {
+    let value = |scope: &str| {
+        read(&format!("net/ipv4/conf/{}/rp_filter", scope))
+            .ok()
+            .and_then(|value| value.parse::<u8>().ok())
+            .unwrap_or(0)
+    };
+    value("all").max(value(interface_name)) == 1
+}
+
+/// Switch reverse path filtering of the interface to loose mode (2) while the
+/// tunnel is up, which wins over a strict `all` and leaves the other
+/// interfaces as they are.
+pub fn relax_rp_filter(interface_name: &str, debug: bool) -> Result<(), error::Error> {
+    let key = format!("net/ipv4/conf/{}/rp_filter", interface_name);
+    set(interface_name, &key, "2", debug)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_value_found_by_the_first_tunnel() {
+        let others = vec![Saved::from([(
+            "net/ipv4/ip_forward".to_string(),
+            "0".to_string(),
+        )])];
+        // wg1 finds forwarding already enabled by wg0: 0 is what must come back.
+        assert_eq!(original(&others, "net/ipv4/ip_forward", "1"), "0");
+        assert_eq!(original(&others, "net/ipv4/conf/all/rp_filter", "1"), "1");
+        assert_eq!(
+            display("net/ipv4/conf/eth0.100/rp_filter"),
+            "net.ipv4.conf.eth0/100.rp_filter"
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use crate::error;
+use crate::paths;
+use crate::utils;
+use std::collections::HashMap;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// Name of the placeholder starting at `text`, which follows a `{`.
+///
+/// Names are upper case like environment variables, so that braces of
+/// `PostUp` scripts are left alone; `${NAME}` is a shell expansion and skipped too.
+fn placeholder(text: &str) -> Option<&str> {
+    let end = text.find('}')?;
+    let name = &text[..end];
+    let mut chars = name.chars();
+    (chars.next()?.is_ascii_uppercase()
+        && chars.all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'))
+    .then_some(name)
+}
+
+/// Replace the `{NAME}` placeholders of `content` with the value `lookup` gives
+/// them, returning the name of the first one it has none for.
+fn substitute(content: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut rendered = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find('{') {
+        let name = placeholder(&rest[start + 1..]).filter(|_| !rest[..start].ends_with('$'));
+        match name {
+            Some(name) => {
+                rendered.push_str(&rest[..start]);
+                rendered.push_str(&lookup(name).ok_or_else(|| name.to_string())?);
+                rest = &rest[start + name.len() + 2..];
+            }
+            None => {
+                rendered.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+fn has_placeholders(content: &str) -> bool {
+    content.match_indices('{').any(|(start, _)| {
+        !content[..start].ends_with('$') && placeholder(&content[start + 1..]).is_some()
+    })
+}
+
+/// Whether a profile has `{NAME}` placeholders to resolve when it comes up.
+pub fn is_template(config_path: &Path) -> bool {
+    std::fs::read_to_string(config_path).is_ok_and(|content| has_placeholders(&content))
+}
+
+/// Where the configuration rendered from a template is kept while it is in use.
+pub fn rendered_path(config_path: &Path) -> Result<PathBuf, error::Error> {
+    let name = config_path
+        .file_name()
+        .ok_or_else(|| error::Error::InvalidFormat {
+            message: "profile without a file name".to_string(),
+        })?;
+    let dir = match paths::runtime_dir() {
+        Some(dir) => dir,
+        None => paths::state_dir()?,
+    };
+    Ok(dir.join("rendered").join(name))
+}
+
+/// Resolve the placeholders of a template profile and keep the result as the
+/// configuration used for the tunnel.
+///
+/// Values come from the `[variables.<interface>]` section of the app config,
+/// then from the environment.
+pub fn render(
+    config_path: &Path,
+    variables: Option<&HashMap<String, String>>,
+) -> Result<(), error::Error> {
+    let content = std::fs::read_to_string(config_path)?;
+    let lookup = |name: &str| {
+        variables
+            .and_then(|variables| variables.get(name).cloned())
+            .or_else(|| std::env::var(name).ok())
+    };
+    let rendered = substitute(&content, lookup).map_err(|name| error::Error::InvalidFormat {
+        message: format!(
+            "{}: no value for {{{}}}, set it in [variables] of the app config",
+            config_path.display(),
+            name
+        ),
+    })?;
+    let path = rendered_path(config_path)?;
+    if let Some(dir) = path.parent() {
+        if let Some(base) = dir.parent() {
+            paths::ensure(base.to_path_buf())?;
+        }
+        paths::ensure(dir.to_path_buf())?;
+    }
+    // Created private before the private key gets written to it.
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)?;
+    utils::fs_write(path, rendered)
+}
+
+/// Forget the configuration rendered for a profile once it is down.
+pub fn discard(config_path: &Path) {
+    if is_template(config_path)
+        && let Ok(path) = rendered_path(config_path)
+    {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn substitutes_upper_case_placeholders() {
+        let lookup = |name: &str| (name == "SITE").then(|| "12".to_string());
+        let content = "Address = 10.0.{SITE}.2/32\nPostUp = echo ${SITE} {lower}\n";
+        assert!(has_placeholders(content));
+        assert_eq!(
+            substitute(content, lookup).unwrap(),
+            "Address = 10.0.12.2/32\nPostUp = echo ${SITE} {lower}\n"
+        );
+        assert_eq!(
+            substitute("Endpoint = {HOST}:51820", lookup),
+            Err("HOST".to_string())
+        );
+        assert!(!has_placeholders("PostUp = f() { :; }; echo ${SITE}"));
+    }
+}
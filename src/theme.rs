@@ -0,0 +1,213 @@
+use crate::cli::{Elevate, Theme};
+use crate::error;
+use crate::status::Status;
+use crate::utils;
+use serde_json::{Value, json};
+use std::path::Path;
+
+/// Colors of markup tooltips and of the generated CSS.
+pub struct Palette {
+    pub error: &'static str,
+    pub up: &'static str,
+    pub warning: &'static str,
+    pub down: &'static str,
+    /// Connection in progress.
+    pub busy: &'static str,
+}
+
+/// rose-pine: love, foam, gold, muted and iris.
+const ROSE_PINE: Palette = Palette {
+    error: "#eb6f92",
+    up: "#9ccfd8",
+    warning: "#f6c177",
+    down: "#6e6a86",
+    busy: "#c4a7e7",
+};
+
+/// Okabe–Ito: vermillion, blue, orange, grey and sky blue, told apart with
+/// any kind of color blindness.
+const OKABE_ITO: Palette = Palette {
+    error: "#d55e00",
+    up: "#0072b2",
+    warning: "#e69f00",
+    down: "#999999",
+    busy: "#56b4e9",
+};
+
+/// Colors of `theme`, none for `mono`.
+pub fn palette(theme: Theme) -> Option<&'static Palette> {
+    match theme {
+        Theme::Default => Some(&ROSE_PINE),
+        Theme::Colorblind => Some(&OKABE_ITO),
+        Theme::Mono => None,
+    }
+}
+
+impl Palette {
+    pub fn color(&self, status: Status) -> &'static str {
+        match status {
+            Status::Error => self.error,
+            Status::Disconnected => self.down,
+            Status::Warning => self.warning,
+            status if status.is_up() => self.up,
+            _ => self.warning,
+        }
+    }
+}
+
+/// How good a status is, so that a theme needs no color per status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Ok,
+    Off,
+    Bad,
+    Caution,
+}
+
+impl Severity {
+    /// Class of the severity, distinct from the `healthy`/`degraded`/`stale`
+    /// of Waybar's `states`.
+    pub fn class(&self) -> &'static str {
+        match self {
+            Severity::Ok => "ok",
+            Severity::Off => "off",
+            Severity::Bad => "bad",
+            Severity::Caution => "caution",
+        }
+    }
+}
+
+pub fn severity(status: Status) -> Severity {
+    match status {
+        Status::Connected | Status::Idle | Status::Confirm => Severity::Ok,
+        Status::Disconnected | Status::InUse => Severity::Off,
+        Status::Error => Severity::Bad,
+        _ => Severity::Caution,
+    }
+}
+
+/// Shape standing for the status where color cannot.
+pub fn shape(status: Status) -> &'static str {
+    match severity(status) {
+        Severity::Ok => "●",
+        Severity::Off => "○",
+        Severity::Bad => "✕",
+        Severity::Caution if status == Status::Warning => "▲",
+        Severity::Caution => "◌",
+    }
+}
+
+/// Adapt the Waybar JSON of a status to `theme`: the severity as a second
+/// class and, in `mono`, no `percentage` for `states` to color by.
+pub fn decorate(theme: Theme, status: Status, json: &mut Value) {
+    if theme == Theme::Default {
+        return;
+    }
+    let mut classes = match json["class"].take() {
+        Value::Array(classes) => classes,
+        class => vec![class],
+    };
+    classes.insert(1, json!(severity(status).class()));
+    json["class"] = Value::Array(classes);
+    if theme == Theme::Mono
+        && let Some(object) = json.as_object_mut()
+    {
+        object.remove("percentage");
+    }
+}
+
+const BLINK: &str = "@keyframes vpn-blink {\n  to { opacity: 0.3; }\n}\n";
+
+/// Waybar CSS for the classes the module emits with `theme`.
+pub fn css(theme: Theme) -> String {
+    let mut css = format!("/* wg-waybar, --theme {} */\n{}", theme.as_str(), BLINK);
+    match palette(theme) {
+        Some(palette) => {
+            for (class, color) in [
+                ("connected", palette.up),
+                ("idle", palette.up),
+                ("confirm", palette.warning),
+                ("disconnected", palette.down),
+                ("error", palette.error),
+                ("warning", palette.warning),
+                ("pending", palette.busy),
+                ("busy", palette.down),
+            ] {
+                css.push_str(&format!("#custom-vpn.{} {{ color: {}; }}\n", class, color));
+            }
+            css.push_str(&format!(
+                "#custom-vpn.connecting,\n#custom-vpn.disconnecting,\n#custom-vpn.reconnecting {{\n  color: {};\n  animation: vpn-blink 0.8s ease-in-out infinite alternate;\n}}\n",
+                palette.busy
+            ));
+            css.push_str(&format!(
+                "#custom-vpn.attention {{ border-bottom: 2px solid {}; }}\n",
+                palette.error
+            ));
+        }
+        None => css.push_str(
+            "#custom-vpn.off { opacity: 0.5; }\n#custom-vpn.bad { font-weight: bold; text-decoration: underline; }\n#custom-vpn.caution { font-style: italic; }\n#custom-vpn.connecting,\n#custom-vpn.disconnecting,\n#custom-vpn.reconnecting {\n  animation: vpn-blink 0.8s ease-in-out infinite alternate;\n}\n#custom-vpn.attention { border-bottom: 2px solid; }\n",
+        ),
+    }
+    if theme == Theme::Colorblind {
+        // Shapes of the border on top of the colors.
+        css.push_str(
+            "#custom-vpn.bad { border-bottom: 2px dashed; }\n#custom-vpn.caution { border-bottom: 2px dotted; }\n",
+        );
+    }
+    css
+}
+
+/// Write the CSS of `theme` to `output` and return the Waybar module running
+/// the status and toggle with it, through `elevate`.
+pub fn init_waybar(
+    theme: Theme,
+    config: &str,
+    signal_num: i32,
+    output: &Path,
+    elevate: Elevate,
+) -> Result<Value, error::Error> {
+    utils::fs_write(output.to_path_buf(), css(theme))?;
+    let exe = std::env::current_exe()?;
+    let mut words: Vec<String> = elevate.program().map(str::to_string).into_iter().collect();
+    words.extend([
+        exe.display().to_string(),
+        "--signal".to_string(),
+        signal_num.to_string(),
+        "--theme".to_string(),
+        theme.as_str().to_string(),
+        config.to_string(),
+    ]);
+    let base = utils::command_line(&words);
+    Ok(json!({
+        "custom/vpn": {
+            "format": "{}",
+            "return-type": "json",
+            "signal": signal_num,
+            "exec": base,
+            "on-click": format!("{} toggle", base),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_severity_class_and_drops_percentage_in_mono() {
+        let mut json = json!({"class": ["error", "dns"], "percentage": 0});
+        decorate(Theme::Mono, Status::Error, &mut json);
+        assert_eq!(json, json!({"class": ["error", "bad", "dns"]}));
+
+        let mut json = json!({"class": "warning", "percentage": 40});
+        decorate(Theme::Colorblind, Status::Warning, &mut json);
+        assert_eq!(
+            json,
+            json!({"class": ["warning", "caution"], "percentage": 40})
+        );
+        assert_eq!(shape(Status::Warning), "▲");
+
+        assert!(css(Theme::Colorblind).contains("#custom-vpn.error { color: #d55e00; }"));
+        assert!(!css(Theme::Mono).contains("color:"));
+    }
+}
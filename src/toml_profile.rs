@@ -0,0 +1,265 @@
+use crate::error;
+use serde::Deserialize;
+
+/// A profile written as TOML rather than INI:
+///
+/// ```toml
+/// tags = ["work"]
+/// note = "Office network, not for streaming"
+/// health_check = "https://intranet.example.com"
+/// expires = "2025-07-01"
+/// renew_command = "vpn-config --renew"
+///
+/// [interface]
+/// private_key = "..."
+/// address = ["10.2.0.2/32"]
+///
+/// [[peer]]
+/// name = "office"
+/// public_key = "..."
+/// endpoint = "vpn.example.com:51820"
+/// allowed_ips = ["0.0.0.0/0"]
+/// excluded_ips = ["192.168.0.0/16"]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlProfile {
+    /// Like `# Tags`.
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Like `# Note`.
+    note: Option<String>,
+    /// Like `# HealthCheck`.
+    health_check: Option<String>,
+    /// Like `# Expires`.
+    expires: Option<String>,
+    /// Like `# RenewCommand`, printing the new profile as TOML.
+    renew_command: Option<String>,
+    interface: TomlInterface,
+    #[serde(default, rename = "peer")]
+    peers: Vec<TomlPeer>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlInterface {
+    private_key: String,
+    address: Vec<String>,
+    #[serde(default)]
+    dns: Vec<String>,
+    listen_port: Option<u32>,
+    /// `"auto"`, `"off"` or a table number, like `Table`.
+    table: Option<toml::Value>,
+    metric: Option<u32>,
+    mtu: Option<u32>,
+    /// A number, `"0xca6c"` or `"off"`, like `FwMark`.
+    fwmark: Option<toml::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlPeer {
+    name: Option<String>,
+    public_key: String,
+    preshared_key: Option<String>,
+    endpoint: Option<String>,
+    allowed_ips: Vec<String>,
+    #[serde(default)]
+    excluded_ips: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+    persistent_keepalive: Option<u16>,
+    metric: Option<u32>,
+}
+
+/// `value` as the INI parser reads it back: on one line, with no comment,
+/// quote or escape of its own.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\0' => escaped.push_str("\\0"),
+            '\\' | ';' | '#' | '"' | '\'' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn push_key(ini: &mut String, key: &str, value: impl std::fmt::Display) {
+    ini.push_str(&format!("{} = {}\n", key, escape(&value.to_string())));
+}
+
+fn push_list(ini: &mut String, key: &str, values: &[String]) {
+    if !values.is_empty() {
+        push_key(ini, key, values.join(", "));
+    }
+}
+
+/// Value of the header `key` of a TOML profile, like `# <key> = <value>` of an
+/// INI one.
+pub fn header(content: &str, key: &str) -> Option<String> {
+    let profile: TomlProfile = toml::from_str(content).ok()?;
+    if key.eq_ignore_ascii_case("Tags") && !profile.tags.is_empty() {
+        Some(profile.tags.join(", "))
+    } else if key.eq_ignore_ascii_case("Note") {
+        profile.note
+    } else if key.eq_ignore_ascii_case("HealthCheck") {
+        profile.health_check
+    } else if key.eq_ignore_ascii_case("Expires") {
+        profile.expires
+    } else if key.eq_ignore_ascii_case("RenewCommand") {
+        profile.renew_command
+    } else {
+        None
+    }
+}
+
+/// The INI configuration a TOML profile stands for, so that both formats go
+/// through the same parsing and checks.
+pub fn to_ini(content: &str) -> Result<String, error::Error> {
+    let profile: TomlProfile = toml::from_str(content)?;
+    // Headers of the profile, read as comments like in an INI one.
+    let mut ini = String::new();
+    if !profile.tags.is_empty() {
+        ini.push_str(&format!("# Tags = {}\n", escape(&profile.tags.join(", "))));
+    }
+    if let Some(note) = &profile.note {
+        ini.push_str(&format!("# Note = {}\n", escape(note)));
+    }
+    if let Some(url) = &profile.health_check {
+        ini.push_str(&format!("# HealthCheck = {}\n", escape(url)));
+    }
+    if let Some(date) = &profile.expires {
+        ini.push_str(&format!("# Expires = {}\n", escape(date)));
+    }
+    if let Some(command) = &profile.renew_command {
+        ini.push_str(&format!("# RenewCommand = {}\n", escape(command)));
+    }
+    let interface = profile.interface;
+    ini.push_str("[Interface]\n");
+    push_key(&mut ini, "PrivateKey", &interface.private_key);
+    push_list(&mut ini, "Address", &interface.address);
+    push_list(&mut ini, "DNS", &interface.dns);
+    if let Some(port) = interface.listen_port {
+        push_key(&mut ini, "ListenPort", port);
+    }
+    match interface.table {
+        Some(toml::Value::String(table)) => push_key(&mut ini, "Table", table),
+        Some(table) => push_key(&mut ini, "Table", table),
+        None => {}
+    }
+    if let Some(metric) = interface.metric {
+        push_key(&mut ini, "Metric", metric);
+    }
+    if let Some(mtu) = interface.mtu {
+        push_key(&mut ini, "MTU", mtu);
+    }
+    match interface.fwmark {
+        Some(toml::Value::String(mark)) => push_key(&mut ini, "FwMark", mark),
+        Some(mark) => push_key(&mut ini, "FwMark", mark),
+        None => {}
+    }
+    for peer in profile.peers {
+        ini.push_str("\n[Peer]\n");
+        if let Some(name) = &peer.name {
+            push_key(&mut ini, "Name", name);
+        }
+        push_key(&mut ini, "PublicKey", &peer.public_key);
+        if let Some(key) = &peer.preshared_key {
+            push_key(&mut ini, "PresharedKey", key);
+        }
+        if let Some(endpoint) = &peer.endpoint {
+            push_key(&mut ini, "Endpoint", endpoint);
+        }
+        push_list(&mut ini, "AllowedIPs", &peer.allowed_ips);
+        push_list(&mut ini, "ExcludedIPs", &peer.excluded_ips);
+        push_list(&mut ini, "Groups", &peer.groups);
+        if let Some(keepalive) = peer.persistent_keepalive {
+            push_key(&mut ini, "PersistentKeepalive", keepalive);
+        }
+        if let Some(metric) = peer.metric {
+            push_key(&mut ini, "Metric", metric);
+        }
+    }
+    Ok(ini)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_the_ini_of_a_toml_profile() {
+        let toml = r#"
+            [interface]
+            private_key = "key"
+            address = ["10.2.0.2/32", "fd00::2/128"]
+            table = 1234
+            mtu = 1380
+
+            [[peer]]
+            public_key = "peer"
+            preshared_key = "psk"
+            endpoint = "vpn.example.com:51820"
+            allowed_ips = ["0.0.0.0/0"]
+            persistent_keepalive = 25
+        "#;
+        assert_eq!(
+            to_ini(toml).unwrap(),
+            "[Interface]\nPrivateKey = key\nAddress = 10.2.0.2/32, fd00::2/128\nTable = 1234\nMTU = 1380\n\n[Peer]\nPublicKey = peer\nPresharedKey = psk\nEndpoint = vpn.example.com:51820\nAllowedIPs = 0.0.0.0/0\nPersistentKeepalive = 25\n"
+        );
+
+        let toml = r#"
+            tags = ["work", "eu"]
+            health_check = "https://intranet.example.com"
+
+            [interface]
+            private_key = "key"
+            address = ["10.2.0.2/32"]
+
+            [[peer]]
+            name = "office"
+            public_key = "peer"
+            allowed_ips = ["0.0.0.0/0"]
+            excluded_ips = ["192.168.0.0/16"]
+        "#;
+        assert_eq!(
+            to_ini(toml).unwrap(),
+            "# Tags = work, eu\n# HealthCheck = https://intranet.example.com\n[Interface]\nPrivateKey = key\nAddress = 10.2.0.2/32\n\n[Peer]\nName = office\nPublicKey = peer\nAllowedIPs = 0.0.0.0/0\nExcludedIPs = 192.168.0.0/16\n"
+        );
+        assert!(
+            to_ini("[interface]\nprivate_key = \"key\"\naddress = []\nsave_config = true\n")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn keeps_values_from_adding_keys() {
+        let toml = r#"
+            tags = ["a # b"]
+
+            [interface]
+            private_key = "key"
+            address = ["10.2.0.2/32"]
+
+            [[peer]]
+            name = "office\n[Peer]\nPublicKey = other ; \"x\""
+            public_key = "peer"
+            allowed_ips = ["0.0.0.0/0"]
+        "#;
+        let ini = ini::Ini::load_from_str(&to_ini(toml).unwrap()).unwrap();
+        assert_eq!(
+            ini.section(Some("Peer")).unwrap().get("Name"),
+            Some("office\n[Peer]\nPublicKey = other ; \"x\"")
+        );
+        assert_eq!(ini.section_all(Some("Peer")).count(), 1);
+        assert_eq!(header(toml, "tags").as_deref(), Some("a # b"));
+    }
+}
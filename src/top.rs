@@ -0,0 +1,201 @@
+use crate::config;
+use crate::error;
+use crate::profiles::Profile;
+use crate::status;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+fn table_name(interface_name: &str) -> String {
+    format!("wg-waybar-top-{}", interface_name)
+}
+
+fn accounting_error(e: impl std::fmt::Display) -> error::Error {
+    error::Error::Accounting(e.to_string())
+}
+
+/// Run `nft`, inside the namespace of the interface if it lives in one, and return its output.
+fn nft(netns: Option<&str>, args: &[&str], stdin: Option<&str>) -> Result<String, error::Error> {
+    let mut command = match netns {
+        Some(name) => {
+            let mut command = Command::new("ip");
+            command.args(["netns", "exec", name, "nft"]);
+            command
+        }
+        None => Command::new("nft"),
+    };
+    let mut child = command
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| accounting_error(format!("failed to run `nft`: {}", e)))?;
+    if let Some(script) = stdin
+        && let Some(mut pipe) = child.stdin.take()
+    {
+        pipe.write_all(script.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(accounting_error(format!(
+            "`nft {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+/// Prefix length of an AllowedIPs entry, a bare address being a host.
+fn prefix_length(network: &str) -> u8 {
+    match network.split_once('/') {
+        Some((_, length)) => length.parse().unwrap_or(0),
+        None if network.contains(':') => 128,
+        None => 32,
+    }
+}
+
+/// One counter per direction and network, the most specific network
+/// counting a packet first and accepting it so that broader ones do not count it again.
+///
+/// Accepting only ends this table's chains; the verdicts of other tables still apply.
+fn ruleset(interface_name: &str, networks: &[String]) -> String {
+    let mut networks: Vec<&String> = networks.iter().collect();
+    networks.sort_by_key(|network| std::cmp::Reverse(prefix_length(network)));
+    let rules = |interface: &str, address: &str, direction: &str| {
+        networks
+            .iter()
+            .map(|network| {
+                let family = if network.contains(':') { "ip6" } else { "ip" };
+                format!(
+                    "    {interface} \"{name}\" {family} {address} {network} counter accept comment \"{direction} {network}\"\n",
+                    name = interface_name,
+                )
+            })
+            .collect::<String>()
+    };
+    let sent = rules("oifname", "daddr", "sent");
+    let received = rules("iifname", "saddr", "received");
+    format!(
+        "table inet {table} {{\n  chain output {{\n    type filter hook output priority filter; policy accept;\n{sent}  }}\n  chain input {{\n    type filter hook input priority filter; policy accept;\n{received}  }}\n  chain forward {{\n    type filter hook forward priority filter; policy accept;\n{sent}{received}  }}\n}}\n",
+        table = table_name(interface_name),
+    )
+}
+
+/// Bytes sent to and received from each network, summed over the chains.
+#[derive(Debug, Default, PartialEq)]
+struct Traffic {
+    sent: u64,
+    received: u64,
+}
+
+/// Counters of the rules in `nft -j list table` output, by network.
+fn parse_counters(listing: &str) -> Result<HashMap<String, Traffic>, error::Error> {
+    let listing: Value = serde_json::from_str(listing)?;
+    let mut traffic: HashMap<String, Traffic> = HashMap::new();
+    let rules = listing["nftables"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|object| object.get("rule"));
+    for rule in rules {
+        let Some((direction, network)) = rule["comment"]
+            .as_str()
+            .and_then(|comment| comment.split_once(' '))
+        else {
+            continue;
+        };
+        let bytes = rule["expr"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find_map(|expr| expr["counter"]["bytes"].as_u64())
+            .unwrap_or_default();
+        let entry = traffic.entry(network.to_string()).or_default();
+        match direction {
+            "sent" => entry.sent += bytes,
+            _ => entry.received += bytes,
+        }
+    }
+    Ok(traffic)
+}
+
+/// Count the traffic of the tunnel by AllowedIPs network for `window` and print
+/// the `count` networks that carried the most.
+pub fn run(
+    profile: &Profile,
+    netns: Option<&str>,
+    window: Duration,
+    count: usize,
+) -> Result<(), error::Error> {
+    if !config::is_active(&profile.name, netns)? {
+        return Err(accounting_error(format!("{} is not up", profile.name)));
+    }
+    let mut networks = config::allowed_ips(&profile.path)?;
+    networks.sort();
+    networks.dedup();
+    let table = table_name(&profile.name);
+    // Declaring then deleting the table replaces the one a killed run left behind.
+    let script = format!(
+        "table inet {table}\ndelete table inet {table}\n{}",
+        ruleset(&profile.name, &networks)
+    );
+    nft(netns, &["-f", "-"], Some(&script))?;
+    std::thread::sleep(window);
+    let listing = nft(netns, &["-j", "list", "table", "inet", &table], None);
+    let _ = nft(netns, &["delete", "table", "inet", &table], None);
+    let mut traffic: Vec<(String, Traffic)> = parse_counters(&listing?)?.into_iter().collect();
+    traffic.sort_by_key(|(_, traffic)| std::cmp::Reverse(traffic.sent + traffic.received));
+
+    let seconds = window.as_secs_f64().max(f64::EPSILON);
+    println!(
+        "{:<24} {:>12} {:>12} {:>14}",
+        "NETWORK", "SENT", "RECEIVED", "RATE"
+    );
+    for (network, traffic) in traffic.iter().take(count) {
+        let total = traffic.sent + traffic.received;
+        println!(
+            "{:<24} {:>12} {:>12} {:>12}/s",
+            network,
+            status::format_bytes(traffic.sent),
+            status::format_bytes(traffic.received),
+            status::format_bytes((total as f64 / seconds) as u64),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_most_specific_networks_first() {
+        let networks = vec!["0.0.0.0/0".to_string(), "10.1.0.0/16".to_string()];
+        let rules = ruleset("wg0", &networks);
+        let specific = rules.find("ip daddr 10.1.0.0/16").unwrap();
+        let default = rules.find("ip daddr 0.0.0.0/0").unwrap();
+        assert!(specific < default);
+
+        let listing = r#"{"nftables": [
+            {"metainfo": {"json_schema_version": 1}},
+            {"rule": {"chain": "output", "comment": "sent 10.1.0.0/16",
+                "expr": [{"counter": {"packets": 2, "bytes": 300}}, {"accept": null}]}},
+            {"rule": {"chain": "input", "comment": "received 10.1.0.0/16",
+                "expr": [{"counter": {"packets": 1, "bytes": 100}}, {"accept": null}]}},
+            {"rule": {"chain": "forward", "comment": "sent 10.1.0.0/16",
+                "expr": [{"counter": {"packets": 1, "bytes": 50}}, {"accept": null}]}}
+        ]}"#;
+        assert_eq!(
+            parse_counters(listing).unwrap()["10.1.0.0/16"],
+            Traffic {
+                sent: 350,
+                received: 100
+            }
+        );
+    }
+}
@@ -0,0 +1,570 @@
+use crate::error::{self, ErrorKind};
+use crate::utils;
+use base64::prelude::*;
+#[cfg(target_os = "linux")]
+use defguard_wireguard_rs::Kernel;
+#[cfg(not(target_os = "linux"))]
+use defguard_wireguard_rs::Userspace;
+use defguard_wireguard_rs::host::{Host, Peer};
+use defguard_wireguard_rs::key::Key;
+use defguard_wireguard_rs::{InterfaceConfiguration, WGApi, WireguardInterfaceApi};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+/// WireGuard implementation driven: the kernel module on Linux, elsewhere
+/// (FreeBSD) a userspace implementation like wireguard-go through its socket.
+#[cfg(target_os = "linux")]
+type Backend = Kernel;
+#[cfg(not(target_os = "linux"))]
+type Backend = Userspace;
+
+/// `--record` or `--replay`, chosen once at start like `--backend`.
+static TRACER: OnceLock<Tracer> = OnceLock::new();
+
+enum Tracer {
+    /// Calls made so far, all written to `path` after each one so that the
+    /// trace survives a crash.
+    Record {
+        path: PathBuf,
+        calls: Mutex<Vec<Call>>,
+    },
+    Replay(Mutex<Replay>),
+}
+
+/// The calls of a trace, answered by call and arguments rather than in order:
+/// threads and retries make the same calls in another order, or more often.
+struct Replay {
+    /// Calls not answered yet, in the recorded order.
+    pending: Vec<Call>,
+    /// Calls answered, repeated when made again past the recorded ones.
+    answered: Vec<Call>,
+}
+
+impl Replay {
+    fn answer(&mut self, call: &str, interface: Option<&str>, args: &Value) -> Option<Call> {
+        let matches = |recorded: &Call| {
+            recorded.call == call
+                && recorded.interface.as_deref() == interface
+                && recorded.args == *args
+        };
+        if let Some(index) = self.pending.iter().position(matches) {
+            let recorded = self.pending.remove(index);
+            self.answered.push(recorded.clone());
+            return Some(recorded);
+        }
+        self.answered
+            .iter()
+            .rev()
+            .find(|recorded| matches(recorded))
+            .cloned()
+    }
+}
+
+/// A call to WireGuard, `ip` or another tool, or a kernel setting read or
+/// written, and what it returned, without private or preshared keys.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Call {
+    call: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    interface: Option<String>,
+    args: Value,
+    result: Result<Value, Failure>,
+}
+
+/// A failed call, kept so that a replay fails the same way.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Failure {
+    kind: ErrorKind,
+    message: String,
+}
+
+/// Record every call to `path`.
+pub fn record(path: PathBuf) {
+    let _ = TRACER.set(Tracer::Record {
+        path,
+        calls: Mutex::new(Vec::new()),
+    });
+}
+
+impl Tracer {
+    fn replay(path: &Path) -> Result<Self, error::Error> {
+        let pending = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        Ok(Tracer::Replay(Mutex::new(Replay {
+            pending,
+            answered: Vec::new(),
+        })))
+    }
+}
+
+/// Answer every call from the trace at `path` instead of the system.
+pub fn replay(path: &Path) -> Result<(), error::Error> {
+    let _ = TRACER.set(Tracer::replay(path)?);
+    Ok(())
+}
+
+pub fn replaying() -> bool {
+    matches!(TRACER.get(), Some(Tracer::Replay(_)))
+}
+
+/// Fail when replaying, for what reaches the system without going through the
+/// tracer, e.g. switching network namespaces.
+pub fn untraced(what: &str) -> Result<(), error::Error> {
+    if replaying() {
+        return Err(error::Error::InvalidFormat {
+            message: format!("{} cannot be replayed", what),
+        });
+    }
+    Ok(())
+}
+
+/// Make `call` through `run`, or take its result from the trace.
+fn traced<T: Serialize + DeserializeOwned, E>(
+    call: &str,
+    interface: Option<&str>,
+    args: impl FnOnce() -> Value,
+    run: impl FnOnce() -> Result<T, E>,
+    failure: impl FnOnce(&E) -> Failure,
+    replayed: impl FnOnce(Failure) -> E,
+) -> Result<T, E> {
+    match TRACER.get() {
+        None => run(),
+        Some(tracer) => tracer.traced(call, interface, args, run, failure, replayed),
+    }
+}
+
+impl Tracer {
+    fn traced<T: Serialize + DeserializeOwned, E>(
+        &self,
+        call: &str,
+        interface: Option<&str>,
+        args: impl FnOnce() -> Value,
+        run: impl FnOnce() -> Result<T, E>,
+        failure: impl FnOnce(&E) -> Failure,
+        replayed: impl FnOnce(Failure) -> E,
+    ) -> Result<T, E> {
+        match self {
+            Tracer::Record { path, calls } => {
+                let result = run();
+                let recorded = match &result {
+                    Ok(value) => Ok(serde_json::to_value(value).unwrap_or_default()),
+                    Err(err) => Err(failure(err)),
+                };
+                let mut calls = calls.lock().unwrap_or_else(|e| e.into_inner());
+                calls.push(Call {
+                    call: call.to_string(),
+                    interface: interface.map(str::to_string),
+                    args: args(),
+                    result: recorded,
+                });
+                if let Err(err) = serde_json::to_string_pretty(&*calls)
+                    .map_err(error::Error::from)
+                    .and_then(|trace| utils::fs_write(path.clone(), trace))
+                {
+                    eprintln!("Could not write the trace {}: {}", path.display(), err);
+                }
+                result
+            }
+            Tracer::Replay(replay) => {
+                let args = args();
+                let answer = replay
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .answer(call, interface, &args);
+                let diverged = |message: String| Failure {
+                    kind: ErrorKind::Other,
+                    message,
+                };
+                let result = match answer {
+                    None => Err(diverged(format!(
+                        "replay: {} {} is not in the trace",
+                        call, args
+                    ))),
+                    Some(recorded) => recorded.result.and_then(|value| {
+                        serde_json::from_value(value).map_err(|e| {
+                            diverged(format!("replay: unreadable result of {}: {}", call, e))
+                        })
+                    }),
+                };
+                result.map_err(replayed)
+            }
+        }
+    }
+}
+
+fn message_failure(message: &str) -> Failure {
+    Failure {
+        kind: ErrorKind::classify(message),
+        message: message.to_string(),
+    }
+}
+
+/// Run `program` with `args`, and `stdin` written to it, through `run`, or take
+/// its outcome from the trace: what it printed, or why it failed.
+pub fn command(
+    program: &str,
+    args: &[&str],
+    stdin: Option<&str>,
+    run: impl FnOnce() -> Result<String, String>,
+) -> Result<String, String> {
+    traced(
+        program,
+        None,
+        || match stdin {
+            Some(stdin) => json!({ "args": args, "stdin": stdin }),
+            None => json!(args),
+        },
+        run,
+        |message| message_failure(message),
+        |failure| failure.message,
+    )
+}
+
+/// Read the kernel setting at `path` through `run`, or take it from the trace.
+pub fn read_setting(path: &Path, run: impl FnOnce() -> io::Result<String>) -> io::Result<String> {
+    traced(
+        "read",
+        None,
+        || json!(path),
+        run,
+        |err| message_failure(&err.to_string()),
+        |failure| io::Error::other(failure.message),
+    )
+}
+
+/// Write `value` to the kernel setting at `path` through `run`, or take the
+/// outcome from the trace.
+pub fn write_setting(
+    path: &Path,
+    value: &str,
+    run: impl FnOnce() -> io::Result<()>,
+) -> io::Result<()> {
+    traced(
+        "write",
+        None,
+        || json!({ "path": path, "value": value }),
+        run,
+        |err| message_failure(&err.to_string()),
+        |failure| io::Error::other(failure.message),
+    )
+}
+
+fn base64(key: &Key) -> String {
+    BASE64_STANDARD.encode(key.as_slice())
+}
+
+fn key(base64: &str) -> Option<Key> {
+    let bytes: [u8; 32] = BASE64_STANDARD.decode(base64).ok()?.try_into().ok()?;
+    Some(Key::new(bytes))
+}
+
+/// A peer as recorded: no preshared key, the handshake as its age so that a
+/// replay shows the same ages.
+#[derive(Serialize, Deserialize)]
+struct PeerRecord {
+    public_key: String,
+    endpoint: Option<SocketAddr>,
+    allowed_ips: Vec<String>,
+    persistent_keepalive_interval: Option<u16>,
+    handshake_age: Option<u64>,
+    rx_bytes: u64,
+    tx_bytes: u64,
+}
+
+impl From<&Peer> for PeerRecord {
+    fn from(peer: &Peer) -> Self {
+        PeerRecord {
+            public_key: base64(&peer.public_key),
+            endpoint: peer.endpoint,
+            allowed_ips: peer
+                .allowed_ips
+                .iter()
+                .map(|network| format!("{}/{}", network.ip, network.cidr))
+                .collect(),
+            persistent_keepalive_interval: peer.persistent_keepalive_interval,
+            handshake_age: peer.last_handshake.map(|time| {
+                SystemTime::now()
+                    .duration_since(time)
+                    .unwrap_or_default()
+                    .as_secs()
+            }),
+            rx_bytes: peer.rx_bytes,
+            tx_bytes: peer.tx_bytes,
+        }
+    }
+}
+
+/// The interface as recorded: whether it had a private key, not the key.
+#[derive(Serialize, Deserialize)]
+struct HostRecord {
+    listen_port: u16,
+    has_private_key: bool,
+    peers: Vec<PeerRecord>,
+}
+
+impl From<&Host> for HostRecord {
+    fn from(host: &Host) -> Self {
+        let mut peers: Vec<PeerRecord> = host.peers.values().map(PeerRecord::from).collect();
+        peers.sort_by(|a, b| a.public_key.cmp(&b.public_key));
+        HostRecord {
+            listen_port: host.listen_port,
+            has_private_key: host.private_key.is_some(),
+            peers,
+        }
+    }
+}
+
+impl From<HostRecord> for Host {
+    fn from(record: HostRecord) -> Self {
+        let mut host = Host {
+            listen_port: record.listen_port,
+            // Redacted: a key of zeros stands for it.
+            private_key: record.has_private_key.then(|| Key::new([0; 32])),
+            ..Host::default()
+        };
+        for peer in record.peers {
+            let Some(public_key) = key(&peer.public_key) else {
+                continue;
+            };
+            let replayed = Peer {
+                endpoint: peer.endpoint,
+                allowed_ips: peer
+                    .allowed_ips
+                    .iter()
+                    .filter_map(|network| network.parse().ok())
+                    .collect(),
+                persistent_keepalive_interval: peer.persistent_keepalive_interval,
+                last_handshake: peer
+                    .handshake_age
+                    .map(|age| SystemTime::now() - Duration::from_secs(age)),
+                rx_bytes: peer.rx_bytes,
+                tx_bytes: peer.tx_bytes,
+                ..Peer::new(public_key.clone())
+            };
+            host.peers.insert(public_key, replayed);
+        }
+        host
+    }
+}
+
+fn wireguard_failure(err: &error::Error) -> Failure {
+    match err {
+        error::Error::WireGuardApi { kind, message } => Failure {
+            kind: *kind,
+            message: message.clone(),
+        },
+        err => Failure {
+            kind: err.kind(),
+            message: err.to_string(),
+        },
+    }
+}
+
+fn wireguard_error(failure: Failure) -> error::Error {
+    error::Error::WireGuardApi {
+        kind: failure.kind,
+        message: failure.message,
+    }
+}
+
+/// The WireGuard API of one interface, recording or replaying each call when
+/// `--record` or `--replay` is given.
+pub struct Api {
+    interface_name: String,
+    /// Not created when replaying, nothing reaching the system then.
+    inner: Option<WGApi<Backend>>,
+}
+
+impl Api {
+    pub fn new(interface_name: &str) -> Result<Self, error::Error> {
+        let inner = if replaying() {
+            None
+        } else {
+            Some(WGApi::<Backend>::new(interface_name.to_string())?)
+        };
+        Ok(Api {
+            interface_name: interface_name.to_string(),
+            inner,
+        })
+    }
+
+    fn call<T: Serialize + DeserializeOwned>(
+        &self,
+        call: &str,
+        args: impl FnOnce() -> Value,
+        run: impl FnOnce(&WGApi<Backend>) -> Result<T, error::Error>,
+    ) -> Result<T, error::Error> {
+        traced(
+            call,
+            Some(&self.interface_name),
+            args,
+            || run(self.inner.as_ref().expect("created unless replaying")),
+            wireguard_failure,
+            wireguard_error,
+        )
+    }
+
+    pub fn create_interface(&self) -> Result<(), error::Error> {
+        self.call(
+            "create_interface",
+            || Value::Null,
+            |api| Ok(api.create_interface()?),
+        )
+    }
+
+    pub fn configure_interface(&self, config: &InterfaceConfiguration) -> Result<(), error::Error> {
+        self.call(
+            "configure_interface",
+            || {
+                json!({
+                    "addresses": config
+                        .addresses
+                        .iter()
+                        .map(|network| format!("{}/{}", network.ip, network.cidr))
+                        .collect::<Vec<_>>(),
+                    "port": config.port,
+                    "mtu": config.mtu,
+                })
+            },
+            |api| Ok(api.configure_interface(config)?),
+        )
+    }
+
+    pub fn configure_dns(
+        &self,
+        dns: &[IpAddr],
+        search_domains: &[&str],
+    ) -> Result<(), error::Error> {
+        self.call(
+            "configure_dns",
+            || json!({ "dns": dns, "search_domains": search_domains }),
+            |api| Ok(api.configure_dns(dns, search_domains)?),
+        )
+    }
+
+    pub fn configure_peer(&self, peer: &Peer) -> Result<(), error::Error> {
+        self.call(
+            "configure_peer",
+            || json!(PeerRecord::from(peer)),
+            |api| Ok(api.configure_peer(peer)?),
+        )
+    }
+
+    pub fn read_interface_data(&self) -> Result<Host, error::Error> {
+        let record = self.call(
+            "read_interface_data",
+            || Value::Null,
+            |api| Ok(HostRecord::from(&api.read_interface_data()?)),
+        )?;
+        Ok(Host::from(record))
+    }
+
+    pub fn remove_interface(&self) -> Result<(), error::Error> {
+        self.call(
+            "remove_interface",
+            || Value::Null,
+            |api| Ok(api.remove_interface()?),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_peers_but_not_their_keys() {
+        let public_key = Key::new([7; 32]);
+        let mut peer = Peer::new(public_key.clone());
+        peer.preshared_key = Some(Key::new([9; 32]));
+        peer.rx_bytes = 1024;
+        peer.last_handshake = Some(SystemTime::now() - Duration::from_secs(30));
+        let mut host = Host {
+            listen_port: 51820,
+            private_key: Some(Key::new([1; 32])),
+            ..Host::default()
+        };
+        host.peers.insert(public_key.clone(), peer);
+        let recorded = serde_json::to_value(HostRecord::from(&host)).unwrap();
+        let text = recorded.to_string();
+        assert!(!text.contains(&base64(&Key::new([1; 32]))));
+        assert!(!text.contains(&base64(&Key::new([9; 32]))));
+
+        let replayed = Host::from(serde_json::from_value::<HostRecord>(recorded).unwrap());
+        assert_eq!(replayed.private_key, Some(Key::new([0; 32])));
+        let peer = &replayed.peers[&public_key];
+        assert_eq!((peer.rx_bytes, peer.preshared_key.clone()), (1024, None));
+        let age = SystemTime::now()
+            .duration_since(peer.last_handshake.unwrap())
+            .unwrap();
+        assert!(age.as_secs() >= 30 && age.as_secs() < 35);
+    }
+
+    fn ip(
+        tracer: &Tracer,
+        args: &[&str],
+        run: impl FnOnce() -> Result<String, String>,
+    ) -> Result<String, String> {
+        tracer.traced(
+            "ip",
+            None,
+            || json!(args),
+            run,
+            |message| message_failure(message),
+            |failure| failure.message,
+        )
+    }
+
+    fn recorded_trace(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("wg-waybar-trace-{}-{}", name, std::process::id()));
+        let recorder = Tracer::Record {
+            path: path.clone(),
+            calls: Mutex::new(Vec::new()),
+        };
+        ip(&recorder, &["link", "show"], || Ok("up".to_string())).unwrap();
+        ip(&recorder, &["route", "get", "192.0.2.1"], || {
+            Ok("eth0".to_string())
+        })
+        .unwrap();
+        ip(&recorder, &["link", "add"], || {
+            Err("Operation not permitted".to_string())
+        })
+        .unwrap_err();
+        path
+    }
+
+    #[test]
+    fn replays_calls_by_arguments() {
+        let path = recorded_trace("replay");
+        let replay = Tracer::replay(&path).unwrap();
+        let system = || -> Result<String, String> { panic!("the replay reached the system") };
+        // Another order than recorded, and a read made once more.
+        assert_eq!(
+            ip(&replay, &["route", "get", "192.0.2.1"], system).unwrap(),
+            "eth0"
+        );
+        assert_eq!(ip(&replay, &["link", "show"], system).unwrap(), "up");
+        assert_eq!(ip(&replay, &["link", "show"], system).unwrap(), "up");
+        assert_eq!(
+            ip(&replay, &["link", "add"], system).unwrap_err(),
+            "Operation not permitted"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn fails_calls_missing_from_the_trace() {
+        let path = recorded_trace("divergence");
+        let replay = Tracer::replay(&path).unwrap();
+        let system = || -> Result<String, String> { panic!("the replay reached the system") };
+        let error = ip(&replay, &["route", "get", "198.51.100.1"], system).unwrap_err();
+        assert!(error.contains("not in the trace"), "{}", error);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,396 @@
+use crate::error;
+use crate::profiles::Profile;
+use crate::status::{Status, StatusReport};
+use crate::utils;
+use std::collections::HashMap;
+use std::sync::Arc;
+use zbus::blocking::{Connection, connection};
+use zbus::interface;
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::{OwnedObjectPath, OwnedValue, Value};
+
+const ITEM_PATH: &str = "/StatusNotifierItem";
+const MENU_PATH: &str = "/MenuBar";
+
+const TOGGLE_ID: i32 = 1;
+/// Menu ids of the profiles start here, in the order of the directory.
+const FIRST_PROFILE_ID: i32 = 10;
+
+/// Receives the requests made from the tray, on a D-Bus thread.
+type Notify = Arc<dyn Fn(TrayEvent) + Send + Sync>;
+
+/// What the user asked for from the tray.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrayEvent {
+    Toggle,
+    Switch(String),
+}
+
+fn tray_error(e: impl std::fmt::Display) -> error::Error {
+    error::Error::Tray(e.to_string())
+}
+
+fn icon_name(status: Status) -> &'static str {
+    match status {
+        Status::Connected | Status::Idle | Status::Confirm | Status::InUse => "network-vpn",
+        Status::Disconnected => "network-vpn-disconnected",
+        Status::Error => "network-error",
+        Status::Warning => "network-vpn-no-route",
+        Status::Connecting
+        | Status::Disconnecting
+        | Status::Reconnecting
+        | Status::Busy
+        | Status::Pending => "network-vpn-acquiring",
+    }
+}
+
+/// Icon name, icon pixmaps as (width, height, ARGB32 data), title and description.
+type ToolTip = (String, Vec<(i32, i32, Vec<u8>)>, String, String);
+
+/// `org.kde.StatusNotifierItem`, the icon itself.
+struct Item {
+    report: StatusReport,
+    notify: Notify,
+}
+
+#[interface(name = "org.kde.StatusNotifierItem")]
+impl Item {
+    fn activate(&self, _x: i32, _y: i32) {
+        (self.notify)(TrayEvent::Toggle);
+    }
+
+    fn secondary_activate(&self, _x: i32, _y: i32) {}
+
+    fn context_menu(&self, _x: i32, _y: i32) {}
+
+    fn scroll(&self, _delta: i32, _orientation: String) {}
+
+    #[zbus(property)]
+    fn category(&self) -> String {
+        "Communications".to_string()
+    }
+
+    #[zbus(property)]
+    fn id(&self) -> String {
+        "wg-waybar".to_string()
+    }
+
+    #[zbus(property)]
+    fn title(&self) -> String {
+        self.report.text.clone()
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> String {
+        match self.report.status {
+            Status::Error => "NeedsAttention",
+            _ => "Active",
+        }
+        .to_string()
+    }
+
+    #[zbus(property)]
+    fn icon_name(&self) -> String {
+        icon_name(self.report.status).to_string()
+    }
+
+    #[zbus(property)]
+    fn tool_tip(&self) -> ToolTip {
+        (
+            String::new(),
+            Vec::new(),
+            self.report.text.clone(),
+            self.report.tooltip.clone(),
+        )
+    }
+
+    #[zbus(property)]
+    fn item_is_menu(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn menu(&self) -> OwnedObjectPath {
+        OwnedObjectPath::try_from(MENU_PATH).expect("valid object path")
+    }
+
+    #[zbus(signal)]
+    async fn new_title(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn new_icon(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn new_tool_tip(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn new_status(emitter: &SignalEmitter<'_>, status: &str) -> zbus::Result<()>;
+}
+
+type MenuProperties = HashMap<String, OwnedValue>;
+
+/// `com.canonical.dbusmenu`, the menu: a toggle entry and one radio entry per profile.
+struct Menu {
+    revision: u32,
+    profiles: Vec<String>,
+    active: Vec<String>,
+    notify: Notify,
+}
+
+fn property(value: impl Into<Value<'static>>) -> OwnedValue {
+    value
+        .into()
+        .try_to_owned()
+        .expect("menu properties hold no file descriptor")
+}
+
+impl Menu {
+    fn properties(&self, id: i32) -> MenuProperties {
+        let mut properties = MenuProperties::new();
+        if id == TOGGLE_ID {
+            properties.insert("label".into(), property("Toggle"));
+        } else if let Some(name) = self.profile(id) {
+            properties.insert("label".into(), property(name.to_string()));
+            properties.insert("toggle-type".into(), property("radio"));
+            let active = self.active.iter().any(|active| active == name);
+            properties.insert("toggle-state".into(), property(i32::from(active)));
+        } else if id == 0 {
+            properties.insert("children-display".into(), property("submenu"));
+        } else {
+            properties.insert("type".into(), property("separator"));
+        }
+        properties
+    }
+
+    fn profile(&self, id: i32) -> Option<&str> {
+        let index = usize::try_from(id.checked_sub(FIRST_PROFILE_ID)?).ok()?;
+        self.profiles.get(index).map(String::as_str)
+    }
+
+    fn ids(&self) -> Vec<i32> {
+        let mut ids = vec![TOGGLE_ID];
+        if !self.profiles.is_empty() {
+            ids.push(TOGGLE_ID + 1);
+        }
+        ids.extend((0..self.profiles.len() as i32).map(|index| FIRST_PROFILE_ID + index));
+        ids
+    }
+}
+
+#[interface(name = "com.canonical.dbusmenu")]
+impl Menu {
+    fn get_layout(
+        &self,
+        parent_id: i32,
+        _recursion_depth: i32,
+        _property_names: Vec<String>,
+    ) -> (u32, (i32, MenuProperties, Vec<OwnedValue>)) {
+        let children = if parent_id == 0 {
+            self.ids()
+                .into_iter()
+                .map(|id| property((id, self.properties(id), Vec::<OwnedValue>::new())))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        (
+            self.revision,
+            (parent_id, self.properties(parent_id), children),
+        )
+    }
+
+    fn get_group_properties(
+        &self,
+        ids: Vec<i32>,
+        _property_names: Vec<String>,
+    ) -> Vec<(i32, MenuProperties)> {
+        ids.into_iter()
+            .map(|id| (id, self.properties(id)))
+            .collect()
+    }
+
+    fn event(&self, id: i32, event_id: String, _data: OwnedValue, _timestamp: u32) {
+        if event_id != "clicked" {
+            return;
+        }
+        let event = match self.profile(id) {
+            Some(name) => TrayEvent::Switch(name.to_string()),
+            None if id == TOGGLE_ID => TrayEvent::Toggle,
+            None => return,
+        };
+        (self.notify)(event);
+    }
+
+    fn about_to_show(&self, _id: i32) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn version(&self) -> u32 {
+        3
+    }
+
+    #[zbus(property)]
+    fn text_direction(&self) -> String {
+        "ltr".to_string()
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> String {
+        "normal".to_string()
+    }
+
+    #[zbus(property)]
+    fn icon_theme_path(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(signal)]
+    async fn layout_updated(
+        emitter: &SignalEmitter<'_>,
+        revision: u32,
+        parent: i32,
+    ) -> zbus::Result<()>;
+}
+
+/// StatusNotifierItem tray icon on the session bus of the invoking user.
+pub struct Tray {
+    connection: Connection,
+}
+
+impl Tray {
+    /// Export the icon and its menu, and register them with the tray host.
+    ///
+    /// Requests made from the tray are passed to `notify`.
+    pub fn start(
+        profiles: &[Profile],
+        notify: impl Fn(TrayEvent) + Send + Sync + 'static,
+    ) -> Result<Self, error::Error> {
+        let notify: Notify = Arc::new(notify);
+        let item = Item {
+            report: StatusReport::error("Starting".to_string()),
+            notify: notify.clone(),
+        };
+        let menu = Menu {
+            revision: 1,
+            profiles: profiles
+                .iter()
+                .map(|profile| profile.name.clone())
+                .collect(),
+            active: Vec::new(),
+            notify,
+        };
+        let builder = match utils::session_bus_address() {
+            Some(address) => connection::Builder::address(address.as_str()),
+            None => connection::Builder::session(),
+        }
+        .map_err(tray_error)?;
+        let name = format!("org.kde.StatusNotifierItem-{}-1", std::process::id());
+        let connection = builder
+            .name(name.as_str())
+            .map_err(tray_error)?
+            .serve_at(ITEM_PATH, item)
+            .map_err(tray_error)?
+            .serve_at(MENU_PATH, menu)
+            .map_err(tray_error)?
+            .build()
+            .map_err(tray_error)?;
+        connection
+            .call_method(
+                Some("org.kde.StatusNotifierWatcher"),
+                "/StatusNotifierWatcher",
+                Some("org.kde.StatusNotifierWatcher"),
+                "RegisterStatusNotifierItem",
+                &(name.as_str(),),
+            )
+            .map_err(tray_error)?;
+        Ok(Self { connection })
+    }
+
+    /// Mirror the status and the active profiles, notifying the host of changes.
+    pub fn update(&self, report: &StatusReport, active: Vec<String>) -> Result<(), error::Error> {
+        let server = self.connection.object_server();
+        let item = server.interface::<_, Item>(ITEM_PATH).map_err(tray_error)?;
+        if item.get().report != *report {
+            item.get_mut().report = report.clone();
+            let status = item.get().status();
+            let emitter = item.signal_emitter();
+            zbus::block_on(async {
+                Item::new_title(emitter).await?;
+                Item::new_icon(emitter).await?;
+                Item::new_tool_tip(emitter).await?;
+                Item::new_status(emitter, &status).await
+            })
+            .map_err(tray_error)?;
+        }
+        let menu = server.interface::<_, Menu>(MENU_PATH).map_err(tray_error)?;
+        if menu.get().active != active {
+            let revision = {
+                let mut menu = menu.get_mut();
+                menu.active = active;
+                menu.revision += 1;
+                menu.revision
+            };
+            zbus::block_on(Menu::layout_updated(menu.signal_emitter(), revision, 0))
+                .map_err(tray_error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn menu(events: &Arc<Mutex<Vec<TrayEvent>>>) -> Menu {
+        let events = events.clone();
+        Menu {
+            revision: 1,
+            profiles: vec!["wg-se".to_string(), "wg-ch".to_string()],
+            active: vec!["wg-ch".to_string()],
+            notify: Arc::new(move |event| events.lock().unwrap().push(event)),
+        }
+    }
+
+    #[test]
+    fn lays_out_the_toggle_and_a_radio_entry_per_profile() {
+        let menu = menu(&Arc::new(Mutex::new(Vec::new())));
+        assert_eq!(menu.ids(), [TOGGLE_ID, TOGGLE_ID + 1, 10, 11]);
+        assert_eq!(menu.properties(TOGGLE_ID)["label"], property("Toggle"));
+        assert_eq!(
+            menu.properties(TOGGLE_ID + 1)["type"],
+            property("separator")
+        );
+        let second = menu.properties(11);
+        assert_eq!(second["label"], property("wg-ch".to_string()));
+        assert_eq!(second["toggle-state"], property(1));
+        assert_eq!(menu.properties(10)["toggle-state"], property(0));
+        assert_eq!(menu.profile(12), None);
+        assert_eq!(menu.profile(TOGGLE_ID), None);
+
+        let (_, (_, _, children)) = menu.get_layout(0, -1, Vec::new());
+        assert_eq!(children.len(), 4);
+    }
+
+    #[test]
+    fn passes_clicks_on_the_menu() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let menu = menu(&events);
+        menu.event(10, "clicked".to_string(), property(0), 0);
+        menu.event(TOGGLE_ID, "clicked".to_string(), property(0), 0);
+        menu.event(11, "hovered".to_string(), property(0), 0);
+        menu.event(TOGGLE_ID + 1, "clicked".to_string(), property(0), 0);
+        assert_eq!(
+            *events.lock().unwrap(),
+            [TrayEvent::Switch("wg-se".to_string()), TrayEvent::Toggle]
+        );
+    }
+
+    #[test]
+    fn shows_the_status_as_an_icon() {
+        assert_eq!(icon_name(Status::Connected), "network-vpn");
+        assert_eq!(icon_name(Status::Connecting), "network-vpn-acquiring");
+        assert_eq!(icon_name(Status::Error), "network-error");
+    }
+}
@@ -0,0 +1,252 @@
+use crate::actions::{self, Context};
+use crate::config;
+use crate::error;
+use crate::profiles::Profile;
+use crate::state::Counters;
+use crate::status::format_bytes;
+use ratatui::DefaultTerminal;
+use ratatui::Frame;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Sparkline};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Throughput samples kept for the graphs, more than a terminal is wide.
+const HISTORY: usize = 512;
+const HELP: &str = "↑/↓ select  enter toggle  s switch  q quit";
+
+/// Live figures of one profile.
+#[derive(Default)]
+struct Row {
+    up: bool,
+    handshake: Option<SystemTime>,
+    /// Last counters read and when, to derive the rates from.
+    counters: Option<(Counters, Instant)>,
+    /// Bytes per second received and sent, oldest first.
+    rx: VecDeque<u64>,
+    tx: VecDeque<u64>,
+}
+
+impl Row {
+    /// Record the counters read at `now`, the rates being taken against the previous reading.
+    fn record(&mut self, counters: Option<Counters>, now: Instant) {
+        let (rx, tx) = match (self.counters, counters) {
+            (Some((last, at)), Some(current)) => {
+                let seconds = now.duration_since(at).as_secs_f64().max(f64::EPSILON);
+                (
+                    rate(last.rx, current.rx, seconds),
+                    rate(last.tx, current.tx, seconds),
+                )
+            }
+            _ => (0, 0),
+        };
+        for (history, rate) in [(&mut self.rx, rx), (&mut self.tx, tx)] {
+            if history.len() == HISTORY {
+                history.pop_front();
+            }
+            history.push_back(rate);
+        }
+        self.counters = counters.map(|counters| (counters, now));
+    }
+
+    fn summary(&self, profile: &Profile) -> String {
+        if !self.up {
+            return format!("○ {}", profile.name);
+        }
+        let mut summary = format!(
+            "● {:<20} ↓ {}/s  ↑ {}/s",
+            profile.name,
+            format_bytes(self.rx.back().copied().unwrap_or_default()),
+            format_bytes(self.tx.back().copied().unwrap_or_default()),
+        );
+        if let Some(age) = self.handshake.and_then(|time| time.elapsed().ok()) {
+            summary.push_str(&format!("  handshake {}s ago", age.as_secs()));
+        }
+        summary
+    }
+}
+
+fn rate(last: u64, current: u64, seconds: f64) -> u64 {
+    // Counters restart from zero when the interface is recreated.
+    (current.saturating_sub(last) as f64 / seconds) as u64
+}
+
+/// Newest `width` samples of `history`, the graph being drawn left to right.
+fn tail(history: &VecDeque<u64>, width: u16) -> Vec<u64> {
+    let skip = history.len().saturating_sub(width as usize);
+    history.iter().skip(skip).copied().collect()
+}
+
+struct Dashboard<'a, 'c> {
+    ctx: &'a Context<'c>,
+    profiles: &'a [Profile],
+    rows: Vec<Row>,
+    list: ListState,
+    /// Outcome of the last action, shown instead of the key help.
+    message: Option<String>,
+}
+
+impl Dashboard<'_, '_> {
+    fn selected(&self) -> usize {
+        self.list
+            .selected()
+            .unwrap_or_default()
+            .min(self.profiles.len() - 1)
+    }
+
+    fn sample(&mut self) {
+        let now = Instant::now();
+        for (profile, row) in self.profiles.iter().zip(&mut self.rows) {
+            let netns = (self.ctx.options_for)(&profile.name).netns;
+            row.up = config::is_active(&profile.name, netns).unwrap_or(false);
+            row.handshake = row
+                .up
+                .then(|| config::last_handshake(&profile.name, netns).ok().flatten())
+                .flatten();
+            let counters = row
+                .up
+                .then(|| config::transfer(&profile.name, netns).ok())
+                .flatten();
+            row.record(counters, now);
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [list_area, rx_area, tx_area, help_area] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(7),
+            Constraint::Length(7),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let items: Vec<ListItem> = self
+            .profiles
+            .iter()
+            .zip(&self.rows)
+            .map(|(profile, row)| ListItem::new(row.summary(profile)))
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title(" Profiles "))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, list_area, &mut self.list);
+
+        let selected = self.selected();
+        let (profile, row) = (&self.profiles[selected], &self.rows[selected]);
+        let graphs = [(rx_area, &row.rx, "Received"), (tx_area, &row.tx, "Sent")];
+        for (area, history, title) in graphs {
+            let peak = history.iter().max().copied().unwrap_or_default();
+            let block = Block::bordered().title(format!(
+                " {} by {} (peak {}/s) ",
+                title,
+                profile.name,
+                format_bytes(peak)
+            ));
+            let graph = Sparkline::default()
+                .data(tail(history, area.width.saturating_sub(2)))
+                .block(block);
+            frame.render_widget(graph, area);
+        }
+        let help = self.message.as_deref().unwrap_or(HELP);
+        frame.render_widget(Paragraph::new(help), help_area);
+    }
+
+    /// Run an action on the selected profile, redrawing first since it may take a while.
+    fn act(&mut self, terminal: &mut DefaultTerminal, action: &str) -> Result<(), error::Error> {
+        let profile = &self.profiles[self.selected()];
+        self.message = Some(format!("{} {}…", action, profile.name));
+        terminal.draw(|frame| self.draw(frame))?;
+        let result = actions::ignore_missing_waybar(match action {
+            "switch" => actions::switch(self.ctx, self.profiles, &profile.name),
+            _ => actions::toggle(self.ctx, profile),
+        });
+        self.message = Some(match result {
+            Ok(()) => format!("{} {}: done", action, profile.name),
+            Err(e) => format!("{} {} failed: {}", action, profile.name, e),
+        });
+        Ok(())
+    }
+
+    fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        interval: Duration,
+    ) -> Result<(), error::Error> {
+        let mut sampled: Option<Instant> = None;
+        loop {
+            if sampled.is_none_or(|at| at.elapsed() >= interval) {
+                self.sample();
+                sampled = Some(Instant::now());
+            }
+            terminal.draw(|frame| self.draw(frame))?;
+            let timeout =
+                interval.saturating_sub(sampled.map(|at| at.elapsed()).unwrap_or_default());
+            if !event::poll(timeout)? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Down | KeyCode::Char('j') => self.list.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => self.list.select_previous(),
+                KeyCode::Enter | KeyCode::Char(' ') | KeyCode::Char('t') => {
+                    self.act(terminal, "toggle")?;
+                    sampled = None;
+                }
+                KeyCode::Char('s') => {
+                    self.act(terminal, "switch")?;
+                    sampled = None;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Full-screen dashboard of the profiles: their status, handshake and
+/// throughput graphs, with keys to toggle and switch.
+pub fn run(ctx: &Context, profiles: &[Profile], interval: Duration) -> Result<(), error::Error> {
+    if profiles.is_empty() {
+        return Err(error::Error::InvalidFormat {
+            message: "no profile to show".to_string(),
+        });
+    }
+    let mut dashboard = Dashboard {
+        ctx,
+        profiles,
+        rows: profiles.iter().map(|_| Row::default()).collect(),
+        list: ListState::default().with_selected(Some(0)),
+        message: None,
+    };
+    let mut terminal = ratatui::init();
+    let result = dashboard.run(&mut terminal, interval);
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_rates_from_counters() {
+        let start = Instant::now();
+        let mut row = Row::default();
+        row.record(Some(Counters { rx: 1000, tx: 0 }), start);
+        row.record(
+            Some(Counters { rx: 5000, tx: 200 }),
+            start + Duration::from_secs(2),
+        );
+        // Down: no counters, so no rate either.
+        row.record(None, start + Duration::from_secs(3));
+        assert_eq!(row.rx, [0, 2000, 0]);
+        assert_eq!(row.tx, [0, 100, 0]);
+        assert_eq!(tail(&row.rx, 2), [2000, 0]);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::config;
+use crate::mock;
+use crate::routing;
+use crate::utils;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Physical interface carrying the encrypted traffic of a tunnel.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Uplink {
+    pub device: String,
+    /// Network name when the device is wireless.
+    pub ssid: Option<String>,
+    /// Whether NetworkManager flags the connection as metered.
+    pub metered: bool,
+}
+
+impl std::fmt::Display for Uplink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.ssid {
+            Some(ssid) => write!(f, "{} ({})", ssid, self.device)?,
+            None => write!(f, "{}", self.device)?,
+        }
+        if self.metered {
+            write!(f, ", metered")?;
+        }
+        Ok(())
+    }
+}
+
+/// Output device of `ip -j route get`.
+fn parse_route_device(output: &str) -> Option<String> {
+    let routes: serde_json::Value = serde_json::from_str(output).ok()?;
+    Some(routes.get(0)?.get("dev")?.as_str()?.to_string())
+}
+
+/// Network name from `iw dev <device> link`.
+fn parse_ssid(output: &str) -> Option<String> {
+    output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("SSID: "))
+        .map(str::to_string)
+}
+
+/// `GENERAL.METERED` from `nmcli`: `yes`, `no`, `yes (guessed)`, `no (guessed)` or `unknown`.
+fn parse_metered(output: &str) -> bool {
+    output.trim().starts_with("yes")
+}
+
+/// Metered flag of the device in NetworkManager, false without NetworkManager.
+fn metered(device: &str) -> bool {
+    utils::run_command(
+        "nmcli",
+        &["-g", "GENERAL.METERED", "device", "show", device],
+        None,
+    )
+    .is_ok_and(|output| parse_metered(&output))
+}
+
+fn uplink(device: String) -> Uplink {
+    Uplink {
+        ssid: ssid(&device),
+        metered: metered(&device),
+        device,
+    }
+}
+
+fn route_device(ip: IpAddr, mark: Option<u32>) -> Option<String> {
+    let ip = ip.to_string();
+    let mark = mark.map(|mark| mark.to_string());
+    let mut args = vec!["-j", "route", "get", &ip];
+    if let Some(mark) = &mark {
+        args.extend(["mark", mark]);
+    }
+    let output = utils::run_command("ip", &args, None).ok()?;
+    parse_route_device(&output)
+}
+
+fn ssid(device: &str) -> Option<String> {
+    if !std::path::Path::new("/sys/class/net")
+        .join(device)
+        .join("wireless")
+        .exists()
+    {
+        return None;
+    }
+    let output = utils::run_command("iw", &["dev", device, "link"], None).ok()?;
+    parse_ssid(&output)
+}
+
+/// Uplink of the tunnel, found by resolving the route to its first peer endpoint.
+pub fn find(interface_name: &str, netns: Option<&str>) -> Option<Uplink> {
+    let endpoint = config::peer_endpoints(interface_name, netns)
+        .ok()?
+        .first()?
+        .ip();
+    let mut device = route_device(endpoint, None)?;
+    if device == interface_name {
+        // With a full tunnel only the marked, encrypted traffic skips the tunnel.
+        device = route_device(endpoint, Some(routing::TUNNEL_FWMARK))?;
+    }
+    Some(uplink(device))
+}
+
+/// Whether `ip route show default` lists a route for `family` (`-4` or `-6`),
+/// `None` if `ip` could not tell.
+fn has_default_route(family: &str) -> Option<bool> {
+    let output =
+        utils::run_command("ip", &[family, "-j", "route", "show", "default"], None).ok()?;
+    Some(parse_route_device(&output).is_some())
+}
+
+/// Whether any endpoint could be reached at all: false only when neither
+/// family has a default route, e.g. in airplane mode. Always with simulated
+/// tunnels.
+pub fn is_online() -> bool {
+    mock::enabled()
+        || !matches!(
+            (has_default_route("-4"), has_default_route("-6")),
+            (Some(false), Some(false))
+        )
+}
+
+/// Uplink a profile that is down would use, from the route to its first endpoint.
+pub fn for_profile(config_path: &Path) -> Option<Uplink> {
+    let endpoint = config::configured_endpoints(config_path)
+        .ok()?
+        .first()?
+        .ip();
+    route_device(endpoint, None).map(uplink)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_route_and_ssid() {
+        let route = r#"[{"dst":"198.51.100.1","gateway":"192.168.1.1","dev":"wlan0","prefsrc":"192.168.1.20","flags":[],"uid":0,"cache":[]}]"#;
+        assert_eq!(parse_route_device(route), Some("wlan0".to_string()));
+        assert_eq!(parse_route_device("[]"), None);
+
+        let link = "Connected to 00:11:22:33:44:55 (on wlan0)\n\tSSID: hotel-wifi\n\tfreq: 5180\n";
+        assert_eq!(parse_ssid(link), Some("hotel-wifi".to_string()));
+        assert_eq!(parse_ssid("Not connected.\n"), None);
+
+        assert!(parse_metered("yes (guessed)\n"));
+        assert!(!parse_metered("no\n"));
+        assert!(!parse_metered("unknown\n"));
+    }
+}
@@ -1,23 +1,145 @@
 use crate::error;
+use crate::trace;
 use libc::{EPERM, ESRCH, kill};
 use std::ffi::OsString;
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use uzers::os::unix::UserExt;
 use uzers::{get_current_uid, get_user_by_name, get_user_by_uid};
 
+/// Waybar's process, looked up among the visible processes and, when none
+/// matches, as the main process of the invoking user's `waybar.service`.
 pub fn find_waybar_pid() -> Option<i32> {
+    scan_processes().or_else(systemd_main_pid)
+}
+
+/// Whether a process name is Waybar's, possibly wrapped (`.waybar-wrapped`),
+/// and not that of wg-waybar itself, e.g. a `watch` left running.
+fn is_waybar(comm: &str) -> bool {
+    comm.contains("waybar") && !comm.trim().starts_with("wg-waybar")
+}
+
+#[cfg(all(target_os = "linux", feature = "procfs"))]
+fn scan_processes() -> Option<i32> {
     for process in procfs::process::all_processes().ok()?.flatten() {
-        if let Ok(stat) = process.stat() {
-            if stat.comm.contains("waybar") {
-                return Some(process.pid);
-            }
+        if let Ok(stat) = process.stat()
+            && is_waybar(&stat.comm)
+        {
+            return Some(process.pid);
         }
     }
     None
 }
 
+/// Same as the procfs crate, reading `/proc/<pid>/comm` directly.
+#[cfg(all(target_os = "linux", not(feature = "procfs")))]
+fn scan_processes() -> Option<i32> {
+    std::fs::read_dir("/proc")
+        .ok()?
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+        .find(|pid| {
+            std::fs::read_to_string(format!("/proc/{}/comm", pid))
+                .is_ok_and(|comm| is_waybar(&comm))
+        })
+}
+
+/// Without procfs, ask `pgrep`, part of the base system on the BSDs, for the
+/// names along with the pids, as it matches wg-waybar too.
+#[cfg(not(target_os = "linux"))]
+fn scan_processes() -> Option<i32> {
+    let output = Command::new("pgrep").args(["-l", "waybar"]).output().ok()?;
+    parse_pgrep(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// The first Waybar among the `<pid> <name>` lines of `pgrep -l`.
+#[cfg(any(test, not(target_os = "linux")))]
+fn parse_pgrep(output: &str) -> Option<i32> {
+    output.lines().find_map(|line| {
+        let (pid, name) = line.trim().split_once(' ')?;
+        is_waybar(name).then(|| pid.parse().ok())?
+    })
+}
+
+/// `systemctl --user` for the invoking user, reaching their manager from root through sudo.
+fn systemctl_user() -> Command {
+    let mut cmd = Command::new("systemctl");
+    cmd.arg("--user");
+    if let Some(user) = get_environ("SUDO_USER") {
+        let mut machine = OsString::from("--machine=");
+        machine.push(user);
+        machine.push("@");
+        cmd.arg(machine);
+    }
+    cmd
+}
+
+/// `MainPID` printed by `systemctl show --value`, 0 meaning not running.
+fn parse_main_pid(output: &str) -> Option<i32> {
+    output.trim().parse().ok().filter(|pid| *pid > 0)
+}
+
+fn systemd_main_pid() -> Option<i32> {
+    let output = systemctl_user()
+        .args(["show", "--property=MainPID", "--value", "waybar.service"])
+        .output()
+        .ok()?;
+    parse_main_pid(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Whether the `/proc` line of `mounts` hides the processes of other users
+/// (`hidepid=1`, `2`, or their names `noaccess` and `invisible`).
+fn hides_processes(mounts: &str) -> bool {
+    mounts
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .filter(|fields| fields.get(1) == Some(&"/proc") && fields.get(2) == Some(&"proc"))
+        .filter_map(|fields| fields.get(3).copied())
+        .flat_map(|options| options.split(','))
+        .filter_map(|option| option.strip_prefix("hidepid="))
+        .any(|value| !matches!(value, "0" | "off"))
+}
+
+/// Have the user's systemd manager send the signal to `waybar.service`, as
+/// that user, for when `/proc` does not let us see their Waybar.
+fn signal_through_systemd(signal_num: i32, debug: bool) -> Result<(), error::SignalError> {
+    let signal = format!("SIGRTMIN+{}", signal_num);
+    let mut cmd = systemctl_user();
+    cmd.args(["kill", "--signal", &signal, "waybar.service"]);
+    crate::hooks::as_invoking_user(&mut cmd);
+    let output = cmd
+        .output()
+        .map_err(|e| error::SignalError::OS(format!("failed to run `systemctl`: {}", e)))?;
+    if output.status.success() {
+        if debug {
+            println!("Sent {} to waybar.service through systemd", signal);
+        }
+        Ok(())
+    } else {
+        Err(error::SignalError::OS(format!(
+            "`systemctl --user kill` failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+/// First and last realtime signal, Waybar's `signal` counting from the first.
+#[cfg(target_os = "linux")]
+fn realtime_signals() -> (i32, i32) {
+    (libc::SIGRTMIN(), libc::SIGRTMAX())
+}
+
+/// `SIGRTMIN` and `SIGRTMAX` of FreeBSD's `<sys/signal.h>`, missing from libc.
+#[cfg(not(target_os = "linux"))]
+fn realtime_signals() -> (i32, i32) {
+    (65, 126)
+}
+
 pub fn send_signal_to_waybar(signal_num: i32, debug: bool) -> Result<(), error::SignalError> {
-    let sigrtmin: i32 = libc::SIGRTMIN();
-    let sigrtmax: i32 = libc::SIGRTMAX();
+    let (sigrtmin, sigrtmax) = realtime_signals();
     if signal_num < 0 || signal_num > (sigrtmax - sigrtmin) {
         return Err(error::SignalError::OutOfRange(
             error::SignalOutOfRangeError(
@@ -26,6 +148,11 @@ pub fn send_signal_to_waybar(signal_num: i32, debug: bool) -> Result<(), error::
         ));
     }
 
+    if scan_processes().is_none()
+        && std::fs::read_to_string("/proc/mounts").is_ok_and(|mounts| hides_processes(&mounts))
+    {
+        return signal_through_systemd(signal_num, debug);
+    }
     let pid = find_waybar_pid().ok_or(error::SignalError::ProcessNotFound(
         error::ProcessNotFoundError("Could not find Waybar process".to_string()),
     ))?;
@@ -49,6 +176,70 @@ pub fn send_signal_to_waybar(signal_num: i32, debug: bool) -> Result<(), error::
     }
 }
 
+/// Run `program` with `args` and `stdin` written to it, returning its stdout,
+/// or its stderr as the error message. Recorded and replayed like WireGuard.
+pub fn run_command(program: &str, args: &[&str], stdin: Option<&str>) -> Result<String, String> {
+    trace::command(program, args, stdin, || {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("failed to run `{}`: {}", program, e))?;
+        if let Some(script) = stdin
+            && let Some(mut pipe) = child.stdin.take()
+        {
+            pipe.write_all(script.as_bytes())
+                .map_err(|e| format!("failed to write to `{}`: {}", program, e))?;
+        }
+        let output = child
+            .wait_with_output()
+            .map_err(|e| format!("failed to run `{}`: {}", program, e))?;
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        } else {
+            Err(format!(
+                "`{} {}` failed: {}",
+                program,
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+    })
+}
+
+/// `word` as the shell reads it back as one word, quoted only when needed.
+fn shell_quote(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c));
+    if plain {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// `words` as one shell command line, e.g. for the `exec` of a Waybar module.
+pub fn command_line<S: AsRef<str>>(words: &[S]) -> String {
+    words
+        .iter()
+        .map(|word| shell_quote(word.as_ref()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Run `ip` (iproute2) with `args`, returning its stderr as the error message.
+pub fn run_ip(args: &[&str]) -> Result<(), String> {
+    run_command("ip", args, None).map(|_| ())
+}
+
 fn to_pathbuf(path: OsString) -> Option<std::path::PathBuf> {
     let path: std::path::PathBuf = std::path::PathBuf::from(path);
     if path.is_absolute() { Some(path) } else { None }
@@ -76,10 +267,10 @@ fn get_home_dir_impl(
         get_user_by_uid(uid).map(|u| u.name().into())
     });
 
-    if let Some(user) = username {
-        if let Some(user) = get_user_by_name(&user) {
-            return Ok(user.home_dir().into());
-        }
+    if let Some(user) = username
+        && let Some(user) = get_user_by_name(&user)
+    {
+        return Ok(user.home_dir().into());
     }
 
     Err(HomeDirNotFoundError {})
@@ -114,6 +305,22 @@ pub fn get_state_home(app_name: &str) -> Result<std::path::PathBuf, HomeDirNotFo
     get_state_home_impl(app_name, get_environ, get_home_dir)
 }
 
+fn get_config_home_impl(
+    app_name: &str,
+    get_envvar: impl Fn(&str) -> Option<OsString>,
+    get_home_dir_fn: impl Fn() -> Result<std::path::PathBuf, HomeDirNotFoundError>,
+) -> Result<std::path::PathBuf, HomeDirNotFoundError> {
+    let default_config_folder = get_home_dir_fn()?.join(".config");
+    let config_home = get_envvar("XDG_CONFIG_HOME")
+        .and_then(to_pathbuf)
+        .unwrap_or(default_config_folder);
+    Ok(config_home.join(app_name))
+}
+
+pub fn get_config_home(app_name: &str) -> Result<std::path::PathBuf, HomeDirNotFoundError> {
+    get_config_home_impl(app_name, get_environ, get_home_dir)
+}
+
 pub fn fs_create_dir(path: std::path::PathBuf) -> Result<(), error::Error> {
     std::fs::create_dir(path.clone())?;
     if let Some(username) = get_environ("SUDO_USER") {
@@ -133,7 +340,41 @@ pub fn fs_create_dir(path: std::path::PathBuf) -> Result<(), error::Error> {
 
 pub fn fs_write<C: AsRef<[u8]>>(path: std::path::PathBuf, content: C) -> Result<(), error::Error> {
     std::fs::write(path.clone(), content)?;
-    if let Some(username) = get_environ("SUDO_USER") {
+    if let Some((uid, gid)) = invoking_owner()? {
+        std::os::unix::fs::chown(path, Some(uid), Some(gid))?
+    }
+    Ok(())
+}
+
+/// Runtime directory of the user who invoked sudo, or of the current user otherwise.
+pub fn user_runtime_dir() -> Option<std::path::PathBuf> {
+    match get_environ("SUDO_USER").and_then(|name| get_user_by_name(&name)) {
+        Some(user) => Some(format!("/run/user/{}", user.uid()).into()),
+        None => get_environ("XDG_RUNTIME_DIR").map(Into::into),
+    }
+}
+
+/// Session bus of the user who invoked sudo, or of the current user otherwise.
+#[cfg(feature = "tray")]
+pub fn session_bus_address() -> Option<String> {
+    if get_environ("SUDO_USER").is_none()
+        && let Some(address) = get_environ("DBUS_SESSION_BUS_ADDRESS")
+    {
+        return address.into_string().ok();
+    }
+    user_runtime_dir().map(|dir| format!("unix:path={}/bus", dir.display()))
+}
+
+/// Append `content` to the file, creating it owned by SUDO_USER if missing.
+#[cfg(feature = "http")]
+pub fn fs_append<C: AsRef<[u8]>>(path: std::path::PathBuf, content: C) -> Result<(), error::Error> {
+    let created = !path.exists();
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?
+        .write_all(content.as_ref())?;
+    if created && let Some(username) = get_environ("SUDO_USER") {
         let username_str = username.to_str().ok_or(error::UnCaughtError(
             "Failed to convert username to str".to_string(),
         ))?;
@@ -146,12 +387,114 @@ pub fn fs_write<C: AsRef<[u8]>>(path: std::path::PathBuf, content: C) -> Result<
     Ok(())
 }
 
+/// Owner of the files created for the user who invoked sudo, if any.
+fn invoking_owner() -> Result<Option<(u32, u32)>, error::Error> {
+    let Some(username) = get_environ("SUDO_USER") else {
+        return Ok(None);
+    };
+    let username_str = username.to_str().ok_or(error::UnCaughtError(
+        "Failed to convert username to str".to_string(),
+    ))?;
+    let user = uzers::get_user_by_name(username_str)
+        .ok_or(error::Error::UserNotFound(username_str.to_string()))?;
+    Ok(Some((user.uid(), user.primary_group_id())))
+}
+
+/// Replace the file at `path` through a temporary file next to it, so that an
+/// interrupted write leaves the previous content rather than half of the new one.
+///
+/// An existing file keeps its mode and owner; a new one gets `mode` and is
+/// owned by SUDO_USER, like with `fs_write`.
+pub fn replace_file<C: AsRef<[u8]>>(
+    path: &Path,
+    content: C,
+    mode: u32,
+) -> Result<(), error::Error> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let (mode, owner) = match std::fs::metadata(path) {
+        Ok(metadata) => (
+            metadata.mode() & 0o7777,
+            Some((metadata.uid(), metadata.gid())),
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (mode, invoking_owner()?),
+        Err(e) => return Err(e.into()),
+    };
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    // Unique, so that concurrent writers never share a temporary file.
+    let temporary = path.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        file_name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = (|| {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&temporary)?;
+        file.write_all(content.as_ref())?;
+        if let Some((uid, gid)) = owner {
+            std::os::unix::fs::fchown(&file, Some(uid), Some(gid))?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&temporary, path)
+    })();
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+    }
+    Ok(written?)
+}
+
+/// Exclusive lock on `path`, held until the returned file is dropped, e.g.
+/// around a read-modify-write of a file others may update at the same time.
+pub fn lock_file(path: &Path) -> Result<std::fs::File, error::Error> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o644)
+        .open(path)
+        // Created by another user, but locking only takes reading it.
+        .or_else(|_| std::fs::File::open(path))?;
+    if unsafe { libc::flock(std::os::fd::AsRawFd::as_raw_fd(&file), libc::LOCK_EX) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(file)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::ffi::OsString;
     use std::path::PathBuf;
 
+    #[test]
+    fn quotes_what_the_shell_would_split() {
+        assert_eq!(
+            command_line(&[
+                "sudo",
+                "/usr/bin/wg-waybar",
+                "--signal",
+                "8",
+                "/etc/wireguard"
+            ]),
+            "sudo /usr/bin/wg-waybar --signal 8 /etc/wireguard"
+        );
+        assert_eq!(
+            command_line(&["/home/a/My VPNs/it's.conf", ""]),
+            r"'/home/a/My VPNs/it'\''s.conf' ''"
+        );
+    }
+
+    #[test]
+    fn tells_waybar_from_wg_waybar() {
+        assert!(is_waybar("waybar\n"));
+        assert!(is_waybar(".waybar-wrapped"));
+        assert!(!is_waybar("wg-waybar\n"));
+        assert_eq!(parse_pgrep("812 wg-waybar\n904 waybar\n"), Some(904));
+        assert_eq!(parse_pgrep("812 wg-waybar\n"), None);
+    }
+
     #[test]
     fn returns_xdg_state_home_when_set() {
         let app_name = "myapp";
@@ -196,4 +539,51 @@ mod tests {
 
         assert!(result.is_err());
     }
+
+    #[test]
+    fn returns_config_home_under_home_dir_when_xdg_config_home_not_set() {
+        let app_name = "myapp";
+
+        let get_envvar = |_key: &str| None;
+
+        let get_home_dir_fn = || Ok(PathBuf::from("/home/user"));
+
+        let result = get_config_home_impl(app_name, get_envvar, get_home_dir_fn).unwrap();
+
+        assert_eq!(result, PathBuf::from("/home/user/.config/myapp"));
+    }
+
+    #[test]
+    fn replaces_files_keeping_their_mode() {
+        let dir = std::env::temp_dir().join(format!("wg-waybar-replace-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wg0.conf");
+        replace_file(&path, "old", 0o600).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+        std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o640))
+            .unwrap();
+        replace_file(&path, "new", 0o600).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(std::fs::metadata(&path).unwrap().mode() & 0o777, 0o640);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_main_pid_of_running_units_only() {
+        assert_eq!(parse_main_pid("4242\n"), Some(4242));
+        assert_eq!(parse_main_pid("0\n"), None);
+        assert_eq!(parse_main_pid(""), None);
+    }
+
+    #[test]
+    fn detects_proc_mounted_with_hidepid() {
+        let mounts =
+            "sysfs /sys sysfs rw,nosuid 0 0\nproc /proc proc rw,nosuid,hidepid=invisible 0 0\n";
+        assert!(hides_processes(mounts));
+        assert!(!hides_processes(
+            "proc /proc proc rw,nosuid,hidepid=0 0 0\n"
+        ));
+        assert!(!hides_processes("proc /proc proc rw,nosuid 0 0\n"));
+    }
 }
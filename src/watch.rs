@@ -0,0 +1,210 @@
+use crate::actions::Context;
+use crate::error;
+use crate::format::Formatter;
+use crate::profiles;
+use crate::refresh::{self, Registration};
+use crate::status::{self, StatusReport};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{SignalKind, signal};
+
+/// How often the status is checked: quickly while it changes, slowly once it settled.
+pub struct Polling {
+    pub fast: Duration,
+    pub idle: Duration,
+    /// Time after a change during which `fast` applies.
+    pub settle: Duration,
+}
+
+impl Polling {
+    fn interval(&self, since_change: Duration) -> Duration {
+        if since_change < self.settle {
+            self.fast
+        } else {
+            self.idle
+        }
+    }
+}
+
+/// Hysteresis of the shown state: a new one is only shown once it lasted
+/// `hold`, so that a flapping link does not make the module blink.
+struct Debouncer<T> {
+    hold: Duration,
+    shown: Option<T>,
+    /// The state seen differing from `shown`, and since when.
+    pending: Option<(T, Instant)>,
+}
+
+impl<T: PartialEq> Debouncer<T> {
+    fn new(hold: Duration) -> Self {
+        Debouncer {
+            hold,
+            shown: None,
+            pending: None,
+        }
+    }
+
+    /// Whether `state`, seen at `now`, is the one to show. The first state is
+    /// shown right away.
+    fn observe(&mut self, state: T, now: Instant) -> bool {
+        if self.shown.is_none() || self.shown.as_ref() == Some(&state) {
+            self.pending = None;
+            self.shown = Some(state);
+            return true;
+        }
+        let since = match self.pending.take() {
+            Some((pending, since)) if pending == state => since,
+            _ => now,
+        };
+        if now.duration_since(since) >= self.hold {
+            self.shown = Some(state);
+            true
+        } else {
+            self.pending = Some((state, since));
+            false
+        }
+    }
+
+    /// Show the next state right away, e.g. once a toggle woke the watch up:
+    /// a change asked for is no flapping.
+    fn flush(&mut self) {
+        self.shown = None;
+        self.pending = None;
+    }
+
+    /// When the pending state will have lasted `hold`.
+    fn deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|(_, since)| *since + self.hold)
+    }
+}
+
+/// Print the status of the shown profile on start and on every change.
+///
+/// The profile is selected again on each check, so switching profiles of a
+/// directory is followed. SIGUSR1, or a datagram to the socket registered in the
+/// runtime directory, triggers a check right away, e.g. from the command toggling
+/// the tunnel. A new status is only printed once it lasted
+/// `hold`, changes of the text or tooltip of the same status and the status
+/// seen after such a wake-up right away; `events` still reports every transition.
+pub async fn run(
+    ctx: &Context<'_>,
+    config_path: &Path,
+    netns: Option<&str>,
+    formatter: &Formatter,
+    polling: &Polling,
+    hold: Duration,
+) -> Result<(), error::Error> {
+    let mut signals = signal(SignalKind::user_defined1())?;
+    let (waker, mut wake) = tokio::sync::mpsc::unbounded_channel();
+    let signal_waker = waker.clone();
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() && signal_waker.send(()).is_ok() {}
+    });
+    // Kept until the loop ends, which removes the socket.
+    let registration = match Registration::bind(refresh::WATCHES) {
+        Ok(registration) => Some(registration),
+        Err(e) => {
+            if ctx.debug {
+                println!("Not registering the watch: {}", e);
+            }
+            None
+        }
+    };
+    if let Some(socket) = registration
+        .as_ref()
+        .map(Registration::try_clone)
+        .transpose()?
+    {
+        std::thread::spawn(
+            move || {
+                while socket.recv(&mut [0; 1]).is_ok() && waker.send(()).is_ok() {}
+            },
+        );
+    }
+    formatter.begin_stream()?;
+    let mut changed = Instant::now();
+    let mut previous = None;
+    let mut debouncer = Debouncer::new(hold);
+    loop {
+        let (name, report) = tokio::task::block_in_place(|| {
+            match profiles::select(config_path, netns, ctx.profile_filter) {
+                Ok(profile) => {
+                    let report = status::report_profile(
+                        &profile,
+                        ctx.store,
+                        &(ctx.options_for)(&profile.name),
+                    )
+                    .unwrap_or_else(|e| {
+                        StatusReport::error(format!("Failed to check VPN status: {}", e))
+                    });
+                    (profile.name, report)
+                }
+                Err(e) => (
+                    String::new(),
+                    StatusReport::error(format!("Failed to parse interface name: {}", e)),
+                ),
+            }
+        });
+        let settled = debouncer.observe((name.clone(), report.status), Instant::now());
+        let current = Some((name, report));
+        if settled {
+            if current != previous
+                && let Some((name, report)) = &current
+            {
+                formatter.output_update(name, report, previous.is_none())?;
+                changed = Instant::now();
+            }
+            previous = current;
+        }
+        let mut interval = polling.interval(changed.elapsed());
+        if let Some(deadline) = debouncer.deadline() {
+            interval = interval.min(deadline.saturating_duration_since(Instant::now()));
+        }
+        if tokio::time::timeout(interval, wake.recv()).await.is_ok() {
+            changed = Instant::now();
+            debouncer.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slows_down_once_settled() {
+        let polling = Polling {
+            fast: Duration::from_secs(1),
+            idle: Duration::from_secs(10),
+            settle: Duration::from_secs(30),
+        };
+        assert_eq!(polling.interval(Duration::from_secs(5)), polling.fast);
+        assert_eq!(polling.interval(Duration::from_secs(30)), polling.idle);
+    }
+
+    #[test]
+    fn shows_a_new_state_once_it_lasted() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut debouncer = Debouncer::new(Duration::from_millis(500));
+        assert!(debouncer.observe("up", at(0)));
+        // A flap shorter than the hold is never shown.
+        assert!(!debouncer.observe("down", at(100)));
+        assert!(debouncer.observe("up", at(300)));
+        assert_eq!(debouncer.deadline(), None);
+
+        assert!(!debouncer.observe("down", at(1000)));
+        assert_eq!(debouncer.deadline(), Some(at(1500)));
+        assert!(!debouncer.observe("down", at(1400)));
+        assert!(debouncer.observe("down", at(1500)));
+
+        // Woken up by a toggle: no hold.
+        assert!(!debouncer.observe("up", at(2000)));
+        debouncer.flush();
+        assert!(debouncer.observe("up", at(2001)));
+
+        let mut immediate = Debouncer::new(Duration::ZERO);
+        assert!(immediate.observe("up", at(0)));
+        assert!(immediate.observe("down", at(0)));
+    }
+}
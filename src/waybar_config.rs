@@ -0,0 +1,210 @@
+use crate::error;
+use crate::paths;
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The signal adopted from a Waybar config, kept until the config or
+/// `--signal` changes so that polls neither parse it nor warn again.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Adopted {
+    config: PathBuf,
+    /// Modification time of the config, in nanoseconds.
+    modified: u128,
+    given: i32,
+    signal: i32,
+}
+
+fn cache_path() -> Result<PathBuf, error::Error> {
+    let dir = match paths::runtime_dir() {
+        Some(dir) => dir,
+        None => paths::state_dir()?,
+    };
+    Ok(dir.join("waybar-signal.json"))
+}
+
+fn modified(path: &Path) -> Result<u128, error::Error> {
+    Ok(std::fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos())
+}
+
+/// JSONC as Waybar reads it: without `//` and `/* */` comments and trailing commas.
+fn strip_jsonc(text: &str) -> String {
+    let mut json = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            json.push(c);
+            match c {
+                '\\' => json.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                json.push(c);
+            }
+            ('/', Some('/')) => while chars.next_if(|&c| c != '\n').is_some() {},
+            ('/', Some('*')) => {
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+            }
+            _ => json.push(c),
+        }
+    }
+    drop_trailing_commas(&json)
+}
+
+/// Commas followed by nothing but whitespace before a closing bracket.
+fn drop_trailing_commas(json: &str) -> String {
+    let mut kept = String::with_capacity(json.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in json.char_indices() {
+        if in_string {
+            in_string = escaped || c != '"';
+            escaped = !escaped && c == '\\';
+        } else if c == '"' {
+            in_string = true;
+        } else if c == ',' && json[i + 1..].trim_start().starts_with(['}', ']']) {
+            continue;
+        }
+        kept.push(c);
+    }
+    kept
+}
+
+/// `(module, signal)` of the custom modules running wg-waybar, in every bar.
+fn module_signals(config: &Value) -> Vec<(String, i64)> {
+    let bars = match config {
+        Value::Array(bars) => bars.iter().collect(),
+        bar => vec![bar],
+    };
+    let mut signals = Vec::new();
+    for bar in bars.into_iter().filter_map(Value::as_object) {
+        for (name, module) in bar {
+            let runs_us = module
+                .get("exec")
+                .and_then(Value::as_str)
+                .is_some_and(|exec| exec.contains("wg-waybar"));
+            if name.starts_with("custom/")
+                && runs_us
+                && let Some(signal) = module.get("signal").and_then(Value::as_i64)
+            {
+                signals.push((name.clone(), signal));
+            }
+        }
+    }
+    signals
+}
+
+/// The signal the Waybar config gives the wg-waybar module, warning when it
+/// differs from `signal_num`, which is kept when the config has none.
+///
+/// The config is read again, and the warnings repeated, only once it changed.
+pub fn adopt_signal(path: &Path, signal_num: i32) -> Result<i32, error::Error> {
+    let config = std::fs::canonicalize(path)?;
+    let modified = modified(&config)?;
+    let cache = cache_path()?;
+    if let Some(adopted) = std::fs::read_to_string(&cache)
+        .ok()
+        .and_then(|content| serde_json::from_str::<Adopted>(&content).ok())
+        && adopted.config == config
+        && adopted.modified == modified
+        && adopted.given == signal_num
+    {
+        return Ok(adopted.signal);
+    }
+    let signal = read_signal(path, signal_num);
+    // A config that cannot be read is only reported once as well.
+    let adopted = Adopted {
+        config,
+        modified,
+        given: signal_num,
+        signal: *signal.as_ref().unwrap_or(&signal_num),
+    };
+    // Parsed again next time if it cannot be kept.
+    if let Some(dir) = cache.parent()
+        && paths::ensure(dir.to_path_buf()).is_ok()
+    {
+        let _ = utils::replace_file(&cache, serde_json::to_string(&adopted)?, 0o644);
+    }
+    signal
+}
+
+fn read_signal(path: &Path, signal_num: i32) -> Result<i32, error::Error> {
+    let text = std::fs::read_to_string(path)?;
+    let config: Value = serde_json::from_str(&strip_jsonc(&text))?;
+    let signals = module_signals(&config);
+    let Some((module, signal)) = signals.first() else {
+        eprintln!(
+            "No custom module running wg-waybar with a signal in {}, keeping --signal {}",
+            path.display(),
+            signal_num
+        );
+        return Ok(signal_num);
+    };
+    if signals.iter().any(|(_, other)| other != signal) {
+        eprintln!(
+            "Warning: the wg-waybar modules of {} use different signals, using {} of {}",
+            path.display(),
+            signal,
+            module
+        );
+    }
+    let signal = i32::try_from(*signal).map_err(|_| error::Error::InvalidFormat {
+        message: format!("signal {} of {} is out of range", signal, module),
+    })?;
+    if signal != signal_num {
+        eprintln!(
+            "Warning: {} in {} has signal {}, not --signal {}; using {}",
+            module,
+            path.display(),
+            signal,
+            signal_num,
+            signal
+        );
+    }
+    Ok(signal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_signal_of_the_module_in_jsonc() {
+        let config = r#"
+        // Top bar
+        {
+            "modules-right": ["custom/vpn", "clock"],
+            /* "custom/old": {"exec": "wg-waybar", "signal": 1}, */
+            "custom/vpn": {
+                "exec": "sudo /usr/local/bin/wg-waybar --signal 9 /etc/wireguard // not a comment",
+                "signal": 8,
+            },
+            "custom/other": {"exec": "date", "signal": 3},
+        }"#;
+        let config: Value = serde_json::from_str(&strip_jsonc(config)).unwrap();
+        assert_eq!(module_signals(&config), [("custom/vpn".to_string(), 8)]);
+        assert_eq!(
+            module_signals(&Value::Array(vec![config.clone(), config])).len(),
+            2
+        );
+    }
+}
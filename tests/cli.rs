@@ -0,0 +1,132 @@
+//! The command line against `--backend mock`, which needs neither WireGuard nor
+//! root, so that these run anywhere.
+
+use serde_json::Value;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+const PROFILE: &str = "[Interface]
+PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+Address = 10.0.0.2/32
+
+[Peer]
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+Endpoint = 192.0.2.1:51820
+AllowedIPs = 0.0.0.0/0
+";
+
+/// A profile directory and XDG directories of its own, removed when dropped.
+struct Sandbox {
+    dir: PathBuf,
+}
+
+impl Sandbox {
+    fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("wg-waybar-cli-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for sub in ["profiles", "run", "state", "config/wg-waybar"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        let profile = dir.join("profiles/wg0.conf");
+        fs::write(&profile, PROFILE).unwrap();
+        fs::set_permissions(&profile, fs::Permissions::from_mode(0o600)).unwrap();
+        // Touch a file rather than signal whatever Waybar runs on this machine.
+        fs::write(
+            dir.join("config/wg-waybar/config.toml"),
+            format!(
+                "[refresh]\nsinks = [\"file\"]\nfile = \"{}\"\n",
+                dir.join("stamp").display()
+            ),
+        )
+        .unwrap();
+        Sandbox { dir }
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_wg-waybar"))
+            .arg("--backend")
+            .arg("mock")
+            // Toggle back right away.
+            .arg("--min-toggle-interval=0")
+            .arg(self.dir.join("profiles/wg0.conf"))
+            .args(args)
+            .env_remove("SUDO_USER")
+            .env("XDG_RUNTIME_DIR", self.dir.join("run"))
+            .env("XDG_STATE_HOME", self.dir.join("state"))
+            .env("XDG_CONFIG_HOME", self.dir.join("config"))
+            .output()
+            .unwrap()
+    }
+
+    fn status(&self) -> Value {
+        let output = self.run(&[]);
+        assert!(output.status.success(), "{:?}", output);
+        serde_json::from_slice(&output.stdout).unwrap()
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn toggles_a_simulated_tunnel() {
+    let sandbox = Sandbox::new("toggle");
+    assert_eq!(sandbox.status()["class"], "disconnected");
+
+    let output = sandbox.run(&["toggle"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(sandbox.dir.join("stamp").exists());
+    assert_ne!(sandbox.status()["class"], "disconnected");
+
+    let output = sandbox.run(&["toggle"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(sandbox.status()["class"], "disconnected");
+}
+
+#[test]
+fn lists_the_profiles_up() {
+    let sandbox = Sandbox::new("list");
+    let list = |sandbox: &Sandbox| {
+        let output = sandbox.run(&["list"]);
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert!(!list(&sandbox).contains('*'));
+    assert!(sandbox.run(&["toggle"]).status.success());
+    assert!(list(&sandbox).contains("* wg0"));
+}
+
+#[test]
+fn shows_the_status_despite_a_malformed_app_config() {
+    let sandbox = Sandbox::new("malformed");
+    fs::write(
+        sandbox.dir.join("config/wg-waybar/config.toml"),
+        "[refresh\n",
+    )
+    .unwrap();
+    let status = sandbox.status();
+    assert_eq!(status["class"], "disconnected");
+    assert!(
+        status["tooltip"]
+            .as_str()
+            .unwrap()
+            .contains("Invalid app config")
+    );
+}
+
+#[test]
+fn waits_for_the_first_handshake() {
+    let sandbox = Sandbox::new("handshake");
+    // The simulated peer answers after a second.
+    let output = sandbox.run(&["--handshake-timeout", "5", "toggle"]);
+    assert!(output.status.success(), "{:?}", output);
+    let status = sandbox.status();
+    assert_eq!(status["class"], "connected");
+    assert!(status["percentage"].as_u64().unwrap() > 0);
+}